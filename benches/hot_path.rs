@@ -0,0 +1,148 @@
+//! Benchmarks for the hot path: framing, reading frames off a connection, fanning a broadcast
+//! out to many connections and draining their send queues.
+//!
+//! Run with `cargo bench`. Connections use the scripted `MockTransport` so the numbers measure
+//! mob itself rather than the kernel.
+
+#[macro_use] extern crate criterion;
+extern crate mio;
+extern crate mob;
+
+use std::sync::Arc;
+
+use criterion::{black_box, BatchSize, BenchmarkId, Criterion, Throughput};
+use mio::Token;
+
+use mob::codec;
+use mob::connection::Connection;
+use mob::transport::mock::{MockTransport, ReadStep, WriteStep};
+
+const SIZES: &[usize] = &[16, 256, 4096, 65536];
+const FAN_OUT: &[usize] = &[10, 100, 1000];
+const QUEUE_DEPTH: usize = 64;
+
+fn payload(size: usize) -> Vec<u8> {
+    (0..size).map(|i| i as u8).collect()
+}
+
+/// A connection whose first write blocks, so everything sent to it stays queued.
+fn blocked_connection(token: usize) -> Connection<MockTransport> {
+    let mut sock = MockTransport::new();
+    sock.push_write(WriteStep::WouldBlock);
+    Connection::new(sock, Token(token))
+}
+
+fn bench_codec(c: &mut Criterion) {
+    let mut group = c.benchmark_group("codec");
+
+    for &size in SIZES {
+        let data = payload(size);
+        let frame = codec::encode(&data);
+        group.throughput(Throughput::Bytes(size as u64));
+
+        group.bench_with_input(BenchmarkId::new("encode", size), &data, |b, data| {
+            b.iter(|| codec::encode(black_box(data)))
+        });
+
+        group.bench_with_input(BenchmarkId::new("decode", size), &frame, |b, frame| {
+            b.iter(|| codec::decode(black_box(frame)))
+        });
+    }
+
+    group.finish();
+}
+
+fn bench_read(c: &mut Criterion) {
+    let mut group = c.benchmark_group("connection_read");
+
+    for &size in SIZES {
+        let mut bytes = Vec::new();
+        for _ in 0..QUEUE_DEPTH {
+            bytes.extend(codec::encode(&payload(size)));
+        }
+        group.throughput(Throughput::Bytes((size * QUEUE_DEPTH) as u64));
+
+        group.bench_with_input(BenchmarkId::from_parameter(size), &bytes, |b, bytes| {
+            b.iter_batched(
+                || {
+                    let mut sock = MockTransport::new();
+                    sock.push_read(ReadStep::Data(bytes.clone()));
+                    Connection::new(sock, Token(0))
+                },
+                |mut conn| {
+                    while let Some(message) = conn.readable().unwrap() {
+                        black_box(message);
+                    }
+                },
+                BatchSize::SmallInput,
+            )
+        });
+    }
+
+    group.finish();
+}
+
+fn bench_fan_out(c: &mut Criterion) {
+    let mut group = c.benchmark_group("broadcast_fan_out");
+    let message = Arc::new(payload(256));
+
+    for &n in FAN_OUT {
+        group.throughput(Throughput::Elements(n as u64));
+
+        group.bench_with_input(BenchmarkId::from_parameter(n), &n, |b, &n| {
+            b.iter_batched(
+                || {
+                    // Every connection already has a message stuck in its queue, so the
+                    // broadcast only enqueues.
+                    let mut conns: Vec<_> = (0..n).map(blocked_connection).collect();
+                    for conn in conns.iter_mut() {
+                        conn.send_message(message.clone()).unwrap();
+                    }
+                    conns
+                },
+                |mut conns| {
+                    for conn in conns.iter_mut() {
+                        conn.send_message(message.clone()).unwrap();
+                    }
+                    conns
+                },
+                BatchSize::SmallInput,
+            )
+        });
+    }
+
+    group.finish();
+}
+
+fn bench_send_queue(c: &mut Criterion) {
+    let mut group = c.benchmark_group("send_queue_drain");
+
+    for &size in SIZES {
+        let message = Arc::new(payload(size));
+        group.throughput(Throughput::Bytes((size * QUEUE_DEPTH) as u64));
+
+        group.bench_with_input(BenchmarkId::from_parameter(size), &message, |b, message| {
+            b.iter_batched(
+                || {
+                    let mut conn = blocked_connection(0);
+                    for _ in 0..QUEUE_DEPTH {
+                        conn.send_message(message.clone()).unwrap();
+                    }
+                    conn
+                },
+                |mut conn| {
+                    while conn.is_writable() {
+                        conn.writable().unwrap();
+                    }
+                    conn
+                },
+                BatchSize::SmallInput,
+            )
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_codec, bench_read, bench_fan_out, bench_send_queue);
+criterion_main!(benches);
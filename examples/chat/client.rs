@@ -0,0 +1,218 @@
+//! The client half of the chat example, a terminal chat with rooms and nicknames.
+//!
+//! mob broadcasts every message to every client, so rooms, nicknames and presence are all kept by
+//! the clients, in what they say to each other. Every message is a line of text, a verb, the room
+//! it is for, the nickname of whoever sent it, and then whatever else the verb needs:
+//!
+//! ```text
+//! say lobby alice hello everyone
+//! join lobby bob
+//! nick lobby bob robert
+//! who lobby robert
+//! here lobby alice
+//! leave lobby robert
+//! ```
+//!
+//! Everyone sees everything, and shows only what is for the room they are in. `who` asks everyone
+//! in a room to answer with `here`. None of this is private, and anyone can claim any nickname.
+//!
+//! ```text
+//! cargo run --example chat [host:port] [nickname]
+//! ```
+
+extern crate mob_client;
+
+use std::env;
+use std::io::{self, BufRead, ErrorKind, Write};
+use std::process;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use mob_client::codec::Frame;
+use mob_client::{Client, Inbox, Outbox};
+
+const HELP: &str = "\
+/nick <name>   change your nickname
+/join <room>   leave this room for another
+/who           list who is in this room
+/quit          leave
+anything else is said to the room";
+
+/// Where we are and who we are, shared between typing and reading.
+struct Me {
+    room: String,
+    nick: String,
+
+    // we have said we are leaving, and are waiting for the server to pass it on
+    quitting: bool,
+}
+
+/// A message between chat clients.
+struct Line<'a> {
+    verb: &'a str,
+    room: &'a str,
+    nick: &'a str,
+    rest: &'a str,
+}
+
+impl<'a> Line<'a> {
+    fn parse(text: &'a str) -> Option<Line<'a>> {
+        let mut parts = text.splitn(4, ' ');
+        Some(Line {
+            verb: parts.next()?,
+            room: parts.next()?,
+            nick: parts.next()?,
+            rest: parts.next().unwrap_or(""),
+        })
+    }
+}
+
+/// Send one chat message, for `me`'s room and from `me`.
+fn send(outbox: &Outbox, me: &Me, verb: &str, rest: &str) -> io::Result<()> {
+    let mut text = format!("{} {} {}", verb, me.room, me.nick);
+    if !rest.is_empty() {
+        text.push(' ');
+        text.push_str(rest);
+    }
+    outbox.send(text.as_bytes())
+}
+
+/// Rooms and nicknames are words, since messages are split on spaces.
+fn is_word(s: &str) -> bool {
+    !s.is_empty() && !s.contains(char::is_whitespace)
+}
+
+/// Show what the server says about itself. A server that says nothing is fine too.
+fn greet(client: &mut Client) -> io::Result<()> {
+    client.set_read_timeout(Some(Duration::from_secs(2)))?;
+    loop {
+        match client.recv_frame() {
+            Ok(Some(Frame::Welcome(welcome))) => {
+                println!("connected to {}", welcome.name);
+                if !welcome.motd.is_empty() {
+                    println!("{}", welcome.motd);
+                }
+                break;
+            }
+            Ok(Some(_)) => continue,
+            Ok(None) => return Err(io::Error::new(ErrorKind::UnexpectedEof, "Server hung up")),
+            Err(ref e) if e.kind() == ErrorKind::TimedOut => break,
+            Err(e) => return Err(e),
+        }
+    }
+    client.set_read_timeout(None)
+}
+
+/// Show the messages for our room as they arrive, and answer `who` for it. Returns once our own
+/// `leave` comes back, after `quitting` is set.
+fn read(inbox: Inbox, outbox: Outbox, me: Arc<Mutex<Me>>) {
+    loop {
+        let payload = match inbox.recv() {
+            Ok(Some(payload)) => payload,
+            Ok(None) => {
+                println!("* server closed the connection");
+                process::exit(0);
+            }
+            Err(e) => {
+                println!("* error: {}", e);
+                process::exit(1);
+            }
+        };
+
+        let text = String::from_utf8_lossy(&payload);
+        let line = match Line::parse(&text) {
+            Some(line) => line,
+            None => continue,
+        };
+
+        let me = me.lock().unwrap();
+        if line.room != me.room {
+            continue;
+        }
+        match line.verb {
+            "say" => println!("<{}> {}", line.nick, line.rest),
+            "join" => println!("* {} joined {}", line.nick, line.room),
+            "leave" if me.quitting && line.nick == me.nick => return,
+            "leave" => println!("* {} left {}", line.nick, line.room),
+            "nick" => println!("* {} is now {}", line.nick, line.rest),
+            "who" => {
+                if let Err(e) = send(&outbox, &me, "here", "") {
+                    println!("* error: {}", e);
+                }
+            }
+            "here" => println!("* {} is here", line.nick),
+            _ => {}
+        }
+    }
+}
+
+fn main() {
+    let mut args = env::args().skip(1);
+    let addr = args.next().unwrap_or_else(|| "127.0.0.1:8000".to_string());
+    let nick = args.next().unwrap_or_else(|| format!("guest{}", process::id() % 10_000));
+    if !is_word(&nick) {
+        eprintln!("a nickname is one word");
+        process::exit(2);
+    }
+
+    if let Err(e) = run(&addr, nick) {
+        eprintln!("error: {}", e);
+        process::exit(1);
+    }
+}
+
+fn run(addr: &str, nick: String) -> io::Result<()> {
+    let mut client = Client::connect(addr)?;
+    greet(&mut client)?;
+    let (outbox, inbox) = client.split()?;
+
+    let me = Arc::new(Mutex::new(Me { room: "lobby".to_string(), nick, quitting: false }));
+    {
+        let me = me.lock().unwrap();
+        println!("you are {} in {}, /help for commands", me.nick, me.room);
+        send(&outbox, &me, "join", "")?;
+    }
+
+    let reader = {
+        let outbox = outbox.clone();
+        let me = me.clone();
+        thread::spawn(move || read(inbox, outbox, me))
+    };
+
+    let stdin = io::stdin();
+    for line in stdin.lock().lines() {
+        let line = line?;
+        let mut words = line.split_whitespace();
+        let mut me = me.lock().unwrap();
+        match (words.next(), words.next()) {
+            (Some("/quit"), _) => break,
+            (Some("/help"), _) => println!("{}", HELP),
+            (Some("/who"), _) => send(&outbox, &me, "who", "")?,
+            (Some("/nick"), Some(new)) if is_word(new) => {
+                send(&outbox, &me, "nick", new)?;
+                me.nick = new.to_string();
+            }
+            (Some("/join"), Some(room)) if is_word(room) => {
+                send(&outbox, &me, "leave", "")?;
+                me.room = room.to_string();
+                send(&outbox, &me, "join", "")?;
+            }
+            (Some(command), _) if command.starts_with('/') => {
+                println!("* not sure what {} means, try /help", line.trim());
+            }
+            (None, _) => {}
+            _ => send(&outbox, &me, "say", &line)?,
+        }
+        io::stdout().flush()?;
+    }
+
+    // Wait for our leave to come back, so everyone else has had it too.
+    {
+        let mut me = me.lock().unwrap();
+        me.quitting = true;
+        send(&outbox, &me, "leave", "")?;
+    }
+    reader.join().unwrap();
+    Ok(())
+}
@@ -0,0 +1,56 @@
+//! The server half of the chat example: a mob server set up for people typing at each other.
+//!
+//! mob has no rooms or nicknames of its own, so the clients keep track of those, see `chat`. All
+//! the server needs to do is take text, say hello, and not let anyone flood the others.
+//!
+//! ```text
+//! cargo run --example chat-server [host:port]
+//! ```
+
+extern crate mio;
+extern crate mob;
+
+use std::env;
+use std::net::TcpListener;
+use std::time::Duration;
+
+use mio::Poll;
+
+use mob::codec::Welcome;
+use mob::filter::{Action, Filters};
+use mob::limit::AcceptLimit;
+use mob::server::Server;
+
+fn main() {
+    let addr = env::args().nth(1).unwrap_or_else(|| "127.0.0.1:8000".to_string());
+    let listener = TcpListener::bind(&addr).expect("Failed to bind address");
+
+    let mut server = Server::from_listener(listener).expect("Failed to create server");
+
+    // Chat is text, and a line nobody could read in one go is a mistake or worse. Rejecting
+    // instead of dropping tells the sender why.
+    server.set_text_only(true);
+    let mut filters = Filters::new();
+    filters.max_len = Some(4096);
+    filters.action = Action::Reject;
+    server.set_filters(filters);
+
+    // Tell clients who we are as they connect, and how often to ping so a dropped laptop does
+    // not linger in everyone's room.
+    server.set_greeting(true);
+    server.set_welcome(Some(Welcome {
+        name: "mob chat".to_string(),
+        heartbeat: Some(Duration::from_secs(30)),
+        motd: "be nice".to_string(),
+        max_payload: 4096,
+        ..Welcome::default()
+    }));
+
+    // Someone typing a lot should not hold everyone else up, nor someone reconnecting in a loop.
+    server.set_high_watermark(Some(1024 * 1024));
+    server.set_accept_limit(Some(AcceptLimit { per_minute: 30, ban: Duration::from_secs(60) }));
+
+    println!("chat server on {}", addr);
+    let mut poll = Poll::new().expect("Failed to create Poll");
+    server.run(&mut poll).expect("Failed to run server");
+}
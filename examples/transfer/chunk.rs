@@ -0,0 +1,108 @@
+//! The messages a file is sent as, shared by `send-file` and `receive-file`.
+//!
+//! A transfer is a `Start`, the file in `Chunk`s numbered from zero, and an `End` with how many
+//! chunks there were and a hash of everything in them. Every message starts with `MAGIC`, so
+//! anything else broadcast on the same server is told apart and ignored.
+
+// Each example uses only part of this.
+#![allow(dead_code)]
+
+use std::io;
+use std::str;
+
+pub const MAGIC: &[u8] = b"MOBF";
+
+/// How much of the file goes in each chunk.
+pub const CHUNK_LEN: usize = 64 * 1024;
+
+#[derive(Debug, PartialEq)]
+pub enum Message<'a> {
+    Start { id: u64, len: u64, name: &'a str },
+    Chunk { id: u64, seq: u64, data: &'a [u8] },
+    End { id: u64, chunks: u64, hash: u64 },
+}
+
+impl<'a> Message<'a> {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = MAGIC.to_vec();
+        match *self {
+            Message::Start { id, len, name } => {
+                buf.push(0);
+                buf.extend_from_slice(&id.to_be_bytes());
+                buf.extend_from_slice(&len.to_be_bytes());
+                buf.extend_from_slice(name.as_bytes());
+            }
+            Message::Chunk { id, seq, data } => {
+                buf.push(1);
+                buf.extend_from_slice(&id.to_be_bytes());
+                buf.extend_from_slice(&seq.to_be_bytes());
+                buf.extend_from_slice(data);
+            }
+            Message::End { id, chunks, hash } => {
+                buf.push(2);
+                buf.extend_from_slice(&id.to_be_bytes());
+                buf.extend_from_slice(&chunks.to_be_bytes());
+                buf.extend_from_slice(&hash.to_be_bytes());
+            }
+        }
+        buf
+    }
+
+    /// The message in `buf`, or `None` if it is not one of ours.
+    pub fn decode(buf: &'a [u8]) -> Option<Message<'a>> {
+        let rest = buf.strip_prefix(MAGIC)?;
+        let (&kind, rest) = rest.split_first()?;
+        if rest.len() < 16 {
+            return None;
+        }
+        let id = u64_at(rest, 0);
+        let second = u64_at(rest, 8);
+        let rest = &rest[16..];
+        match kind {
+            0 => Some(Message::Start { id, len: second, name: str::from_utf8(rest).ok()? }),
+            1 => Some(Message::Chunk { id, seq: second, data: rest }),
+            2 if rest.len() == 8 => {
+                Some(Message::End { id, chunks: second, hash: u64_at(rest, 0) })
+            }
+            _ => None,
+        }
+    }
+
+    pub fn id(&self) -> u64 {
+        match *self {
+            Message::Start { id, .. } | Message::Chunk { id, .. } | Message::End { id, .. } => id,
+        }
+    }
+}
+
+fn u64_at(buf: &[u8], at: usize) -> u64 {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&buf[at..at + 8]);
+    u64::from_be_bytes(bytes)
+}
+
+/// A 64 bit FNV-1a hash, fed a chunk at a time.
+pub struct Hash(u64);
+
+impl Default for Hash {
+    fn default() -> Hash {
+        Hash(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Hash {
+    pub fn update(&mut self, data: &[u8]) {
+        for &b in data {
+            self.0 ^= b as u64;
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    pub fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+pub fn invalid(what: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, what)
+}
@@ -0,0 +1,140 @@
+//! Receive the files `send-file` sends through a mob server, into a directory.
+//!
+//! Any number of transfers can be under way at once, from any number of senders. A transfer that
+//! loses a chunk, because the server skipped us for being too far behind or for any other
+//! reason, is abandoned and its part written file deleted. The rest carry on.
+//!
+//! ```text
+//! cargo run --example receive-file <host:port> [dir]
+//! ```
+
+extern crate mob_client;
+
+mod chunk;
+
+use std::collections::HashMap;
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+
+use mob_client::Client;
+use mob_client::codec::Frame;
+
+use chunk::{Hash, Message};
+
+/// A file being received.
+struct Transfer {
+    path: PathBuf,
+    file: File,
+    len: u64,
+    next_seq: u64,
+    hash: Hash,
+}
+
+impl Transfer {
+    fn start(dir: &Path, len: u64, name: &str) -> io::Result<Transfer> {
+        // Only ever write into `dir`, whatever the sender called the file.
+        let name = Path::new(name).file_name()
+            .ok_or_else(|| chunk::invalid(format!("unusable file name {:?}", name)))?;
+        let path = dir.join(name);
+        let file = File::create(&path)?;
+        Ok(Transfer { path, file, len, next_seq: 0, hash: Hash::default() })
+    }
+
+    fn chunk(&mut self, seq: u64, data: &[u8]) -> io::Result<()> {
+        if seq != self.next_seq {
+            return Err(chunk::invalid(format!("expected chunk {}, got {}", self.next_seq, seq)));
+        }
+        self.next_seq += 1;
+        self.hash.update(data);
+        self.file.write_all(data)
+    }
+
+    fn end(&mut self, chunks: u64, hash: u64) -> io::Result<()> {
+        if chunks != self.next_seq || hash != self.hash.finish() {
+            return Err(chunk::invalid("chunks missing or damaged".to_string()));
+        }
+        self.file.sync_all()?;
+        let written = self.file.metadata()?.len();
+        if written != self.len {
+            return Err(chunk::invalid(format!("{} bytes of {}", written, self.len)));
+        }
+        Ok(())
+    }
+}
+
+fn receive(addr: &str, dir: &Path) -> io::Result<()> {
+    let mut client = Client::connect(addr)?;
+    let mut transfers: HashMap<u64, Transfer> = HashMap::new();
+    println!("receiving into {}", dir.display());
+
+    loop {
+        let payload = match client.recv_frame()? {
+            Some(Frame::Data(payload)) => payload,
+            Some(Frame::Missed(n)) => {
+                // Whatever was missed, every transfer under way may have lost a chunk to it.
+                for (_, t) in transfers.drain() {
+                    eprintln!("abandoned {}, {} messages were skipped", t.path.display(), n);
+                    let _ = fs::remove_file(&t.path);
+                }
+                continue;
+            }
+            Some(_) => continue,
+            None => return Ok(()),
+        };
+        let message = match Message::decode(&payload) {
+            Some(message) => message,
+            None => continue,
+        };
+
+        let id = message.id();
+        let result = match message {
+            Message::Start { len, name, .. } => {
+                Transfer::start(dir, len, name).map(|t| {
+                    println!("receiving {} ({} bytes)", t.path.display(), len);
+                    transfers.insert(id, t);
+                })
+            }
+            Message::Chunk { seq, data, .. } => match transfers.get_mut(&id) {
+                Some(t) => t.chunk(seq, data),
+                None => Ok(()),
+            },
+            Message::End { chunks, hash, .. } => match transfers.remove(&id) {
+                Some(mut t) => match t.end(chunks, hash) {
+                    Ok(()) => {
+                        println!("received {}", t.path.display());
+                        Ok(())
+                    }
+                    Err(e) => {
+                        let _ = fs::remove_file(&t.path);
+                        Err(e)
+                    }
+                },
+                None => Ok(()),
+            },
+        };
+
+        if let Err(e) = result {
+            eprintln!("abandoned transfer {:x}, {}", id, e);
+            if let Some(t) = transfers.remove(&id) {
+                let _ = fs::remove_file(&t.path);
+            }
+        }
+    }
+}
+
+fn main() {
+    let mut args = env::args().skip(1);
+    let addr = args.next().unwrap_or_else(|| {
+        eprintln!("usage: receive-file <host:port> [dir]");
+        process::exit(2);
+    });
+    let dir = PathBuf::from(args.next().unwrap_or_else(|| ".".to_string()));
+
+    if let Err(e) = receive(&addr, &dir) {
+        eprintln!("error: {}", e);
+        process::exit(1);
+    }
+}
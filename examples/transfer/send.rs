@@ -0,0 +1,102 @@
+//! Send a file through a mob server to every `receive-file` connected to it.
+//!
+//! The file goes as a `Start`, then chunks, then an `End`, see `chunk`. The server broadcasts
+//! each one back to us too, which is how we know it got that far. At most `WINDOW` chunks are out
+//! at a time, so a big file waits on the server instead of piling up in its queues.
+//!
+//! ```text
+//! cargo run --example send-file <host:port> <path>
+//! ```
+
+extern crate mob_client;
+
+mod chunk;
+
+use std::collections::hash_map::RandomState;
+use std::env;
+use std::fs::File;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, ErrorKind, Read};
+use std::path::Path;
+use std::process;
+use std::time::Instant;
+
+use mob_client::Client;
+use mob_client::codec::Frame;
+
+use chunk::{Hash, Message, CHUNK_LEN};
+
+/// The most chunks sent that have not come back yet.
+const WINDOW: u64 = 16;
+
+/// Wait for our own messages to come back until no more than `most` are out. Returns how many
+/// are still out.
+fn drain(client: &mut Client, id: u64, mut out: u64, most: u64) -> io::Result<u64> {
+    while out > most {
+        match client.recv_frame()? {
+            Some(Frame::Data(payload)) => {
+                if Message::decode(&payload).map(|m| m.id()) == Some(id) {
+                    out -= 1;
+                }
+            }
+            Some(Frame::Missed(n)) => {
+                return Err(chunk::invalid(format!("the server skipped {} of our messages", n)));
+            }
+            Some(Frame::Error(reason)) | Some(Frame::Close(reason)) => {
+                return Err(io::Error::other(format!("the server said {}", reason)));
+            }
+            Some(_) => {}
+            None => return Err(io::Error::new(ErrorKind::UnexpectedEof, "Server hung up")),
+        }
+    }
+    Ok(out)
+}
+
+fn send(addr: &str, path: &Path) -> io::Result<()> {
+    let mut file = File::open(path)?;
+    let len = file.metadata()?.len();
+    let name = path.file_name().and_then(|n| n.to_str())
+        .ok_or_else(|| chunk::invalid(format!("{} has no usable file name", path.display())))?;
+
+    let mut client = Client::connect(addr)?;
+    let id = RandomState::new().build_hasher().finish();
+    let started = Instant::now();
+
+    client.send(&Message::Start { id, len, name }.encode())?;
+    let mut out = 1;
+
+    let mut buf = vec![0u8; CHUNK_LEN];
+    let mut hash = Hash::default();
+    let mut seq = 0;
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hash.update(&buf[..n]);
+        client.send(&Message::Chunk { id, seq, data: &buf[..n] }.encode())?;
+        seq += 1;
+        out = drain(&mut client, id, out + 1, WINDOW)?;
+    }
+
+    client.send(&Message::End { id, chunks: seq, hash: hash.finish() }.encode())?;
+    drain(&mut client, id, out + 1, 0)?;
+
+    let secs = started.elapsed().as_secs_f64();
+    println!("sent {} ({} bytes in {} chunks) in {:.2}s, {:.1} MB/s",
+             name, len, seq, secs, len as f64 / secs / 1_000_000.0);
+    Ok(())
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.len() != 2 {
+        eprintln!("usage: send-file <host:port> <path>");
+        process::exit(2);
+    }
+
+    if let Err(e) = send(&args[0], Path::new(&args[1])) {
+        eprintln!("error: {}", e);
+        process::exit(1);
+    }
+}
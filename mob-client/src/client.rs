@@ -0,0 +1,631 @@
+use std::cmp;
+use std::collections::VecDeque;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, Error, ErrorKind, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use codec::{self, Assembler, Endian, Frame, FrameReader, Route};
+use eyeballs;
+
+/// How long a client waits before giving up. `None` waits forever, which is the default.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Timeouts {
+    /// Establishing the connection.
+    pub connect: Option<Duration>,
+
+    /// Each read in `recv`.
+    pub read: Option<Duration>,
+
+    /// Each write in `send`.
+    pub write: Option<Duration>,
+}
+
+/// Keeps a quiet connection honest.
+///
+/// Once nothing has been heard from the server for `interval`, the client sends a `PING`. If the
+/// server does not answer within `timeout`, the connection is considered dead. Anything the
+/// server sends counts as an answer.
+///
+/// NAT boxes and firewalls drop idle TCP sessions without telling either end, so without a
+/// heartbeat a listening client can wait forever on a connection that is already gone.
+#[derive(Clone, Copy, Debug)]
+pub struct Heartbeat {
+    pub interval: Duration,
+    pub timeout: Duration,
+}
+
+/// Write a whole frame to `stream`.
+///
+/// Several threads may write to the same connection, for example a `Client` sending heartbeats
+/// from its read loop while a `Dispatcher` sends messages. The lock stops their frames from being
+/// interleaved on the wire.
+pub fn write_frame(mut stream: &TcpStream, lock: &Mutex<()>, frame: &[u8]) -> io::Result<()> {
+    let _guard = lock.lock().unwrap_or_else(|e| e.into_inner());
+    stream.write_all(frame).map_err(|e| timed_out(e, "Timed out sending a message"))
+}
+
+/// The frames to send a message of `kind` on `route` in. A message too large for one frame is
+/// split into `FRAGMENT` frames, under a random id so it cannot be confused with another
+/// client's.
+pub(crate) fn encode_message(endian: Endian, kind: u8, route: Route, payload: &[u8])
+    -> Vec<Vec<u8>>
+{
+    if payload.len() <= codec::MAX_PAYLOAD_LEN {
+        let mut frame = endian.encode_routed_header(kind, route, payload.len()).to_vec();
+        frame.extend_from_slice(payload);
+        return vec![frame];
+    }
+
+    let id = RandomState::new().build_hasher().finish();
+    endian.encode_fragments(id, kind, route, payload, codec::MAX_PAYLOAD_LEN)
+}
+
+/// The `CREDIT` frame granting the server `messages` more.
+pub(crate) fn encode_grant(endian: Endian, messages: u64) -> Vec<u8> {
+    let payload = codec::encode_credit(messages);
+    let mut frame = endian.encode_frame_header(codec::CREDIT, payload.len()).to_vec();
+    frame.extend_from_slice(&payload);
+    frame
+}
+
+/// Write each of `frames` to `stream`, see `write_frame`.
+pub(crate) fn write_frames(stream: &TcpStream, lock: &Mutex<()>, frames: &[Vec<u8>])
+    -> io::Result<()>
+{
+    for frame in frames {
+        write_frame(stream, lock, frame)?;
+    }
+    Ok(())
+}
+
+/// Turn the errors a timed out socket operation returns into a `TimedOut` error.
+///
+/// Depending on the platform, a read or write that times out fails with either `WouldBlock` or
+/// `TimedOut`.
+fn timed_out(e: Error, what: &str) -> Error {
+    match e.kind() {
+        ErrorKind::WouldBlock | ErrorKind::TimedOut => Error::new(ErrorKind::TimedOut, what),
+        _ => e,
+    }
+}
+
+/// A blocking connection to a mob server.
+pub struct Client {
+    reader: FrameReader<TcpStream>,
+
+    // shared with every `Dispatcher` and `Outbox` made from this client, see `write_frame`
+    write_lock: Arc<Mutex<()>>,
+
+    // how long `recv` waits, kept here because heartbeats take over the socket's read timeout
+    read_timeout: Option<Duration>,
+
+    heartbeat: Option<Heartbeat>,
+
+    // when the server last sent us anything
+    last_heard: Instant,
+
+    // when we sent a PING that has not been answered yet
+    ping_sent: Option<Instant>,
+
+    // the correlation id of our next request
+    next_id: u64,
+
+    // frames that arrived while `request` waited for its reply, with their routes
+    pending: VecDeque<(Route, Frame)>,
+
+    // the route of the frame returned last
+    route: Route,
+
+    // messages that have arrived in part, as `FRAGMENT` frames
+    assembler: Assembler,
+
+    // how many messages the server may have on their way to us, if we limit it, and how many
+    // have arrived since we last granted it more credit
+    credit_window: Option<u64>,
+    consumed: u64,
+
+    // the byte order of the headers we write. The reader keeps track of the ones we read
+    endian: Endian,
+}
+
+impl Client {
+    /// Connect to the server at `addr`.
+    ///
+    /// If `addr` resolves to more than one address, they are raced as RFC 8305 describes, so a
+    /// host whose IPv6 is broken is reached over IPv4 after a quarter of a second rather than
+    /// once IPv6 gives up. Addresses of the same family are tried in the order they resolved.
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> io::Result<Client> {
+        Client::connect_with(addr, Timeouts::default())
+    }
+
+    /// Connect to the server at `addr` as `connect` does, giving up after `timeouts.connect`,
+    /// and apply the read and write timeouts. The connect timeout covers every address tried.
+    pub fn connect_with<A: ToSocketAddrs>(addr: A, timeouts: Timeouts) -> io::Result<Client> {
+        let addrs: Vec<SocketAddr> = addr.to_socket_addrs()?.collect();
+        if addrs.is_empty() {
+            return Err(Error::new(ErrorKind::InvalidInput, "Address resolved to nothing"));
+        }
+        let stream = eyeballs::connect(addrs, timeouts.connect)?;
+
+        let mut client = Client::from_stream(stream);
+        client.set_read_timeout(timeouts.read)?;
+        client.set_write_timeout(timeouts.write)?;
+        Ok(client)
+    }
+
+    /// Use an already connected stream, for callers that want to set socket options or
+    /// timeouts first.
+    pub fn from_stream(stream: TcpStream) -> Client {
+        Client {
+            reader: FrameReader::new(stream),
+            write_lock: Arc::new(Mutex::new(())),
+            read_timeout: None,
+            heartbeat: None,
+            last_heard: Instant::now(),
+            ping_sent: None,
+            // Every client broadcasts its requests to everyone, so ids must not collide between
+            // clients. Start each one somewhere random.
+            next_id: RandomState::new().build_hasher().finish(),
+            pending: VecDeque::new(),
+            route: Route::default(),
+            assembler: Assembler::new(),
+            credit_window: None,
+            consumed: 0,
+            endian: Endian::Big,
+        }
+    }
+
+    /// Send a message. The server broadcasts it to every connected client, including this one.
+    ///
+    /// A message larger than `codec::MAX_PAYLOAD_LEN` is sent in pieces, as `FRAGMENT` frames,
+    /// which clients put back together before `recv` returns it.
+    ///
+    /// If the write timeout expires, the error is `TimedOut`. Part of the message may have been
+    /// sent by then, so the connection should not be used again.
+    pub fn send(&mut self, msg: &[u8]) -> io::Result<()> {
+        self.send_prioritized(msg, 0)
+    }
+
+    /// Send a message at `priority`, see `send`. Anything above zero is urgent, and servers
+    /// send it ahead of the ordinary messages already queued for each client. Messages of the
+    /// same priority stay in order.
+    pub fn send_prioritized(&mut self, msg: &[u8], priority: u8) -> io::Result<()> {
+        self.send_routed(msg, Route { priority, stream: 0 })
+    }
+
+    /// Send a message on `route`, see `send_prioritized`. The server broadcasts it on the same
+    /// stream, so one connection can carry several independent ones, and `route` tells them
+    /// apart on the way back in. Streams are not ordered with respect to each other any more
+    /// than priorities are.
+    pub fn send_routed(&mut self, msg: &[u8], route: Route) -> io::Result<()> {
+        // One write for each header and payload, so they go out in the same packet.
+        let frames = encode_message(self.endian, codec::DATA, route, msg);
+        write_frames(self.reader.get_ref(), &self.write_lock, &frames)
+    }
+
+    /// The route of the message or frame `recv`, `recv_frame` or `try_recv` returned last, to
+    /// tell which stream it arrived on.
+    pub fn route(&self) -> Route {
+        self.route
+    }
+
+    /// Wait for the next broadcast.
+    ///
+    /// Returns `None` once the server has closed the connection. If the read timeout expires
+    /// first, the error is `TimedOut`. Whatever part of a message had arrived is kept, so `recv`
+    /// can be called again.
+    ///
+    /// With a heartbeat set, this is also where PINGs are sent. A server that does not answer in
+    /// time is a `ConnectionAborted` error. PINGs from the server are answered here too, so a
+    /// client that stops calling `recv` looks gone to a server checking on its clients.
+    ///
+    /// Everything other than a broadcast is skipped. Use `recv_frame` to see the rest too.
+    pub fn recv(&mut self) -> io::Result<Option<Vec<u8>>> {
+        loop {
+            match self.recv_frame()? {
+                Some(Frame::Data(msg)) => return Ok(Some(msg)),
+                Some(_) => continue,
+                None => return Ok(None),
+            }
+        }
+    }
+
+    /// Wait for the next frame other than a `Ping` or `Pong`: a broadcast, request, reply, error,
+    /// missed count, receipt, client list, welcome or greeting. Otherwise the same as `recv`.
+    ///
+    /// Never returns a `Ping`, `Pong` or `Fragment`. Fragmented messages are returned whole once
+    /// their last piece arrives.
+    pub fn recv_frame(&mut self) -> io::Result<Option<Frame>> {
+        match self.pending.pop_front() {
+            Some((route, frame)) => {
+                self.route = route;
+                Ok(Some(frame))
+            }
+            None => {
+                let frame = self.read_wire()?;
+                self.route = self.reader.route();
+                Ok(frame)
+            }
+        }
+    }
+
+    /// Send `msg` as a request and wait for the reply carrying its correlation id.
+    ///
+    /// Anyone connected can reply, see `reply`. Other frames that arrive in the meantime are
+    /// kept for `recv` and `recv_frame`, except our own request, which the server broadcasts
+    /// back to us too.
+    ///
+    /// If the server sends an `ERROR` before our request has come back, the request is taken to
+    /// be rejected and fails with `InvalidInput`. A `CLOSE` fails it with `ConnectionAborted`.
+    pub fn request(&mut self, msg: &[u8]) -> io::Result<Vec<u8>> {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        self.send_tagged(codec::REQUEST, id, msg)?;
+
+        // Until our request comes back, an error may be the server rejecting it.
+        let mut echoed = false;
+        loop {
+            match self.read_wire()? {
+                Some(Frame::Reply { id: reply_id, payload }) if reply_id == id => {
+                    return Ok(payload);
+                }
+                Some(Frame::Request { id: request_id, .. }) if request_id == id => echoed = true,
+                Some(Frame::Error(reason)) if !echoed => {
+                    return Err(Error::new(ErrorKind::InvalidInput,
+                                          format!("Server rejected the request: {}", reason)));
+                }
+                Some(Frame::Close(reason)) => {
+                    return Err(Error::new(ErrorKind::ConnectionAborted,
+                                          format!("Server closed the connection: {}", reason)));
+                }
+                Some(frame) => self.pending.push_back((self.reader.route(), frame)),
+                None => {
+                    return Err(Error::new(ErrorKind::UnexpectedEof,
+                                          "Server closed the connection before a reply"));
+                }
+            }
+        }
+    }
+
+    /// Ask the server who is connected. Returns the id of every connected client, this one's
+    /// first.
+    ///
+    /// Other frames that arrive in the meantime are kept for `recv` and `recv_frame`. A `CLOSE`
+    /// fails it with `ConnectionAborted`.
+    pub fn who(&mut self) -> io::Result<Vec<u64>> {
+        let frame = self.endian.encode_control(codec::WHO);
+        write_frame(self.reader.get_ref(), &self.write_lock, &frame)?;
+
+        loop {
+            match self.read_wire()? {
+                Some(Frame::Who(ids)) => return Ok(ids),
+                Some(Frame::Close(reason)) => {
+                    return Err(Error::new(ErrorKind::ConnectionAborted,
+                                          format!("Server closed the connection: {}", reason)));
+                }
+                Some(frame) => self.pending.push_back((self.reader.route(), frame)),
+                None => {
+                    return Err(Error::new(ErrorKind::UnexpectedEof,
+                                          "Server closed the connection before answering"));
+                }
+            }
+        }
+    }
+
+    /// Ask the server to switch the byte order of length headers, and wait for it to agree.
+    ///
+    /// Other frames that arrive in the meantime are kept for `recv` and `recv_frame`. A `CLOSE`
+    /// fails it with `ConnectionAborted`. `Dispatcher`s and `Outbox`es made before the switch
+    /// still write in the old order, so switch first.
+    pub fn switch_endian(&mut self, endian: Endian) -> io::Result<()> {
+        let payload = codec::encode_endian(endian);
+        let mut frame = self.endian.encode_frame_header(codec::ENDIAN, payload.len()).to_vec();
+        frame.extend(payload);
+        write_frame(self.reader.get_ref(), &self.write_lock, &frame)?;
+        self.endian = endian;
+
+        // The reader switches as it reads the answer.
+        loop {
+            match self.read_wire()? {
+                Some(Frame::Endian(_)) => return Ok(()),
+                Some(Frame::Close(reason)) => {
+                    return Err(Error::new(ErrorKind::ConnectionAborted,
+                                          format!("Server closed the connection: {}", reason)));
+                }
+                Some(frame) => self.pending.push_back((self.reader.route(), frame)),
+                None => {
+                    return Err(Error::new(ErrorKind::UnexpectedEof,
+                                          "Server closed the connection before answering"));
+                }
+            }
+        }
+    }
+
+    /// Read and write headers in this byte order, without asking the server. For servers that
+    /// are configured to use it for every connection.
+    pub fn set_endian(&mut self, endian: Endian) {
+        self.endian = endian;
+        self.reader.set_endian(endian);
+    }
+
+    /// The byte order of the headers this client writes.
+    pub fn endian(&self) -> Endian {
+        self.endian
+    }
+
+    /// Answer the request with correlation id `id`.
+    pub fn reply(&mut self, id: u64, msg: &[u8]) -> io::Result<()> {
+        self.send_tagged(codec::REPLY, id, msg)
+    }
+
+    /// Send a `REQUEST` or `REPLY`, in pieces if it is too large for one frame.
+    fn send_tagged(&mut self, kind: u8, id: u64, msg: &[u8]) -> io::Result<()> {
+        let frames = if msg.len() + codec::CORRELATION_ID_LEN <= codec::MAX_PAYLOAD_LEN {
+            vec![self.endian.encode_tagged(kind, id, msg)]
+        } else {
+            let mut payload = id.to_be_bytes().to_vec();
+            payload.extend_from_slice(msg);
+            encode_message(self.endian, kind, Route::default(), &payload)
+        };
+        write_frames(self.reader.get_ref(), &self.write_lock, &frames)
+    }
+
+    /// Read the next frame other than a `Ping`, `Pong` or `Fragment` off the connection, putting
+    /// fragmented messages back together.
+    fn read_wire(&mut self) -> io::Result<Option<Frame>> {
+        loop {
+            let frame = match self.read_piece()? {
+                Some(frame) => frame,
+                None => return Ok(None),
+            };
+            if let Some(frame) = self.assemble(frame)? {
+                return Ok(Some(frame));
+            }
+        }
+    }
+
+    /// Add `frame` to the message it is a piece of, if it is a `Fragment`. Returns the frame to
+    /// pass on, if any: anything else as it is, or the whole message once its last piece is in.
+    fn assemble(&mut self, frame: Frame) -> io::Result<Option<Frame>> {
+        self.replenish(&frame)?;
+        match frame {
+            Frame::Fragment { id, kind, last, part } => self.assembler.add(id, kind, last, &part),
+            Frame::Missed(n) => {
+                // The missing messages may include pieces of the ones being put together.
+                self.assembler.clear();
+                Ok(Some(Frame::Missed(n)))
+            }
+            frame => Ok(Some(frame)),
+        }
+    }
+
+    /// Read the next frame other than a `Ping` or `Pong` off the connection, answering PINGs and
+    /// sending heartbeats while we wait.
+    fn read_piece(&mut self) -> io::Result<Option<Frame>> {
+        let deadline = self.read_timeout.map(|t| Instant::now() + t);
+        let heartbeat = match self.heartbeat {
+            Some(heartbeat) => heartbeat,
+            None => return self.read_answering_pings(deadline),
+        };
+
+        loop {
+            let now = Instant::now();
+
+            // Either wait for the answer to our PING, or until it is time to send one.
+            let wake = match self.ping_sent {
+                Some(sent) if now >= sent + heartbeat.timeout => {
+                    return Err(Error::new(ErrorKind::ConnectionAborted,
+                                          "No heartbeat from the server"));
+                }
+                Some(sent) => sent + heartbeat.timeout,
+                None if now >= self.last_heard + heartbeat.interval => {
+                    write_frame(self.reader.get_ref(),
+                                &self.write_lock,
+                                &self.endian.encode_control(codec::PING))?;
+                    self.ping_sent = Some(now);
+                    now + heartbeat.timeout
+                }
+                None => self.last_heard + heartbeat.interval,
+            };
+
+            let wake = match deadline {
+                Some(deadline) if now >= deadline => {
+                    return Err(Error::new(ErrorKind::TimedOut, "Timed out waiting for a message"));
+                }
+                Some(deadline) => cmp::min(wake, deadline),
+                None => wake,
+            };
+
+            // A zero read timeout is an error, so always wait at least a little.
+            let wait = cmp::max(wake - now, Duration::from_millis(1));
+            self.reader.get_ref().set_read_timeout(Some(wait))?;
+
+            match self.reader.read() {
+                Ok(Some(frame)) => {
+                    self.heard();
+                    match frame {
+                        Frame::Ping => self.pong()?,
+                        Frame::Pong => {},
+                        frame => return Ok(Some(frame)),
+                    }
+                }
+                Ok(None) => return Ok(None),
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock
+                    || e.kind() == ErrorKind::TimedOut => {},
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Return the next broadcast if one has already arrived, without waiting. Requests and
+    /// replies are skipped.
+    ///
+    /// Returns `None` if no complete message is available yet. A closed connection is an
+    /// `UnexpectedEof` error, so it cannot be mistaken for an empty one.
+    pub fn try_recv(&mut self) -> io::Result<Option<Vec<u8>>> {
+        while let Some((route, frame)) = self.pending.pop_front() {
+            if let Frame::Data(msg) = frame {
+                self.route = route;
+                return Ok(Some(msg));
+            }
+        }
+
+        self.reader.get_ref().set_nonblocking(true)?;
+        let result = self.read_message();
+        self.reader.get_ref().set_nonblocking(false)?;
+
+        match result {
+            Ok(Some(msg)) => Ok(Some(msg)),
+            Ok(None) => Err(Error::new(ErrorKind::UnexpectedEof, "Server closed the connection")),
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Read up to the next message, noting that we heard from the server on the way.
+    fn read_message(&mut self) -> io::Result<Option<Vec<u8>>> {
+        loop {
+            match self.reader.read()? {
+                Some(frame) => {
+                    self.heard();
+                    if let Some(Frame::Data(msg)) = self.assemble(frame)? {
+                        self.route = self.reader.route();
+                        return Ok(Some(msg));
+                    }
+                }
+                None => return Ok(None),
+            }
+        }
+    }
+
+    /// Count `frame` against the credit window, if there is one, and grant the server more
+    /// credit once half the window has arrived.
+    fn replenish(&mut self, frame: &Frame) -> io::Result<()> {
+        let window = match self.credit_window {
+            Some(window) => window,
+            None => return Ok(()),
+        };
+
+        match *frame {
+            Frame::Data(_) | Frame::Request { .. } | Frame::Reply { .. } |
+            Frame::Fragment { .. } => self.consumed += 1,
+            _ => return Ok(()),
+        }
+
+        if self.consumed >= cmp::max(window / 2, 1) {
+            let consumed = self.consumed;
+            self.consumed = 0;
+            self.grant_credit(consumed)?;
+        }
+        Ok(())
+    }
+
+    /// Read the next frame other than a `Ping` or `Pong`, answering PINGs, until `deadline`.
+    /// Without this, every PING from a server checking on us would start the socket's read
+    /// timeout again, and a quiet connection would never time out.
+    fn read_answering_pings(&mut self, deadline: Option<Instant>) -> io::Result<Option<Frame>> {
+        let mut shortened = false;
+        let result = loop {
+            match self.reader.read() {
+                Ok(Some(Frame::Ping)) => {
+                    self.pong()?;
+                    if let Some(deadline) = deadline {
+                        let left = deadline.saturating_duration_since(Instant::now());
+                        if left == Duration::from_secs(0) {
+                            break Err(Error::new(ErrorKind::TimedOut,
+                                                 "Timed out waiting for a message"));
+                        }
+                        self.reader.get_ref().set_read_timeout(Some(left))?;
+                        shortened = true;
+                    }
+                }
+                Ok(Some(Frame::Pong)) => continue,
+                Ok(frame) => break Ok(frame),
+                Err(e) => break Err(timed_out(e, "Timed out waiting for a message")),
+            }
+        };
+
+        if shortened {
+            self.reader.get_ref().set_read_timeout(self.read_timeout)?;
+        }
+        result
+    }
+
+    /// Answer a `PING` from the server.
+    fn pong(&self) -> io::Result<()> {
+        let pong = self.endian.encode_control(codec::PONG);
+        write_frame(self.reader.get_ref(), &self.write_lock, &pong)
+    }
+
+    fn heard(&mut self) {
+        self.last_heard = Instant::now();
+        self.ping_sent = None;
+    }
+
+    /// Let the server queue us `messages` more messages, fragments counting one each.
+    ///
+    /// The first grant turns on flow control for the connection, for good. From then on the
+    /// server only queues as many messages as it has been granted in all, and skips the rest,
+    /// which a `MISSED` frame counts once there is credit again. Requests and replies count, but
+    /// nothing else the server sends does.
+    pub fn grant_credit(&self, messages: u64) -> io::Result<()> {
+        let frame = encode_grant(self.endian, messages);
+        write_frame(self.reader.get_ref(), &self.write_lock, &frame)
+    }
+
+    /// Turn on flow control, see `grant_credit`, with `window` messages of credit. More is
+    /// granted as messages are received, so the server never has more than `window` on their
+    /// way to us, however slowly we read. Clients that are split have to grant credit through
+    /// their `Outbox` instead.
+    pub fn set_credit_window(&mut self, window: u64) -> io::Result<()> {
+        self.credit_window = Some(window);
+        self.consumed = 0;
+        self.grant_credit(window)
+    }
+
+    /// Stop sending. The server closes the connection in response, which ends `recv`.
+    pub fn shutdown(&self) -> io::Result<()> {
+        self.reader.get_ref().shutdown(Shutdown::Write)
+    }
+
+    /// Set how long `recv` waits for data before failing with `TimedOut`.
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        self.read_timeout = timeout;
+        self.reader.get_ref().set_read_timeout(timeout)
+    }
+
+    /// Send heartbeats while waiting in `recv`, or stop sending them with `None`.
+    pub fn set_heartbeat(&mut self, heartbeat: Option<Heartbeat>) -> io::Result<()> {
+        self.heartbeat = heartbeat;
+        self.heard();
+
+        if heartbeat.is_none() {
+            self.reader.get_ref().set_read_timeout(self.read_timeout)?;
+        }
+
+        Ok(())
+    }
+
+    /// Set how long `send` waits to write before failing with `TimedOut`.
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.reader.get_ref().set_write_timeout(timeout)
+    }
+
+    /// The address of the server.
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.reader.get_ref().peer_addr()
+    }
+
+    /// Get a reference to the underlying stream.
+    pub fn get_ref(&self) -> &TcpStream {
+        self.reader.get_ref()
+    }
+
+    /// The lock writes to this client's connection must hold, see `write_frame`.
+    pub(crate) fn write_lock(&self) -> Arc<Mutex<()>> {
+        self.write_lock.clone()
+    }
+}
@@ -0,0 +1,1336 @@
+//! Framing for the mob wire protocol.
+//!
+//! Every message on the wire is an 8 byte, big endian length header followed by that many bytes
+//! of payload. A connection can switch its headers to little endian, see `Endian`. Numbers inside
+//! payloads stay big endian.
+//!
+//! For serial gateways and other devices that delimit frames with zero bytes, `encode_cobs` and
+//! `decode_cobs` implement Consistent Overhead Byte Stuffing.
+//!
+//! The top byte of the header is the frame kind. It is zero for messages, so a message header is
+//! just its length. The three bytes after it are the `Route` of a frame with a payload: its
+//! priority, zero for most, and the stream it belongs to, zero unless the client uses several.
+//! Servers send frames with a higher priority ahead of ordinary messages queued before them, and
+//! keep every frame on the stream it came on. That leaves four bytes for the length. Control
+//! frames, such as `PING`, set the kind and leave the rest of the header zero. They have no
+//! payload. `REQUEST` and `REPLY` frames are broadcast like messages, but the first 8 bytes of
+//! their payload are a big endian correlation id. Only the server sends `ERROR` frames, to tell a
+//! client why its message was rejected, `CLOSE` frames, to tell it why it is being disconnected,
+//! `MISSED` frames, to tell it how many messages it was too far behind to be sent, and `RECEIPT`
+//! frames, to tell it how many clients a message of its own was queued for. A client sends a `WHO`
+//! control frame to ask who is connected, and the server answers with a `WHO` frame listing them. A
+//! server may open each connection with a `GREETING` frame listing what it supports and a `WELCOME`
+//! frame describing itself. Federated servers introduce themselves to each other with `PEER` and
+//! pass messages on in `FORWARD` frames, which clients never see.
+//!
+//! The server may also send every client a `NOTICE`, ahead of whatever else is queued for it. A
+//! client can limit how many messages the server queues for it by granting `CREDIT`.
+//!
+//! A message too large for one frame is sent as `FRAGMENT` frames, each carrying a piece of it.
+//! The server broadcasts them like any other message, and `Assembler` joins them back together.
+
+use std::collections::HashMap;
+use std::io::{self, Error, ErrorKind, Read};
+use std::str::FromStr;
+use std::time::Duration;
+
+/// The size of the length header in bytes.
+pub const HEADER_LEN: usize = 8;
+
+/// The largest payload a peer may send. Connections announcing anything bigger are closed.
+pub const MAX_PAYLOAD_LEN: usize = 16 * 1024 * 1024;
+
+/// An ordinary message, to be broadcast.
+pub const DATA: u8 = 0;
+
+/// Asks the other end to prove it is alive, which it does with a `PONG`. Servers checking on
+/// quiet clients send them too.
+pub const PING: u8 = 1;
+
+/// The answer to a `PING`.
+pub const PONG: u8 = 2;
+
+/// A message that expects a `REPLY` carrying the same correlation id.
+pub const REQUEST: u8 = 3;
+
+/// The answer to a `REQUEST`.
+pub const REPLY: u8 = 4;
+
+/// Sent by the server to the sender of a message it rejected. The payload is a UTF-8 reason.
+pub const ERROR: u8 = 5;
+
+/// Sent by the server as the last frame on a connection it is closing. The payload is a UTF-8
+/// reason, which may be empty.
+pub const CLOSE: u8 = 6;
+
+/// Sent by the server in place of the messages a client was too far behind to be sent. The payload
+/// is an 8 byte big endian count of how many were skipped.
+pub const MISSED: u8 = 7;
+
+/// Sent by the server, if asked to, to the sender of each message once it is broadcast. The
+/// payload is two 8 byte big endian numbers: which of the sender's messages it is, counting from
+/// one, and how many clients it was queued for.
+pub const RECEIPT: u8 = 8;
+
+/// Asks the server who is connected, as a control frame. The server answers with a `WHO` frame
+/// whose payload is the id of every connected client, 8 bytes big endian each, the asker's first.
+pub const WHO: u8 = 9;
+
+/// Sent by the server, if configured to, as the first frame on every connection. The payload is a
+/// `Welcome`, see `encode_welcome`.
+pub const WELCOME: u8 = 10;
+
+/// Sent by the server, if configured to, before anything else on every connection. The payload
+/// is `Capabilities`, see `encode_greeting`.
+pub const GREETING: u8 = 11;
+
+/// Switches the byte order of length headers. The payload is one byte, 0 for big endian and 1 for
+/// little endian. A client sends it in the current order and sends everything after it in the new
+/// one. The server answers with the same frame, also in the current order, and sends everything
+/// after its answer in the new order.
+pub const ENDIAN: u8 = 12;
+
+/// Sent by a server that connects to another as a federation peer, before anything else. The
+/// payload is its 8 byte big endian server id, then the secret the servers share for
+/// federating. From then on, each sends the other `FORWARD` frames in place of the messages it
+/// broadcasts.
+pub const PEER: u8 = 13;
+
+/// A message passed between federated servers. The payload is its `Origin`, then its kind, then
+/// the message itself, see `encode_forward`.
+pub const FORWARD: u8 = 14;
+
+/// The size of what comes before the message in a `FORWARD` payload.
+pub const FORWARD_HEADER_LEN: usize = 17;
+
+/// A piece of a message too large for one frame. The payload is the message's 8 byte big endian
+/// id, a byte that is 1 on the last piece and 0 on the others, the kind of the whole message, and
+/// then the piece, see `encode_fragment`. The pieces of a message are sent in order, and it is
+/// them joined together.
+pub const FRAGMENT: u8 = 15;
+
+/// The size of what comes before the piece in a `FRAGMENT` payload.
+pub const FRAGMENT_HEADER_LEN: usize = 10;
+
+/// Sent by the server to every client at once, for announcements such as an upcoming shutdown.
+/// The payload is UTF-8 text. It goes out ahead of any messages queued for a client, even one too
+/// far behind to be sent messages.
+pub const NOTICE: u8 = 16;
+
+/// Sent by a client to let the server queue it more messages. The payload is an 8 byte big endian
+/// count. A client that never sends one is sent everything. Once it has, the server only queues
+/// it as many messages as it has been granted in all, and skips the rest, see `MISSED`.
+pub const CREDIT: u8 = 17;
+
+/// The byte that ends every COBS encoded frame, and that appears nowhere else in one.
+pub const COBS_DELIMITER: u8 = 0;
+
+/// The version of the protocol described here, as announced in `WELCOME` frames.
+pub const PROTOCOL_VERSION: u64 = 1;
+
+/// The size of the correlation id at the front of `REQUEST` and `REPLY` payloads.
+pub const CORRELATION_ID_LEN: usize = 8;
+
+/// How a frame with a payload is passed on, as carried in the three header bytes after its kind.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Route {
+    /// How urgent the frame is. Zero is ordinary, and anything higher goes ahead of ordinary
+    /// messages queued for the same client.
+    pub priority: u8,
+
+    /// The logical stream the frame belongs to, so one connection can carry several independent
+    /// ones, such as chat next to telemetry. The server passes every frame on in the stream it
+    /// came in, and does not otherwise look at it.
+    pub stream: u16,
+}
+
+/// A frame read off the wire.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Frame {
+    Data(Vec<u8>),
+    Ping,
+    Pong,
+    Request { id: u64, payload: Vec<u8> },
+    Reply { id: u64, payload: Vec<u8> },
+    Error(String),
+    Close(String),
+    Missed(u64),
+    Receipt { seq: u64, queued: u64 },
+    Who(Vec<u64>),
+    Welcome(Welcome),
+    Greeting(Capabilities),
+    Endian(Endian),
+    Fragment { id: u64, kind: u8, last: bool, part: Vec<u8> },
+    Notice(String),
+}
+
+/// The byte order of length headers.
+///
+/// The kind is the top byte of the header either way, so it is the first byte on the wire in big
+/// endian headers and the last in little endian ones.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum Endian {
+    #[default]
+    Big,
+
+    /// For legacy clients that write their length prefixes little endian.
+    Little,
+}
+
+impl FromStr for Endian {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Endian, String> {
+        match s {
+            "big" => Ok(Endian::Big),
+            "little" => Ok(Endian::Little),
+            _ => Err(format!("unknown byte order {}, expected big or little", s)),
+        }
+    }
+}
+
+impl Endian {
+    /// Turn a big endian header into one in this order, or back again.
+    fn arrange(self, mut header: [u8; HEADER_LEN]) -> [u8; HEADER_LEN] {
+        if self == Endian::Little {
+            header.reverse();
+        }
+        header
+    }
+
+    /// The header at the front of `buf`, in big endian order. `buf` must hold at least
+    /// `HEADER_LEN` bytes.
+    fn big_endian(self, buf: &[u8]) -> [u8; HEADER_LEN] {
+        let mut header = [0u8; HEADER_LEN];
+        header.copy_from_slice(&buf[..HEADER_LEN]);
+        self.arrange(header)
+    }
+
+    /// Encode the header for a frame of `kind` whose payload is `len` bytes.
+    pub fn encode_frame_header(self, kind: u8, len: usize) -> [u8; HEADER_LEN] {
+        self.arrange(encode_frame_header(kind, len))
+    }
+
+    /// Encode the header for a frame of `kind` and `priority` whose payload is `len` bytes.
+    pub fn encode_prioritized_header(self, kind: u8, priority: u8, len: usize)
+        -> [u8; HEADER_LEN]
+    {
+        self.arrange(encode_prioritized_header(kind, priority, len))
+    }
+
+    /// Encode the header for a frame of `kind` sent on `route` whose payload is `len` bytes.
+    pub fn encode_routed_header(self, kind: u8, route: Route, len: usize) -> [u8; HEADER_LEN] {
+        self.arrange(encode_routed_header(kind, route, len))
+    }
+
+    /// Encode the header of a control frame.
+    pub fn encode_control(self, kind: u8) -> [u8; HEADER_LEN] {
+        self.arrange(encode_control(kind))
+    }
+
+    /// Decode the payload length of any kind of frame. `buf` must hold at least `HEADER_LEN`
+    /// bytes.
+    pub fn decode_len(self, buf: &[u8]) -> u64 {
+        decode_len(&self.big_endian(buf))
+    }
+
+    /// The kind of the frame whose header is at the front of `buf`.
+    pub fn decode_kind(self, buf: &[u8]) -> u8 {
+        decode_kind(&self.big_endian(buf))
+    }
+
+    /// The priority of the frame whose header is at the front of `buf`.
+    pub fn decode_priority(self, buf: &[u8]) -> u8 {
+        decode_priority(&self.big_endian(buf))
+    }
+
+    /// The route of the frame whose header is at the front of `buf`.
+    pub fn decode_route(self, buf: &[u8]) -> Route {
+        decode_route(&self.big_endian(buf))
+    }
+
+    /// Whether the header at the front of `buf` is a well formed control frame.
+    pub fn is_control(self, buf: &[u8]) -> bool {
+        is_control(&self.big_endian(buf))
+    }
+
+    /// Encode a whole frame, header and payload, into a new buffer.
+    pub fn encode(self, payload: &[u8]) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(HEADER_LEN + payload.len());
+        buf.extend_from_slice(&self.encode_frame_header(DATA, payload.len()));
+        buf.extend_from_slice(payload);
+        buf
+    }
+
+    /// Encode a whole `REQUEST` or `REPLY` frame, correlation id and payload.
+    pub fn encode_tagged(self, kind: u8, id: u64, payload: &[u8]) -> Vec<u8> {
+        let len = CORRELATION_ID_LEN + payload.len();
+        let mut buf = Vec::with_capacity(HEADER_LEN + len);
+        buf.extend_from_slice(&self.encode_frame_header(kind, len));
+        buf.extend_from_slice(&id.to_be_bytes());
+        buf.extend_from_slice(payload);
+        buf
+    }
+
+    /// Split a message of `kind` into `FRAGMENT` frames with at most `max_payload` bytes of
+    /// payload each, all carrying `id` and sent on `route`. `max_payload` has to leave room for
+    /// a piece after the `FRAGMENT_HEADER_LEN` bytes of header.
+    pub fn encode_fragments(self, id: u64, kind: u8, route: Route, message: &[u8],
+                            max_payload: usize) -> Vec<Vec<u8>>
+    {
+        let mut pieces = message.chunks(max_payload - FRAGMENT_HEADER_LEN).peekable();
+        let mut frames = Vec::new();
+        while let Some(part) = pieces.next() {
+            let payload = encode_fragment(id, kind, pieces.peek().is_none(), part);
+            let mut frame = self.encode_routed_header(FRAGMENT, route, payload.len()).to_vec();
+            frame.extend_from_slice(&payload);
+            frames.push(frame);
+        }
+        frames
+    }
+
+    /// Decode the frame at the front of `buf`, whatever its kind. See `decode`.
+    pub fn decode(self, buf: &[u8]) -> Option<(&[u8], usize)> {
+        if buf.len() < HEADER_LEN {
+            return None;
+        }
+
+        let len = self.decode_len(buf);
+        if ((buf.len() - HEADER_LEN) as u64) < len {
+            return None;
+        }
+
+        let end = HEADER_LEN + len as usize;
+        Some((&buf[HEADER_LEN..end], end))
+    }
+}
+
+/// What a server supports, as listed in a `GREETING` frame.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Capabilities {
+    /// The protocol versions the server speaks.
+    pub versions: Vec<u64>,
+
+    /// How frames may be delimited on the wire, such as `length`.
+    pub codecs: Vec<String>,
+
+    /// Optional features that are turned on, such as `receipts`.
+    pub features: Vec<String>,
+}
+
+impl Capabilities {
+    /// Whether `feature` is turned on.
+    pub fn has_feature(&self, feature: &str) -> bool {
+        self.features.iter().any(|f| f == feature)
+    }
+}
+
+/// What a server tells clients about itself in a `WELCOME` frame.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Welcome {
+    /// The server's name.
+    pub name: String,
+
+    /// The protocol version the server speaks.
+    pub version: u64,
+
+    /// The longest payload the server accepts.
+    pub max_payload: u64,
+
+    /// How often the server would like clients to send a `PING` when they are otherwise quiet, if
+    /// at all.
+    pub heartbeat: Option<Duration>,
+
+    /// A message of the day from the operator, which may be empty.
+    pub motd: String,
+}
+
+impl Default for Welcome {
+    fn default() -> Welcome {
+        Welcome {
+            name: "mob".to_string(),
+            version: PROTOCOL_VERSION,
+            max_payload: MAX_PAYLOAD_LEN as u64,
+            heartbeat: None,
+            motd: String::new(),
+        }
+    }
+}
+
+/// Encode the length header for a payload of `len` bytes.
+pub fn encode_header(len: usize) -> [u8; HEADER_LEN] {
+    (len as u64).to_be_bytes()
+}
+
+/// Decode a length header. `buf` must hold at least `HEADER_LEN` bytes.
+pub fn decode_header(buf: &[u8]) -> u64 {
+    let mut header = [0u8; HEADER_LEN];
+    header.copy_from_slice(&buf[..HEADER_LEN]);
+    u64::from_be_bytes(header)
+}
+
+/// Encode the header for a frame of `kind` whose payload is `len` bytes.
+pub fn encode_frame_header(kind: u8, len: usize) -> [u8; HEADER_LEN] {
+    let mut header = encode_header(len);
+    header[0] = kind;
+    header
+}
+
+/// Encode the header for a frame of `kind` and `priority` whose payload is `len` bytes. A
+/// priority of zero is an ordinary frame, and anything higher is urgent.
+pub fn encode_prioritized_header(kind: u8, priority: u8, len: usize) -> [u8; HEADER_LEN] {
+    encode_routed_header(kind, Route { priority, stream: 0 }, len)
+}
+
+/// Encode the header for a frame of `kind` sent on `route` whose payload is `len` bytes.
+pub fn encode_routed_header(kind: u8, route: Route, len: usize) -> [u8; HEADER_LEN] {
+    let mut header = encode_frame_header(kind, len);
+    header[1] = route.priority;
+    header[2..4].copy_from_slice(&route.stream.to_be_bytes());
+    header
+}
+
+/// Decode the payload length of any kind of frame. `buf` must hold at least `HEADER_LEN` bytes.
+pub fn decode_len(buf: &[u8]) -> u64 {
+    decode_header(buf) & 0xffff_ffff
+}
+
+/// Encode the header of a control frame.
+pub fn encode_control(kind: u8) -> [u8; HEADER_LEN] {
+    let mut header = [0u8; HEADER_LEN];
+    header[0] = kind;
+    header
+}
+
+/// The kind of the frame whose header is at the front of `buf`.
+pub fn decode_kind(buf: &[u8]) -> u8 {
+    buf[0]
+}
+
+/// The priority of the frame whose header is at the front of `buf`, zero unless it was sent as
+/// urgent.
+pub fn decode_priority(buf: &[u8]) -> u8 {
+    buf[1]
+}
+
+/// The route of the frame whose header is at the front of `buf`, the default unless it was sent
+/// with a priority or on a stream.
+pub fn decode_route(buf: &[u8]) -> Route {
+    Route { priority: buf[1], stream: u16::from_be_bytes([buf[2], buf[3]]) }
+}
+
+/// Whether the header at the front of `buf` is a well formed control frame: a kind other than
+/// `DATA`, and nothing else.
+pub fn is_control(buf: &[u8]) -> bool {
+    buf[0] != DATA && buf[1..HEADER_LEN].iter().all(|&b| b == 0)
+}
+
+/// Encode a whole frame, header and payload, into a new buffer.
+pub fn encode(payload: &[u8]) -> Vec<u8> {
+    Endian::Big.encode(payload)
+}
+
+/// Encode a whole `REQUEST` or `REPLY` frame, correlation id and payload.
+pub fn encode_tagged(kind: u8, id: u64, payload: &[u8]) -> Vec<u8> {
+    Endian::Big.encode_tagged(kind, id, payload)
+}
+
+/// Encode the payload of an `ENDIAN` frame.
+pub fn encode_endian(endian: Endian) -> Vec<u8> {
+    vec![endian as u8]
+}
+
+/// Decode the payload of an `ENDIAN` frame, or `None` if it is not one.
+pub fn decode_endian(payload: &[u8]) -> Option<Endian> {
+    match *payload {
+        [0] => Some(Endian::Big),
+        [1] => Some(Endian::Little),
+        _ => None,
+    }
+}
+
+/// Encode the payload of a `PEER` frame: the server id, then the federation secret.
+pub fn encode_peer(id: u64, secret: &[u8]) -> Vec<u8> {
+    let mut payload = id.to_be_bytes().to_vec();
+    payload.extend_from_slice(secret);
+    payload
+}
+
+/// Decode the payload of a `PEER` frame into the server id and the federation secret, or `None`
+/// if it is not one.
+pub fn decode_peer(payload: &[u8]) -> Option<(u64, &[u8])> {
+    if payload.len() >= 8 { Some((decode_u64(&payload[..8]), &payload[8..])) } else { None }
+}
+
+/// Where a forwarded message was first sent: the id of that server and its sequence number for
+/// the message. Sequence numbers start at one and only go up, so a server that has seen a message
+/// from an origin has seen every earlier one it is going to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Origin {
+    pub server: u64,
+    pub seq: u64,
+}
+
+/// Encode the payload of a `FORWARD` frame: the origin server id and sequence number as 8 byte
+/// big endian numbers, the kind of the message in one byte, and the message.
+pub fn encode_forward(origin: Origin, kind: u8, message: &[u8]) -> Vec<u8> {
+    let mut payload = Vec::with_capacity(FORWARD_HEADER_LEN + message.len());
+    payload.extend_from_slice(&origin.server.to_be_bytes());
+    payload.extend_from_slice(&origin.seq.to_be_bytes());
+    payload.push(kind);
+    payload.extend_from_slice(message);
+    payload
+}
+
+/// Decode the payload of a `FORWARD` frame into its origin, kind and message, or `None` if it is
+/// too short.
+pub fn decode_forward(payload: &[u8]) -> Option<(Origin, u8, &[u8])> {
+    if payload.len() < FORWARD_HEADER_LEN {
+        return None;
+    }
+
+    let origin = Origin { server: decode_u64(&payload[..8]), seq: decode_u64(&payload[8..16]) };
+    Some((origin, payload[16], &payload[FORWARD_HEADER_LEN..]))
+}
+
+/// Encode the payload of a `FRAGMENT` frame: the id of the message, whether this is its last
+/// piece, the kind of the message and the piece.
+pub fn encode_fragment(id: u64, kind: u8, last: bool, part: &[u8]) -> Vec<u8> {
+    let mut payload = Vec::with_capacity(FRAGMENT_HEADER_LEN + part.len());
+    payload.extend_from_slice(&id.to_be_bytes());
+    payload.push(last as u8);
+    payload.push(kind);
+    payload.extend_from_slice(part);
+    payload
+}
+
+/// Decode the payload of a `FRAGMENT` frame into the id of its message, whether it is the last
+/// piece, the kind of the message and the piece. Returns `None` if it is too short, or the message
+/// is not a `DATA`, `REQUEST` or `REPLY`.
+pub fn decode_fragment(payload: &[u8]) -> Option<(u64, bool, u8, &[u8])> {
+    if payload.len() < FRAGMENT_HEADER_LEN {
+        return None;
+    }
+
+    let last = match payload[8] {
+        0 => false,
+        1 => true,
+        _ => return None,
+    };
+    match payload[9] {
+        kind @ (DATA | REQUEST | REPLY) => {
+            Some((decode_u64(&payload[..8]), last, kind, &payload[FRAGMENT_HEADER_LEN..]))
+        }
+        _ => None,
+    }
+}
+
+/// Joins the pieces of `FRAGMENT` frames back into messages.
+///
+/// Pieces of different messages may be interleaved, but those of each message have to be added
+/// in order, as they arrive over a connection.
+#[derive(Debug, Default)]
+pub struct Assembler {
+    // the kind and the pieces so far of each message whose last piece has not arrived yet
+    partial: HashMap<u64, (u8, Vec<u8>)>,
+}
+
+impl Assembler {
+    pub fn new() -> Assembler {
+        Assembler::default()
+    }
+
+    /// Add the next piece of message `id`. Returns the whole message as a frame once its last
+    /// piece is added, or an `InvalidData` error if it is a request or reply too short for its
+    /// correlation id.
+    pub fn add(&mut self, id: u64, kind: u8, last: bool, part: &[u8]) -> io::Result<Option<Frame>> {
+        if !last {
+            self.partial.entry(id).or_insert_with(|| (kind, Vec::new())).1.extend_from_slice(part);
+            return Ok(None);
+        }
+
+        let (kind, mut payload) = self.partial.remove(&id).unwrap_or_else(|| (kind, Vec::new()));
+        payload.extend_from_slice(part);
+        if kind == DATA {
+            return Ok(Some(Frame::Data(payload)));
+        }
+
+        if payload.len() < CORRELATION_ID_LEN {
+            return Err(Error::new(ErrorKind::InvalidData, "Missing correlation id"));
+        }
+        let id = decode_u64(&payload[..CORRELATION_ID_LEN]);
+        let payload = payload.split_off(CORRELATION_ID_LEN);
+        Ok(Some(if kind == REQUEST {
+            Frame::Request { id, payload }
+        } else {
+            Frame::Reply { id, payload }
+        }))
+    }
+
+    /// Forget the messages still waiting for pieces, when some of their pieces were lost.
+    pub fn clear(&mut self) {
+        self.partial.clear();
+    }
+
+    /// How many messages are waiting for more pieces.
+    pub fn pending(&self) -> usize {
+        self.partial.len()
+    }
+}
+
+/// Encode the payload of a `CREDIT` frame.
+pub fn encode_credit(messages: u64) -> Vec<u8> {
+    messages.to_be_bytes().to_vec()
+}
+
+/// Decode the payload of a `CREDIT` frame.
+pub fn decode_credit(payload: &[u8]) -> Option<u64> {
+    if payload.len() != 8 {
+        return None;
+    }
+    Some(decode_u64(payload))
+}
+
+/// Encode the payload of a `RECEIPT` frame.
+pub fn encode_receipt(seq: u64, queued: u64) -> Vec<u8> {
+    let mut payload = seq.to_be_bytes().to_vec();
+    payload.extend_from_slice(&queued.to_be_bytes());
+    payload
+}
+
+/// Encode the payload of a `WHO` answer.
+pub fn encode_who(ids: &[u64]) -> Vec<u8> {
+    ids.iter().flat_map(|id| id.to_be_bytes().to_vec()).collect()
+}
+
+/// Encode the payload of a `WELCOME` frame: the protocol version, the longest payload accepted
+/// and the heartbeat interval in milliseconds, zero for none, as 8 byte big endian numbers. Then
+/// the length of the name in the same way, the name and the message of the day, both UTF-8.
+pub fn encode_welcome(welcome: &Welcome) -> Vec<u8> {
+    let heartbeat = welcome.heartbeat.map(|h| h.as_millis() as u64).unwrap_or(0);
+
+    let mut payload = Vec::with_capacity(32 + welcome.name.len() + welcome.motd.len());
+    payload.extend_from_slice(&welcome.version.to_be_bytes());
+    payload.extend_from_slice(&welcome.max_payload.to_be_bytes());
+    payload.extend_from_slice(&heartbeat.to_be_bytes());
+    payload.extend_from_slice(&(welcome.name.len() as u64).to_be_bytes());
+    payload.extend_from_slice(welcome.name.as_bytes());
+    payload.extend_from_slice(welcome.motd.as_bytes());
+    payload
+}
+
+/// Decode the payload of a `WELCOME` frame, or `None` if it is too short for the name it claims.
+fn decode_welcome(payload: &[u8]) -> Option<Welcome> {
+    if payload.len() < 32 {
+        return None;
+    }
+
+    let name_len = decode_u64(&payload[24..32]);
+    if name_len > (payload.len() - 32) as u64 {
+        return None;
+    }
+    let (name, motd) = payload[32..].split_at(name_len as usize);
+
+    Some(Welcome {
+        name: String::from_utf8_lossy(name).into_owned(),
+        version: decode_u64(&payload[..8]),
+        max_payload: decode_u64(&payload[8..16]),
+        heartbeat: match decode_u64(&payload[16..24]) {
+            0 => None,
+            ms => Some(Duration::from_millis(ms)),
+        },
+        motd: String::from_utf8_lossy(motd).into_owned(),
+    })
+}
+
+/// Encode the payload of a `GREETING` frame. It is UTF-8 text, one line each for `versions`,
+/// `codecs` and `features`, with the name of the line and its values separated by spaces.
+pub fn encode_greeting(capabilities: &Capabilities) -> Vec<u8> {
+    let versions: Vec<String> = capabilities.versions.iter().map(|v| v.to_string()).collect();
+
+    let mut text = String::new();
+    for (name, values) in &[("versions", &versions),
+                            ("codecs", &capabilities.codecs),
+                            ("features", &capabilities.features)] {
+        text.push_str(name);
+        for value in values.iter() {
+            text.push(' ');
+            text.push_str(value);
+        }
+        text.push('\n');
+    }
+    text.into_bytes()
+}
+
+/// Decode the payload of a `GREETING` frame. Lines it does not know, and versions that are not
+/// numbers, are left out so that servers can add to it.
+fn decode_greeting(payload: &[u8]) -> Capabilities {
+    let mut capabilities = Capabilities::default();
+
+    for line in String::from_utf8_lossy(payload).lines() {
+        let mut words = line.split_whitespace();
+        let values = match words.next() {
+            Some("versions") => {
+                capabilities.versions.extend(words.filter_map(|v| v.parse::<u64>().ok()));
+                continue;
+            }
+            Some("codecs") => &mut capabilities.codecs,
+            Some("features") => &mut capabilities.features,
+            _ => continue,
+        };
+        values.extend(words.map(|w| w.to_string()));
+    }
+
+    capabilities
+}
+
+/// Encode `payload` with Consistent Overhead Byte Stuffing, so that it contains no zero bytes. The
+/// `COBS_DELIMITER` that ends the frame is not included.
+///
+/// Each zero is replaced by the distance to the next one, and a distance byte is added in front.
+/// Runs of 254 bytes without a zero get one extra byte, so the overhead is at most one byte in
+/// 254.
+pub fn encode_cobs(payload: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(payload.len() + payload.len() / 254 + 1);
+
+    // where the distance byte of the current run goes, and the distance so far
+    let mut code_at = 0;
+    let mut code = 1u8;
+    out.push(0);
+
+    for (i, &byte) in payload.iter().enumerate() {
+        if byte != 0 {
+            out.push(byte);
+            code += 1;
+        }
+
+        // A full run at the very end needs no empty run after it.
+        if byte == 0 || (code == 0xff && i + 1 < payload.len()) {
+            out[code_at] = code;
+            code_at = out.len();
+            code = 1;
+            out.push(0);
+        }
+    }
+
+    out[code_at] = code;
+    out
+}
+
+/// Decode a frame encoded by `encode_cobs`, without its delimiter. Returns `None` if it is not
+/// valid COBS, because it holds a zero or a distance that runs past its end.
+pub fn decode_cobs(frame: &[u8]) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(frame.len());
+    let mut pos = 0;
+
+    while pos < frame.len() {
+        let code = frame[pos] as usize;
+        if code == 0 || pos + code > frame.len() {
+            return None;
+        }
+
+        let run = &frame[pos + 1..pos + code];
+        if run.contains(&0) {
+            return None;
+        }
+        out.extend_from_slice(run);
+        pos += code;
+
+        // A full length run stands for no zero, and neither does the end of the frame.
+        if code < 0xff && pos < frame.len() {
+            out.push(0);
+        }
+    }
+
+    Some(out)
+}
+
+/// Decode an 8 byte big endian number. `buf` must hold exactly 8 bytes.
+fn decode_u64(buf: &[u8]) -> u64 {
+    let mut n = [0u8; 8];
+    n.copy_from_slice(buf);
+    u64::from_be_bytes(n)
+}
+
+/// Decode the frame at the front of `buf`, whatever its kind.
+///
+/// Returns the payload and the total number of bytes the frame occupies, or `None` if `buf` does
+/// not hold a complete frame yet.
+pub fn decode(buf: &[u8]) -> Option<(&[u8], usize)> {
+    Endian::Big.decode(buf)
+}
+
+/// Reads whole frames off a blocking stream.
+///
+/// Reads are made in large chunks, so one read may return several frames, or only part of one.
+/// Whatever is left over after the last complete frame is kept for the next call.
+pub struct FrameReader<R> {
+    inner: R,
+    buf: Vec<u8>,
+
+    // where the unconsumed bytes in `buf` start
+    pos: usize,
+
+    // the byte order of the headers being read
+    endian: Endian,
+
+    // the route of the last frame read
+    route: Route,
+}
+
+impl<R: Read> FrameReader<R> {
+    pub fn new(inner: R) -> FrameReader<R> {
+        FrameReader {
+            inner,
+            buf: Vec::with_capacity(64 * 1024),
+            pos: 0,
+            endian: Endian::Big,
+            route: Route::default(),
+        }
+    }
+
+    /// The route of the last frame read. Frames without a header, and pings and pongs, have the
+    /// default one.
+    pub fn route(&self) -> Route {
+        self.route
+    }
+
+    /// Read headers in this byte order from the next frame on. Big endian by default.
+    pub fn set_endian(&mut self, endian: Endian) {
+        self.endian = endian;
+    }
+
+    /// The byte order headers are read in.
+    pub fn endian(&self) -> Endian {
+        self.endian
+    }
+
+    /// Read the next message's payload, skipping over control, request, reply and fragment frames.
+    ///
+    /// Returns `None` when the stream ends cleanly between frames. A stream that ends part way
+    /// through a frame is an `UnexpectedEof` error.
+    pub fn read_frame(&mut self) -> io::Result<Option<Vec<u8>>> {
+        loop {
+            match self.read()? {
+                Some(Frame::Data(payload)) => return Ok(Some(payload)),
+                Some(_) => continue,
+                None => return Ok(None),
+            }
+        }
+    }
+
+    /// Read the next frame, of any kind.
+    ///
+    /// Returns `None` when the stream ends cleanly between frames. A stream that ends part way
+    /// through a frame is an `UnexpectedEof` error, and an unknown frame kind is `InvalidData`.
+    pub fn read(&mut self) -> io::Result<Option<Frame>> {
+        loop {
+            let available = &self.buf[self.pos..];
+
+            let endian = self.endian;
+            let kind = if available.len() >= HEADER_LEN {
+                let kind = endian.decode_kind(available);
+                match kind {
+                    PING if endian.is_control(available) => {
+                        self.pos += HEADER_LEN;
+                        self.route = Route::default();
+                        return Ok(Some(Frame::Ping));
+                    }
+                    PONG if endian.is_control(available) => {
+                        self.pos += HEADER_LEN;
+                        self.route = Route::default();
+                        return Ok(Some(Frame::Pong));
+                    }
+                    DATA | REQUEST | REPLY | ERROR | CLOSE | MISSED | RECEIPT | WHO | WELCOME |
+                    GREETING | ENDIAN | FRAGMENT | NOTICE => {},
+                    _ => return Err(Error::new(ErrorKind::InvalidData, "Unknown frame kind")),
+                }
+
+                let len = endian.decode_len(available);
+                if len > MAX_PAYLOAD_LEN as u64 {
+                    return Err(Error::new(ErrorKind::InvalidData, "Message too large"));
+                }
+                if (kind == REQUEST || kind == REPLY) && len < CORRELATION_ID_LEN as u64 {
+                    return Err(Error::new(ErrorKind::InvalidData, "Missing correlation id"));
+                }
+                if kind == MISSED && len != 8 {
+                    return Err(Error::new(ErrorKind::InvalidData, "Malformed missed count"));
+                }
+                if kind == RECEIPT && len != 16 {
+                    return Err(Error::new(ErrorKind::InvalidData, "Malformed receipt"));
+                }
+                if kind == WHO && !len.is_multiple_of(8) {
+                    return Err(Error::new(ErrorKind::InvalidData, "Malformed client list"));
+                }
+                if kind == ENDIAN && len != 1 {
+                    return Err(Error::new(ErrorKind::InvalidData, "Malformed byte order"));
+                }
+                if kind == FRAGMENT && len < FRAGMENT_HEADER_LEN as u64 {
+                    return Err(Error::new(ErrorKind::InvalidData, "Malformed fragment"));
+                }
+                kind
+            } else {
+                DATA
+            };
+
+            if let Some((payload, used)) = endian.decode(available) {
+                self.route = endian.decode_route(available);
+                let frame = match kind {
+                    DATA => Frame::Data(payload.to_vec()),
+                    ERROR => Frame::Error(String::from_utf8_lossy(payload).into_owned()),
+                    CLOSE => Frame::Close(String::from_utf8_lossy(payload).into_owned()),
+                    NOTICE => Frame::Notice(String::from_utf8_lossy(payload).into_owned()),
+                    MISSED => Frame::Missed(decode_u64(payload)),
+                    RECEIPT => Frame::Receipt {
+                        seq: decode_u64(&payload[..8]),
+                        queued: decode_u64(&payload[8..]),
+                    },
+                    WHO => Frame::Who(payload.chunks(8).map(decode_u64).collect()),
+                    GREETING => Frame::Greeting(decode_greeting(payload)),
+                    ENDIAN => match decode_endian(payload) {
+                        Some(endian) => {
+                            // Whatever follows the switch is in the new order.
+                            self.endian = endian;
+                            Frame::Endian(endian)
+                        }
+                        None => {
+                            return Err(Error::new(ErrorKind::InvalidData, "Malformed byte order"));
+                        }
+                    },
+                    FRAGMENT => match decode_fragment(payload) {
+                        Some((id, last, kind, part)) => {
+                            Frame::Fragment { id, kind, last, part: part.to_vec() }
+                        }
+                        None => {
+                            return Err(Error::new(ErrorKind::InvalidData, "Malformed fragment"));
+                        }
+                    },
+                    WELCOME => match decode_welcome(payload) {
+                        Some(welcome) => Frame::Welcome(welcome),
+                        None => {
+                            return Err(Error::new(ErrorKind::InvalidData, "Malformed welcome"));
+                        }
+                    },
+                    _ => {
+                        let (id_bytes, payload) = payload.split_at(CORRELATION_ID_LEN);
+                        let mut id = [0u8; CORRELATION_ID_LEN];
+                        id.copy_from_slice(id_bytes);
+                        let id = u64::from_be_bytes(id);
+                        let payload = payload.to_vec();
+                        if kind == REQUEST {
+                            Frame::Request { id, payload }
+                        } else {
+                            Frame::Reply { id, payload }
+                        }
+                    }
+                };
+                self.pos += used;
+                return Ok(Some(frame));
+            }
+
+            if self.fill()? == 0 {
+                return if self.pos == self.buf.len() {
+                    Ok(None)
+                } else {
+                    Err(Error::new(ErrorKind::UnexpectedEof, "Stream ended mid frame"))
+                };
+            }
+        }
+    }
+
+    /// Read more bytes from the stream, returning how many were read.
+    fn fill(&mut self) -> io::Result<usize> {
+        // Move the unconsumed tail to the front so the buffer does not grow without bound.
+        if self.pos > 0 {
+            self.buf.drain(..self.pos);
+            self.pos = 0;
+        }
+
+        let len = self.buf.len();
+        let want = ::std::cmp::max(64 * 1024, self.buf.capacity() - len);
+        self.buf.resize(len + want, 0);
+
+        loop {
+            match self.inner.read(&mut self.buf[len..]) {
+                Ok(n) => {
+                    self.buf.truncate(len + n);
+                    return Ok(n);
+                }
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    self.buf.truncate(len);
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    /// Get a reference to the underlying stream.
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, ErrorKind, Read};
+
+    use std::time::Duration;
+
+    use super::{decode_cobs, decode_forward, decode_kind, decode_len, decode_priority, encode,
+                encode_cobs, encode_control, encode_endian, encode_forward, encode_frame_header,
+                encode_greeting, encode_header, encode_prioritized_header, encode_receipt,
+                encode_tagged, encode_welcome, encode_who, Assembler,
+                Capabilities, CLOSE, DATA, ENDIAN, ERROR, Endian, Frame, FrameReader, GREETING,
+                MAX_PAYLOAD_LEN, MISSED, Origin, PING, PONG, RECEIPT, REPLY, REQUEST, Route,
+                WELCOME, WHO, Welcome};
+
+    /// A reader that hands out its bytes in fixed size chunks.
+    struct Chunked {
+        data: Vec<u8>,
+        chunk: usize,
+    }
+
+    impl Read for Chunked {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = self.chunk.min(self.data.len()).min(buf.len());
+            buf[..n].copy_from_slice(&self.data[..n]);
+            self.data.drain(..n);
+            Ok(n)
+        }
+    }
+
+    fn frames(payloads: &[&[u8]]) -> Vec<u8> {
+        payloads.iter().flat_map(|p| encode(p)).collect()
+    }
+
+    #[test]
+    fn reads_several_frames_from_one_read() {
+        let data = frames(&[b"one", b"two", b"three"]);
+        let mut reader = FrameReader::new(&data[..]);
+
+        assert_eq!(reader.read_frame().unwrap(), Some(b"one".to_vec()));
+        assert_eq!(reader.read_frame().unwrap(), Some(b"two".to_vec()));
+        assert_eq!(reader.read_frame().unwrap(), Some(b"three".to_vec()));
+        assert_eq!(reader.read_frame().unwrap(), None);
+    }
+
+    #[test]
+    fn reads_frames_split_across_reads() {
+        let data = frames(&[b"hello", b"", b"world"]);
+        let mut reader = FrameReader::new(Chunked { data, chunk: 3 });
+
+        assert_eq!(reader.read_frame().unwrap(), Some(b"hello".to_vec()));
+        assert_eq!(reader.read_frame().unwrap(), Some(b"".to_vec()));
+        assert_eq!(reader.read_frame().unwrap(), Some(b"world".to_vec()));
+        assert_eq!(reader.read_frame().unwrap(), None);
+    }
+
+    #[test]
+    fn eof_mid_frame_is_an_error() {
+        let data = frames(&[b"truncated"]);
+        let mut reader = FrameReader::new(&data[..data.len() - 1]);
+
+        let e = reader.read_frame().unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn oversized_length_is_an_error() {
+        let data = encode_header(MAX_PAYLOAD_LEN + 1);
+        let mut reader = FrameReader::new(&data[..]);
+
+        let e = reader.read_frame().unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn reads_control_frames_between_messages() {
+        let mut data = encode(b"before");
+        data.extend_from_slice(&encode_control(PONG));
+        data.extend_from_slice(&encode_control(PING));
+        data.extend_from_slice(&encode(b"after"));
+
+        let mut reader = FrameReader::new(&data[..]);
+        assert_eq!(reader.read().unwrap(), Some(Frame::Data(b"before".to_vec())));
+        assert_eq!(reader.read().unwrap(), Some(Frame::Pong));
+        assert_eq!(reader.read().unwrap(), Some(Frame::Ping));
+        assert_eq!(reader.read().unwrap(), Some(Frame::Data(b"after".to_vec())));
+
+        // read_frame only hands out messages
+        let mut reader = FrameReader::new(&data[..]);
+        assert_eq!(reader.read_frame().unwrap(), Some(b"before".to_vec()));
+        assert_eq!(reader.read_frame().unwrap(), Some(b"after".to_vec()));
+    }
+
+    #[test]
+    fn unknown_frame_kind_is_an_error() {
+        let mut header = encode_control(PONG);
+        header[7] = 1;
+        let mut reader = FrameReader::new(&header[..]);
+        assert_eq!(reader.read().unwrap_err().kind(), ErrorKind::InvalidData);
+
+        let header = encode_control(0x7f);
+        let mut reader = FrameReader::new(&header[..]);
+        assert_eq!(reader.read().unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn reads_requests_and_replies() {
+        let mut data = encode_tagged(REQUEST, 7, b"question");
+        data.extend(encode(b"chatter"));
+        data.extend(encode_tagged(REPLY, 7, b""));
+
+        let mut reader = FrameReader::new(&data[..]);
+        assert_eq!(reader.read().unwrap(),
+                   Some(Frame::Request { id: 7, payload: b"question".to_vec() }));
+        assert_eq!(reader.read().unwrap(), Some(Frame::Data(b"chatter".to_vec())));
+        assert_eq!(reader.read().unwrap(), Some(Frame::Reply { id: 7, payload: Vec::new() }));
+
+        // read_frame only hands out messages
+        let mut reader = FrameReader::new(&data[..]);
+        assert_eq!(reader.read_frame().unwrap(), Some(b"chatter".to_vec()));
+        assert_eq!(reader.read_frame().unwrap(), None);
+    }
+
+    #[test]
+    fn request_without_correlation_id_is_an_error() {
+        let mut data = encode_frame_header(REQUEST, 3).to_vec();
+        data.extend_from_slice(b"abc");
+
+        let mut reader = FrameReader::new(&data[..]);
+        assert_eq!(reader.read().unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn reads_errors() {
+        let mut data = encode_frame_header(ERROR, 9).to_vec();
+        data.extend_from_slice(b"not UTF-8");
+
+        let mut reader = FrameReader::new(&data[..]);
+        assert_eq!(reader.read().unwrap(), Some(Frame::Error("not UTF-8".to_string())));
+    }
+
+    #[test]
+    fn reads_close_reasons() {
+        let mut data = encode_frame_header(CLOSE, 0).to_vec();
+        data.extend_from_slice(&encode_frame_header(CLOSE, 7));
+        data.extend_from_slice(b"goodbye");
+
+        let mut reader = FrameReader::new(&data[..]);
+        assert_eq!(reader.read().unwrap(), Some(Frame::Close(String::new())));
+        assert_eq!(reader.read().unwrap(), Some(Frame::Close("goodbye".to_string())));
+    }
+
+    #[test]
+    fn reads_missed_counts() {
+        let mut data = encode_frame_header(MISSED, 8).to_vec();
+        data.extend_from_slice(&12u64.to_be_bytes());
+        data.extend(encode(b"caught up"));
+
+        let mut reader = FrameReader::new(&data[..]);
+        assert_eq!(reader.read().unwrap(), Some(Frame::Missed(12)));
+        assert_eq!(reader.read_frame().unwrap(), Some(b"caught up".to_vec()));
+
+        let mut data = encode_frame_header(MISSED, 4).to_vec();
+        data.extend_from_slice(&[0; 4]);
+        let mut reader = FrameReader::new(&data[..]);
+        assert_eq!(reader.read().unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn reads_receipts() {
+        let mut data = encode_frame_header(RECEIPT, 16).to_vec();
+        data.extend(encode_receipt(3, 41));
+
+        let mut reader = FrameReader::new(&data[..]);
+        assert_eq!(reader.read().unwrap(), Some(Frame::Receipt { seq: 3, queued: 41 }));
+
+        let mut data = encode_frame_header(RECEIPT, 8).to_vec();
+        data.extend_from_slice(&[0; 8]);
+        let mut reader = FrameReader::new(&data[..]);
+        assert_eq!(reader.read().unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn reads_client_lists() {
+        let ids = encode_who(&[4, 0, 17]);
+        let mut data = encode_frame_header(WHO, ids.len()).to_vec();
+        data.extend(ids);
+        data.extend_from_slice(&encode_frame_header(WHO, 3));
+        data.extend_from_slice(&[0; 3]);
+
+        let mut reader = FrameReader::new(&data[..]);
+        assert_eq!(reader.read().unwrap(), Some(Frame::Who(vec![4, 0, 17])));
+        assert_eq!(reader.read().unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn reads_welcomes() {
+        let welcome = Welcome {
+            name: "lobby".to_string(),
+            heartbeat: Some(Duration::from_secs(30)),
+            motd: "be nice".to_string(),
+            ..Welcome::default()
+        };
+        let payload = encode_welcome(&welcome);
+        let mut data = encode_frame_header(WELCOME, payload.len()).to_vec();
+        data.extend(payload);
+
+        // A name longer than what follows it.
+        let mut bad = encode_welcome(&Welcome::default());
+        bad[31] += 1;
+        data.extend_from_slice(&encode_frame_header(WELCOME, bad.len()));
+        data.extend(bad);
+
+        let mut reader = FrameReader::new(&data[..]);
+        assert_eq!(reader.read().unwrap(), Some(Frame::Welcome(welcome)));
+        assert_eq!(reader.read().unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn reads_greetings() {
+        let capabilities = Capabilities {
+            versions: vec![1, 2],
+            codecs: vec!["length".to_string()],
+            features: vec!["receipts".to_string(), "who".to_string()],
+        };
+        let payload = encode_greeting(&capabilities);
+        assert_eq!(payload, b"versions 1 2\ncodecs length\nfeatures receipts who\n".to_vec());
+
+        // Lines from a newer server are skipped.
+        let newer = b"versions 1 x\nrooms lobby\nfeatures\n";
+        let mut data = encode_frame_header(GREETING, payload.len()).to_vec();
+        data.extend(payload);
+        data.extend_from_slice(&encode_frame_header(GREETING, newer.len()));
+        data.extend_from_slice(newer);
+
+        let mut reader = FrameReader::new(&data[..]);
+        assert_eq!(reader.read().unwrap(), Some(Frame::Greeting(capabilities)));
+        let understood = Capabilities { versions: vec![1], ..Capabilities::default() };
+        assert_eq!(reader.read().unwrap(), Some(Frame::Greeting(understood)));
+    }
+
+    #[test]
+    fn routes_are_carried_beside_the_kind() {
+        let header = encode_prioritized_header(DATA, 9, 300);
+        assert_eq!(decode_priority(&header), 9);
+        assert_eq!(decode_kind(&header), DATA);
+        assert_eq!(decode_len(&header), 300);
+        assert_eq!(decode_priority(&encode_frame_header(DATA, 300)), 0);
+
+        let route = Route { priority: 1, stream: 0x1234 };
+        let header = Endian::Little.encode_routed_header(REQUEST, route, 300);
+        assert_eq!(Endian::Little.decode_route(&header), route);
+        assert_eq!(Endian::Little.decode_priority(&header), 1);
+        assert_eq!(Endian::Little.decode_len(&header), 300);
+    }
+
+    #[test]
+    fn little_endian_headers_are_reversed() {
+        assert_eq!(Endian::Little.encode(b"hi"), vec![2, 0, 0, 0, 0, 0, 0, 0, b'h', b'i']);
+        assert_eq!(Endian::Little.encode_control(PING), [0, 0, 0, 0, 0, 0, 0, PING]);
+
+        let header = Endian::Little.encode_frame_header(REQUEST, 300);
+        assert_eq!(Endian::Little.decode_kind(&header), REQUEST);
+        assert_eq!(Endian::Little.decode_len(&header), 300);
+        assert!(!Endian::Little.is_control(&header));
+    }
+
+    #[test]
+    fn reader_switches_byte_order_after_an_endian_frame() {
+        let switch = encode_endian(Endian::Little);
+        let mut data = encode(b"before");
+        data.extend_from_slice(&encode_frame_header(ENDIAN, switch.len()));
+        data.extend(switch);
+        data.extend(Endian::Little.encode(b"after"));
+        data.extend_from_slice(&Endian::Little.encode_control(PONG));
+
+        let mut reader = FrameReader::new(&data[..]);
+        assert_eq!(reader.read().unwrap(), Some(Frame::Data(b"before".to_vec())));
+        assert_eq!(reader.read().unwrap(), Some(Frame::Endian(Endian::Little)));
+        assert_eq!(reader.endian(), Endian::Little);
+        assert_eq!(reader.read().unwrap(), Some(Frame::Data(b"after".to_vec())));
+        assert_eq!(reader.read().unwrap(), Some(Frame::Pong));
+        assert_eq!(reader.read().unwrap(), None);
+    }
+
+    #[test]
+    fn fragments_are_reassembled_in_order() {
+        let message: Vec<u8> = (0..100).collect();
+        let mut frames = Endian::Big.encode_fragments(7, DATA, Route::default(), &message, 40);
+        assert_eq!(frames.len(), 4);
+        assert!(frames.iter().all(|frame| frame.len() <= 8 + 40));
+
+        // A message in between does not get mixed in.
+        let reply = Endian::Big.encode_fragments(8, REPLY, Route::default(),
+                                                 b"\0\0\0\0\0\0\0\x09ok", 40);
+        frames.insert(2, reply.concat());
+        let data = frames.concat();
+
+        let mut reader = FrameReader::new(&data[..]);
+        let mut assembler = Assembler::new();
+        let mut assembled = Vec::new();
+        while let Some(frame) = reader.read().unwrap() {
+            match frame {
+                Frame::Fragment { id, kind, last, part } => {
+                    assembled.extend(assembler.add(id, kind, last, &part).unwrap());
+                }
+                frame => panic!("unexpected {:?}", frame),
+            }
+        }
+
+        assert_eq!(assembled, vec![
+            Frame::Reply { id: 9, payload: b"ok".to_vec() },
+            Frame::Data(message),
+        ]);
+        assert_eq!(assembler.pending(), 0);
+    }
+
+    #[test]
+    fn cobs_round_trips() {
+        let long: Vec<u8> = (1..=255).collect();
+        let mut zeros_in_long = long.clone();
+        zeros_in_long[100] = 0;
+        zeros_in_long.push(0);
+
+        let payloads: Vec<&[u8]> = vec![b"", b"\0", b"\0\0", b"hello", b"\0hel\0lo\0",
+                                        &long[..254], &long, &zeros_in_long];
+        for payload in payloads {
+            let encoded = encode_cobs(payload);
+            assert!(!encoded.contains(&0), "{:?}", payload);
+            assert!(encoded.len() <= payload.len() + payload.len() / 254 + 1, "{:?}", payload);
+            assert_eq!(decode_cobs(&encoded), Some(payload.to_vec()));
+        }
+    }
+
+    #[test]
+    fn cobs_matches_the_reference_encoding() {
+        assert_eq!(encode_cobs(b""), vec![0x01]);
+        assert_eq!(encode_cobs(b"\0"), vec![0x01, 0x01]);
+        assert_eq!(encode_cobs(b"\x11\x22\0\x33"), vec![0x03, 0x11, 0x22, 0x02, 0x33]);
+        assert_eq!(encode_cobs(b"\x11\0\0\0"), vec![0x02, 0x11, 0x01, 0x01, 0x01]);
+
+        let long: Vec<u8> = (1..=255).collect();
+        let mut full_run = vec![0xff];
+        full_run.extend_from_slice(&long[..254]);
+        assert_eq!(encode_cobs(&long[..254]), full_run);
+        full_run.extend_from_slice(&[0x02, 0xff]);
+        assert_eq!(encode_cobs(&long), full_run);
+    }
+
+    #[test]
+    fn invalid_cobs_is_rejected() {
+        assert_eq!(decode_cobs(&[0x00]), None);
+        assert_eq!(decode_cobs(&[0x05, 0x11, 0x22]), None);
+        assert_eq!(decode_cobs(&[0x03, 0x11, 0x00]), None);
+    }
+
+    #[test]
+    fn forwarded_messages_keep_their_origin_and_kind() {
+        let origin = Origin { server: 0xfeed, seq: 7 };
+        let payload = encode_forward(origin, REQUEST, b"\0\0\0\0\0\0\0\x01hi");
+        assert_eq!(decode_forward(&payload), Some((origin, REQUEST, &b"\0\0\0\0\0\0\0\x01hi"[..])));
+        assert_eq!(decode_forward(&payload[..16]), None);
+    }
+}
@@ -0,0 +1,185 @@
+//! Happy Eyeballs, RFC 8305: connecting to a host with both IPv6 and IPv4 addresses without
+//! waiting on a family that is broken.
+//!
+//! The addresses are tried in the order the resolver gave them, but alternating between the
+//! families, so a run of unreachable IPv6 addresses cannot hold up IPv4. Each attempt starts
+//! once the one before it has failed, or after `ATTEMPT_DELAY` if it is still going, and the
+//! first to connect wins. Attempts still going by then are left to finish on their own threads,
+//! and their connections are closed.
+
+use std::io::{self, Error, ErrorKind};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::Arc;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long an attempt has before the next one starts alongside it, as RFC 8305 recommends.
+pub const ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Connect to the first of `addrs` to answer, racing them as described above. `timeout` is for
+/// the whole race, not each attempt.
+pub fn connect(addrs: Vec<SocketAddr>, timeout: Option<Duration>) -> io::Result<TcpStream> {
+    // Nothing to race.
+    if addrs.len() == 1 {
+        return connect_one(addrs[0], timeout);
+    }
+
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    race(interleave(addrs), ATTEMPT_DELAY, deadline, Arc::new(|addr, deadline: Option<Instant>| {
+        connect_one(addr, deadline.map(|d| d.saturating_duration_since(Instant::now())))
+    }))
+}
+
+fn connect_one(addr: SocketAddr, timeout: Option<Duration>) -> io::Result<TcpStream> {
+    match timeout {
+        Some(timeout) if timeout == Duration::from_secs(0) => Err(timed_out()),
+        Some(timeout) => TcpStream::connect_timeout(&addr, timeout),
+        None => TcpStream::connect(addr),
+    }
+}
+
+fn timed_out() -> Error {
+    Error::new(ErrorKind::TimedOut, "Timed out connecting")
+}
+
+/// `addrs` in the same order within each family, but alternating between the families, starting
+/// with that of the first.
+fn interleave(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let first_v6 = addrs.first().is_some_and(SocketAddr::is_ipv6);
+    let (first, second): (Vec<_>, Vec<_>) =
+        addrs.into_iter().partition(|addr| addr.is_ipv6() == first_v6);
+
+    let mut interleaved = Vec::with_capacity(first.len() + second.len());
+    let (mut first, mut second) = (first.into_iter(), second.into_iter());
+    loop {
+        match (first.next(), second.next()) {
+            (None, None) => return interleaved,
+            (a, b) => interleaved.extend(a.into_iter().chain(b)),
+        }
+    }
+}
+
+/// Race `connect` to each of `addrs` in turn, starting the next attempt whenever one fails or
+/// `delay` passes without an answer, and return the first connection made. Fails with the last
+/// error if every attempt does, or `TimedOut` once `deadline` passes.
+fn race<T, F>(addrs: Vec<SocketAddr>, delay: Duration, deadline: Option<Instant>, connect: Arc<F>)
+    -> io::Result<T>
+    where T: Send + 'static,
+          F: Fn(SocketAddr, Option<Instant>) -> io::Result<T> + Send + Sync + 'static
+{
+    let (results, answers) = mpsc::channel();
+    let mut addrs = addrs.into_iter().peekable();
+    let mut attempts = 0;
+    let mut last_err = Error::new(ErrorKind::InvalidInput, "No addresses to connect to");
+
+    loop {
+        if let Some(addr) = addrs.next() {
+            let (results, connect) = (results.clone(), connect.clone());
+            thread::Builder::new().name("mob-connect".to_string()).spawn(move || {
+                // The race may be over, in which case the connection is closed here.
+                let _ = results.send(connect(addr, deadline));
+            })?;
+            attempts += 1;
+        } else if attempts == 0 {
+            return Err(last_err);
+        }
+
+        // Wait for an answer until the next attempt is due, or the deadline.
+        let now = Instant::now();
+        let next = if addrs.peek().is_some() { Some(now + delay) } else { None };
+        let until = match (next, deadline) {
+            (Some(next), Some(deadline)) => Some(if next < deadline { next } else { deadline }),
+            (until, None) | (None, until) => until,
+        };
+        let answer = match until {
+            Some(until) => answers.recv_timeout(until.saturating_duration_since(now)),
+            None => answers.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+
+        match answer {
+            Ok(Ok(connection)) => return Ok(connection),
+            Ok(Err(e)) => {
+                attempts -= 1;
+                last_err = e;
+            }
+            Err(_) if deadline.is_some_and(|deadline| Instant::now() >= deadline) => {
+                return Err(timed_out());
+            }
+            Err(_) => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Error, ErrorKind};
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use super::{interleave, race};
+
+    fn addrs(addrs: &[&str]) -> Vec<SocketAddr> {
+        addrs.iter().map(|addr| addr.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn families_alternate_starting_with_the_first() {
+        assert_eq!(interleave(addrs(&["[::1]:1", "[::2]:1", "[::3]:1", "10.0.0.1:1"])),
+                   addrs(&["[::1]:1", "10.0.0.1:1", "[::2]:1", "[::3]:1"]));
+        assert_eq!(interleave(addrs(&["10.0.0.1:1", "10.0.0.2:1", "[::1]:1"])),
+                   addrs(&["10.0.0.1:1", "[::1]:1", "10.0.0.2:1"]));
+    }
+
+    #[test]
+    fn a_hanging_address_only_delays_the_next_one() {
+        let hanging: SocketAddr = "[::1]:1".parse().unwrap();
+        let connect = Arc::new(move |addr: SocketAddr, _| {
+            if addr == hanging {
+                thread::sleep(Duration::from_secs(5));
+            }
+            Ok(addr)
+        });
+
+        let started = Instant::now();
+        let won = race(addrs(&["[::1]:1", "10.0.0.1:1"]), Duration::from_millis(50), None,
+                       connect).unwrap();
+        assert_eq!(won, "10.0.0.1:1".parse().unwrap());
+        assert!(started.elapsed() < Duration::from_secs(1), "{:?}", started.elapsed());
+    }
+
+    #[test]
+    fn a_failed_address_does_not_wait_out_the_delay() {
+        let connect = Arc::new(|addr: SocketAddr, _| {
+            if addr.is_ipv6() {
+                return Err(Error::new(ErrorKind::ConnectionRefused, "refused"));
+            }
+            Ok(addr)
+        });
+
+        let started = Instant::now();
+        let won = race(addrs(&["[::1]:1", "10.0.0.1:1"]), Duration::from_secs(5), None, connect);
+        assert_eq!(won.unwrap(), "10.0.0.1:1".parse().unwrap());
+        assert!(started.elapsed() < Duration::from_secs(1), "{:?}", started.elapsed());
+
+        let refused = Arc::new(|_, _| Err::<(), _>(Error::new(ErrorKind::ConnectionRefused, "no")));
+        let err = race(addrs(&["[::1]:1", "10.0.0.1:1"]), Duration::from_secs(5), None, refused);
+        assert_eq!(err.unwrap_err().kind(), ErrorKind::ConnectionRefused);
+    }
+
+    #[test]
+    fn the_deadline_covers_the_whole_race() {
+        let connect = Arc::new(|_, _| {
+            thread::sleep(Duration::from_secs(5));
+            Ok(())
+        });
+
+        let deadline = Instant::now() + Duration::from_millis(100);
+        let err = race(addrs(&["[::1]:1", "10.0.0.1:1"]), Duration::from_millis(50),
+                       Some(deadline), connect);
+        assert_eq!(err.unwrap_err().kind(), ErrorKind::TimedOut);
+        assert!(Instant::now() < deadline + Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,311 @@
+use std::cmp;
+use std::io::{self, Error, ErrorKind};
+use std::net::SocketAddr;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use client::{Client, Heartbeat, Timeouts};
+
+/// How long an endpoint is avoided after its first failure. Each further failure doubles it.
+const MIN_BACKOFF: Duration = Duration::from_millis(100);
+
+/// The longest an endpoint is avoided for.
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+struct Endpoint {
+    // every address the server has, raced when connecting
+    addrs: Vec<SocketAddr>,
+
+    // consecutive failures, reset by a successful connect
+    failures: u32,
+
+    // when the endpoint may be tried again, if it is cooling down after a failure
+    retry_at: Option<Instant>,
+}
+
+impl Endpoint {
+    fn is_healthy(&self, now: Instant) -> bool {
+        self.retry_at.map(|at| at <= now).unwrap_or(true)
+    }
+}
+
+/// An ordered list of server addresses to connect to, the first being the primary.
+///
+/// An endpoint that fails to connect, or whose connection is lost, cools down for a while before
+/// it is tried again, doubling each time it fails in a row. Connecting always prefers the
+/// earliest healthy endpoint, so the primary is used again as soon as it has cooled down.
+pub struct Endpoints {
+    endpoints: Vec<Endpoint>,
+
+    // the endpoint of the last successful connect, and the address it was reached at
+    current: Option<(usize, SocketAddr)>,
+
+    timeouts: Timeouts,
+    heartbeat: Option<Heartbeat>,
+}
+
+impl Endpoints {
+    /// An endpoint for each of `addrs`. Panics if `addrs` is empty.
+    pub fn new(addrs: Vec<SocketAddr>) -> Endpoints {
+        Endpoints::from_hosts(addrs.into_iter().map(|addr| vec![addr]).collect())
+    }
+
+    /// An endpoint for each of `hosts`, given as every address it resolved to. Connecting to one
+    /// races its addresses, as `Client::connect` does. Panics if `hosts`, or any one of them, is
+    /// empty.
+    pub fn from_hosts(hosts: Vec<Vec<SocketAddr>>) -> Endpoints {
+        assert!(!hosts.is_empty(), "at least one address is needed");
+        assert!(hosts.iter().all(|addrs| !addrs.is_empty()), "every host needs an address");
+
+        Endpoints {
+            endpoints: hosts.into_iter()
+                .map(|addrs| Endpoint { addrs, failures: 0, retry_at: None })
+                .collect(),
+            current: None,
+            timeouts: Timeouts::default(),
+            heartbeat: None,
+        }
+    }
+
+    /// Set the timeouts used by every client this connects.
+    pub fn set_timeouts(&mut self, timeouts: Timeouts) {
+        self.timeouts = timeouts;
+    }
+
+    /// Set the heartbeat used by every client this connects.
+    pub fn set_heartbeat(&mut self, heartbeat: Option<Heartbeat>) {
+        self.heartbeat = heartbeat;
+    }
+
+    /// Connect to the first healthy endpoint that accepts us.
+    ///
+    /// If every endpoint is cooling down, waits until the first of them is due and tries that.
+    /// Fails with the last connect error if nothing could be reached.
+    pub fn connect(&mut self) -> io::Result<Client> {
+        let now = Instant::now();
+        let mut candidates: Vec<usize> = (0..self.endpoints.len())
+            .filter(|&i| self.endpoints[i].is_healthy(now))
+            .collect();
+
+        if candidates.is_empty() {
+            let (i, at) = self.endpoints.iter()
+                .enumerate()
+                .filter_map(|(i, e)| e.retry_at.map(|at| (i, at)))
+                .min_by_key(|&(_, at)| at)
+                .unwrap();
+            thread::sleep(at.saturating_duration_since(now));
+            candidates.push(i);
+        }
+
+        let mut last_err = Error::new(ErrorKind::NotConnected, "No endpoint to connect to");
+        for i in candidates {
+            let result = Client::connect_with(&self.endpoints[i].addrs[..], self.timeouts)
+                .and_then(|mut client| client.set_heartbeat(self.heartbeat).map(|_| client));
+            match result {
+                Ok(client) => {
+                    let endpoint = &mut self.endpoints[i];
+                    endpoint.failures = 0;
+                    endpoint.retry_at = None;
+                    let addr = client.peer_addr().unwrap_or(endpoint.addrs[0]);
+                    self.current = Some((i, addr));
+                    return Ok(client);
+                }
+                Err(e) => {
+                    self.fail(i);
+                    last_err = e;
+                }
+            }
+        }
+
+        self.current = None;
+        Err(last_err)
+    }
+
+    /// Record that the connection to the current endpoint was lost, so the next `connect` moves
+    /// on to another one.
+    pub fn disconnected(&mut self) {
+        if let Some((i, _)) = self.current.take() {
+            self.fail(i);
+        }
+    }
+
+    /// The address of the endpoint we last connected to, if still connected.
+    pub fn current(&self) -> Option<SocketAddr> {
+        self.current.map(|(_, addr)| addr)
+    }
+
+    fn fail(&mut self, i: usize) {
+        let endpoint = &mut self.endpoints[i];
+        endpoint.failures += 1;
+
+        let backoff = MIN_BACKOFF.checked_mul(1 << cmp::min(endpoint.failures - 1, 16))
+            .map(|b| cmp::min(b, MAX_BACKOFF))
+            .unwrap_or(MAX_BACKOFF);
+        endpoint.retry_at = Some(Instant::now() + backoff);
+    }
+}
+
+/// A client that moves to the next endpoint when its connection fails.
+///
+/// Messages in flight when a connection is lost are lost with it, and broadcasts made while
+/// reconnecting are missed.
+pub struct FailoverClient {
+    endpoints: Endpoints,
+    client: Option<Client>,
+}
+
+impl FailoverClient {
+    /// Connect to the first endpoint in `addrs` that accepts us.
+    pub fn connect(addrs: Vec<SocketAddr>) -> io::Result<FailoverClient> {
+        FailoverClient::connect_with(addrs, Timeouts::default())
+    }
+
+    /// Connect to the first endpoint in `addrs` that accepts us, using `timeouts` for this and
+    /// every later connection.
+    pub fn connect_with(addrs: Vec<SocketAddr>, timeouts: Timeouts) -> io::Result<FailoverClient> {
+        let mut endpoints = Endpoints::new(addrs);
+        endpoints.set_timeouts(timeouts);
+        FailoverClient::from_endpoints(endpoints)
+    }
+
+    /// Connect to the first of `endpoints` that accepts us, and move through them from then on.
+    pub fn from_endpoints(mut endpoints: Endpoints) -> io::Result<FailoverClient> {
+        let client = endpoints.connect()?;
+        Ok(FailoverClient { endpoints, client: Some(client) })
+    }
+
+    /// Send a message, reconnecting and trying again if the connection has failed.
+    ///
+    /// A timed out send may have left part of the message behind, so it counts as a failed
+    /// connection too.
+    pub fn send(&mut self, msg: &[u8]) -> io::Result<()> {
+        let mut attempts = self.endpoints.endpoints.len();
+        loop {
+            let result = self.client()?.send(msg);
+            match result {
+                Err(ref e) if e.kind() != ErrorKind::InvalidInput && attempts > 1 => {
+                    attempts -= 1;
+                    self.failed();
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Send heartbeats while waiting in `recv`. A server that stops answering them counts as a
+    /// lost connection, so we move on to the next endpoint.
+    pub fn set_heartbeat(&mut self, heartbeat: Option<Heartbeat>) -> io::Result<()> {
+        self.endpoints.set_heartbeat(heartbeat);
+        match self.client {
+            Some(ref mut client) => client.set_heartbeat(heartbeat),
+            None => Ok(()),
+        }
+    }
+
+    /// Wait for the next broadcast, reconnecting whenever the connection is lost.
+    ///
+    /// Fails if no endpoint can be reached, or with `TimedOut` if the read timeout expires. A
+    /// quiet server is not a failed one, so a timeout does not move to the next endpoint. A
+    /// missed heartbeat does.
+    pub fn recv(&mut self) -> io::Result<Vec<u8>> {
+        loop {
+            match self.client()?.recv() {
+                Ok(Some(msg)) => return Ok(msg),
+                Err(e) if e.kind() == ErrorKind::TimedOut => return Err(e),
+                Ok(None) | Err(_) => self.failed(),
+            }
+        }
+    }
+
+    /// The address of the server we are connected to.
+    pub fn current(&self) -> Option<SocketAddr> {
+        self.endpoints.current()
+    }
+
+    fn client(&mut self) -> io::Result<&mut Client> {
+        if self.client.is_none() {
+            self.client = Some(self.endpoints.connect()?);
+        }
+
+        Ok(self.client.as_mut().unwrap())
+    }
+
+    fn failed(&mut self) {
+        self.client = None;
+        self.endpoints.disconnected();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{SocketAddr, TcpListener};
+    use std::time::Instant;
+
+    use super::Endpoints;
+
+    /// An address nothing is listening on.
+    fn closed_addr() -> SocketAddr {
+        TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap()
+    }
+
+    #[test]
+    fn skips_unreachable_primary() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let live = listener.local_addr().unwrap();
+        let mut endpoints = Endpoints::new(vec![closed_addr(), live]);
+
+        endpoints.connect().unwrap();
+        assert_eq!(endpoints.current(), Some(live));
+        assert!(!endpoints.endpoints[0].is_healthy(Instant::now()));
+    }
+
+    #[test]
+    fn moves_on_after_disconnect_and_prefers_primary_again() {
+        let primary = TcpListener::bind("127.0.0.1:0").unwrap();
+        let secondary = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addrs = vec![primary.local_addr().unwrap(), secondary.local_addr().unwrap()];
+        let mut endpoints = Endpoints::new(addrs.clone());
+
+        endpoints.connect().unwrap();
+        assert_eq!(endpoints.current(), Some(addrs[0]));
+
+        endpoints.disconnected();
+        endpoints.connect().unwrap();
+        assert_eq!(endpoints.current(), Some(addrs[1]));
+
+        // once the primary has cooled down it is preferred again
+        endpoints.endpoints[0].retry_at = Some(Instant::now());
+        endpoints.connect().unwrap();
+        assert_eq!(endpoints.current(), Some(addrs[0]));
+    }
+
+    #[test]
+    fn backoff_doubles_with_each_failure() {
+        let mut endpoints = Endpoints::new(vec![closed_addr()]);
+
+        endpoints.fail(0);
+        let first = endpoints.endpoints[0].retry_at.unwrap() - Instant::now();
+        endpoints.fail(0);
+        let second = endpoints.endpoints[0].retry_at.unwrap() - Instant::now();
+
+        assert!(second > first + first / 2, "{:?} then {:?}", first, second);
+    }
+
+    #[test]
+    fn a_host_is_reached_at_any_of_its_addresses() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let live = listener.local_addr().unwrap();
+        let mut endpoints = Endpoints::from_hosts(vec![vec![closed_addr(), live]]);
+
+        endpoints.connect().unwrap();
+        assert_eq!(endpoints.current(), Some(live));
+    }
+
+    #[test]
+    fn fails_when_nothing_is_reachable() {
+        let mut endpoints = Endpoints::new(vec![closed_addr(), closed_addr()]);
+
+        assert!(endpoints.connect().is_err());
+        assert_eq!(endpoints.current(), None);
+    }
+}
@@ -0,0 +1,418 @@
+//! Payload formats, for applications that send each message as an `Envelope` instead of bytes.
+//!
+//! The server never needs to know how a payload is laid out, but one can be told to reject
+//! payloads that are not in a given format, so every client on a listener can count on it.
+//!
+//! ```
+//! use mob_client::format::{self, Envelope};
+//!
+//! let json = format::by_name("json").unwrap();
+//! let envelope = Envelope { topic: "chat".to_string(), body: b"hello".to_vec() };
+//! let payload = json.encode(&envelope);
+//! assert_eq!(payload, br#"{"topic":"chat","body":"hello"}"#.to_vec());
+//! assert_eq!(json.decode(&payload).unwrap(), envelope);
+//! ```
+
+use std::char;
+use std::io::{self, Error, ErrorKind};
+use std::str;
+use std::sync::Arc;
+
+/// A message as applications see it, before it is encoded into a payload.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Envelope {
+    /// What the message is about, for receivers to sort messages by. Empty if there is none.
+    pub topic: String,
+    pub body: Vec<u8>,
+}
+
+/// A way of laying an `Envelope` out in a payload.
+pub trait PayloadFormat: Send + Sync {
+    /// The name the format is known by, as `by_name` takes it.
+    fn name(&self) -> &'static str;
+
+    fn encode(&self, envelope: &Envelope) -> Vec<u8>;
+
+    /// Decode a payload, failing with `InvalidData` if it is not in this format.
+    fn decode(&self, payload: &[u8]) -> io::Result<Envelope>;
+}
+
+/// The format called `name`, one of `raw`, `json` or `msgpack`.
+pub fn by_name(name: &str) -> Option<Arc<dyn PayloadFormat>> {
+    match name {
+        "raw" => Some(Arc::new(Raw)),
+        "json" => Some(Arc::new(Json)),
+        "msgpack" => Some(Arc::new(MessagePack)),
+        _ => None,
+    }
+}
+
+fn invalid(what: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, what)
+}
+
+/// The body as it is, with no topic. Every payload decodes, and the topic is dropped on the way
+/// out.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Raw;
+
+impl PayloadFormat for Raw {
+    fn name(&self) -> &'static str {
+        "raw"
+    }
+
+    fn encode(&self, envelope: &Envelope) -> Vec<u8> {
+        envelope.body.clone()
+    }
+
+    fn decode(&self, payload: &[u8]) -> io::Result<Envelope> {
+        Ok(Envelope { topic: String::new(), body: payload.to_vec() })
+    }
+}
+
+/// A JSON object with a `topic` and a `body`, both strings. Bodies that are not UTF-8 have the
+/// bad bytes replaced on the way out, so use `msgpack` for binary ones.
+///
+/// Other members are skipped if their values are strings too. A missing topic is empty.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Json;
+
+impl PayloadFormat for Json {
+    fn name(&self) -> &'static str {
+        "json"
+    }
+
+    fn encode(&self, envelope: &Envelope) -> Vec<u8> {
+        let mut out = String::from("{\"topic\":");
+        quote(&mut out, &envelope.topic);
+        out.push_str(",\"body\":");
+        quote(&mut out, &String::from_utf8_lossy(&envelope.body));
+        out.push('}');
+        out.into_bytes()
+    }
+
+    fn decode(&self, payload: &[u8]) -> io::Result<Envelope> {
+        let mut parser = JsonParser { s: payload, pos: 0 };
+        let mut topic = None;
+        let mut body = None;
+
+        parser.expect(b'{')?;
+        if !parser.eat(b'}') {
+            loop {
+                let name = parser.string()?;
+                parser.expect(b':')?;
+                let value = parser.string()?;
+                match name.as_str() {
+                    "topic" => topic = Some(value),
+                    "body" => body = Some(value.into_bytes()),
+                    _ => {}
+                }
+                if parser.eat(b'}') {
+                    break;
+                }
+                parser.expect(b',')?;
+            }
+        }
+        parser.end()?;
+
+        let body = body.ok_or_else(|| invalid("Envelope has no body"))?;
+        Ok(Envelope { topic: topic.unwrap_or_default(), body })
+    }
+}
+
+/// Append `s` to `out` as a quoted JSON string.
+fn quote(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+/// Just enough of a JSON parser for an object of strings.
+struct JsonParser<'a> {
+    s: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> JsonParser<'a> {
+    fn skip_whitespace(&mut self) {
+        while self.pos < self.s.len() && b" \t\r\n".contains(&self.s[self.pos]) {
+            self.pos += 1;
+        }
+    }
+
+    /// Skip whitespace, then `b` if it comes next. Returns whether it did.
+    fn eat(&mut self, b: u8) -> bool {
+        self.skip_whitespace();
+        if self.s.get(self.pos) == Some(&b) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, b: u8) -> io::Result<()> {
+        if self.eat(b) {
+            Ok(())
+        } else {
+            Err(invalid("Malformed JSON envelope"))
+        }
+    }
+
+    fn end(&mut self) -> io::Result<()> {
+        self.skip_whitespace();
+        if self.pos == self.s.len() {
+            Ok(())
+        } else {
+            Err(invalid("Trailing data after JSON envelope"))
+        }
+    }
+
+    fn string(&mut self) -> io::Result<String> {
+        self.expect(b'"')?;
+        let mut out = Vec::new();
+        loop {
+            let b = *self.s.get(self.pos).ok_or_else(|| invalid("Unterminated JSON string"))?;
+            self.pos += 1;
+            match b {
+                b'"' => break,
+                b'\\' => {
+                    let escape = *self.s.get(self.pos)
+                        .ok_or_else(|| invalid("Unterminated JSON string"))?;
+                    self.pos += 1;
+                    let c = match escape {
+                        b'"' => '"',
+                        b'\\' => '\\',
+                        b'/' => '/',
+                        b'b' => '\u{8}',
+                        b'f' => '\u{c}',
+                        b'n' => '\n',
+                        b'r' => '\r',
+                        b't' => '\t',
+                        b'u' => self.unicode_escape()?,
+                        _ => return Err(invalid("Bad escape in JSON string")),
+                    };
+                    let mut buf = [0; 4];
+                    out.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+                }
+                b if b < 0x20 => return Err(invalid("Control character in JSON string")),
+                b => out.push(b),
+            }
+        }
+        String::from_utf8(out).map_err(|_| invalid("JSON string is not UTF-8"))
+    }
+
+    /// The character of a `\u` escape whose `\u` has been read, along with the low half of a
+    /// surrogate pair if it is the high half of one.
+    fn unicode_escape(&mut self) -> io::Result<char> {
+        let high = self.hex4()?;
+        let code = if (0xd800..0xdc00).contains(&high) {
+            if self.s.get(self.pos..self.pos + 2) != Some(&b"\\u"[..]) {
+                return Err(invalid("Unpaired surrogate in JSON string"));
+            }
+            self.pos += 2;
+            let low = self.hex4()?;
+            if !(0xdc00..0xe000).contains(&low) {
+                return Err(invalid("Unpaired surrogate in JSON string"));
+            }
+            0x10000 + ((high - 0xd800) << 10) + (low - 0xdc00)
+        } else {
+            high
+        };
+        char::from_u32(code).ok_or_else(|| invalid("Unpaired surrogate in JSON string"))
+    }
+
+    fn hex4(&mut self) -> io::Result<u32> {
+        let digits = self.s.get(self.pos..self.pos + 4)
+            .and_then(|digits| str::from_utf8(digits).ok())
+            .and_then(|digits| u32::from_str_radix(digits, 16).ok())
+            .ok_or_else(|| invalid("Bad escape in JSON string"))?;
+        self.pos += 4;
+        Ok(digits)
+    }
+}
+
+/// A MessagePack map with a `topic` string and a `body` binary, so bodies need not be text.
+///
+/// A body sent as a string is taken too, and other entries are skipped if their values are
+/// strings or binaries. A missing topic is empty.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MessagePack;
+
+impl PayloadFormat for MessagePack {
+    fn name(&self) -> &'static str {
+        "msgpack"
+    }
+
+    fn encode(&self, envelope: &Envelope) -> Vec<u8> {
+        let mut out = vec![0x82];
+        pack_str(&mut out, b"topic");
+        pack_str(&mut out, envelope.topic.as_bytes());
+        pack_str(&mut out, b"body");
+        pack_len(&mut out, envelope.body.len(), None, [0xc4, 0xc5, 0xc6]);
+        out.extend_from_slice(&envelope.body);
+        out
+    }
+
+    fn decode(&self, payload: &[u8]) -> io::Result<Envelope> {
+        let mut unpacker = Unpacker { s: payload, pos: 0 };
+        let entries = match unpacker.byte()? {
+            b @ 0x80..=0x8f => (b & 0x0f) as usize,
+            0xde => unpacker.uint(2)?,
+            0xdf => unpacker.uint(4)?,
+            _ => return Err(invalid("MessagePack envelope is not a map")),
+        };
+
+        let mut topic = None;
+        let mut body = None;
+        for _ in 0..entries {
+            let name = unpacker.bytes()?;
+            let value = unpacker.bytes()?;
+            match name {
+                b"topic" => {
+                    let value = str::from_utf8(value)
+                        .map_err(|_| invalid("MessagePack topic is not UTF-8"))?;
+                    topic = Some(value.to_string());
+                }
+                b"body" => body = Some(value.to_vec()),
+                _ => {}
+            }
+        }
+        if unpacker.pos != payload.len() {
+            return Err(invalid("Trailing data after MessagePack envelope"));
+        }
+
+        let body = body.ok_or_else(|| invalid("Envelope has no body"))?;
+        Ok(Envelope { topic: topic.unwrap_or_default(), body })
+    }
+}
+
+fn pack_str(out: &mut Vec<u8>, s: &[u8]) {
+    pack_len(out, s.len(), Some(0xa0), [0xd9, 0xda, 0xdb]);
+    out.extend_from_slice(s);
+}
+
+/// Append the header of a string or binary of `len` bytes, using `fixed` for lengths under 32 if
+/// there is a fixed form, and otherwise the first of `markers` with room for the length.
+fn pack_len(out: &mut Vec<u8>, len: usize, fixed: Option<u8>, markers: [u8; 3]) {
+    match fixed {
+        Some(fixed) if len < 32 => out.push(fixed | len as u8),
+        _ if len <= u8::MAX as usize => out.extend_from_slice(&[markers[0], len as u8]),
+        _ if len <= u16::MAX as usize => {
+            out.push(markers[1]);
+            out.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        _ => {
+            out.push(markers[2]);
+            out.extend_from_slice(&(len as u32).to_be_bytes());
+        }
+    }
+}
+
+/// Just enough of a MessagePack reader for a map of strings and binaries.
+struct Unpacker<'a> {
+    s: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Unpacker<'a> {
+    fn take(&mut self, n: usize) -> io::Result<&'a [u8]> {
+        let s = self.s;
+        let taken = s.get(self.pos..self.pos + n)
+            .ok_or_else(|| invalid("Truncated MessagePack envelope"))?;
+        self.pos += n;
+        Ok(taken)
+    }
+
+    fn byte(&mut self) -> io::Result<u8> {
+        self.take(1).map(|b| b[0])
+    }
+
+    /// A big endian unsigned integer `n` bytes long.
+    fn uint(&mut self, n: usize) -> io::Result<usize> {
+        Ok(self.take(n)?.iter().fold(0, |acc, &b| acc << 8 | b as usize))
+    }
+
+    /// The contents of a string or binary.
+    fn bytes(&mut self) -> io::Result<&'a [u8]> {
+        let len = match self.byte()? {
+            b @ 0xa0..=0xbf => (b & 0x1f) as usize,
+            0xd9 | 0xc4 => self.uint(1)?,
+            0xda | 0xc5 => self.uint(2)?,
+            0xdb | 0xc6 => self.uint(4)?,
+            _ => return Err(invalid("MessagePack envelope holds something other than strings")),
+        };
+        self.take(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{by_name, Envelope, Json, MessagePack, PayloadFormat, Raw};
+
+    fn envelope(topic: &str, body: &[u8]) -> Envelope {
+        Envelope { topic: topic.to_string(), body: body.to_vec() }
+    }
+
+    #[test]
+    fn formats_are_found_by_name() {
+        for name in &["raw", "json", "msgpack"] {
+            assert_eq!(by_name(name).unwrap().name(), *name);
+        }
+        assert!(by_name("xml").is_none());
+    }
+
+    #[test]
+    fn raw_payloads_are_the_body() {
+        assert_eq!(Raw.encode(&envelope("chat", b"hi")), b"hi".to_vec());
+        assert_eq!(Raw.decode(b"hi").unwrap(), envelope("", b"hi"));
+    }
+
+    #[test]
+    fn json_round_trips_escapes() {
+        let sent = envelope("a \"quoted\"\ttopic", "line\nbreak \u{1} é 😀".as_bytes());
+        assert_eq!(Json.decode(&Json.encode(&sent)).unwrap(), sent);
+
+        let payload = br#" { "id": "7", "body" : "\ud83d\ude00\u00e9\/" } "#;
+        assert_eq!(Json.decode(payload).unwrap(), envelope("", "😀é/".as_bytes()));
+    }
+
+    #[test]
+    fn json_rejects_what_is_not_an_envelope() {
+        for payload in &[&b"hello"[..], b"{}", b"{\"body\":1}", b"{\"body\":\"x\"} x",
+                         b"{\"body\":\"\\ud83d\"}", b"{\"body\":\"x"] {
+            assert!(Json.decode(payload).is_err(), "{:?}", payload);
+        }
+    }
+
+    #[test]
+    fn msgpack_round_trips_binary_bodies() {
+        let sent = envelope("chat", &[0, 0xff, 0x80]);
+        let payload = MessagePack.encode(&sent);
+        assert_eq!(&payload[..7], &[0x82, 0xa5, b't', b'o', b'p', b'i', b'c'][..]);
+        assert_eq!(MessagePack.decode(&payload).unwrap(), sent);
+
+        let long = envelope(&"t".repeat(300), &[7; 70_000]);
+        assert_eq!(MessagePack.decode(&MessagePack.encode(&long)).unwrap(), long);
+    }
+
+    #[test]
+    fn msgpack_rejects_what_is_not_an_envelope() {
+        let mut trailing = MessagePack.encode(&envelope("", b"x"));
+        trailing.push(0);
+        for payload in &[&b"hello"[..], &[0x80], &[0x81, 0xa4, b'b', b'o', b'd', b'y', 0x01],
+                         &[0x81, 0xa4, b'b', b'o', b'd', b'y', 0xc4, 0x05, b'x'], &trailing] {
+            assert!(MessagePack.decode(payload).is_err(), "{:?}", payload);
+        }
+    }
+}
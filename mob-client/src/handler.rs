@@ -0,0 +1,107 @@
+use std::io;
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+use codec::{self, Endian, Route};
+use client::{self, Client};
+
+/// Callbacks for push style consumption of broadcasts.
+///
+/// Every callback is made from the read loop started by `Client::spawn`. Only `on_message` has to
+/// be implemented.
+pub trait Handler {
+    /// The read loop has started. `addr` is the server's address.
+    fn on_connect(&mut self, _addr: SocketAddr) {}
+
+    /// A broadcast arrived.
+    fn on_message(&mut self, msg: Vec<u8>);
+
+    /// A broadcast arrived on `stream`. Passes it to `on_message` unless implemented, for
+    /// handlers that do not use streams.
+    fn on_stream_message(&mut self, _stream: u16, msg: Vec<u8>) {
+        self.on_message(msg);
+    }
+
+    /// The connection is gone. This is always the last callback.
+    fn on_disconnect(&mut self) {}
+
+    /// Reading failed. `on_disconnect` follows.
+    fn on_error(&mut self, _err: io::Error) {}
+}
+
+/// The sending half of a client whose broadcasts are delivered to a `Handler`.
+pub struct Dispatcher<H> {
+    stream: TcpStream,
+    write_lock: Arc<Mutex<()>>,
+    endian: Endian,
+    thread: JoinHandle<H>,
+}
+
+impl<H> Dispatcher<H> {
+    /// Send a message, in pieces if it is too large for one frame, see `Client::send`.
+    pub fn send(&mut self, msg: &[u8]) -> io::Result<()> {
+        self.send_routed(msg, Route::default())
+    }
+
+    /// Send a message on `route`, see `Client::send_routed`. It comes back to the handler's
+    /// `on_stream_message`.
+    pub fn send_routed(&mut self, msg: &[u8], route: Route) -> io::Result<()> {
+        let frames = client::encode_message(self.endian, codec::DATA, route, msg);
+        client::write_frames(&self.stream, &self.write_lock, &frames)
+    }
+
+    /// Stop sending. The server closes the connection in response, which ends the read loop.
+    pub fn shutdown(&self) -> io::Result<()> {
+        self.stream.shutdown(Shutdown::Write)
+    }
+
+    /// Wait for the read loop to finish and get the handler back.
+    pub fn join(self) -> H {
+        match self.thread.join() {
+            Ok(handler) => handler,
+            Err(panic) => ::std::panic::resume_unwind(panic),
+        }
+    }
+}
+
+/// Read broadcasts off `client` until the connection ends, passing each one to `handler`.
+fn read_loop<H: Handler>(mut client: Client, mut handler: H) -> H {
+    match client.peer_addr() {
+        Ok(addr) => handler.on_connect(addr),
+        Err(e) => {
+            handler.on_error(e);
+            handler.on_disconnect();
+            return handler;
+        }
+    }
+
+    loop {
+        match client.recv() {
+            Ok(Some(msg)) => handler.on_stream_message(client.route().stream, msg),
+            Ok(None) => break,
+            Err(e) => {
+                handler.on_error(e);
+                break;
+            }
+        }
+    }
+
+    handler.on_disconnect();
+    handler
+}
+
+impl Client {
+    /// Start a thread that reads broadcasts and hands them to `handler`.
+    ///
+    /// The returned `Dispatcher` is used to send messages, and to get the handler back once the
+    /// connection is over.
+    pub fn spawn<H: Handler + Send + 'static>(self, handler: H) -> io::Result<Dispatcher<H>> {
+        let stream = self.get_ref().try_clone()?;
+        let write_lock = self.write_lock();
+        let endian = self.endian();
+        let thread = thread::spawn(move || read_loop(self, handler));
+
+        Ok(Dispatcher { stream, write_lock, endian, thread })
+    }
+}
@@ -0,0 +1,42 @@
+//! A blocking client for the mob multi-echo server.
+//!
+//! `Client` connects to a server, sends messages and receives the messages broadcast by every
+//! connected client, including its own. The framing it uses is in `codec`, for programs that
+//! would rather drive the socket themselves.
+//!
+//! ```no_run
+//! use mob_client::Client;
+//!
+//! let mut client = Client::connect("127.0.0.1:8000").unwrap();
+//! client.send(b"hello").unwrap();
+//! let msg = client.recv().unwrap();
+//! ```
+//!
+//! Programs that would rather be called when a message arrives than wait in `recv` can implement
+//! `Handler` and hand it to `Client::spawn`. Programs that want to send and receive from
+//! different threads can `Client::split` it into an `Outbox` and an `Inbox`.
+//!
+//! A host name that resolves to more than one address is connected to with Happy Eyeballs, racing
+//! its IPv6 and IPv4 addresses, see `Client::connect`.
+//!
+//! `FailoverClient` takes a list of servers and moves on to the next one whenever its connection
+//! fails.
+//!
+//! `Client::request` and `Client::reply` layer simple RPC on top of the broadcast, matching each
+//! reply to its request by a correlation id.
+//!
+//! `format` lays typed `Envelope`s out in payloads as JSON, MessagePack or plain bytes, behind
+//! the `PayloadFormat` trait.
+
+pub mod codec;
+mod client;
+mod eyeballs;
+mod failover;
+pub mod format;
+mod handler;
+mod split;
+
+pub use client::{Client, Heartbeat, Timeouts};
+pub use failover::{Endpoints, FailoverClient};
+pub use handler::{Dispatcher, Handler};
+pub use split::{Inbox, Outbox};
@@ -0,0 +1,151 @@
+use std::io::{self, Error, ErrorKind};
+use std::net::{Shutdown, TcpStream};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use codec::{self, Endian, Route};
+use client::{self, Client};
+
+/// The sending half of a split client.
+///
+/// Messages are queued for a writer thread, so sending never waits on the network, or on a read.
+/// Cloned outboxes share the same connection. Once every clone is dropped, the writer thread
+/// finishes writing whatever is queued and shuts down the sending side of the connection.
+#[derive(Clone)]
+pub struct Outbox {
+    tx: Sender<Vec<u8>>,
+    endian: Endian,
+}
+
+impl Outbox {
+    /// Queue a message to be sent. Like `Client::send`, a message too large for one frame is
+    /// sent in pieces.
+    ///
+    /// Fails if the writer thread has stopped. The write error that stopped it is delivered to
+    /// the `Inbox`.
+    pub fn send(&self, msg: &[u8]) -> io::Result<()> {
+        self.send_prioritized(msg, 0)
+    }
+
+    /// Queue a message to be sent at `priority`, see `Client::send_prioritized`.
+    pub fn send_prioritized(&self, msg: &[u8], priority: u8) -> io::Result<()> {
+        self.send_routed(msg, Route { priority, stream: 0 })
+    }
+
+    /// Queue a message to be sent on `route`, see `Client::send_routed`.
+    pub fn send_routed(&self, msg: &[u8], route: Route) -> io::Result<()> {
+        for frame in client::encode_message(self.endian, codec::DATA, route, msg) {
+            self.tx.send(frame)
+                .map_err(|_| Error::new(ErrorKind::BrokenPipe, "Writer has stopped"))?;
+        }
+        Ok(())
+    }
+
+    /// Queue a grant of `messages` more credit, see `Client::grant_credit`. The `Inbox` does not
+    /// grant any itself.
+    pub fn grant_credit(&self, messages: u64) -> io::Result<()> {
+        self.tx.send(client::encode_grant(self.endian, messages))
+            .map_err(|_| Error::new(ErrorKind::BrokenPipe, "Writer has stopped"))
+    }
+}
+
+/// The receiving half of a split client.
+///
+/// Broadcasts are read on their own thread and delivered here. Errors from either thread are
+/// delivered here too.
+pub struct Inbox {
+    rx: Receiver<io::Result<Vec<u8>>>,
+}
+
+impl Inbox {
+    /// Wait for the next broadcast.
+    ///
+    /// Returns `None` once the connection is closed.
+    pub fn recv(&self) -> io::Result<Option<Vec<u8>>> {
+        match self.rx.recv() {
+            Ok(result) => result.map(Some),
+            Err(_) => Ok(None),
+        }
+    }
+
+    /// Return the next broadcast if one has already arrived, without waiting.
+    ///
+    /// Returns `None` if nothing has arrived yet. A closed connection is an `UnexpectedEof`
+    /// error, so it cannot be mistaken for an empty one.
+    pub fn try_recv(&self) -> io::Result<Option<Vec<u8>>> {
+        match self.rx.try_recv() {
+            Ok(result) => result.map(Some),
+            Err(TryRecvError::Empty) => Ok(None),
+            Err(TryRecvError::Disconnected) => {
+                Err(Error::new(ErrorKind::UnexpectedEof, "Server closed the connection"))
+            }
+        }
+    }
+
+    /// Wait up to `timeout` for the next broadcast.
+    ///
+    /// Returns `None` if nothing arrived in time. A closed connection is an `UnexpectedEof`
+    /// error.
+    pub fn recv_timeout(&self, timeout: Duration) -> io::Result<Option<Vec<u8>>> {
+        match self.rx.recv_timeout(timeout) {
+            Ok(result) => result.map(Some),
+            Err(RecvTimeoutError::Timeout) => Ok(None),
+            Err(RecvTimeoutError::Disconnected) => {
+                Err(Error::new(ErrorKind::UnexpectedEof, "Server closed the connection"))
+            }
+        }
+    }
+}
+
+/// Write every queued frame to `stream` until all outboxes are gone.
+fn write_loop(stream: TcpStream,
+              write_lock: Arc<Mutex<()>>,
+              frames: Receiver<Vec<u8>>,
+              errors: Sender<io::Result<Vec<u8>>>) {
+    for frame in frames {
+        if let Err(e) = client::write_frame(&stream, &write_lock, &frame) {
+            let _ = errors.send(Err(e));
+            return;
+        }
+    }
+
+    let _ = stream.shutdown(Shutdown::Write);
+}
+
+/// Read broadcasts off `client` and deliver them until the connection ends or the inbox is gone.
+fn read_loop(mut client: Client, inbox: Sender<io::Result<Vec<u8>>>) {
+    loop {
+        let result = match client.recv() {
+            Ok(Some(msg)) => Ok(msg),
+            Ok(None) => return,
+            Err(e) => Err(e),
+        };
+
+        let failed = result.is_err();
+        if inbox.send(result).is_err() || failed {
+            return;
+        }
+    }
+}
+
+impl Client {
+    /// Split the client into a sending and a receiving half, each served by its own thread.
+    ///
+    /// Unlike a plain `Client`, a slow `recv` never holds up a `send`, and the two halves can be
+    /// moved to different threads of the application.
+    pub fn split(self) -> io::Result<(Outbox, Inbox)> {
+        let stream = self.get_ref().try_clone()?;
+        let (frames_tx, frames_rx) = mpsc::channel();
+        let (inbox_tx, inbox_rx) = mpsc::channel();
+
+        let write_lock = self.write_lock();
+        let endian = self.endian();
+        let errors = inbox_tx.clone();
+        thread::spawn(move || write_loop(stream, write_lock, frames_rx, errors));
+        thread::spawn(move || read_loop(self, inbox_tx));
+
+        Ok((Outbox { tx: frames_tx, endian }, Inbox { rx: inbox_rx }))
+    }
+}
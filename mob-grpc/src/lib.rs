@@ -0,0 +1,148 @@
+//! A gRPC gateway into a mob server's broadcast.
+//!
+//! Each call to the bidirectional `Chat` method of the `mob.Mob` service, see `proto/mob.proto`,
+//! gets its own connection to the mob server. Messages on the request stream are sent to the
+//! server, and every broadcast comes back on the response stream, so gRPC services can publish
+//! and subscribe next to ordinary mob clients.
+//!
+//! The server itself is single threaded and speaks only the mob protocol, so the gateway is a
+//! separate process that talks to it over TCP like any other client.
+
+use std::io::{self, Error};
+use std::net::{SocketAddr, TcpListener};
+
+use mob_client::Client;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::{ReceiverStream, TcpListenerStream};
+use tonic::{Request, Response, Status, Streaming};
+
+pub mod proto;
+
+use proto::{Message, Mob, MobServer};
+
+/// How many broadcasts may wait for a slow gRPC client before reading from mob stops.
+const RESPONSE_BUFFER: usize = 64;
+
+/// Bridges `Chat` streams to the mob server at `mob`.
+pub struct Gateway {
+    mob: SocketAddr,
+}
+
+impl Gateway {
+    pub fn new(mob: SocketAddr) -> Gateway {
+        Gateway { mob }
+    }
+}
+
+#[tonic::async_trait]
+impl Mob for Gateway {
+    type ChatStream = ReceiverStream<Result<Message, Status>>;
+
+    async fn chat(&self, request: Request<Streaming<Message>>)
+        -> Result<Response<Self::ChatStream>, Status>
+    {
+        let mob = self.mob;
+        let client = tokio::task::spawn_blocking(move || Client::connect(mob))
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?
+            .map_err(|e| Status::unavailable(format!("Failed to reach mob: {}", e)))?;
+        let (outbox, inbox) = client.split()
+            .map_err(|e| Status::unavailable(format!("Failed to reach mob: {}", e)))?;
+
+        // Broadcasts are read on a thread of their own, because the mob client blocks. It ends
+        // once mob closes the connection, which it does when the request stream ends below.
+        let (tx, rx) = mpsc::channel(RESPONSE_BUFFER);
+        tokio::task::spawn_blocking(move || loop {
+            let next = match inbox.recv() {
+                Ok(Some(payload)) => Ok(Message { payload }),
+                Ok(None) => return,
+                Err(e) => Err(Status::unavailable(format!("Lost mob: {}", e))),
+            };
+            let failed = next.is_err();
+            if tx.blocking_send(next).is_err() || failed {
+                return;
+            }
+        });
+
+        // Dropping the outbox hangs up on mob.
+        let mut incoming = request.into_inner();
+        tokio::spawn(async move {
+            while let Ok(Some(message)) = incoming.message().await {
+                if outbox.send(&message.payload).is_err() {
+                    return;
+                }
+            }
+        });
+
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+}
+
+/// Serve the gateway on `listener` until it fails, bridging to the mob server at `mob`.
+///
+/// This runs its own tokio runtime and blocks the calling thread.
+pub fn serve(listener: TcpListener, mob: SocketAddr) -> io::Result<()> {
+    let runtime = tokio::runtime::Builder::new_multi_thread().enable_all().build()?;
+
+    runtime.block_on(async move {
+        listener.set_nonblocking(true)?;
+        let incoming = TcpListenerStream::new(tokio::net::TcpListener::from_std(listener)?);
+
+        tonic::transport::Server::builder()
+            .add_service(MobServer::new(Gateway::new(mob)))
+            .serve_with_incoming(incoming)
+            .await
+            .map_err(Error::other)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{SocketAddr, TcpListener};
+    use std::thread;
+
+    use mob_client::codec::{self, FrameReader};
+    use tokio::sync::mpsc;
+    use tokio_stream::wrappers::ReceiverStream;
+
+    use super::proto::{Message, MobClient};
+    use super::serve;
+
+    /// Stand in for a mob server with one client, which gets back whatever it sends.
+    fn echo_mob() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut writer = stream.try_clone().unwrap();
+            let mut reader = FrameReader::new(stream);
+            while let Ok(Some(payload)) = reader.read_frame() {
+                ::std::io::Write::write_all(&mut writer, &codec::encode(&payload)).unwrap();
+            }
+        });
+
+        addr
+    }
+
+    #[test]
+    fn chat_round_trips_through_mob() {
+        let mob = echo_mob();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let gateway = listener.local_addr().unwrap();
+        thread::spawn(move || serve(listener, mob).unwrap());
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async move {
+            let mut client = MobClient::connect(format!("http://{}", gateway)).await.unwrap();
+            let (tx, rx) = mpsc::channel(1);
+            let mut broadcasts = client.chat(ReceiverStream::new(rx)).await.unwrap().into_inner();
+
+            for payload in [&b"hello"[..], b"again"] {
+                tx.send(Message { payload: payload.to_vec() }).await.unwrap();
+                let message = broadcasts.message().await.unwrap().unwrap();
+                assert_eq!(message.payload, payload);
+            }
+        });
+    }
+}
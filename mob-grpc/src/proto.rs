@@ -0,0 +1,119 @@
+//! The message and service from `proto/mob.proto`.
+//!
+//! This is what `tonic-build` would generate, written out by hand so that building does not need
+//! `protoc`. Keep the two in step.
+
+use std::convert::Infallible;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use tonic::body::BoxBody;
+use tonic::codec::ProstCodec;
+use tonic::codegen::{http, Body, BoxFuture, Service, StdError};
+use tonic::server::{Grpc, NamedService, StreamingService};
+use tonic::transport::{Channel, Endpoint};
+use tonic::{IntoStreamingRequest, Request, Response, Status, Streaming};
+
+/// The path of the `Chat` method.
+const CHAT: &str = "/mob.Mob/Chat";
+
+/// A payload going to or coming from the broadcast.
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Message {
+    #[prost(bytes = "vec", tag = "1")]
+    pub payload: Vec<u8>,
+}
+
+/// The `Mob` service.
+#[tonic::async_trait]
+pub trait Mob: Send + Sync + 'static {
+    type ChatStream: tokio_stream::Stream<Item = Result<Message, Status>> + Send + 'static;
+
+    async fn chat(&self, request: Request<Streaming<Message>>)
+        -> Result<Response<Self::ChatStream>, Status>;
+}
+
+/// Serves a `Mob` implementation over gRPC.
+pub struct MobServer<T> {
+    inner: Arc<T>,
+}
+
+impl<T> MobServer<T> {
+    pub fn new(inner: T) -> MobServer<T> {
+        MobServer { inner: Arc::new(inner) }
+    }
+}
+
+impl<T> Clone for MobServer<T> {
+    fn clone(&self) -> MobServer<T> {
+        MobServer { inner: self.inner.clone() }
+    }
+}
+
+impl<T> NamedService for MobServer<T> {
+    const NAME: &'static str = "mob.Mob";
+}
+
+impl<T, B> Service<http::Request<B>> for MobServer<T>
+    where T: Mob,
+          B: Body + Send + 'static,
+          B::Error: Into<StdError> + Send + 'static
+{
+    type Response = http::Response<BoxBody>;
+    type Error = Infallible;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: http::Request<B>) -> Self::Future {
+        if req.uri().path() != CHAT {
+            return Box::pin(async { Ok(Status::unimplemented("Unknown method").into_http()) });
+        }
+
+        let method = ChatMethod(self.inner.clone());
+        Box::pin(async move {
+            let mut grpc = Grpc::new(ProstCodec::default());
+            Ok(grpc.streaming(method, req).await)
+        })
+    }
+}
+
+/// Adapts `Mob::chat` to what `Grpc::streaming` calls.
+struct ChatMethod<T>(Arc<T>);
+
+impl<T: Mob> StreamingService<Message> for ChatMethod<T> {
+    type Response = Message;
+    type ResponseStream = T::ChatStream;
+    type Future = BoxFuture<Response<Self::ResponseStream>, Status>;
+
+    fn call(&mut self, request: Request<Streaming<Message>>) -> Self::Future {
+        let inner = self.0.clone();
+        Box::pin(async move { inner.chat(request).await })
+    }
+}
+
+/// A client for the `Mob` service.
+pub struct MobClient {
+    inner: tonic::client::Grpc<Channel>,
+}
+
+impl MobClient {
+    /// Connect to a gateway at `dst`, such as `http://127.0.0.1:50051`.
+    pub async fn connect(dst: String) -> Result<MobClient, tonic::transport::Error> {
+        let channel = Endpoint::new(dst)?.connect().await?;
+        Ok(MobClient { inner: tonic::client::Grpc::new(channel) })
+    }
+
+    /// Send everything on `request` to the broadcast, and get back every broadcast.
+    pub async fn chat(&mut self, request: impl IntoStreamingRequest<Message = Message>)
+        -> Result<Response<Streaming<Message>>, Status>
+    {
+        self.inner.ready().await
+            .map_err(|e| Status::unavailable(format!("Gateway is not ready: {}", e)))?;
+
+        let path = http::uri::PathAndQuery::from_static(CHAT);
+        self.inner.streaming(request.into_streaming_request(), path, ProstCodec::default()).await
+    }
+}
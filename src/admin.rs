@@ -0,0 +1,800 @@
+//! The admin socket, a TCP port operators can change a running server through.
+//!
+//! Each line sent to it is a command, and each command gets a line back, starting with `ok` or
+//! `error`. Commands that answer with a list say how long it is on that line, and follow it with
+//! one line for each item, indented by two spaces. There are no prompts or colours, so `nc`, or
+//! `rlwrap nc` for line editing and history, will do as a client, and so will a script.
+//! Connections are handled on threads of their own, away from the servers' poll loops.
+//!
+//! ```text
+//! stats                             a line of figures for each server
+//! list                              a line for each connection, as <server>/<token> and so on
+//! kick 0/12                         close connection 12 of server 0, once its queue is written
+//! drain                             turn new clients away, and close every client there is
+//! export csv                        figures for each connection, a header line and then a row
+//! export json                       figures for each connection, an object on each line
+//! loglevel                          the log filter now
+//! loglevel trace mob::connection    log mob::connection up to trace from now on
+//! loglevel warn                     log every other module up to warn from now on
+//! announce back in 5 minutes        send every client a NOTICE saying so
+//! reload --text --motd "be nice"    change every server's settings to these
+//! ```
+//!
+//! Servers are numbered in the order they were added. With only one, `kick` takes a bare token.
+//!
+//! Without secrets, anyone who can connect may run every command. Once a secret is set, a
+//! connection may only ask for `help` until it sends `auth <secret>`, and what it may run after
+//! that depends on whose secret it was. The read only role may look, with `stats`, `list`,
+//! `rooms`, `export` and `loglevel` without arguments. The operator role may also change things. A
+//! connection that gets the secret wrong is kept waiting a second before it is answered, and is
+//! closed after three tries. Secrets are compared in constant time, but cross the network as
+//! they are, so the port still wants a private network or a tunnel.
+//!
+//! A line longer than 16 KiB is answered with an error and the connection closed, before anything
+//! in it is looked at, secret or not. So is a connection that sends nothing for five minutes.
+//!
+//! `reload` takes flags as the command line does, quoted the same way, and checks all of them
+//! before any server is changed, so a mistake leaves every server as it was and is answered with
+//! everything wrong with the flags. Settings the flags leave out go back to their defaults.
+//! Flags for what cannot change while the server runs, such as capacities, are refused.
+//!
+//! The same figures `export` gives can be fetched over HTTP too, for tools that would rather not
+//! speak the line protocol, see `spawn_http`. `GET /connections.csv` and `GET
+//! /connections.json` answer with every connection of every server, the JSON as one array. Once
+//! a secret is set, a request needs it in an `Authorization: Bearer <secret>` header, and either
+//! role's secret will do.
+
+use std::cmp;
+use std::io::{self, BufRead, BufReader, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::Duration;
+
+use mio::Token;
+
+use dump::Json;
+use logging::LogHandle;
+use server::{Announcer, ConnectionStats, Request, Settings};
+
+/// How long to wait for a server to answer, before saying it did not.
+const ANSWER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long a connection that sent the wrong secret waits for its answer.
+const AUTH_FAILURE_DELAY: Duration = Duration::from_secs(1);
+
+/// How many wrong secrets a connection may send before it is closed.
+const MAX_AUTH_FAILURES: u32 = 3;
+
+/// The longest line a connection may send, not counting its line ending.
+const MAX_LINE: usize = 16 * 1024;
+
+/// How long a connection may go without sending anything, before it is closed.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// How long an HTTP client has to send its request, before it is hung up on.
+const HTTP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The most header lines an HTTP request may have.
+const MAX_HTTP_HEADERS: usize = 100;
+
+/// The longest request line or header line an HTTP request may have.
+const MAX_HTTP_LINE: usize = 8 * 1024;
+
+/// The columns `export csv` answers with, in order.
+pub const CSV_HEADER: &str = "server,token,addr,peer,uptime_ms,bytes_read,bytes_written,\
+                              messages_read,frames_written,queued_frames,queued_bytes,dropped";
+
+/// What a connection to the admin socket may do, once it has sent a secret.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Role {
+    /// Look at the servers, without changing anything.
+    ReadOnly,
+
+    /// Anything, including kicking clients, draining and reloading.
+    Operator,
+}
+
+/// Turns the flags given to `reload` into settings, or into everything wrong with them.
+pub type Reload = dyn Fn(Vec<String>) -> Result<Settings, Vec<String>> + Send + Sync;
+
+/// What commands sent to the admin socket act on.
+#[derive(Clone, Default)]
+pub struct Admin {
+    log: Option<LogHandle>,
+    reload: Option<Arc<Reload>>,
+
+    // the secret that grants each role, if any
+    secrets: Vec<(Role, String)>,
+
+    // every server `announce` reaches, shared with the clones handed to them
+    announcers: Arc<Mutex<Vec<Announcer>>>,
+}
+
+impl Admin {
+    pub fn new() -> Admin {
+        Admin::default()
+    }
+
+    /// Let `loglevel` change the filter of the logger `log` belongs to. Without it, `loglevel`
+    /// fails.
+    pub fn set_log(&mut self, log: Option<LogHandle>) {
+        self.log = log;
+    }
+
+    /// Let `reload` parse and check its flags with `reload`. Without it, `reload` fails.
+    pub fn set_reload(&mut self, reload: Option<Arc<Reload>>) {
+        self.reload = reload;
+    }
+
+    /// Require connections to the admin socket to send `secret` with `auth` to be given `role`,
+    /// replacing any secret set for it before. `None` removes it. With no secrets at all, every
+    /// connection is an operator from the start.
+    pub fn set_secret(&mut self, role: Role, secret: Option<String>) {
+        self.secrets.retain(|&(r, _)| r != role);
+        self.secrets.extend(secret.map(|secret| (role, secret)));
+    }
+
+    /// A connection's view of the admin socket, with the role it starts out with.
+    pub fn session(&self) -> Session<'_> {
+        let role = if self.secrets.is_empty() { Some(Role::Operator) } else { None };
+        Session { admin: self, role, failures: 0 }
+    }
+
+    /// The role `secret` grants, the highest if it is the secret for more than one.
+    fn authenticate(&self, secret: &str) -> Option<Role> {
+        // Every secret is compared, so how long it takes says nothing about which was close.
+        self.secrets.iter()
+            .filter(|&(_, s)| same(s.as_bytes(), secret.as_bytes()))
+            .map(|&(role, _)| role)
+            .fold(None, |best, role| cmp::max(best, Some(role)))
+    }
+
+    /// Let every command that acts on servers reach the server `announcer` belongs to, as well as
+    /// any added before. This can be done on a clone, even once the admin socket is running.
+    pub fn add_announcer(&self, announcer: Announcer) {
+        self.announcers.lock().unwrap_or_else(|e| e.into_inner()).push(announcer);
+    }
+
+    /// Run one command as an operator and return the lines to answer it with, without the last
+    /// line ending. Connections to the admin socket go through a `Session` instead.
+    pub fn execute(&self, line: &str) -> String {
+        self.execute_as(Role::Operator, line)
+    }
+
+    fn execute_as(&self, role: Role, line: &str) -> String {
+        let mut words = line.split_whitespace();
+        let command = words.next();
+        let needed = match command {
+            Some("kick") | Some("drain") | Some("announce") | Some("reload") => Role::Operator,
+            Some("loglevel") if line.split_whitespace().count() > 1 => Role::Operator,
+            _ => Role::ReadOnly,
+        };
+        if role < needed {
+            return format!("error {} needs the operator role", command.unwrap_or_default());
+        }
+
+        let result = match command {
+            Some("stats") => self.stats(),
+            Some("list") => self.list(),
+            Some("export") => self.export(&words.collect::<Vec<_>>()),
+            Some("kick") => self.kick(&words.collect::<Vec<_>>()),
+            Some("drain") => self.drain(),
+            Some("rooms") => {
+                Err("there are no rooms, every client hears every other".to_string())
+            }
+            Some("loglevel") => self.loglevel(&words.collect::<Vec<_>>()),
+            Some("announce") => self.announce(line.trim()["announce".len()..].trim()),
+            Some("reload") => self.reload(line.trim()["reload".len()..].trim()),
+            Some("help") => {
+                Ok("commands: auth <secret>, stats, list, export csv|json, \
+                    kick [<server>/]<token>, drain, loglevel [<level> [<module>]], \
+                    announce <text>, reload <flags>, help"
+                    .to_string())
+            }
+            Some(command) => Err(format!("unknown command {}", command)),
+            None => Err("no command".to_string()),
+        };
+
+        match result {
+            Ok(answer) => format!("ok {}", answer),
+            Err(e) => format!("error {}", e),
+        }
+    }
+
+    /// Ask every server `request`, one after another, and return their answers in order.
+    fn ask(&self, request: Request) -> Result<Vec<Vec<String>>, String> {
+        self.gather(|announcer| announcer.ask(request))?.into_iter().collect()
+    }
+
+    /// Ask every server for something with `ask`, one after another, and return what they sent
+    /// back in order.
+    fn gather<T, F>(&self, ask: F) -> Result<Vec<T>, String>
+        where F: Fn(&Announcer) -> io::Result<Receiver<T>>
+    {
+        // Cloned, so a slow server does not hold up `add_announcer`.
+        let announcers = self.announcers.lock().unwrap_or_else(|e| e.into_inner()).clone();
+        if announcers.is_empty() {
+            return Err("no servers".to_string());
+        }
+
+        let mut answers = Vec::new();
+        for (i, announcer) in announcers.iter().enumerate() {
+            let answer = ask(announcer).map_err(|e| format!("server {} {}", i, e))?;
+            match answer.recv_timeout(ANSWER_TIMEOUT) {
+                Ok(answer) => answers.push(answer),
+                Err(RecvTimeoutError::Timeout) => {
+                    return Err(format!("server {} did not answer", i));
+                }
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err(format!("server {} stopped", i));
+                }
+            }
+        }
+        Ok(answers)
+    }
+
+    fn stats(&self) -> Result<String, String> {
+        let answers = self.ask(Request::Stats)?;
+        let lines = answers.iter().enumerate()
+            .flat_map(|(i, lines)| lines.iter().map(move |line| format!("{} {}", i, line)));
+        Ok(list(format!("{} servers", answers.len()), lines))
+    }
+
+    fn list(&self) -> Result<String, String> {
+        let answers = self.ask(Request::List)?;
+        let count = answers.iter().map(Vec::len).sum::<usize>();
+        let lines = answers.iter().enumerate()
+            .flat_map(|(i, lines)| lines.iter().map(move |line| format!("{}/{}", i, line)));
+        Ok(list(format!("{} connections", count), lines))
+    }
+
+    fn export(&self, args: &[&str]) -> Result<String, String> {
+        let csv = match *args {
+            ["csv"] => true,
+            ["json"] => false,
+            _ => return Err("usage: export csv|json".to_string()),
+        };
+        let stats = self.connection_stats()?;
+        let first = format!("{} connections", stats.len());
+        if csv {
+            let rows = stats.iter().map(|&(server, ref c)| csv_row(server, c));
+            Ok(list(first, Some(CSV_HEADER.to_string()).into_iter().chain(rows)))
+        } else {
+            Ok(list(first, stats.iter().map(|&(server, ref c)| json(server, c).to_string())))
+        }
+    }
+
+    /// Figures about every connection of every server, with the number of its server.
+    fn connection_stats(&self) -> Result<Vec<(usize, ConnectionStats)>, String> {
+        let answers = self.gather(Announcer::export)?;
+        Ok(answers.into_iter().enumerate()
+            .flat_map(|(i, stats)| stats.into_iter().map(move |c| (i, c)))
+            .collect())
+    }
+
+    /// The status line, content type and body to answer an HTTP request with. `authorization`
+    /// is the value of its `Authorization` header, if it had one.
+    fn http(&self, method: &str, path: &str, authorization: Option<&str>)
+        -> (&'static str, &'static str, String)
+    {
+        const TEXT: &str = "text/plain";
+        if !self.secrets.is_empty() {
+            let secret = authorization.and_then(|value| {
+                let mut words = value.splitn(2, ' ');
+                match (words.next(), words.next()) {
+                    (Some(scheme), Some(secret)) if scheme.eq_ignore_ascii_case("bearer") => {
+                        Some(secret.trim())
+                    }
+                    _ => None,
+                }
+            });
+            if secret.and_then(|secret| self.authenticate(secret)).is_none() {
+                return ("401 Unauthorized", TEXT, "a secret is needed\n".to_string());
+            }
+        }
+
+        let csv = match path {
+            "/connections.csv" => true,
+            "/connections.json" => false,
+            _ => return ("404 Not Found", TEXT, format!("nothing at {}\n", path)),
+        };
+        if method != "GET" {
+            return ("405 Method Not Allowed", TEXT, "only GET is allowed\n".to_string());
+        }
+
+        let stats = match self.connection_stats() {
+            Ok(stats) => stats,
+            Err(e) => return ("503 Service Unavailable", TEXT, e + "\n"),
+        };
+        if csv {
+            let body = stats.iter().fold(CSV_HEADER.to_string() + "\n", |body, &(server, ref c)| {
+                body + &csv_row(server, c) + "\n"
+            });
+            ("200 OK", "text/csv", body)
+        } else {
+            let array = Json::Array(stats.iter().map(|&(server, ref c)| json(server, c)).collect());
+            ("200 OK", "application/json", array.to_string() + "\n")
+        }
+    }
+
+    fn kick(&self, args: &[&str]) -> Result<String, String> {
+        let id = match *args {
+            [id] => id,
+            _ => return Err("usage: kick [<server>/]<token>".to_string()),
+        };
+        let announcers = self.announcers.lock().unwrap_or_else(|e| e.into_inner()).clone();
+        let (server, token) = match id.find('/') {
+            Some(slash) => (id[..slash].parse().ok(), id[slash + 1..].parse().ok()),
+            None if announcers.len() == 1 => (Some(0), id.parse().ok()),
+            None => return Err("which server? kick <server>/<token>".to_string()),
+        };
+        let (announcer, token) = match (server.and_then(|i: usize| announcers.get(i)), token) {
+            (Some(announcer), Some(token)) => (announcer, Token(token)),
+            _ => return Err(format!("no connection {}", id)),
+        };
+
+        let answer = announcer.ask(Request::Kick(token))
+            .map_err(|e| format!("kicking failed, {}", e))?;
+        match answer.recv_timeout(ANSWER_TIMEOUT) {
+            Ok(answer) => answer.map(|_| format!("kicked {}", id)),
+            Err(_) => Err("the server did not answer".to_string()),
+        }
+    }
+
+    fn drain(&self) -> Result<String, String> {
+        let answers = self.ask(Request::Drain)?;
+        info!("draining every server");
+        Ok(format!("draining {} servers", answers.len()))
+    }
+
+    fn loglevel(&self, args: &[&str]) -> Result<String, String> {
+        let log = self.log.as_ref().ok_or_else(|| "logging is not set up".to_string())?;
+        match *args {
+            [] => Ok(log.filter().to_string()),
+            [level] | [level, _] => {
+                let level = level.parse().map_err(|_| format!("unknown log level {}", level))?;
+                let filter = log.set_level(args.get(1).cloned(), level);
+                info!("log filter changed to {}", filter);
+                Ok(filter.to_string())
+            }
+            _ => Err("usage: loglevel [<level> [<module>]]".to_string()),
+        }
+    }
+
+    fn announce(&self, text: &str) -> Result<String, String> {
+        if text.is_empty() {
+            return Err("usage: announce <text>".to_string());
+        }
+
+        let announcers = self.announcers.lock().unwrap_or_else(|e| e.into_inner());
+        if announcers.is_empty() {
+            return Err("no servers to announce to".to_string());
+        }
+        for announcer in announcers.iter() {
+            announcer.announce(text).map_err(|e| format!("announcing failed, {}", e))?;
+        }
+        Ok(format!("announced to {} servers", announcers.len()))
+    }
+
+    fn reload(&self, flags: &str) -> Result<String, String> {
+        let reload = self.reload.as_ref().ok_or_else(|| "reloading is not set up".to_string())?;
+        let settings = reload(words(flags)?).map_err(|errors| errors.join("; "))?;
+
+        let announcers = self.announcers.lock().unwrap_or_else(|e| e.into_inner());
+        if announcers.is_empty() {
+            return Err("no servers to reload".to_string());
+        }
+        for announcer in announcers.iter() {
+            announcer.reconfigure(settings.clone())
+                .map_err(|e| format!("reloading failed, {}", e))?;
+        }
+        info!("settings reloaded; flags={:?}", flags);
+        Ok(format!("reloaded {} servers", announcers.len()))
+    }
+}
+
+/// What one connection to the admin socket has been allowed to do so far.
+pub struct Session<'a> {
+    admin: &'a Admin,
+
+    // what the connection may do, `None` until it has sent a secret if one is needed
+    role: Option<Role>,
+
+    // how many wrong secrets it has sent
+    failures: u32,
+}
+
+impl<'a> Session<'a> {
+    /// Run one command as `Admin::execute` does, if the connection's role allows it, or
+    /// authenticate it if the command is `auth`.
+    pub fn execute(&mut self, line: &str) -> String {
+        let mut words = line.split_whitespace();
+        match (words.next(), self.role) {
+            (Some("auth"), _) => {
+                let secret = line.trim()["auth".len()..].trim();
+                match self.admin.authenticate(secret) {
+                    Some(role) => {
+                        self.role = Some(role);
+                        info!("admin connection authenticated; role={:?}", role);
+                        format!("ok {}", match role {
+                            Role::ReadOnly => "read only",
+                            Role::Operator => "operator",
+                        })
+                    }
+                    None if self.admin.secrets.is_empty() => "error no secret is set".to_string(),
+                    None => {
+                        self.failures += 1;
+                        warn!("admin connection sent the wrong secret; tries={}", self.failures);
+                        "error wrong secret".to_string()
+                    }
+                }
+            }
+            (Some("help"), None) => self.admin.execute_as(Role::ReadOnly, line),
+            (_, None) => "error authenticate first, with auth <secret>".to_string(),
+            (_, Some(role)) => self.admin.execute_as(role, line),
+        }
+    }
+
+    /// How many wrong secrets the connection has sent.
+    pub fn failures(&self) -> u32 {
+        self.failures
+    }
+}
+
+/// Whether `a` and `b` are the same, taking as long to say so wherever they differ.
+pub(crate) fn same(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// A connection of server `server` as a row under `CSV_HEADER`.
+fn csv_row(server: usize, c: &ConnectionStats) -> String {
+    let t = &c.traffic;
+    format!("{},{},{},{},{},{},{},{},{},{},{},{}", server, c.token.0,
+            c.addr.map(|addr| addr.to_string()).unwrap_or_default(), c.peer,
+            c.uptime.as_millis(), t.bytes_read, t.bytes_written, c.messages_read,
+            t.frames_written, c.queued_frames, c.queued_bytes, t.dropped)
+}
+
+/// A connection of server `server` as a JSON object, with the fields `CSV_HEADER` names.
+fn json(server: usize, c: &ConnectionStats) -> Json {
+    let t = &c.traffic;
+    Json::object(vec![
+        ("server", server.into()),
+        ("token", c.token.0.into()),
+        ("addr", c.addr.map(|addr| addr.to_string()).into()),
+        ("peer", c.peer.into()),
+        ("uptime_ms", (c.uptime.as_millis() as u64).into()),
+        ("bytes_read", t.bytes_read.into()),
+        ("bytes_written", t.bytes_written.into()),
+        ("messages_read", c.messages_read.into()),
+        ("frames_written", t.frames_written.into()),
+        ("queued_frames", c.queued_frames.into()),
+        ("queued_bytes", c.queued_bytes.into()),
+        ("dropped", t.dropped.into()),
+    ])
+}
+
+/// `first`, then each of `items` on a line of its own, indented.
+fn list<I: Iterator<Item=String>>(first: String, items: I) -> String {
+    items.fold(first, |answer, item| answer + "\n  " + &item)
+}
+
+/// Split `line` into words at whitespace, as a shell would, except inside single or double
+/// quotes.
+fn words(line: &str) -> Result<Vec<String>, String> {
+    let mut words = Vec::new();
+    let mut word = None;
+    let mut quote = None;
+    for c in line.chars() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), c) => word.get_or_insert_with(String::new).push(c),
+            (None, '\'') | (None, '"') => {
+                quote = Some(c);
+                word.get_or_insert_with(String::new);
+            }
+            (None, c) if c.is_whitespace() => words.extend(word.take()),
+            (None, c) => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    if quote.is_some() {
+        return Err("unterminated quote".to_string());
+    }
+    words.extend(word);
+    Ok(words)
+}
+
+/// Answer commands from connections to `listener` on a thread of their own, each connection on
+/// a thread of its own too.
+pub fn spawn(listener: TcpListener, admin: Admin) -> io::Result<thread::JoinHandle<()>> {
+    accept(listener, admin, "mob-admin", serve)
+}
+
+/// Answer HTTP requests for the figures `export` gives from connections to `listener`, on
+/// threads as `spawn` does.
+pub fn spawn_http(listener: TcpListener, admin: Admin) -> io::Result<thread::JoinHandle<()>> {
+    accept(listener, admin, "mob-admin-http", serve_http)
+}
+
+/// Accept connections to `listener` on a thread called `name`, and have `serve` handle each of
+/// them on a thread of its own.
+fn accept(listener: TcpListener, admin: Admin, name: &str,
+          serve: fn(TcpStream, &Admin) -> io::Result<()>)
+    -> io::Result<thread::JoinHandle<()>>
+{
+    let admin = Arc::new(admin);
+    let conn_name = format!("{}-conn", name);
+    thread::Builder::new().name(name.to_string()).spawn(move || {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    warn!("Failed to accept admin connection, {:?}", e);
+                    continue;
+                }
+            };
+
+            let admin = admin.clone();
+            let spawned = thread::Builder::new().name(conn_name.clone()).spawn(move || {
+                if let Err(e) = serve(stream, &admin) {
+                    debug!("admin connection failed, {:?}", e);
+                }
+            });
+            if let Err(e) = spawned {
+                warn!("Failed to start admin connection thread, {:?}", e);
+            }
+        }
+    })
+}
+
+/// A line read by `read_line`.
+#[derive(Debug, PartialEq, Eq)]
+enum Line {
+    /// A line, without its line ending.
+    Read(String),
+
+    /// A line longer than the limit, of which no more than the limit has been read.
+    TooLong,
+
+    /// The end of the stream.
+    End,
+}
+
+/// Read the next line from `reader`, as `BufRead::lines` does, but without reading more than
+/// `limit` bytes of it, line ending aside.
+fn read_line<R: BufRead>(reader: &mut R, limit: usize) -> io::Result<Line> {
+    let mut line = String::new();
+    let n = reader.take(limit as u64 + 2).read_line(&mut line)?;
+    if n == 0 {
+        return Ok(Line::End);
+    }
+
+    if line.ends_with('\n') {
+        line.pop();
+        if line.ends_with('\r') {
+            line.pop();
+        }
+    }
+    if line.len() > limit {
+        return Ok(Line::TooLong);
+    }
+    Ok(Line::Read(line))
+}
+
+/// Answer each line read from `stream` until it is closed.
+fn serve(stream: TcpStream, admin: &Admin) -> io::Result<()> {
+    stream.set_read_timeout(Some(IDLE_TIMEOUT))?;
+    let mut writer = stream.try_clone()?;
+    let mut reader = BufReader::new(stream);
+    let mut session = admin.session();
+    loop {
+        let line = match read_line(&mut reader, MAX_LINE) {
+            Ok(Line::Read(line)) => line,
+            Ok(Line::End) => break,
+            Ok(Line::TooLong) => {
+                writeln!(writer, "error line too long, the most is {} bytes", MAX_LINE)?;
+                break;
+            }
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+                debug!("closing idle admin connection");
+                writeln!(writer, "error idle for {} seconds", IDLE_TIMEOUT.as_secs())?;
+                break;
+            }
+            Err(e) => return Err(e),
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let failures = session.failures();
+        let answer = session.execute(&line);
+        if session.failures() > failures {
+            thread::sleep(AUTH_FAILURE_DELAY);
+        }
+        writeln!(writer, "{}", answer)?;
+        if session.failures() >= MAX_AUTH_FAILURES {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Answer one HTTP request read from `stream`, and close it.
+fn serve_http(stream: TcpStream, admin: &Admin) -> io::Result<()> {
+    stream.set_read_timeout(Some(HTTP_TIMEOUT))?;
+    let mut writer = stream.try_clone()?;
+    let (status, content_type, body) = read_http(&mut BufReader::new(stream), admin)?;
+
+    write!(writer, "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n",
+           status, content_type, body.len())?;
+    if status.starts_with("401") {
+        write!(writer, "WWW-Authenticate: Bearer\r\n")?;
+    } else if status.starts_with("405") {
+        write!(writer, "Allow: GET\r\n")?;
+    }
+    write!(writer, "Connection: close\r\n\r\n{}", body)?;
+    writer.flush()
+}
+
+/// Read an HTTP request from `reader`, and return the status line, content type and body to
+/// answer it with.
+fn read_http<R: BufRead>(reader: &mut R, admin: &Admin)
+    -> io::Result<(&'static str, &'static str, String)>
+{
+    const TEXT: &str = "text/plain";
+    let request = match read_line(reader, MAX_HTTP_LINE)? {
+        Line::Read(line) => line,
+        Line::End => String::new(),
+        Line::TooLong => {
+            return Ok(("414 URI Too Long", TEXT, "the request line is too long\n".to_string()));
+        }
+    };
+
+    let mut authorization = None;
+    for _ in 0..MAX_HTTP_HEADERS {
+        let line = match read_line(reader, MAX_HTTP_LINE)? {
+            Line::Read(line) => line,
+            Line::End => break,
+            Line::TooLong => {
+                let body = "a header is too long\n".to_string();
+                return Ok(("431 Request Header Fields Too Large", TEXT, body));
+            }
+        };
+        if line.trim().is_empty() {
+            break;
+        }
+        if let Some(colon) = line.find(':') {
+            if line[..colon].trim().eq_ignore_ascii_case("authorization") {
+                authorization = Some(line[colon + 1..].trim().to_string());
+            }
+        }
+    }
+
+    let mut words = request.split_whitespace();
+    let answer = match (words.next(), words.next()) {
+        (Some(method), Some(path)) => admin.http(method, path, authorization.as_deref()),
+        _ => ("400 Bad Request", TEXT, "not an HTTP request\n".to_string()),
+    };
+    if answer.0.starts_with("401") && authorization.is_some() {
+        thread::sleep(AUTH_FAILURE_DELAY);
+    }
+    Ok(answer)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::sync::Arc;
+
+    use super::{list, read_http, read_line, words, Admin, Line, Reload, Role};
+
+    #[test]
+    fn commands_are_answered_with_ok_or_error() {
+        let admin = Admin::new();
+        assert_eq!(admin.execute("frobnicate"), "error unknown command frobnicate");
+        assert_eq!(admin.execute("loglevel"), "error logging is not set up");
+        assert_eq!(admin.execute("announce"), "error usage: announce <text>");
+        assert_eq!(admin.execute("announce  hello "), "error no servers to announce to");
+        assert_eq!(admin.execute("stats"), "error no servers");
+        assert_eq!(admin.execute("kick"), "error usage: kick [<server>/]<token>");
+        assert_eq!(admin.execute("kick 3"), "error which server? kick <server>/<token>");
+        assert_eq!(admin.execute("kick 0/3"), "error no connection 0/3");
+        assert_eq!(admin.execute("export"), "error usage: export csv|json");
+        assert_eq!(admin.execute("export csv"), "error no servers");
+        assert!(admin.execute("help").starts_with("ok commands: "));
+    }
+
+    #[test]
+    fn reload_changes_nothing_unless_every_flag_is_good() {
+        let mut admin = Admin::new();
+        assert_eq!(admin.execute("reload --text"), "error reloading is not set up");
+
+        let reload: Arc<Reload> = Arc::new(|flags| {
+            match flags.iter().position(|f| f == "--bad") {
+                Some(_) => Err(flags.iter().map(|f| format!("{} is bad", f)).collect()),
+                None => Ok(Default::default()),
+            }
+        });
+        admin.set_reload(Some(reload));
+        assert_eq!(admin.execute("reload --bad 'two words'"),
+                   "error --bad is bad; two words is bad");
+        assert_eq!(admin.execute("reload \"--motd"), "error unterminated quote");
+        assert_eq!(admin.execute("reload --text"), "error no servers to reload");
+    }
+
+    #[test]
+    fn secrets_grant_roles_and_roles_limit_commands() {
+        let mut admin = Admin::new();
+        assert_eq!(admin.session().execute("auth anything"), "error no secret is set");
+        assert_eq!(admin.session().execute("drain"), "error no servers");
+
+        admin.set_secret(Role::ReadOnly, Some("look".to_string()));
+        admin.set_secret(Role::Operator, Some("touch".to_string()));
+        let mut session = admin.session();
+        assert!(session.execute("help").starts_with("ok commands: "));
+        assert_eq!(session.execute("stats"), "error authenticate first, with auth <secret>");
+        assert_eq!(session.execute("auth loo"), "error wrong secret");
+        assert_eq!(session.failures(), 1);
+
+        assert_eq!(session.execute("auth look"), "ok read only");
+        assert_eq!(session.execute("stats"), "error no servers");
+        assert_eq!(session.execute("loglevel"), "error logging is not set up");
+        assert_eq!(session.execute("loglevel trace"), "error loglevel needs the operator role");
+        assert_eq!(session.execute("kick 0/1"), "error kick needs the operator role");
+
+        assert_eq!(session.execute("auth  touch "), "ok operator");
+        assert_eq!(session.execute("kick 0/1"), "error no connection 0/1");
+
+        admin.set_secret(Role::Operator, None);
+        assert_eq!(admin.session().execute("auth touch"), "error wrong secret");
+    }
+
+    #[test]
+    fn http_requests_need_a_known_path_get_and_the_secret() {
+        let mut admin = Admin::new();
+        assert_eq!(admin.http("GET", "/connections.csv", None),
+                   ("503 Service Unavailable", "text/plain", "no servers\n".to_string()));
+        assert_eq!(admin.http("GET", "/", None).0, "404 Not Found");
+        assert_eq!(admin.http("POST", "/connections.json", None).0, "405 Method Not Allowed");
+
+        admin.set_secret(Role::ReadOnly, Some("look".to_string()));
+        assert_eq!(admin.http("GET", "/connections.csv", None).0, "401 Unauthorized");
+        assert_eq!(admin.http("GET", "/connections.csv", Some("Bearer loo")).0,
+                   "401 Unauthorized");
+        assert_eq!(admin.http("GET", "/", Some("Basic look")).0, "401 Unauthorized");
+        assert_eq!(admin.http("GET", "/connections.csv", Some("bearer look")).0,
+                   "503 Service Unavailable");
+    }
+
+    #[test]
+    fn lists_are_a_count_and_then_an_indented_line_for_each_item() {
+        let items = vec!["0/1 open".to_string(), "0/2 closing".to_string()];
+        assert_eq!(list("2 connections".to_string(), items.into_iter()),
+                   "2 connections\n  0/1 open\n  0/2 closing");
+        assert_eq!(list("0 connections".to_string(), Vec::new().into_iter()), "0 connections");
+    }
+
+    #[test]
+    fn lines_longer_than_the_limit_are_not_read_in_full() {
+        let mut reader = Cursor::new(b"four\r\nfive!\nsix\n1234567890".to_vec());
+        assert_eq!(read_line(&mut reader, 4).unwrap(), Line::Read("four".to_string()));
+        assert_eq!(read_line(&mut reader, 4).unwrap(), Line::TooLong);
+        assert_eq!(read_line(&mut reader, 4).unwrap(), Line::Read("six".to_string()));
+        assert_eq!(read_line(&mut reader, 4).unwrap(), Line::TooLong);
+        assert_eq!(reader.position(), 22);
+
+        let mut reader = Cursor::new(format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(9000)));
+        assert_eq!(read_http(&mut reader, &Admin::new()).unwrap().0, "414 URI Too Long");
+        let mut reader = Cursor::new(format!("GET / HTTP/1.1\r\nX: {}\r\n\r\n", "a".repeat(9000)));
+        assert_eq!(read_http(&mut reader, &Admin::new()).unwrap().0,
+                   "431 Request Header Fields Too Large");
+    }
+
+    #[test]
+    fn words_are_split_as_a_shell_would() {
+        assert_eq!(words(" --motd \"be nice\"  --name=it' 's '' ").unwrap(),
+                   vec!["--motd", "be nice", "--name=it s", ""]);
+    }
+}
@@ -0,0 +1,219 @@
+//! Payloads shared between the connection that read them and the ones they are queued for.
+//!
+//! A connection reads into one buffer for many frames, and hands each payload out as a slice of
+//! it. Broadcasting queues the same slice for every connection, so a message is never copied on
+//! its way through. The buffer is read into again in place once nothing queued still points
+//! into it, and a new one is only started when something does. Buffers are held in an `Arc`, so
+//! a payload can be handed to another thread as it is, as shards do with what they broadcast.
+
+use std::cmp;
+use std::fmt;
+use std::io::{self, Read};
+use std::ops::{Deref, Range};
+use std::sync::Arc;
+
+/// How much a connection reads at a time, unless a frame needs more room than that.
+pub const READ_CHUNK: usize = 16 * 1024;
+
+/// A payload, held in a buffer that other payloads may share. Cloning one shares the buffer
+/// rather than copying it.
+#[derive(Clone, Default)]
+pub struct Payload {
+    buf: Arc<Vec<u8>>,
+    start: usize,
+    end: usize,
+}
+
+impl Payload {
+    /// The bytes in `range` of this payload, in the same buffer.
+    pub fn slice(&self, range: Range<usize>) -> Payload {
+        assert!(range.start <= range.end && range.end <= self.len(), "slice out of bounds");
+        Payload {
+            buf: self.buf.clone(),
+            start: self.start + range.start,
+            end: self.start + range.end,
+        }
+    }
+}
+
+impl Deref for Payload {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.buf[self.start..self.end]
+    }
+}
+
+impl AsRef<[u8]> for Payload {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl From<Vec<u8>> for Payload {
+    fn from(buf: Vec<u8>) -> Payload {
+        Payload::from(Arc::new(buf))
+    }
+}
+
+impl From<Arc<Vec<u8>>> for Payload {
+    fn from(buf: Arc<Vec<u8>>) -> Payload {
+        let end = buf.len();
+        Payload { buf, start: 0, end }
+    }
+}
+
+impl PartialEq for Payload {
+    fn eq(&self, other: &Payload) -> bool {
+        **self == **other
+    }
+}
+
+impl Eq for Payload {}
+
+impl fmt::Debug for Payload {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+/// Where a connection reads frames into, and hands their payloads out of.
+#[derive(Default)]
+pub struct ReadBuffer {
+    buf: Arc<Vec<u8>>,
+
+    // how much of `buf` has been read into, and how much of that has been taken
+    filled: usize,
+    consumed: usize,
+}
+
+impl ReadBuffer {
+    pub fn new() -> ReadBuffer {
+        ReadBuffer::default()
+    }
+
+    /// The bytes read that have not been taken yet.
+    pub fn unread(&self) -> &[u8] {
+        &self.buf[self.consumed..self.filled]
+    }
+
+    pub fn len(&self) -> usize {
+        self.filled - self.consumed
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.filled == self.consumed
+    }
+
+    /// Skip the next `n` unread bytes.
+    pub fn consume(&mut self, n: usize) {
+        assert!(n <= self.len(), "consumed more than was read");
+        self.consumed += n;
+    }
+
+    /// Take the next `n` unread bytes as a payload, without copying them.
+    pub fn take(&mut self, n: usize) -> Payload {
+        assert!(n <= self.len(), "took more than was read");
+        let start = self.consumed;
+        self.consumed += n;
+        Payload { buf: self.buf.clone(), start, end: self.consumed }
+    }
+
+    /// Throw away whatever has not been taken.
+    pub fn clear(&mut self) {
+        self.consumed = self.filled;
+    }
+
+    /// Read once from `r`, after making room for at least `want` unread bytes. Returns what the
+    /// read did, so `Ok(0)` is the end of the stream.
+    pub fn read_from<R: Read>(&mut self, r: &mut R, want: usize) -> io::Result<usize> {
+        self.reserve(want);
+        let buf = Arc::get_mut(&mut self.buf).expect("a reserved buffer is not shared");
+        let n = r.read(&mut buf[self.filled..])?;
+        self.filled += n;
+        Ok(n)
+    }
+
+    /// Make room to read more, for at least `want` unread bytes in all. The unread bytes are
+    /// moved to the front of the buffer if payloads taken from it are all gone, and to the front
+    /// of a new one if not.
+    fn reserve(&mut self, want: usize) {
+        let size = cmp::max(READ_CHUNK, want);
+        let unread = self.len();
+
+        let buf = match Arc::get_mut(&mut self.buf) {
+            Some(buf) => buf,
+            None => {
+                let mut buf = vec![0; size];
+                buf[..unread].copy_from_slice(self.unread());
+                self.buf = Arc::new(buf);
+                self.consumed = 0;
+                self.filled = unread;
+                return;
+            }
+        };
+
+        // Start from the front again whenever everything has been taken, and give back what one
+        // large frame needed once it has gone.
+        if unread == 0 {
+            self.consumed = 0;
+            self.filled = 0;
+            if buf.len() > size {
+                *buf = vec![0; size];
+            }
+        }
+
+        if self.filled < buf.len() && buf.len() - self.consumed >= want {
+            return;
+        }
+
+        buf.copy_within(self.consumed..self.filled, 0);
+        self.consumed = 0;
+        self.filled = unread;
+        if buf.len() < size {
+            buf.resize(size, 0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::{Payload, ReadBuffer, READ_CHUNK};
+
+    #[test]
+    fn payloads_share_the_buffer_they_were_read_into() {
+        let mut buf = ReadBuffer::new();
+        buf.read_from(&mut Cursor::new(b"onetwo".to_vec()), 3).unwrap();
+
+        let one = buf.take(3);
+        let two = buf.take(3);
+        assert_eq!(&*one, b"one");
+        assert_eq!(two, Payload::from(b"two".to_vec()));
+        assert_eq!(two.slice(1..3), Payload::from(b"wo".to_vec()));
+        assert_eq!(one.as_ptr().wrapping_add(3), two.as_ptr());
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn the_buffer_is_reused_once_its_payloads_are_gone() {
+        let mut buf = ReadBuffer::new();
+        buf.read_from(&mut Cursor::new(b"abcd".to_vec()), 4).unwrap();
+        let first = buf.take(2);
+        let before = buf.unread().as_ptr();
+
+        // Still in use, so the unread bytes move to a new buffer.
+        buf.read_from(&mut Cursor::new(Vec::new()), READ_CHUNK + 1).unwrap();
+        assert_ne!(buf.unread().as_ptr(), before);
+        assert_eq!(buf.unread(), b"cd");
+        assert_eq!(&*first, b"ab");
+
+        // Nothing points into this one, so they move to its front instead.
+        buf.consume(1);
+        let after = buf.unread().as_ptr();
+        buf.read_from(&mut Cursor::new(b"ef".to_vec()), READ_CHUNK + 1).unwrap();
+        assert_eq!(buf.unread().as_ptr(), after.wrapping_sub(1));
+        assert_eq!(buf.unread(), b"def");
+    }
+}
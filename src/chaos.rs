@@ -0,0 +1,254 @@
+//! mob-chaos: abuse a running mob server and check that it survives.
+//!
+//! Hundreds of clients connect, send valid frames and garbage, stall half way through a frame
+//! and disconnect abruptly, over and over, for as long as requested. A probe keeps checking that
+//! the server still answers. Once the run is over, every chaos client is gone, so the server
+//! should be able to seat a full house of fresh clients again. If it cannot, slab slots leaked.
+//!
+//! ```text
+//! mob-chaos --addr 127.0.0.1:8000 --clients 300 --duration 3600 --capacity 128
+//! ```
+
+extern crate mob;
+
+mod rng;
+
+use std::env;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::process;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use mob::codec;
+
+use rng::Rng;
+
+struct Options {
+    addr: SocketAddr,
+    clients: usize,
+    duration: Duration,
+    capacity: usize,
+    seed: u64,
+}
+
+fn usage() -> ! {
+    eprintln!("usage: mob-chaos [--addr host:port] [--clients N] [--duration SECS] \
+               [--capacity N] [--seed N]");
+    process::exit(2);
+}
+
+fn parse_options() -> Options {
+    let mut opts = Options {
+        addr: "127.0.0.1:8000".parse().unwrap(),
+        clients: 200,
+        duration: Duration::from_secs(60),
+        capacity: 128,
+        seed: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs(),
+    };
+
+    let mut args = env::args().skip(1);
+    while let Some(flag) = args.next() {
+        let value = args.next().unwrap_or_else(|| usage());
+        match flag.as_str() {
+            "--addr" => opts.addr = value.parse().unwrap_or_else(|_| usage()),
+            "--clients" => opts.clients = value.parse().unwrap_or_else(|_| usage()),
+            "--duration" => {
+                opts.duration = Duration::from_secs(value.parse().unwrap_or_else(|_| usage()))
+            }
+            "--capacity" => opts.capacity = value.parse().unwrap_or_else(|_| usage()),
+            "--seed" => opts.seed = value.parse().unwrap_or_else(|_| usage()),
+            _ => usage(),
+        }
+    }
+
+    opts
+}
+
+#[derive(Default)]
+struct Stats {
+    connects: AtomicUsize,
+    refused: AtomicUsize,
+    valid: AtomicUsize,
+    garbage: AtomicUsize,
+    stalls: AtomicUsize,
+    aborts: AtomicUsize,
+    probe_failures: AtomicUsize,
+}
+
+/// Read and discard whatever the server has sent us, so we never look like a slow consumer for
+/// longer than we mean to.
+fn drain(stream: &mut TcpStream) -> io::Result<()> {
+    let mut buf = [0u8; 4096];
+    loop {
+        match stream.read(&mut buf) {
+            Ok(0) => return Err(ErrorKind::UnexpectedEof.into()),
+            Ok(_) => {},
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// One chaos client. Runs a random series of actions on a connection, then reconnects.
+fn chaos(addr: SocketAddr, seed: u64, deadline: Instant, stats: &Stats) {
+    let mut rng = Rng::new(seed);
+
+    while Instant::now() < deadline {
+        let mut stream = match TcpStream::connect(addr) {
+            Ok(s) => s,
+            Err(_) => {
+                stats.refused.fetch_add(1, Ordering::Relaxed);
+                thread::sleep(Duration::from_millis(rng.below(100)));
+                continue;
+            }
+        };
+        stats.connects.fetch_add(1, Ordering::Relaxed);
+        let _ = stream.set_read_timeout(Some(Duration::from_millis(5)));
+
+        for _ in 0..rng.below(50) {
+            if Instant::now() >= deadline {
+                break;
+            }
+
+            let result = match rng.below(10) {
+                // mostly behave
+                0..=5 => {
+                    stats.valid.fetch_add(1, Ordering::Relaxed);
+                    let len = rng.below(4096) as usize;
+                    stream.write_all(&codec::encode(&rng.bytes(len)))
+                }
+                6 => {
+                    stats.garbage.fetch_add(1, Ordering::Relaxed);
+                    let len = 1 + rng.below(512) as usize;
+                    stream.write_all(&rng.bytes(len))
+                }
+                7 => {
+                    // announce a frame, send part of it, then go quiet for a while
+                    stats.stalls.fetch_add(1, Ordering::Relaxed);
+                    let len = 2 + rng.below(4096) as usize;
+                    let frame = codec::encode(&rng.bytes(len));
+                    let cut = 1 + rng.below(frame.len() as u64 - 1) as usize;
+                    let r = stream.write_all(&frame[..cut]);
+                    thread::sleep(Duration::from_millis(rng.below(2000)));
+                    r
+                }
+                8 => {
+                    thread::sleep(Duration::from_millis(rng.below(200)));
+                    Ok(())
+                }
+                _ => {
+                    // walk away without a goodbye, possibly mid frame
+                    stats.aborts.fetch_add(1, Ordering::Relaxed);
+                    let frame = codec::encode(&rng.bytes(64));
+                    let cut = rng.below(frame.len() as u64) as usize;
+                    let _ = stream.write_all(&frame[..cut]);
+                    break;
+                }
+            };
+
+            // The server closing us after garbage is expected. Start over.
+            if result.and_then(|_| drain(&mut stream)).is_err() {
+                break;
+            }
+        }
+    }
+}
+
+/// Connect, send a frame and wait for the broadcast to come back to us.
+fn probe(addr: SocketAddr, marker: &[u8]) -> io::Result<TcpStream> {
+    let mut stream = TcpStream::connect_timeout(&addr, Duration::from_secs(5))?;
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    stream.write_all(&codec::encode(marker))?;
+    wait_for(&mut stream, marker)?;
+    Ok(stream)
+}
+
+/// Read frames until one carries `marker`.
+fn wait_for(stream: &mut TcpStream, marker: &[u8]) -> io::Result<()> {
+    let mut header = [0u8; codec::HEADER_LEN];
+    loop {
+        stream.read_exact(&mut header)?;
+        let mut payload = vec![0u8; codec::decode_header(&header) as usize];
+        stream.read_exact(&mut payload)?;
+        if payload == marker {
+            return Ok(());
+        }
+    }
+}
+
+fn main() {
+    let opts = parse_options();
+    let deadline = Instant::now() + opts.duration;
+    let stats = Arc::new(Stats::default());
+
+    println!("mob-chaos: {} clients against {} for {:?}, seed {}",
+             opts.clients, opts.addr, opts.duration, opts.seed);
+
+    let mut workers = Vec::with_capacity(opts.clients);
+    for i in 0..opts.clients {
+        let stats = stats.clone();
+        let addr = opts.addr;
+        let seed = opts.seed.wrapping_add(i as u64);
+        workers.push(thread::spawn(move || chaos(addr, seed, deadline, &stats)));
+    }
+
+    // Keep checking the server still does its job while the chaos runs.
+    let mut probes = 0;
+    while Instant::now() < deadline {
+        thread::sleep(Duration::from_secs(1));
+        probes += 1;
+        match probe(opts.addr, format!("probe {}", probes).as_bytes()) {
+            Ok(_) => {},
+            // The chaos clients may have every slot, in which case the server turns us away.
+            // That is still an answer.
+            Err(ref e) if e.kind() == ErrorKind::UnexpectedEof
+                || e.kind() == ErrorKind::ConnectionReset => {},
+            Err(e) => {
+                stats.probe_failures.fetch_add(1, Ordering::Relaxed);
+                println!("probe {} failed: {}", probes, e);
+            }
+        }
+    }
+
+    for worker in workers {
+        let _ = worker.join();
+    }
+
+    println!("connects={} refused={} valid={} garbage={} stalls={} aborts={} probes={} \
+              probe_failures={}",
+             stats.connects.load(Ordering::Relaxed),
+             stats.refused.load(Ordering::Relaxed),
+             stats.valid.load(Ordering::Relaxed),
+             stats.garbage.load(Ordering::Relaxed),
+             stats.stalls.load(Ordering::Relaxed),
+             stats.aborts.load(Ordering::Relaxed),
+             probes,
+             stats.probe_failures.load(Ordering::Relaxed));
+
+    // Give the server a moment to notice the last disconnects, then fill every slot. A leaked
+    // slot means one of these connections gets turned away.
+    thread::sleep(Duration::from_secs(1));
+
+    let mut seated = Vec::with_capacity(opts.capacity);
+    for i in 0..opts.capacity {
+        match probe(opts.addr, format!("seat {}", i).as_bytes()) {
+            Ok(stream) => seated.push(stream),
+            Err(e) => {
+                println!("FAIL: only {} of {} slots available ({})", i, opts.capacity, e);
+                process::exit(1);
+            }
+        }
+    }
+
+    if stats.probe_failures.load(Ordering::Relaxed) > 0 {
+        println!("FAIL: server stopped answering during the run");
+        process::exit(1);
+    }
+
+    println!("OK: server survived and all {} slots are free", opts.capacity);
+}
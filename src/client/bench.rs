@@ -0,0 +1,421 @@
+//! The `bench` subcommand: a load generator for a mob server.
+//!
+//! Opens a number of connections, sends frames on each of them at a configured rate for a
+//! configured time and reports the aggregate throughput. Every connection also reads the
+//! broadcasts coming back, so the server never sees it as a slow consumer.
+//!
+//! Each payload starts with the id of the connection that sent it and the time it was sent.
+//! When a connection receives the broadcast of its own message it records the round trip time,
+//! and the latency distribution is reported at the end of the run.
+//!
+//! With `--report`, the results are also written to a file, as JSON or CSV, for runs to be
+//! compared by a script. Both hold the totals and a line for each connection.
+//!
+//! ```text
+//! mob-client bench --connections 50 --size 64..1024 --rate 100 --duration 30
+//! mob-client bench --report bench.csv --report-format csv
+//! ```
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use mob::codec::{self, FrameReader};
+use mob::dump::Json;
+
+use rng::Rng;
+
+/// How big each message is.
+#[derive(Clone, Copy, Debug)]
+pub enum SizeDist {
+    Fixed(usize),
+    Uniform(usize, usize),
+}
+
+impl SizeDist {
+    pub fn parse(s: &str) -> Option<SizeDist> {
+        match s.find("..") {
+            None => s.parse().ok().map(SizeDist::Fixed),
+            Some(i) => {
+                let min = s[..i].parse().ok()?;
+                let max = s[i + 2..].parse().ok()?;
+                if min > max {
+                    return None;
+                }
+                Some(SizeDist::Uniform(min, max))
+            }
+        }
+    }
+
+    fn sample(&self, rng: &mut Rng) -> usize {
+        match *self {
+            SizeDist::Fixed(n) => n,
+            SizeDist::Uniform(min, max) => min + rng.below((max - min + 1) as u64) as usize,
+        }
+    }
+}
+
+/// How `--report` writes the results.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ReportFormat {
+    #[default]
+    Json,
+    Csv,
+}
+
+impl FromStr for ReportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ReportFormat, String> {
+        match s {
+            "json" => Ok(ReportFormat::Json),
+            "csv" => Ok(ReportFormat::Csv),
+            _ => Err(format!("unknown report format {}, expected json or csv", s)),
+        }
+    }
+}
+
+pub struct Options {
+    pub connections: usize,
+    pub size: SizeDist,
+
+    // messages per second per connection, 0 means as fast as possible
+    pub rate: u64,
+    pub duration: Duration,
+
+    // where to write the results for scripts, and how
+    pub report: Option<PathBuf>,
+    pub report_format: ReportFormat,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            connections: 10,
+            size: SizeDist::Fixed(64),
+            rate: 10,
+            duration: Duration::from_secs(10),
+            report: None,
+            report_format: ReportFormat::default(),
+        }
+    }
+}
+
+/// What one connection did, counted as it goes.
+#[derive(Default)]
+struct Totals {
+    sent_msgs: AtomicUsize,
+    sent_bytes: AtomicUsize,
+    recv_msgs: AtomicUsize,
+    recv_bytes: AtomicUsize,
+    errors: AtomicUsize,
+}
+
+/// What a connection, or all of them, did over the run.
+#[derive(Default)]
+struct Stats {
+    sent_msgs: usize,
+    sent_bytes: usize,
+    recv_msgs: usize,
+    recv_bytes: usize,
+    errors: usize,
+
+    // round trip times in microseconds, sorted
+    samples: Vec<u64>,
+}
+
+impl Stats {
+    fn new(totals: &Totals, mut samples: Vec<u64>) -> Stats {
+        samples.sort_unstable();
+        Stats {
+            sent_msgs: totals.sent_msgs.load(Ordering::Relaxed),
+            sent_bytes: totals.sent_bytes.load(Ordering::Relaxed),
+            recv_msgs: totals.recv_msgs.load(Ordering::Relaxed),
+            recv_bytes: totals.recv_bytes.load(Ordering::Relaxed),
+            errors: totals.errors.load(Ordering::Relaxed),
+            samples,
+        }
+    }
+
+    fn add(&mut self, other: &Stats) {
+        self.sent_msgs += other.sent_msgs;
+        self.sent_bytes += other.sent_bytes;
+        self.recv_msgs += other.recv_msgs;
+        self.recv_bytes += other.recv_bytes;
+        self.errors += other.errors;
+        self.samples.extend_from_slice(&other.samples);
+    }
+
+    /// The 50th, 95th and 99th percentiles and the maximum, if there are samples.
+    fn latency(&self) -> Option<[u64; 4]> {
+        let max = *self.samples.last()?;
+        let sorted = &self.samples;
+        Some([percentile(sorted, 50.0), percentile(sorted, 95.0), percentile(sorted, 99.0), max])
+    }
+
+    fn json(&self) -> Json {
+        let latency = self.latency().map(|[p50, p95, p99, max]| {
+            Json::object(vec![
+                ("samples", self.samples.len().into()),
+                ("p50", p50.into()),
+                ("p95", p95.into()),
+                ("p99", p99.into()),
+                ("max", max.into()),
+            ])
+        });
+        Json::object(vec![
+            ("sent_msgs", self.sent_msgs.into()),
+            ("sent_bytes", self.sent_bytes.into()),
+            ("recv_msgs", self.recv_msgs.into()),
+            ("recv_bytes", self.recv_bytes.into()),
+            ("errors", self.errors.into()),
+            ("latency_us", latency.into()),
+        ])
+    }
+
+    fn csv(&self, connection: &str, elapsed: Duration) -> String {
+        let latency = match self.latency() {
+            Some(l) => format!("{},{},{},{}", l[0], l[1], l[2], l[3]),
+            None => ",,,".to_string(),
+        };
+        format!("{},{},{},{},{},{},{},{},{}", connection, elapsed.as_micros(), self.sent_msgs,
+                self.sent_bytes, self.recv_msgs, self.recv_bytes, self.errors,
+                self.samples.len(), latency)
+    }
+}
+
+/// The columns of a CSV report.
+const CSV_HEADER: &str = "connection,elapsed_us,sent_msgs,sent_bytes,recv_msgs,recv_bytes,\
+                          errors,samples,p50_us,p95_us,p99_us,max_us";
+
+/// Write the results to `opts.report`: the settings, the totals with throughput, and each
+/// connection, as JSON; or a row for each connection and one for the total, as CSV.
+fn write_report(opts: &Options, started: SystemTime, elapsed: Duration, total: &Stats,
+                conns: &[Stats]) -> io::Result<()>
+{
+    let path = match opts.report {
+        Some(ref path) => path,
+        None => return Ok(()),
+    };
+    let mut out = BufWriter::new(File::create(path)?);
+
+    match opts.report_format {
+        ReportFormat::Json => {
+            let per_sec = |n: usize| (n as f64 / elapsed.as_secs_f64()) as u64;
+            let started = started.duration_since(UNIX_EPOCH).unwrap_or_default();
+            let report = Json::object(vec![
+                ("started_unix_ms", (started.as_millis() as u64).into()),
+                ("connections", opts.connections.into()),
+                ("size", Json::debug(opts.size)),
+                ("rate", opts.rate.into()),
+                ("duration_us", (opts.duration.as_micros() as u64).into()),
+                ("elapsed_us", (elapsed.as_micros() as u64).into()),
+                ("sent_msgs_per_sec", per_sec(total.sent_msgs).into()),
+                ("sent_bytes_per_sec", per_sec(total.sent_bytes).into()),
+                ("recv_bytes_per_sec", per_sec(total.recv_bytes).into()),
+                ("total", total.json()),
+                ("per_connection", Json::Array(conns.iter().map(Stats::json).collect())),
+            ]);
+            writeln!(out, "{}", report)?;
+        }
+        ReportFormat::Csv => {
+            writeln!(out, "{}", CSV_HEADER)?;
+            for (id, conn) in conns.iter().enumerate() {
+                writeln!(out, "{}", conn.csv(&id.to_string(), elapsed))?;
+            }
+            writeln!(out, "{}", total.csv("total", elapsed))?;
+        }
+    }
+    out.flush()
+}
+
+/// The bytes at the start of every payload: the sending connection's id and the send time in
+/// nanoseconds since the start of the run.
+const STAMP_LEN: usize = 12;
+
+fn stamp(payload: &mut [u8], id: u32, start: Instant) {
+    let sent = start.elapsed().as_nanos() as u64;
+    payload[..4].copy_from_slice(&id.to_be_bytes());
+    payload[4..STAMP_LEN].copy_from_slice(&sent.to_be_bytes());
+}
+
+/// The round trip time of a payload if it was sent by connection `id`.
+fn round_trip(payload: &[u8], id: u32, start: Instant) -> Option<Duration> {
+    if payload.len() < STAMP_LEN || payload[..4] != id.to_be_bytes() {
+        return None;
+    }
+
+    let mut sent = [0u8; 8];
+    sent.copy_from_slice(&payload[4..STAMP_LEN]);
+    let sent = Duration::from_nanos(u64::from_be_bytes(sent));
+    Some(start.elapsed() - sent)
+}
+
+/// Send frames at the configured rate until the deadline.
+fn send_loop(mut stream: TcpStream, opts: &Options, id: u32, start: Instant, deadline: Instant,
+             totals: &Totals) -> io::Result<()>
+{
+    let mut rng = Rng::new(start.elapsed().as_nanos() as u64 ^ id as u64);
+    let interval = 1_000_000_000u64.checked_div(opts.rate).map(Duration::from_nanos);
+
+    let mut next = Instant::now();
+    while Instant::now() < deadline {
+        // Payloads are never smaller than the stamp they carry.
+        let mut payload = vec![b'x'; opts.size.sample(&mut rng).max(STAMP_LEN)];
+        stamp(&mut payload, id, start);
+        let frame = codec::encode(&payload);
+        stream.write_all(&frame)?;
+
+        totals.sent_msgs.fetch_add(1, Ordering::Relaxed);
+        totals.sent_bytes.fetch_add(frame.len(), Ordering::Relaxed);
+
+        // Pace against a fixed schedule rather than sleeping a fixed amount, so slow writes do
+        // not drag the rate down.
+        if let Some(interval) = interval {
+            next += interval;
+            let now = Instant::now();
+            if next > now {
+                thread::sleep(next - now);
+            }
+        }
+    }
+
+    stream.shutdown(Shutdown::Write)
+}
+
+/// Read broadcasts until the server closes the connection, returning the round trip times of
+/// our own messages in microseconds.
+fn recv_loop(stream: TcpStream, id: u32, start: Instant, totals: &Totals)
+    -> io::Result<Vec<u64>>
+{
+    let mut samples = Vec::new();
+    let mut reader = FrameReader::new(stream);
+
+    while let Some(payload) = reader.read_frame()? {
+        totals.recv_msgs.fetch_add(1, Ordering::Relaxed);
+        totals.recv_bytes.fetch_add(codec::HEADER_LEN + payload.len(), Ordering::Relaxed);
+
+        if let Some(rtt) = round_trip(&payload, id, start) {
+            samples.push(rtt.as_micros() as u64);
+        }
+    }
+
+    Ok(samples)
+}
+
+/// The value below which `p` percent of the sorted samples fall.
+fn percentile(sorted: &[u64], p: f64) -> u64 {
+    let rank = ((p / 100.0) * sorted.len() as f64).ceil() as usize;
+    sorted[rank.saturating_sub(1).min(sorted.len() - 1)]
+}
+
+/// Print percentiles and a histogram with power of two buckets.
+fn report_latency(stats: &Stats) {
+    let [p50, p95, p99, max] = match stats.latency() {
+        Some(latency) => latency,
+        None => {
+            println!("latency: no samples");
+            return;
+        }
+    };
+    let samples = &stats.samples;
+    println!("latency (us): p50={} p95={} p99={} max={} samples={}",
+             p50, p95, p99, max, samples.len());
+
+    let mut buckets = [0usize; 64];
+    for &us in samples {
+        buckets[(64 - us.leading_zeros()) as usize] += 1;
+    }
+
+    let first = buckets.iter().position(|&n| n > 0).unwrap();
+    let last = buckets.iter().rposition(|&n| n > 0).unwrap();
+    let most = *buckets.iter().max().unwrap();
+
+    for (i, &n) in buckets.iter().enumerate().take(last + 1).skip(first) {
+        let upper = if i == 0 { 0 } else { (1u64 << i) - 1 };
+        let bar = "#".repeat((n * 50).div_ceil(most));
+        println!("  <= {:>9}us {:>9} {}", upper, n, bar);
+    }
+}
+
+/// Run the benchmark against the server at `addr` and print the results.
+pub fn run(addr: SocketAddr, opts: Options) -> io::Result<()> {
+    let opts = Arc::new(opts);
+    let totals: Vec<Arc<Totals>> = (0..opts.connections).map(|_| Arc::default()).collect();
+
+    println!("mob-client: {} connections to {}, size {:?}, rate {}/s per connection, for {:?}",
+             opts.connections, addr, opts.size, opts.rate, opts.duration);
+
+    let started = SystemTime::now();
+    let start = Instant::now();
+    let deadline = start + opts.duration;
+    let mut senders = Vec::with_capacity(opts.connections);
+    let mut receivers = Vec::with_capacity(opts.connections);
+
+    for (i, conn) in totals.iter().enumerate() {
+        let stream = TcpStream::connect(addr)?;
+        let reader = stream.try_clone()?;
+
+        let id = i as u32;
+
+        let (o, t) = (opts.clone(), conn.clone());
+        senders.push(thread::spawn(move || {
+            if let Err(e) = send_loop(stream, &o, id, start, deadline, &t) {
+                eprintln!("connection {}: send failed: {}", id, e);
+                t.errors.fetch_add(1, Ordering::Relaxed);
+            }
+        }));
+
+        let t = conn.clone();
+        receivers.push(thread::spawn(move || {
+            recv_loop(reader, id, start, &t).unwrap_or_else(|e| {
+                eprintln!("connection {}: receive failed: {}", id, e);
+                t.errors.fetch_add(1, Ordering::Relaxed);
+                Vec::new()
+            })
+        }));
+    }
+
+    // Report progress once a second while the run is going.
+    let mut last = 0;
+    while Instant::now() < deadline {
+        thread::sleep(Duration::from_secs(1));
+        let sent: usize = totals.iter().map(|t| t.sent_msgs.load(Ordering::Relaxed)).sum();
+        println!("{:>6.1}s  {:>10} msgs/s", start.elapsed().as_secs_f64(), sent - last);
+        last = sent;
+    }
+
+    // Senders stop at the deadline. Readers stop once the server has closed our connections,
+    // which it does when it sees our write side shut down.
+    for t in senders {
+        let _ = t.join();
+    }
+
+    let mut conns = Vec::with_capacity(opts.connections);
+    for (t, totals) in receivers.into_iter().zip(&totals) {
+        conns.push(Stats::new(totals, t.join().unwrap_or_default()));
+    }
+    let mut total = Stats::default();
+    for conn in &conns {
+        total.add(conn);
+    }
+    total.samples.sort_unstable();
+
+    let elapsed = start.elapsed();
+    let secs = elapsed.as_secs_f64();
+    println!("sent {} msgs, {} bytes in {:.2}s", total.sent_msgs, total.sent_bytes, secs);
+    println!("send throughput: {:.0} msgs/s, {:.2} MiB/s",
+             total.sent_msgs as f64 / secs, total.sent_bytes as f64 / secs / (1024.0 * 1024.0));
+    println!("recv throughput: {:.2} MiB/s",
+             total.recv_bytes as f64 / secs / (1024.0 * 1024.0));
+    println!("errors: {}", total.errors);
+
+    report_latency(&total);
+    write_report(&opts, started, elapsed, &total, &conns)
+}
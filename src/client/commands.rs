@@ -0,0 +1,207 @@
+//! The `send`, `listen` and `pipe` subcommands.
+
+use std::collections::VecDeque;
+use std::io::{self, BufRead, ErrorKind, Write};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+
+use mob::codec::{self, FrameReader};
+use mob_client::{Client, Endpoints, FailoverClient, Heartbeat, Inbox, Timeouts};
+
+use Options;
+
+/// Connect to the first of `--addr` that accepts us.
+///
+/// `--timeout` only applies to the commands that wait on the server for a bounded amount of
+/// work, so the interactive ones pass `with_timeout` false.
+pub fn connect(opts: &Options, with_timeout: bool) -> io::Result<Client> {
+    let mut endpoints = Endpoints::from_hosts(opts.addrs.clone());
+    endpoints.set_timeouts(timeouts(opts, with_timeout));
+    endpoints.connect()
+}
+
+fn timeouts(opts: &Options, with_timeout: bool) -> Timeouts {
+    let timeout = if with_timeout { opts.timeout } else { None };
+    Timeouts { connect: opts.connect_timeout, read: timeout, write: timeout }
+}
+
+/// Wait until every message in `sent` has been broadcast back to us, in order.
+///
+/// This way `send` only succeeds once the server has broadcast every message, not merely once
+/// they were written to the socket.
+fn await_echoes(client: &mut Client, mut sent: VecDeque<Vec<u8>>) -> io::Result<()> {
+    while let Some(expected) = sent.pop_front() {
+        loop {
+            match client.recv()? {
+                Some(ref payload) if *payload == expected => break,
+                Some(_) => {},
+                None => {
+                    return Err(io::Error::new(ErrorKind::UnexpectedEof,
+                                              "Server closed the connection"));
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Send `msg` `--count` times, sleeping `--interval` between each.
+pub fn send(opts: &Options, msg: &[u8]) -> io::Result<()> {
+    let mut client = connect(opts, true)?;
+    let count = opts.count.unwrap_or(1);
+
+    for i in 0..count {
+        if i > 0 {
+            thread::sleep(opts.interval);
+        }
+        client.send(msg)?;
+    }
+
+    await_echoes(&mut client, vec![msg.to_vec(); count].into())?;
+    client.shutdown()
+}
+
+/// What `listen` reads from.
+enum Receiver {
+    Single(Client),
+    Failover(FailoverClient),
+}
+
+/// Print every broadcast received, one per line or length delimited, until the server closes
+/// the connection or `--count` messages have arrived.
+///
+/// Given more than one `--addr`, a closed connection fails over to the next server instead. So
+/// does a server that stops answering `--heartbeat`.
+pub fn listen(opts: &Options) -> io::Result<()> {
+    let heartbeat = opts.heartbeat.map(|interval| Heartbeat { interval, timeout: interval });
+    let mut client = if opts.addrs.len() > 1 {
+        let mut endpoints = Endpoints::from_hosts(opts.addrs.clone());
+        endpoints.set_timeouts(timeouts(opts, true));
+        let mut client = FailoverClient::from_endpoints(endpoints)?;
+        client.set_heartbeat(heartbeat)?;
+        Receiver::Failover(client)
+    } else {
+        let mut client = connect(opts, true)?;
+        client.set_heartbeat(heartbeat)?;
+        Receiver::Single(client)
+    };
+    let stdout = io::stdout();
+    let mut received = 0;
+
+    while opts.count.map(|n| received < n).unwrap_or(true) {
+        let payload = match client {
+            Receiver::Single(ref mut c) => c.recv()?,
+            Receiver::Failover(ref mut c) => Some(c.recv()?),
+        };
+
+        match payload {
+            Some(payload) => {
+                write_payload(&mut stdout.lock(), &payload, opts.length_delimited)?;
+                received += 1;
+            }
+            None => break,
+        }
+    }
+
+    Ok(())
+}
+
+/// Write a received payload to `out`, either as a line or as a length delimited frame.
+fn write_payload<W: Write>(out: &mut W, payload: &[u8], length_delimited: bool) -> io::Result<()> {
+    if length_delimited {
+        out.write_all(&codec::encode(payload))?;
+    } else {
+        out.write_all(payload)?;
+        out.write_all(b"\n")?;
+    }
+    out.flush()
+}
+
+/// What the stdin and stdout halves of `pipe` share.
+struct PipeState {
+    // messages we sent that have not been broadcast back to us yet
+    pending: VecDeque<Vec<u8>>,
+
+    // the connection is gone, so nothing pending will ever come back
+    closed: bool,
+}
+
+/// Write every broadcast that is not one of our own pending messages to stdout.
+fn print_broadcasts(inbox: &Inbox,
+                    state: &(Mutex<PipeState>, Condvar),
+                    length_delimited: bool) -> io::Result<()> {
+    let stdout = io::stdout();
+    let (lock, cvar) = state;
+
+    while let Some(payload) = inbox.recv()? {
+        let mut state = lock.lock().unwrap();
+
+        if state.pending.front() == Some(&payload) {
+            state.pending.pop_front();
+            if state.pending.is_empty() {
+                cvar.notify_all();
+            }
+        } else {
+            write_payload(&mut stdout.lock(), &payload, length_delimited)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Send each line, or each length delimited frame, read from stdin as a message, and write
+/// every broadcast from other clients to stdout in the same format.
+///
+/// Our own messages come back to us as part of the broadcast. Those are not written out, and
+/// once stdin is exhausted we wait for all of them before hanging up.
+pub fn pipe(opts: &Options) -> io::Result<()> {
+    let (outbox, inbox) = connect(opts, false)?.split()?;
+    let length_delimited = opts.length_delimited;
+
+    let state = Arc::new((Mutex::new(PipeState { pending: VecDeque::new(), closed: false }),
+                          Condvar::new()));
+
+    let printer = {
+        let state = state.clone();
+        thread::spawn(move || -> io::Result<()> {
+            let result = print_broadcasts(&inbox, &state, length_delimited);
+
+            let (lock, cvar) = &*state;
+            lock.lock().unwrap().closed = true;
+            cvar.notify_all();
+            result
+        })
+    };
+
+    let send = |msg: Vec<u8>| -> io::Result<()> {
+        // Record it first, the echo may arrive before send returns.
+        state.0.lock().unwrap().pending.push_back(msg.clone());
+        outbox.send(&msg)
+    };
+
+    let stdin = io::stdin();
+    if length_delimited {
+        let mut frames = FrameReader::new(stdin.lock());
+        while let Some(msg) = frames.read_frame()? {
+            send(msg)?;
+        }
+    } else {
+        for line in stdin.lock().lines() {
+            send(line?.into_bytes())?;
+        }
+    }
+
+    {
+        let (lock, cvar) = &*state;
+        let mut state = lock.lock().unwrap();
+        while !state.pending.is_empty() && !state.closed {
+            state = cvar.wait(state).unwrap();
+        }
+    }
+
+    // Dropping the outbox hangs up. The server closes the connection in response, which ends
+    // the printer.
+    drop(outbox);
+    printer.join().unwrap_or_else(|_| Err(io::Error::other("Printer thread panicked")))
+}
@@ -0,0 +1,207 @@
+//! mob-client: a command line client for a mob server.
+//!
+//! `--addr` may be given more than once. The first address is the primary, and the others are
+//! tried in order when it cannot be reached. Each is resolved once, at startup, and a host with
+//! more than one address is connected to at whichever answers first.
+//!
+//! ```text
+//! mob-client [--addr host:port] send <msg> [--count N] [--interval MS]
+//! mob-client [--addr host:port] listen [--count N] [--length-delimited]
+//! mob-client [--addr host:port] pipe [--length-delimited]
+//! mob-client [--addr host:port] chat
+//! mob-client [--addr host:port] bench [--connections N] [--size N | MIN..MAX] [--rate N]
+//!                                     [--duration SECS] [--report PATH] [--report-format json|csv]
+//! ```
+
+extern crate mob;
+extern crate mob_client;
+
+#[path = "../rng.rs"]
+mod rng;
+
+mod bench;
+mod commands;
+mod repl;
+
+use std::env;
+use std::net::SocketAddr;
+use std::process;
+use std::time::Duration;
+
+use mob::resolve::{resolve, Family};
+
+use bench::SizeDist;
+
+const USAGE: &str = "\
+usage: mob-client [options] <command> [args]
+
+commands:
+    send <msg>    send a message, --count times, --interval apart
+    listen        print broadcasts as they arrive, stopping after --count if given
+    pipe          send each line read from stdin as a message and print broadcasts from
+                  everyone else
+    chat          interactive prompt, type /help for commands
+    bench         run a load test
+
+options:
+    --addr <host:port>      server address, the host a name or an address, repeat to fail
+                            over to the next one in order [default: 127.0.0.1:8000]
+    --family <family>       any, ipv4 or ipv6, the addresses of each --addr to use
+                            [default: any]
+    --count <n>             number of messages to send or receive
+    --interval <ms>         time between messages sent by send [default: 0]
+    --connect-timeout <ms>  give up connecting to a server after this long
+    --timeout <ms>          give up on a read or write after this long, in send and listen
+    --heartbeat <ms>        in listen, ping a quiet server after this long and give up on it if
+                            it does not answer within the same time
+    --length-delimited      read and print mob frames instead of lines in listen and pipe
+
+bench options:
+    --connections <n>       connections to open [default: 10]
+    --size <n | min..max>   message size, fixed or uniformly distributed [default: 64]
+    --rate <n>              messages per second per connection, 0 for unlimited [default: 10]
+    --duration <secs>       how long to run [default: 10]
+    --report <path>         also write the results to path, for scripts to compare
+    --report-format <fmt>   json, or csv with a row for each connection [default: json]";
+
+/// The subcommand to run.
+pub enum Command {
+    Send(String),
+    Listen,
+    Pipe,
+    Chat,
+    Bench,
+}
+
+/// Options shared by the subcommands.
+pub struct Options {
+    // the primary server first, then the ones to fail over to, each with every address it
+    // resolved to
+    pub addrs: Vec<Vec<SocketAddr>>,
+    pub count: Option<usize>,
+    pub interval: Duration,
+    pub connect_timeout: Option<Duration>,
+    pub timeout: Option<Duration>,
+    pub heartbeat: Option<Duration>,
+
+    // listen and pipe read and write length prefixed frames rather than lines
+    pub length_delimited: bool,
+}
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    process::exit(2);
+}
+
+fn parse<T: ::std::str::FromStr>(flag: &str, value: Option<String>) -> T {
+    let value = value.unwrap_or_else(|| {
+        eprintln!("{} needs a value", flag);
+        usage();
+    });
+
+    value.parse().unwrap_or_else(|_| {
+        eprintln!("invalid value for {}: {}", flag, value);
+        usage();
+    })
+}
+
+fn parse_args() -> (Command, Options, bench::Options) {
+    let mut opts = Options {
+        addrs: Vec::new(),
+        count: None,
+        interval: Duration::from_millis(0),
+        connect_timeout: None,
+        timeout: None,
+        heartbeat: None,
+        length_delimited: false,
+    };
+    let mut bench_opts = bench::Options::default();
+    let mut positional = Vec::new();
+    let mut names = Vec::new();
+    let mut family = Family::default();
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--addr" => names.push(parse::<String>(&arg, args.next())),
+            "--family" => family = parse(&arg, args.next()),
+            "--count" => opts.count = Some(parse(&arg, args.next())),
+            "--interval" => opts.interval = Duration::from_millis(parse(&arg, args.next())),
+            "--connect-timeout" => {
+                opts.connect_timeout = Some(Duration::from_millis(parse(&arg, args.next())))
+            }
+            "--timeout" => opts.timeout = Some(Duration::from_millis(parse(&arg, args.next()))),
+            "--heartbeat" => opts.heartbeat = Some(Duration::from_millis(parse(&arg, args.next()))),
+            "--length-delimited" => opts.length_delimited = true,
+            "--connections" => bench_opts.connections = parse(&arg, args.next()),
+            "--size" => {
+                let value: String = parse(&arg, args.next());
+                bench_opts.size = SizeDist::parse(&value).unwrap_or_else(|| {
+                    eprintln!("invalid value for --size: {}", value);
+                    usage();
+                });
+            }
+            "--rate" => bench_opts.rate = parse(&arg, args.next()),
+            "--duration" => bench_opts.duration = Duration::from_secs(parse(&arg, args.next())),
+            "--report" => bench_opts.report = Some(parse(&arg, args.next())),
+            "--report-format" => bench_opts.report_format = parse(&arg, args.next()),
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                process::exit(0);
+            }
+            _ if arg.starts_with("--") => {
+                eprintln!("unknown option {}", arg);
+                usage();
+            }
+            _ => positional.push(arg),
+        }
+    }
+
+    let mut positional = positional.into_iter();
+    let command = match positional.next().as_deref() {
+        Some("send") => match positional.next() {
+            Some(msg) => Command::Send(msg),
+            None => usage(),
+        },
+        Some("listen") => Command::Listen,
+        Some("pipe") => Command::Pipe,
+        Some("chat") => Command::Chat,
+        Some("bench") => Command::Bench,
+        _ => usage(),
+    };
+
+    if positional.next().is_some() || bench_opts.connections == 0 {
+        usage();
+    }
+
+    if names.is_empty() {
+        names.push("127.0.0.1:8000".to_string());
+    }
+    for name in names {
+        let addrs = resolve(&name, family).unwrap_or_else(|e| {
+            eprintln!("mob-client: {}", e);
+            process::exit(1);
+        });
+        opts.addrs.push(addrs);
+    }
+
+    (command, opts, bench_opts)
+}
+
+fn main() {
+    let (command, opts, bench_opts) = parse_args();
+
+    let result = match command {
+        Command::Send(msg) => commands::send(&opts, msg.as_bytes()),
+        Command::Listen => commands::listen(&opts),
+        Command::Pipe => commands::pipe(&opts),
+        Command::Chat => repl::run(&opts),
+        // Every connection goes to the same address, so runs compare.
+        Command::Bench => bench::run(opts.addrs[0][0], bench_opts),
+    };
+
+    if let Err(e) = result {
+        eprintln!("mob-client: {}", e);
+        process::exit(1);
+    }
+}
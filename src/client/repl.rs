@@ -0,0 +1,146 @@
+//! The interactive `chat` subcommand.
+//!
+//! Lines typed at the prompt are sent as messages, and lines starting with `/` are commands.
+//! Broadcasts from other clients are printed above the prompt as they arrive.
+
+use std::collections::VecDeque;
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+
+use Options;
+use commands;
+
+const PROMPT: &str = "> ";
+
+/// How many lines `/history` remembers.
+const HISTORY_LEN: usize = 100;
+
+const HELP: &str = "\
+/help       show this help
+/history    show the lines entered so far
+/quit       leave
+/join, /nick and /who need rooms and nicknames, which this server does not have yet";
+
+/// Writes to the terminal, keeping the prompt at the bottom.
+struct Screen {
+    interactive: bool,
+}
+
+impl Screen {
+    /// Print a line above the prompt, then redraw the prompt.
+    fn print(&self, line: &str) {
+        let stdout = io::stdout();
+        let mut out = stdout.lock();
+        if self.interactive {
+            // move to the start of the line and clear whatever was typed so far
+            let _ = write!(out, "\r\x1b[K{}\n{}", line, PROMPT);
+        } else {
+            let _ = writeln!(out, "{}", line);
+        }
+        let _ = out.flush();
+    }
+
+    fn prompt(&self) {
+        if self.interactive {
+            let mut out = io::stdout();
+            let _ = out.write_all(PROMPT.as_bytes());
+            let _ = out.flush();
+        }
+    }
+}
+
+/// Run the interactive client until `/quit` or the end of stdin.
+pub fn run(opts: &Options) -> io::Result<()> {
+    let (outbox, inbox) = commands::connect(opts, false)?.split()?;
+    let interactive = io::stdin().is_terminal() && io::stdout().is_terminal();
+    let screen = Arc::new(Screen { interactive });
+
+    // Our own messages are broadcast back to us. They are already on screen, so the reader skips
+    // them.
+    let pending = Arc::new(Mutex::new(VecDeque::new()));
+    let hung_up = Arc::new(AtomicBool::new(false));
+
+    let reader = {
+        let screen = screen.clone();
+        let pending = pending.clone();
+        let hung_up = hung_up.clone();
+        thread::spawn(move || {
+            loop {
+                match inbox.recv() {
+                    Ok(Some(payload)) => {
+                        let mut pending = pending.lock().unwrap();
+                        if pending.front() == Some(&payload) {
+                            pending.pop_front();
+                        } else {
+                            screen.print(&String::from_utf8_lossy(&payload));
+                        }
+                    }
+                    Ok(None) => {
+                        if !hung_up.load(Ordering::SeqCst) {
+                            screen.print("server closed the connection");
+                        }
+                        break;
+                    }
+                    Err(e) => {
+                        screen.print(&format!("error: {}", e));
+                        break;
+                    }
+                }
+            }
+        })
+    };
+
+    let mut history = VecDeque::with_capacity(HISTORY_LEN);
+    let stdin = io::stdin();
+    let mut lines = stdin.lock().lines();
+
+    // Once the reader is done the server is gone, and there is no point sending anything.
+    while !reader.is_finished() {
+        screen.prompt();
+
+        let line = match lines.next() {
+            Some(line) => line?,
+            None => break,
+        };
+        if line.is_empty() {
+            continue;
+        }
+
+        if history.len() == HISTORY_LEN {
+            history.pop_front();
+        }
+        history.push_back(line.clone());
+
+        if line.starts_with('/') {
+            let command = line.split_whitespace().next().unwrap_or("");
+            match command {
+                "/quit" => break,
+                "/help" => println!("{}", HELP),
+                "/history" => {
+                    for (i, line) in history.iter().enumerate() {
+                        println!("{:4}  {}", i + 1, line);
+                    }
+                }
+                "/join" | "/nick" | "/who" => {
+                    println!("{} is not supported by this server yet", command);
+                }
+                _ => println!("unknown command {}, try /help", command),
+            }
+            continue;
+        }
+
+        let msg = line.into_bytes();
+        pending.lock().unwrap().push_back(msg.clone());
+        outbox.send(&msg)?;
+    }
+
+    // Dropping the outbox hangs up. The server closes the connection in response, which ends
+    // the reader.
+    hung_up.store(true, Ordering::SeqCst);
+    drop(outbox);
+    reader.join().unwrap();
+    Ok(())
+}
@@ -0,0 +1,263 @@
+//! mob-conformance: check that a running mob server handles malformed input as documented.
+//!
+//! Each case connects fresh clients, sends something the protocol section of the README has an
+//! answer for, and checks the server did exactly that. An observer connection is used to make
+//! sure nothing malformed leaks into the broadcast.
+//!
+//! ```text
+//! mob-conformance --addr 127.0.0.1:8000
+//! ```
+
+extern crate mob;
+
+use std::env;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::process;
+use std::thread;
+use std::time::Duration;
+
+use mob::codec;
+
+type CaseResult = Result<(), String>;
+type Case = fn(SocketAddr) -> CaseResult;
+
+const TIMEOUT: Duration = Duration::from_secs(2);
+
+fn connect(addr: SocketAddr) -> io::Result<TcpStream> {
+    let stream = TcpStream::connect_timeout(&addr, TIMEOUT)?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_nodelay(true)?;
+    Ok(stream)
+}
+
+fn write_frame(stream: &mut TcpStream, payload: &[u8]) -> io::Result<()> {
+    stream.write_all(&codec::encode(payload))
+}
+
+fn read_frame(stream: &mut TcpStream) -> io::Result<Vec<u8>> {
+    let mut header = [0u8; codec::HEADER_LEN];
+    stream.read_exact(&mut header)?;
+    let mut payload = vec![0u8; codec::decode_header(&header) as usize];
+    stream.read_exact(&mut payload)?;
+    Ok(payload)
+}
+
+/// Connect and round trip a frame, so we know the server has accepted us.
+fn join(addr: SocketAddr, name: &str) -> Result<TcpStream, String> {
+    let mut stream = connect(addr).map_err(|e| format!("{} failed to connect: {}", name, e))?;
+    expect_round_trip(&mut stream, name)?;
+    Ok(stream)
+}
+
+/// Send a marker and read until it comes back. Any other frame received first is returned as an
+/// error, which is how cases check that nothing unexpected was broadcast.
+fn expect_round_trip(stream: &mut TcpStream, name: &str) -> CaseResult {
+    let marker = format!("marker from {}", name).into_bytes();
+    write_frame(stream, &marker).map_err(|e| format!("{} failed to write: {}", name, e))?;
+
+    loop {
+        let frame = read_frame(stream).map_err(|e| format!("{} failed to read: {}", name, e))?;
+        if frame == marker {
+            return Ok(());
+        }
+        if !frame.starts_with(b"marker from ") {
+            return Err(format!("{} received unexpected frame {:?}", name, frame));
+        }
+    }
+}
+
+/// Expect the server to close the connection.
+fn expect_closed(stream: &mut TcpStream, name: &str) -> CaseResult {
+    let mut buf = [0u8; 64];
+    loop {
+        match stream.read(&mut buf) {
+            Ok(0) => return Ok(()),
+            Ok(_) => continue,
+            Err(ref e) if e.kind() == ErrorKind::ConnectionReset => return Ok(()),
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+                return Err(format!("{} was not closed by the server", name));
+            }
+            Err(e) => return Err(format!("{} failed to read: {}", name, e)),
+        }
+    }
+}
+
+fn valid_frame_is_broadcast(addr: SocketAddr) -> CaseResult {
+    let mut observer = join(addr, "observer")?;
+    let mut client = join(addr, "client")?;
+    read_frame(&mut observer).map_err(|e| e.to_string())?;
+
+    write_frame(&mut client, b"hello").map_err(|e| e.to_string())?;
+    match read_frame(&mut observer) {
+        Ok(ref f) if f == b"hello" => Ok(()),
+        other => Err(format!("observer expected hello, got {:?}", other)),
+    }
+}
+
+fn zero_length_frame_is_ignored(addr: SocketAddr) -> CaseResult {
+    let mut observer = join(addr, "observer")?;
+    let mut client = join(addr, "client")?;
+
+    write_frame(&mut client, b"").map_err(|e| e.to_string())?;
+
+    // The connection stays open and the empty frame is not broadcast.
+    expect_round_trip(&mut client, "client")?;
+    expect_round_trip(&mut observer, "observer")
+}
+
+fn header_split_across_writes_is_accepted(addr: SocketAddr) -> CaseResult {
+    let mut observer = join(addr, "observer")?;
+    let mut client = join(addr, "client")?;
+    read_frame(&mut observer).map_err(|e| e.to_string())?;
+
+    let frame = codec::encode(b"slowly");
+    for byte in frame.iter() {
+        client.write_all(&[*byte]).map_err(|e| e.to_string())?;
+        thread::sleep(Duration::from_millis(5));
+    }
+
+    match read_frame(&mut observer) {
+        Ok(ref f) if f == b"slowly" => Ok(()),
+        other => Err(format!("observer expected slowly, got {:?}", other)),
+    }
+}
+
+fn oversize_length_closes_connection(addr: SocketAddr) -> CaseResult {
+    let mut observer = join(addr, "observer")?;
+    let mut client = join(addr, "client")?;
+
+    client.write_all(&codec::encode_header(codec::MAX_PAYLOAD_LEN + 1))
+        .map_err(|e| e.to_string())?;
+
+    expect_closed(&mut client, "client")?;
+    expect_round_trip(&mut observer, "observer")
+}
+
+fn maximum_length_prefix_closes_connection(addr: SocketAddr) -> CaseResult {
+    let mut observer = join(addr, "observer")?;
+    let mut client = join(addr, "client")?;
+
+    client.write_all(&[0xff; codec::HEADER_LEN]).map_err(|e| e.to_string())?;
+
+    expect_closed(&mut client, "client")?;
+    expect_round_trip(&mut observer, "observer")
+}
+
+fn truncated_header_is_dropped(addr: SocketAddr) -> CaseResult {
+    let mut observer = join(addr, "observer")?;
+    let mut client = join(addr, "client")?;
+
+    client.write_all(&codec::encode_header(5)[..3]).map_err(|e| e.to_string())?;
+    client.shutdown(Shutdown::Write).map_err(|e| e.to_string())?;
+
+    expect_closed(&mut client, "client")?;
+    expect_round_trip(&mut observer, "observer")
+}
+
+fn truncated_payload_is_dropped(addr: SocketAddr) -> CaseResult {
+    let mut observer = join(addr, "observer")?;
+    let mut client = join(addr, "client")?;
+
+    let frame = codec::encode(b"never finished");
+    client.write_all(&frame[..frame.len() - 3]).map_err(|e| e.to_string())?;
+    client.shutdown(Shutdown::Write).map_err(|e| e.to_string())?;
+
+    expect_closed(&mut client, "client")?;
+    expect_round_trip(&mut observer, "observer")
+}
+
+fn bytes_after_close_are_not_broadcast(addr: SocketAddr) -> CaseResult {
+    let mut observer = join(addr, "observer")?;
+    let mut client = join(addr, "client")?;
+
+    client.write_all(&codec::encode_header(codec::MAX_PAYLOAD_LEN + 1))
+        .map_err(|e| e.to_string())?;
+    expect_closed(&mut client, "client")?;
+
+    // Once closed, anything else we send is discarded. The writes themselves start failing once
+    // the peer has answered with a reset.
+    for _ in 0..10 {
+        if write_frame(&mut client, b"ghost").is_err() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    expect_round_trip(&mut observer, "observer")
+}
+
+fn ping_is_answered_only_to_sender(addr: SocketAddr) -> CaseResult {
+    let mut observer = join(addr, "observer")?;
+    let mut client = join(addr, "client")?;
+    read_frame(&mut observer).map_err(|e| e.to_string())?;
+
+    client.write_all(&codec::encode_control(codec::PING)).map_err(|e| e.to_string())?;
+
+    let mut header = [0u8; codec::HEADER_LEN];
+    client.read_exact(&mut header).map_err(|e| format!("client failed to read: {}", e))?;
+    if header != codec::encode_control(codec::PONG) {
+        return Err(format!("client expected a pong, got header {:?}", header));
+    }
+
+    expect_round_trip(&mut observer, "observer")
+}
+
+fn unknown_frame_kind_closes_connection(addr: SocketAddr) -> CaseResult {
+    let mut observer = join(addr, "observer")?;
+    let mut client = join(addr, "client")?;
+
+    client.write_all(&codec::encode_control(0x7f)).map_err(|e| e.to_string())?;
+
+    expect_closed(&mut client, "client")?;
+    expect_round_trip(&mut observer, "observer")
+}
+
+fn main() {
+    let mut addr: SocketAddr = "127.0.0.1:8000".parse().unwrap();
+
+    let mut args = env::args().skip(1);
+    while let Some(flag) = args.next() {
+        match (flag.as_str(), args.next()) {
+            ("--addr", Some(value)) => {
+                addr = value.parse().unwrap_or_else(|e| {
+                    eprintln!("invalid address {}: {}", value, e);
+                    process::exit(2);
+                });
+            }
+            _ => {
+                eprintln!("usage: mob-conformance [--addr host:port]");
+                process::exit(2);
+            }
+        }
+    }
+
+    let cases: &[(&str, Case)] = &[
+        ("valid frame is broadcast", valid_frame_is_broadcast),
+        ("zero length frame is ignored", zero_length_frame_is_ignored),
+        ("header split across writes is accepted", header_split_across_writes_is_accepted),
+        ("oversize length closes connection", oversize_length_closes_connection),
+        ("maximum length prefix closes connection", maximum_length_prefix_closes_connection),
+        ("truncated header is dropped", truncated_header_is_dropped),
+        ("truncated payload is dropped", truncated_payload_is_dropped),
+        ("bytes after close are not broadcast", bytes_after_close_are_not_broadcast),
+        ("ping is answered only to sender", ping_is_answered_only_to_sender),
+        ("unknown frame kind closes connection", unknown_frame_kind_closes_connection),
+    ];
+
+    let mut failed = 0;
+    for &(name, case) in cases {
+        match case(addr) {
+            Ok(()) => println!("PASS {}", name),
+            Err(e) => {
+                failed += 1;
+                println!("FAIL {}: {}", name, e);
+            }
+        }
+    }
+
+    println!("{} passed, {} failed", cases.len() - failed, failed);
+    if failed > 0 {
+        process::exit(1);
+    }
+}
@@ -1,49 +1,514 @@
-use std::collections::VecDeque;
+use std::cmp;
+use std::collections::{HashMap, VecDeque};
 use std::io;
-use std::io::prelude::*;
 use std::io::{Error, ErrorKind};
-use std::rc::Rc;
-
-use byteorder::{ByteOrder, BigEndian};
+use std::net::{Shutdown, SocketAddr};
+use std::sync::Arc;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
 
 use mio::{Poll, PollOpt, Ready, Token};
 use mio::net::TcpStream;
 use mio::unix::UnixReady;
 
+use buffer::{Payload, ReadBuffer};
+use codec::{self, Endian, Route};
+use dump::Json;
+use metrics::{Failure, Metrics};
+use spill::{Spill, SpillFile};
+use telnet;
+use transport::Transport;
+
+/// A message read from a client, to be broadcast.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Message {
+    /// The frame kind, `DATA`, `REQUEST`, `REPLY` or `FRAGMENT`. The server does not look inside
+    /// any of them, but passes the kind on to every connection it broadcasts to.
+    ///
+    /// A `WHO` with no payload is a question for the server, and is not broadcast. `PEER` and
+    /// `FORWARD` come from federated servers, which may also send whatever they send clients.
+    pub kind: u8,
+
+    /// How urgent the sender says it is and which of its streams it is on, from the frame header.
+    /// The default for ordinary messages.
+    pub route: Route,
+
+    /// Shares the buffer the connection read it into, see `buffer`.
+    pub payload: Payload,
+}
+
+impl Message {
+    /// An ordinary message.
+    pub fn data(payload: Vec<u8>) -> Message {
+        Message { kind: codec::DATA, route: Route::default(), payload: Payload::from(payload) }
+    }
+
+    /// The payload without the correlation id of a request or reply.
+    pub fn body(&self) -> &[u8] {
+        match self.kind {
+            codec::REQUEST | codec::REPLY => &self.payload[codec::CORRELATION_ID_LEN..],
+            _ => &self.payload,
+        }
+    }
+}
+
+/// A frame encoded once, length header and all, to be queued for every connection a broadcast
+/// goes to. Connections writing big endian length headers write straight out of it, each from
+/// however far into it they have got. The others encode its payload the way they always do.
+#[derive(Clone, Debug)]
+pub struct SharedFrame {
+    kind: u8,
+    route: Route,
+    bytes: Payload,
+}
+
+impl SharedFrame {
+    pub fn new(kind: u8, route: Route, payload: &[u8]) -> SharedFrame {
+        let mut bytes = Vec::with_capacity(codec::HEADER_LEN + payload.len());
+        bytes.extend_from_slice(&codec::encode_routed_header(kind, route, payload.len()));
+        bytes.extend_from_slice(payload);
+        SharedFrame { kind, route, bytes: Payload::from(bytes) }
+    }
+
+    pub fn kind(&self) -> u8 {
+        self.kind
+    }
+
+    /// The payload, in the same buffer as the header.
+    pub fn payload(&self) -> Payload {
+        self.bytes.slice(codec::HEADER_LEN..self.bytes.len())
+    }
+}
+
+/// The send queues, in the order they are drained.
+///
+/// A frame only waits behind the frames in its own band, so control frames go out ahead of a
+/// backlog of messages. Otherwise a connection with a deep queue would never get a timely PONG.
+/// Messages sent with a priority go out ahead of the ordinary ones, but behind control frames.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Band {
+    Control,
+    Urgent,
+    Data,
+}
+
+const BANDS: [Band; 3] = [Band::Control, Band::Urgent, Band::Data];
+
+impl Band {
+    fn of(kind: u8, priority: u8) -> Band {
+        match kind {
+            codec::PING | codec::PONG | codec::ERROR | codec::RECEIPT | codec::WHO |
+            codec::WELCOME | codec::GREETING | codec::ENDIAN | codec::PEER |
+            codec::NOTICE => Band::Control,
+            _ if priority > 0 => Band::Urgent,
+            _ => Band::Data,
+        }
+    }
+}
+
+/// A frame waiting to be staged.
+struct Queued {
+    kind: u8,
+    route: Route,
+    payload: Payload,
+
+    // the whole frame with a big endian header, if it was encoded once for several connections
+    shared: Option<Payload>,
+}
+
+impl Queued {
+    fn new(kind: u8, route: Route, payload: Payload) -> Queued {
+        Queued { kind, route, payload, shared: None }
+    }
+}
+
+/// Frames waiting to be staged, one queue per band.
+type SendQueue = VecDeque<Queued>;
+
+/// Whether frames of `kind` are messages, which peers using flow control need credit for.
+fn is_message(kind: u8) -> bool {
+    matches!(kind, codec::DATA | codec::REQUEST | codec::REPLY | codec::FRAGMENT)
+}
+
+/// How many messages a connection has room to queue before its queue has to grow, unless the
+/// server is configured otherwise.
+pub const DEFAULT_QUEUE_CAPACITY: usize = 32;
+
+/// The most bytes handed to the socket in one write, unless the server is configured otherwise.
+pub const DEFAULT_WRITE_BATCH: usize = 64 * 1024;
+
+/// How long a peer that shut down its write side has to read what is queued for it before we
+/// close the connection anyway. It is the write deadline the half close sets.
+pub const HALF_CLOSE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Append what is left of a frame, from `offset` bytes into the `parts` it is made of, to `batch`
+/// without letting it grow past `limit`. Returns how much of the frame was left, staged or not.
+fn gather(batch: &mut Vec<u8>, limit: usize, parts: &[&[u8]], offset: usize) -> usize {
+    let left = parts.iter().map(|part| part.len()).sum::<usize>() - offset;
+
+    let mut skip = offset;
+    for part in parts {
+        let from = cmp::min(skip, part.len());
+        skip -= from;
+
+        let part = &part[from..];
+        let room = limit - batch.len();
+        batch.extend_from_slice(&part[..cmp::min(room, part.len())]);
+    }
+
+    left
+}
+
+/// How frames are delimited on a connection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum Framing {
+    /// A length header in front of every frame. This is the mob protocol proper.
+    #[default]
+    Length,
+
+    /// Consistent Overhead Byte Stuffing, for serial gateways and embedded devices. Each frame is
+    /// encoded so it holds no zero bytes, and a zero ends it. There are no headers to carry a
+    /// kind, so only messages are sent and received.
+    Cobs,
+
+    /// Lines of text, for `telnet` and other line oriented tools. Lines end in LF, with or without
+    /// a CR in front, and are written with CRLF. Telnet negotiation is stripped out. Like COBS,
+    /// only messages are sent and received.
+    Text,
+}
+
+impl Framing {
+    /// The byte that ends each frame, with delimited framing.
+    fn delimiter(self) -> u8 {
+        match self {
+            Framing::Cobs => codec::COBS_DELIMITER,
+            _ => b'\n',
+        }
+    }
+
+    /// The longest a frame with its delimiter may be, with delimited framing.
+    fn max_frame_len(self) -> usize {
+        match self {
+            Framing::Cobs => MAX_COBS_LEN,
+            _ => codec::MAX_PAYLOAD_LEN + 2,
+        }
+    }
+}
+
+impl FromStr for Framing {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Framing, String> {
+        match s {
+            "length" => Ok(Framing::Length),
+            "cobs" => Ok(Framing::Cobs),
+            "text" => Ok(Framing::Text),
+            _ => Err(format!("unknown framing {}, expected length, cobs or text", s)),
+        }
+    }
+}
+
+/// The longest a COBS encoded frame of `codec::MAX_PAYLOAD_LEN` bytes can be.
+const MAX_COBS_LEN: usize = codec::MAX_PAYLOAD_LEN + codec::MAX_PAYLOAD_LEN / 254 + 1;
+
+/// How much of its fragmented messages a peer may have in flight at once.
+///
+/// A fragmented message counts from its first piece to its last, whether the server holds its
+/// pieces back or broadcasts them straight away, since receivers have to hold them until the last
+/// one either way. A peer over either limit is closed, as is one that keeps a message waiting
+/// longer than `timeout` for its next piece.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FragmentLimits {
+    /// Messages started and not finished.
+    pub messages: usize,
+
+    /// Bytes of their pieces so far.
+    pub bytes: usize,
+
+    /// How long a message may wait for its next piece.
+    pub timeout: Duration,
+}
+
+impl Default for FragmentLimits {
+    fn default() -> FragmentLimits {
+        FragmentLimits {
+            messages: 16,
+            bytes: 256 * 1024 * 1024,
+            timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+/// What has gone through a connection since it was made.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Traffic {
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+
+    /// Frames staged for writing, control frames included.
+    pub frames_written: u64,
+
+    /// Messages skipped because the peer was too far behind, frames dropped because the
+    /// connection was closing, and frames shed from its queue.
+    pub dropped: u64,
+}
+
+/// How often to make sure a quiet peer is still there, and how many `PING`s it may leave
+/// unanswered before it is given up on, see `Connection::keep_alive`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct KeepAlive {
+    pub interval: Duration,
+    pub misses: u32,
+}
+
+/// What `Connection::keep_alive` found.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Liveness {
+    /// The peer has been heard from lately, or cannot be pinged.
+    Alive,
+
+    /// The peer has been quiet, and a `PING` was queued for it.
+    Pinged,
+
+    /// The peer left too many `PING`s unanswered, and is most likely gone.
+    Dead,
+}
+
+/// Which of its deadlines a connection missed, see `Connection::missed_deadline`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Deadline {
+    /// The peer did not send its next message in time.
+    Read,
+
+    /// What was queued for the peer was not written in time.
+    Write,
+}
+
+/// A fragmented message the peer has started and not finished.
+struct Partial {
+    // when its latest piece arrived
+    updated: Instant,
+
+    // bytes of its pieces so far
+    bytes: usize,
+
+    // the payloads of its pieces, if they are being held back
+    pieces: Vec<Payload>,
+}
+
+/// How long small messages may be held back so that several go out in one write.
+///
+/// Messages are held until `delay` has passed since the first of them was queued, or until
+/// `max_bytes` are waiting, whichever comes first. Control frames are never held.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Coalesce {
+    pub delay: Duration,
+    pub max_bytes: usize,
+}
+
+/// How a connection is registered with the poller.
+///
+/// By default readiness is reported on edges, and only once per registration, so a connection
+/// is registered again after every event. A registration that is not oneshot stays armed, and is
+/// only registered again when what the connection is interested in changes. Level triggered
+/// registrations report readiness for as long as it lasts rather than when it starts.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PollStrategy {
+    pub edge: bool,
+    pub oneshot: bool,
+}
+
+impl Default for PollStrategy {
+    fn default() -> PollStrategy {
+        PollStrategy { edge: true, oneshot: true }
+    }
+}
+
+impl PollStrategy {
+    fn opts(self) -> PollOpt {
+        let trigger = if self.edge { PollOpt::edge() } else { PollOpt::level() };
+        if self.oneshot {
+            trigger | PollOpt::oneshot()
+        } else {
+            trigger
+        }
+    }
+}
+
 /// A stateful wrapper around a non-blocking stream. This connection is not
 /// the SERVER connection. This connection represents the client connections
 /// _accepted_ by the SERVER connection.
-pub struct Connection {
+///
+/// The stream is usually a `TcpStream`, but any `Transport` will do.
+pub struct Connection<T: Transport = TcpStream> {
     // handle to the accepted socket
-    sock: TcpStream,
+    sock: T,
 
     // token used to register with the poller
     pub token: Token,
 
+    // who is at the other end, if we were told
+    addr: Option<SocketAddr>,
+
     // set of events we are interested in
     interest: Ready,
 
-    // messages waiting to be sent out
-    send_queue: VecDeque<Rc<Vec<u8>>>,
+    // how we register with the poller, and what we last registered for while that stays armed
+    poll_strategy: PollStrategy,
+    registered: Option<Ready>,
+
+    // frames waiting to be sent out, along with their kind, one queue per band
+    send_queues: [SendQueue; 3],
+
+    // what has been read with length headers and not yet taken, kept from one frame to the next
+    // so payloads can be handed out of it without copying them
+    recv: ReadBuffer,
+
+    // the header of the frame at the front of `recv`
+    read_header: [u8; codec::HEADER_LEN],
+
+    // how frames are delimited, and with delimited framing, the bytes read but not yet split
+    // into frames
+    framing: Framing,
+    read_buf: Vec<u8>,
+
+    // strips telnet commands out of what is read, with text framing
+    telnet: telnet::Filter,
+
+    // encoded frames on their way to the socket, and how many bytes of them are written
+    write_buf: Vec<u8>,
+    write_pos: usize,
+
+    // the most bytes `write_buf` is topped up to
+    write_batch: usize,
+
+    // the band whose front frame is part staged, if it did not fit in `write_buf`, and how many
+    // bytes of its header and payload are already staged. That frame has to be finished before
+    // anything else is staged
+    write_continuation: Option<Band>,
+    write_offset: usize,
+
+    // when the peer shut down its write side, if it has
+    read_closed_at: Option<Instant>,
+
+    // the server has stopped reading from the peer for now, see `pause_reading`
+    read_paused: bool,
 
-    // track whether a read received `WouldBlock` and store the number of
-    // byte we are supposed to read
-    read_continuation: Option<u64>,
+    // when we started closing the connection, if we have, and whether our write side is shut
+    // down yet
+    closing_at: Option<Instant>,
+    write_shut: bool,
 
-    // track whether a write received `WouldBlock`
-    write_continuation: bool,
+    // a PONG is queued and has not been written yet. PINGs received in the meantime share it
+    pong_owed: bool,
 
+    // how long to hold small messages back, if at all
+    coalesce: Option<Coalesce>,
+
+    // when the messages being held back are due to be written, and how many bytes they are
+    hold_until: Option<Instant>,
+    held_bytes: usize,
+
+    // messages skipped because the peer was too far behind, that it has not been told about yet
+    missed: u64,
+
+    // how many more messages the peer has granted us, once it has sent a `CREDIT` frame
+    credit: Option<u64>,
+
+    // messages read from the peer so far
+    messages_read: u64,
+
+    // when the connection was made, and what has gone through it since
+    connected_at: Instant,
+    traffic: Traffic,
+
+    // when the peer last sent anything, when we last sent it a `PING` to see if it is still
+    // there, and how many of those it has not answered since it was last heard from
+    last_heard: Instant,
+    pinged_at: Instant,
+    pings_unanswered: u32,
+
+    // when the peer has to have sent its next message by, and when what is queued for it has to
+    // have been written by, see `set_read_deadline` and `set_write_deadline`
+    read_deadline: Option<Instant>,
+    write_deadline: Option<Instant>,
+
+    // broadcasts written to disk because the peer was too far behind, to go out after
+    // everything queued in memory, see `spill_shared`
+    spilled: Option<SpillFile>,
+
+    // the fragmented messages the peer is in the middle of sending, by id, how many bytes of
+    // them have arrived between them, and how much of them it may have in flight
+    fragments: HashMap<u64, Partial>,
+    fragment_bytes: usize,
+    fragment_limits: FragmentLimits,
+
+    // the byte order of the headers we read and of the ones we write. They differ from when the
+    // peer asks to switch until our answer is staged
+    read_endian: Endian,
+    write_endian: Endian,
+
+    // the peer is another server federated with ours, see `set_peer`
+    peer: bool,
+
+    // where reads and writes are counted, if anywhere
+    metrics: Option<Arc<Metrics>>,
 }
 
-impl Connection {
-    pub fn new(sock: TcpStream, token: Token) -> Connection {
+impl<T: Transport> Connection<T> {
+    pub fn new(sock: T, token: Token) -> Connection<T> {
+        Connection::with_capacity(sock, token, DEFAULT_QUEUE_CAPACITY, DEFAULT_WRITE_BATCH)
+    }
+
+    /// Create a connection whose message queue starts with room for `queue` messages, and that
+    /// writes at most `write_batch` bytes at a time.
+    pub fn with_capacity(sock: T, token: Token, queue: usize, write_batch: usize)
+        -> Connection<T>
+    {
         Connection {
             sock,
             token,
+            addr: None,
             interest: Ready::from(UnixReady::hup()),
-            send_queue: VecDeque::with_capacity(32),
-            read_continuation: None,
-            write_continuation: false,
+            poll_strategy: PollStrategy::default(),
+            registered: None,
+            send_queues: [VecDeque::new(), VecDeque::new(), VecDeque::with_capacity(queue)],
+            recv: ReadBuffer::new(),
+            read_header: [0u8; codec::HEADER_LEN],
+            framing: Framing::Length,
+            read_buf: Vec::new(),
+            telnet: telnet::Filter::new(),
+            write_buf: Vec::new(),
+            write_pos: 0,
+            write_batch,
+            write_continuation: None,
+            write_offset: 0,
+            read_closed_at: None,
+            read_paused: false,
+            closing_at: None,
+            write_shut: false,
+            pong_owed: false,
+            coalesce: None,
+            hold_until: None,
+            held_bytes: 0,
+            missed: 0,
+            credit: None,
+            messages_read: 0,
+            connected_at: Instant::now(),
+            traffic: Traffic::default(),
+            last_heard: Instant::now(),
+            pinged_at: Instant::now(),
+            pings_unanswered: 0,
+            read_deadline: None,
+            write_deadline: None,
+            spilled: None,
+            fragments: HashMap::new(),
+            fragment_bytes: 0,
+            fragment_limits: FragmentLimits::default(),
+            read_endian: Endian::Big,
+            write_endian: Endian::Big,
+            peer: false,
+            metrics: None,
         }
     }
 
@@ -53,246 +518,2157 @@ impl Connection {
     ///
     /// The recieve buffer is sent back to `Server` so the message can be broadcast to all
     /// listening connections.
-    pub fn readable(&mut self) -> io::Result<Option<Vec<u8>>> {
+    pub fn readable(&mut self) -> io::Result<Option<Message>> {
+        if self.read_closed_at.is_some() || self.read_paused {
+            return Ok(None);
+        }
 
-        let msg_len = match self.read_message_length()? {
-            None => { return Ok(None); },
-            Some(n) => n,
-        };
+        if self.closing_at.is_some() {
+            return self.discard();
+        }
 
-        if msg_len == 0 {
-            debug!("message is zero bytes; token={:?}", self.token);
-            return Ok(None);
+        if self.framing != Framing::Length {
+            return self.read_delimited();
         }
 
-        let msg_len = msg_len as usize;
+        // Hand out whatever is already buffered before reading more, so nothing is left behind
+        // once the socket would block.
+        loop {
+            let want = match self.frame_len()? {
+                Some(len) if self.recv.len() >= codec::HEADER_LEN + len => {
+                    match self.take_frame(len)? {
+                        Some(message) => return Ok(Some(message)),
+                        None => continue,
+                    }
+                }
+                Some(len) => codec::HEADER_LEN + len,
+                None => codec::HEADER_LEN,
+            };
 
-        debug!("Expected message length is {}", msg_len);
+            match self.recv.read_from(&mut self.sock, want) {
+                Ok(0) => return self.read_eof(),
+                Ok(n) => {
+                    debug!("CONN : we read {} bytes", n);
+                    self.traffic.bytes_read += n as u64;
+                    self.count(|m| m.bytes_read.add(n as u64));
+                    self.heard();
+                }
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+                    debug!("CONN : read encountered WouldBlock");
+                    return Ok(None);
+                }
+                Err(e) => {
+                    error!("Failed to read buffer for token {:?}, error: {}", self.token, e);
+                    self.count(|m| m.failed(Failure::ReadFailed));
+                    return Err(e);
+                }
+            }
+        }
+    }
 
-        // Here we allocate and set the length with unsafe code. The risks of this are discussed
-        // at https://stackoverflow.com/a/30979689/329496 and are mitigated as recv_buf is
-        // abandoned below if we don't read msg_leg bytes from the socket
-        let mut recv_buf : Vec<u8> = Vec::with_capacity(msg_len);
-        unsafe { recv_buf.set_len(msg_len); }
+    /// The payload length of the frame at the front of `recv`, once its header has been read
+    /// into `read_header`. Control frames have none. Fails if the header is no good, before
+    /// waiting for a payload it would be refused with anyway.
+    fn frame_len(&mut self) -> io::Result<Option<usize>> {
+        if self.recv.len() < codec::HEADER_LEN {
+            return Ok(None);
+        }
+        self.read_header.copy_from_slice(&self.recv.unread()[..codec::HEADER_LEN]);
+        if !self.has_payload() {
+            return Ok(Some(0));
+        }
 
-        // UFCS: resolve "multiple applicable items in scope [E0034]" error
-        let sock_ref = <TcpStream as Read>::by_ref(&mut self.sock);
+        let kind = self.read_endian.decode_kind(&self.read_header);
+        let msg_len = self.read_endian.decode_len(&self.read_header);
+        if kind == codec::ENDIAN && msg_len != 1 {
+            warn!("malformed byte order switch; token={:?}", self.token);
+            return Err(self.fail(Failure::InvalidLength, "Malformed byte order"));
+        }
 
-        match sock_ref.take(msg_len as u64).read(&mut recv_buf) {
-            Ok(n) => {
-                debug!("CONN : we read {} bytes", n);
+        if kind == codec::CREDIT && msg_len != 8 {
+            warn!("malformed credit; token={:?}", self.token);
+            return Err(self.fail(Failure::InvalidLength, "Malformed credit"));
+        }
 
-                // TODO handle a read continuation here
-                if n < msg_len as usize {
-                    return Err(Error::new(ErrorKind::InvalidData, "Did not read enough bytes"));
-                }
+        // Refuse to make room for whatever a garbage header claims.
+        if msg_len > codec::MAX_PAYLOAD_LEN as u64 {
+            warn!("message length {} exceeds maximum; token={:?}", msg_len, self.token);
+            return Err(self.fail(Failure::FrameTooLarge, "Message too large"));
+        }
 
-                self.read_continuation = None;
+        let tagged = kind == codec::REQUEST || kind == codec::REPLY;
+        if tagged && msg_len < codec::CORRELATION_ID_LEN as u64 {
+            warn!("frame kind {} without correlation id; token={:?}", kind, self.token);
+            return Err(self.fail(Failure::InvalidLength, "Missing correlation id"));
+        }
 
-                Ok(Some(recv_buf.to_vec()))
-            }
-            Err(e) => {
+        if kind == codec::FRAGMENT && msg_len < codec::FRAGMENT_HEADER_LEN as u64 {
+            warn!("fragment without a header; token={:?}", self.token);
+            return Err(self.fail(Failure::InvalidLength, "Malformed fragment"));
+        }
 
-                if e.kind() == ErrorKind::WouldBlock {
-                    debug!("CONN : read encountered WouldBlock");
+        Ok(Some(msg_len as usize))
+    }
 
-                    // We are being forced to try again, but we already read the two bytes off of the
-                    // wire that determined the length. We need to store the message length so we can
-                    // resume next time we get readable.
-                    self.read_continuation = Some(msg_len as u64);
-                    Ok(None)
-                } else {
-                    error!("Failed to read buffer for token {:?}, error: {}", self.token, e);
-                    Err(e)
-                }
-            }
+    /// Whether the frame whose header is in `read_header` carries a payload, as opposed to being
+    /// a control frame.
+    fn has_payload(&self) -> bool {
+        match self.read_endian.decode_kind(&self.read_header) {
+            codec::DATA | codec::REQUEST | codec::REPLY | codec::ENDIAN | codec::PEER |
+            codec::FORWARD | codec::FRAGMENT | codec::CREDIT => true,
+            _ => self.peer && !self.read_endian.is_control(&self.read_header),
         }
     }
 
-    fn read_message_length(&mut self) -> io::Result<Option<u64>> {
-        if let Some(n) = self.read_continuation {
-            return Ok(Some(n));
+    /// Take the frame at the front of `recv`, whose payload of `len` bytes has all been read.
+    /// Returns the message it carries, if it is one the server has to see.
+    fn take_frame(&mut self, len: usize) -> io::Result<Option<Message>> {
+        self.recv.consume(codec::HEADER_LEN);
+        if !self.has_payload() {
+            return self.control();
         }
 
-        let mut buf = [0u8; 8];
+        let kind = self.read_endian.decode_kind(&self.read_header);
+        if len == 0 {
+            debug!("message is zero bytes; token={:?}", self.token);
+            return Ok(None);
+        }
+        let payload = self.recv.take(len);
 
-        let bytes = match self.sock.read(&mut buf) {
-            Ok(n) => n,
-            Err(e) => {
-                if e.kind() == ErrorKind::WouldBlock {
-                    return Ok(None);
-                } else {
-                    return Err(e);
-                }
-            }
-        };
+        if kind == codec::ENDIAN {
+            return self.switch_endian(payload);
+        }
 
-        if bytes < 8 {
-            warn!("Found message length of {} bytes", bytes);
-            return Err(Error::new(ErrorKind::InvalidData, "Invalid message length"));
+        if kind == codec::CREDIT {
+            self.grant(codec::decode_credit(&payload).unwrap_or(0));
+            return Ok(None);
         }
 
-        let msg_len = BigEndian::read_u64(buf.as_ref());
-        Ok(Some(msg_len))
-    }
+        self.messages_read += 1;
+        self.read_deadline = None;
+        self.count(|m| {
+            m.frames_read.inc();
+            m.frame_sizes_read.observe(len as u64);
+        });
 
-    /// Handle a writable event from the poller.
-    ///
-    /// Send one message from the send queue to the client. If the queue is empty, remove interest
-    /// in write events.
-    /// TODO: Figure out if sending more than one message is optimal. Maybe we should be trying to
-    /// flush until the kernel sends back EAGAIN?
-    pub fn writable(&mut self) -> io::Result<()> {
+        let route = self.read_endian.decode_route(&self.read_header);
+        Ok(Some(Message { kind, route, payload }))
+    }
 
-        self.send_queue.pop_front()
-            .ok_or_else(|| Error::new(ErrorKind::Other, "Could not pop send queue"))
-            .and_then(|buf| {
-                self.write_message(buf)
-            })?;
+    /// Handle the peer hanging up, which is only fine between frames.
+    fn read_eof(&mut self) -> io::Result<Option<Message>> {
+        let unread = self.recv.len();
+        if unread == 0 {
+            // The peer shut down its write side between frames. It may still be reading, so
+            // stop watching for reads but carry on writing.
+            self.read_closed();
+            return Ok(None);
+        }
 
-        if self.send_queue.is_empty() {
-            self.interest.remove(Ready::writable());
+        self.count(|m| m.failed(Failure::Truncated));
+        if unread < codec::HEADER_LEN {
+            warn!("Found message length of {} bytes", unread);
+            return Err(Error::new(ErrorKind::UnexpectedEof, "Connection closed"));
         }
+        Err(Error::new(ErrorKind::UnexpectedEof, "Connection closed mid message"))
+    }
 
+    /// Count the payload of a `FRAGMENT` read from the peer against its `FragmentLimits`, for
+    /// servers that broadcast it straight away.
+    pub fn pass_fragment(&mut self, payload: &[u8]) -> io::Result<()> {
+        let (id, last, _) = self.add_fragment(payload)?;
+        if last {
+            self.finish_fragment(id);
+        }
         Ok(())
     }
 
-    fn write_message_length(&mut self, buf: &Rc<Vec<u8>>) -> io::Result<Option<()>> {
-        if self.write_continuation {
-            return Ok(Some(()));
+    /// Hold back the payload of a `FRAGMENT` read from the peer until the rest of its message has
+    /// arrived, for servers that have to see a whole message before broadcasting any of it. It
+    /// counts against the `FragmentLimits` like any other.
+    ///
+    /// Returns the whole message and the payloads of the pieces it came in once its last piece is
+    /// held.
+    pub fn hold_fragment(&mut self, payload: Payload)
+        -> io::Result<Option<(Message, Vec<Payload>)>>
+    {
+        let (id, last, kind) = self.add_fragment(&payload)?;
+        if !last {
+            if let Some(partial) = self.fragments.get_mut(&id) {
+                partial.pieces.push(payload);
+            }
+            return Ok(None);
         }
 
-        let len = buf.len();
-        let mut send_buf = [0u8; 8];
-        BigEndian::write_u64(&mut send_buf, len as u64);
+        let mut pieces = self.finish_fragment(id);
+        pieces.push(payload);
 
-        let len = send_buf.len();
-        match self.sock.write(&send_buf) {
-            Ok(n) => {
-                if n < len {
-                    let e = Error::new(ErrorKind::Other, "Message length failed");
-                    error!("Failed to send message length for {:?}, error: {}", self.token, e);
-                    Err(e)
-                } else {
-                    debug!("Sent message length of {} bytes", n);
-                    Ok(Some(()))
-                }
-            }
-            Err(e) => {
-                if e.kind() == ErrorKind::WouldBlock {
-                    debug!("client flushing buf; WouldBlock");
+        let mut joined = Vec::new();
+        for piece in &pieces {
+            joined.extend_from_slice(&piece[codec::FRAGMENT_HEADER_LEN..]);
+        }
+        let whole = Message { kind, route: Route::default(), payload: Payload::from(joined) };
 
-                    Ok(None)
-                } else {
-                    error!("Failed to send buffer for {:?}, error: {}", self.token, e);
-                    Err(e)
-                }
-            }
+        let tagged = kind == codec::REQUEST || kind == codec::REPLY;
+        if tagged && whole.payload.len() < codec::CORRELATION_ID_LEN {
+            warn!("fragmented frame kind {} without correlation id; token={:?}", kind, self.token);
+            return Err(self.fail(Failure::InvalidLength, "Missing correlation id"));
         }
+        Ok(Some((whole, pieces)))
     }
 
-    fn write_message(&mut self, buf: Rc<Vec<u8>>) -> io::Result<()> {
-        match self.write_message_length(&buf) {
-            Ok(None) => {
-                // put message back into the queue so we can try again
-                self.send_queue.push_front(buf);
-                return Ok(());
-            },
-            Ok(Some(())) => {
-                ()
-            },
-            Err(e) => {
-                error!("Failed to send buffer for {:?}, error: {}", self.token, e);
-                return Err(e);
+    /// Count a piece of a fragmented message, failing if it takes the peer over its limits.
+    /// Returns the id of the message, whether this is its last piece and the kind of message.
+    fn add_fragment(&mut self, payload: &[u8]) -> io::Result<(u64, bool, u8)> {
+        let (id, last, kind, len) = match codec::decode_fragment(payload) {
+            Some((id, last, kind, part)) => (id, last, kind, part.len()),
+            None => {
+                warn!("malformed fragment; token={:?}", self.token);
+                return Err(self.fail(Failure::Malformed, "Malformed fragment"));
             }
+        };
+
+        let limits = self.fragment_limits;
+        let started = self.fragments.contains_key(&id);
+        if !started && !last && self.fragments.len() >= limits.messages {
+            warn!("more than {} fragmented messages; token={:?}", limits.messages, self.token);
+            return Err(self.fail(Failure::FragmentLimit, "Too many fragmented messages"));
+        }
+        if self.fragment_bytes + len > limits.bytes {
+            warn!("fragmented messages over {} bytes; token={:?}", limits.bytes, self.token);
+            return Err(self.fail(Failure::FragmentLimit, "Fragmented messages too large"));
         }
 
-        let len = buf.len();
-        match self.sock.write(&*buf) {
-            Ok(n) => {
-                debug!("CONN : we wrote {} bytes", n);
-                // if we wrote a partial message, then put remaining part of message back
-                // into the queue so we can try again
-                if n < len {
-                    let remaining = Rc::new(buf[n..].to_vec());
-                    self.send_queue.push_front(remaining);
-                    self.write_continuation = true;
-                } else {
-                    self.write_continuation = false;
-                }
-                Ok(())
-            },
-            Err(e) => {
-                if e.kind() == ErrorKind::WouldBlock {
-                    debug!("client flushing buf; WouldBlock");
+        // A message that comes in one piece is finished as soon as it starts.
+        if started || !last {
+            let partial = self.fragments.entry(id).or_insert_with(|| {
+                Partial { updated: Instant::now(), bytes: 0, pieces: Vec::new() }
+            });
+            partial.updated = Instant::now();
+            partial.bytes += len;
+            self.fragment_bytes += len;
+        }
+        Ok((id, last, kind))
+    }
 
-                    // put message back into the queue so we can try again
-                    self.send_queue.push_front(buf);
-                    self.write_continuation = true;
-                    Ok(())
-                } else {
-                    error!("Failed to send buffer for {:?}, error: {}", self.token, e);
-                    Err(e)
-                }
+    /// Stop counting a fragmented message whose last piece has arrived, and return the pieces of
+    /// it that were held back.
+    fn finish_fragment(&mut self, id: u64) -> Vec<Payload> {
+        match self.fragments.remove(&id) {
+            Some(partial) => {
+                self.fragment_bytes -= partial.bytes;
+                partial.pieces
             }
+            None => Vec::new(),
         }
     }
 
-    /// Queue an outgoing message to the client.
+    /// Limit how much of its fragmented messages the peer may have in flight.
+    pub fn set_fragment_limits(&mut self, limits: FragmentLimits) {
+        self.fragment_limits = limits;
+    }
+
+    /// Note that the peer is still there, because it sent something.
+    fn heard(&mut self) {
+        self.last_heard = Instant::now();
+        self.pings_unanswered = 0;
+    }
+
+    /// Queue a `PING` for the peer if it has been quiet for `keep_alive.interval` as of `now`,
+    /// counting from when it was last heard from or last pinged, whichever was later. Anything
+    /// the peer sends answers a `PING`, not only a `PONG`.
     ///
-    /// This will cause the connection to register interests in write events with the poller.
-    /// The connection can still safely have an interest in read events. The read and write buffers
-    /// operate independently of each other.
-    pub fn send_message(&mut self, message: Rc<Vec<u8>>) -> io::Result<()> {
-        trace!("connection send_message; token={:?}", self.token);
-
-        // if the queue is empty then try and write. if we get WouldBlock the message will get
-        // queued up for later. if the queue already has items in it, then we know that we got
-        // WouldBlock from a previous write, so queue it up and wait for the next write event.
-        if self.send_queue.is_empty() {
-            self.write_message(message)?;
-        } else {
-            self.send_queue.push_back(message);
+    /// A peer that has let `keep_alive.misses` `PING`s in a row go unanswered for an interval
+    /// each is dead. Peers whose framing has no room for control frames are never pinged, and
+    /// peers we are not reading from are taken to be there, since their answers would not be
+    /// read.
+    pub fn keep_alive(&mut self, now: Instant, keep_alive: KeepAlive) -> io::Result<Liveness> {
+        if self.framing != Framing::Length || self.read_closed_at.is_some()
+            || self.closing_at.is_some()
+        {
+            return Ok(Liveness::Alive);
+        }
+        if self.read_paused {
+            self.last_heard = now;
+            self.pings_unanswered = 0;
+            return Ok(Liveness::Alive);
         }
 
-        if !self.send_queue.is_empty() && !self.interest.is_writable() {
-            self.interest.insert(Ready::writable());
+        let quiet_since = cmp::max(self.last_heard, self.pinged_at);
+        if now.saturating_duration_since(quiet_since) < keep_alive.interval {
+            return Ok(Liveness::Alive);
+        }
+        if self.pings_unanswered >= keep_alive.misses {
+            return Ok(Liveness::Dead);
         }
 
-        Ok(())
+        trace!("pinging quiet peer; token={:?} unanswered={}", self.token, self.pings_unanswered);
+        self.pinged_at = now;
+        self.pings_unanswered += 1;
+        self.send_frame(codec::PING, Payload::default())?;
+        Ok(Liveness::Pinged)
     }
 
-    /// Register interest in read events with poll.
-    ///
-    /// This will let our connection accept reads starting next poller tick.
-    pub fn register(&mut self, poll: &mut Poll) -> io::Result<()> {
-        trace!("connection register; token={:?}", self.token);
+    /// Give the peer until `deadline` to send its next whole message, or take the deadline away
+    /// with `None`. Reading a message meets it and clears it, so each deadline is for one
+    /// message: a handshake can be held to a stricter one than the messages that follow.
+    pub fn set_read_deadline(&mut self, deadline: Option<Instant>) {
+        self.read_deadline = deadline;
+    }
 
-        self.interest.insert(Ready::readable());
+    /// When the peer has to have sent its next message by, if ever.
+    pub fn read_deadline(&self) -> Option<Instant> {
+        self.read_deadline
+    }
 
-        poll.register(
-            &self.sock,
-            self.token,
-            self.interest,
-            PollOpt::edge() | PollOpt::oneshot()
-        ).or_else(|e| {
-            error!("Failed to reregister {:?}, {:?}", self.token, e);
-            Err(e)
-        })
+    /// Give ourselves until `deadline` to write everything queued for the peer, or take the
+    /// deadline away with `None`. Running out of things to write meets it and clears it, so
+    /// frames queued after that have no deadline until one is set again.
+    ///
+    /// The peer shutting down its write side sets one `HALF_CLOSE_TIMEOUT` away, unless an
+    /// earlier one is set already.
+    pub fn set_write_deadline(&mut self, deadline: Option<Instant>) {
+        self.write_deadline = deadline;
     }
 
-    /// Re-register interest in read events with poll.
-    pub fn reregister(&mut self, poll: &mut Poll) -> io::Result<()> {
-        trace!("connection reregister; token={:?}", self.token);
+    /// When what is queued for the peer has to have been written by, if ever.
+    pub fn write_deadline(&self) -> Option<Instant> {
+        self.write_deadline
+    }
 
-        poll.reregister(
-            &self.sock,
+    /// Which deadline the connection has missed as of `now`, if any. A read deadline can only be
+    /// missed while the peer can still send and we are reading, and a write deadline while
+    /// something is left to write. Neither counts once the connection is closing, which has its
+    /// own timeout.
+    pub fn missed_deadline(&self, now: Instant) -> Option<Deadline> {
+        if self.closing_at.is_some() {
+            return None;
+        }
+        if self.write_deadline.is_some_and(|at| at <= now) && self.pending() {
+            return Some(Deadline::Write);
+        }
+        let reading = self.read_closed_at.is_none() && !self.read_paused;
+        if reading && self.read_deadline.is_some_and(|at| at <= now) {
+            return Some(Deadline::Read);
+        }
+        None
+    }
+
+    /// Whether a fragmented message from the peer has waited too long for its next piece, as of
+    /// `now`.
+    pub fn has_stalled_fragment(&self, now: Instant) -> bool {
+        let timeout = self.fragment_limits.timeout;
+        self.fragments.values().any(|partial| now.duration_since(partial.updated) >= timeout)
+    }
+
+    /// Read the next message with delimited framing.
+    ///
+    /// Bytes are read a chunk at a time, so whatever follows the delimiter is kept in `read_buf`
+    /// for the next call. Empty frames are skipped, like zero length ones with length headers.
+    fn read_delimited(&mut self) -> io::Result<Option<Message>> {
+        let delimiter = self.framing.delimiter();
+        let mut searched = 0;
+        loop {
+            let found = self.read_buf[searched..].iter().position(|&b| b == delimiter);
+            if let Some(end) = found.map(|i| searched + i) {
+                let frame: Vec<u8> = self.read_buf.drain(..=end).collect();
+                searched = 0;
+
+                let payload = match self.framing {
+                    Framing::Cobs => codec::decode_cobs(&frame[..end]).ok_or_else(|| {
+                        warn!("malformed COBS frame; token={:?}", self.token);
+                        self.fail(Failure::Malformed, "Malformed COBS frame")
+                    })?,
+                    _ => telnet::decode_line(&frame).to_vec(),
+                };
+                if payload.is_empty() {
+                    debug!("message is zero bytes; token={:?}", self.token);
+                    continue;
+                }
+
+                self.messages_read += 1;
+                self.read_deadline = None;
+                self.count(|m| {
+                    m.frames_read.inc();
+                    m.frame_sizes_read.observe(payload.len() as u64);
+                });
+                return Ok(Some(Message::data(payload)));
+            }
+            searched = self.read_buf.len();
+
+            if self.read_buf.len() > self.framing.max_frame_len() {
+                warn!("delimited frame exceeds maximum; token={:?}", self.token);
+                return Err(self.fail(Failure::FrameTooLarge, "Message too large"));
+            }
+
+            let mut chunk = [0u8; 4096];
+            let read = self.sock.read(&mut chunk);
+            if let Ok(n) = read {
+                self.traffic.bytes_read += n as u64;
+                self.count(|m| m.bytes_read.add(n as u64));
+                self.heard();
+            }
+            match read {
+                Ok(0) if self.read_buf.is_empty() => {
+                    self.read_closed();
+                    return Ok(None);
+                }
+                Ok(0) => {
+                    self.count(|m| m.failed(Failure::Truncated));
+                    let e = Error::new(ErrorKind::UnexpectedEof, "Connection closed mid message");
+                    return Err(e);
+                }
+                Ok(n) if self.framing == Framing::Text => {
+                    self.telnet.filter(&chunk[..n], &mut self.read_buf);
+                }
+                Ok(n) => self.read_buf.extend_from_slice(&chunk[..n]),
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => return Ok(None),
+                Err(e) => {
+                    error!("Failed to read buffer for token {:?}, error: {}", self.token, e);
+                    self.count(|m| m.failed(Failure::ReadFailed));
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    /// Read headers in the byte order the peer just asked for, and answer with the same `ENDIAN`
+    /// frame. Everything staged after the answer is written in the new order too.
+    fn switch_endian(&mut self, payload: Payload) -> io::Result<Option<Message>> {
+        let endian = codec::decode_endian(&payload).ok_or_else(|| {
+            warn!("unknown byte order; token={:?}", self.token);
+            self.fail(Failure::Malformed, "Malformed byte order")
+        })?;
+
+        debug!("switching to {:?} headers; token={:?}", endian, self.token);
+        self.read_endian = endian;
+        self.queue(Band::Control).push_back(Queued::new(codec::ENDIAN, Route::default(), payload));
+        self.interest.insert(Ready::writable());
+        Ok(None)
+    }
+
+    /// Let the peer be sent `messages` more messages. If it was too far behind for some and is
+    /// now all caught up, it is told how many straight away.
+    fn grant(&mut self, messages: u64) {
+        let credit = self.credit.unwrap_or(0).saturating_add(messages);
+        debug!("granted credit; credit={} token={:?}", credit, self.token);
+        self.credit = Some(credit);
+
+        if !self.pending() && self.missed > 0 && self.closing_at.is_none() {
+            self.queue_missed();
+            self.interest.insert(Ready::writable());
+        }
+    }
+
+    /// Whether the peer has credit for another message, which it always has unless it has asked
+    /// for flow control.
+    pub fn has_credit(&self) -> bool {
+        self.credit != Some(0)
+    }
+
+    /// Read and write headers in this byte order, for peers that cannot ask for it themselves.
+    /// Big endian by default.
+    pub fn set_endian(&mut self, endian: Endian) {
+        self.read_endian = endian;
+        self.write_endian = endian;
+    }
+
+    /// Handle the control frame whose header was just read into `read_header`.
+    fn control(&mut self) -> io::Result<Option<Message>> {
+        let endian = self.read_endian;
+        match endian.decode_kind(&self.read_header) {
+            codec::PING if endian.is_control(&self.read_header) => {
+                debug!("ping; token={:?}", self.token);
+                if !self.pong_owed {
+                    self.pong_owed = true;
+                    let pong = Queued::new(codec::PONG, Route::default(), Payload::default());
+                    self.queue(Band::Control).push_back(pong);
+                    self.interest.insert(Ready::writable());
+                }
+                Ok(None)
+            }
+            codec::PONG if endian.is_control(&self.read_header) => {
+                // Hearing it is what counts, and reading it did that.
+                debug!("pong; token={:?}", self.token);
+                Ok(None)
+            }
+            codec::WHO if endian.is_control(&self.read_header) => {
+                debug!("who; token={:?}", self.token);
+                let route = Route::default();
+                Ok(Some(Message { kind: codec::WHO, route, payload: Payload::default() }))
+            }
+            kind => {
+                warn!("unknown frame kind {}; token={:?}", kind, self.token);
+                Err(self.fail(Failure::UnknownKind, "Unknown frame kind"))
+            }
+        }
+    }
+
+    /// Read and throw away whatever the peer sends while we are closing, until it hangs up.
+    ///
+    /// Closing a socket with unread data in it resets the connection, which can destroy what we
+    /// wrote before the peer gets to read it.
+    fn discard(&mut self) -> io::Result<Option<Message>> {
+        let mut buf = [0u8; 4096];
+        loop {
+            match self.sock.read(&mut buf) {
+                Ok(0) => {
+                    self.read_closed();
+                    return Ok(None);
+                }
+                Ok(n) => {
+                    debug!("discarding {} bytes from closing {:?}", n, self.token);
+                    self.traffic.bytes_read += n as u64;
+                    self.count(|m| m.bytes_read.add(n as u64));
+                }
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => return Ok(None),
+                Err(e) => {
+                    self.count(|m| m.failed(Failure::ReadFailed));
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    fn read_closed(&mut self) {
+        debug!("peer finished sending; token={:?}", self.token);
+        let now = Instant::now();
+        self.read_closed_at = Some(now);
+        let deadline = now + HALF_CLOSE_TIMEOUT;
+        self.write_deadline = Some(self.write_deadline.map_or(deadline, |d| cmp::min(d, deadline)));
+        self.interest.remove(Ready::readable());
+        self.interest.remove(UnixReady::hup());
+    }
+
+    /// Handle a writable event from the poller.
+    ///
+    /// Write as many queued frames as fit in one batch, see `flush`. If nothing is left to write
+    /// afterwards, remove interest in write events.
+    pub fn writable(&mut self) -> io::Result<()> {
+        self.release();
+        self.unspill()?;
+        self.flush()?;
+
+        // Caught up, so say what was skipped without waiting for the next message.
+        if !self.pending() && self.missed > 0 && self.closing_at.is_none() {
+            self.queue_missed();
+            self.flush()?;
+        }
+
+        if !self.pending() {
+            self.interest.remove(Ready::writable());
+        }
+
+        self.shut_once_flushed()
+    }
+
+    /// Shut down our write side once a closing connection has written everything, so the peer
+    /// sees the end of the stream.
+    fn shut_once_flushed(&mut self) -> io::Result<()> {
+        if self.closing_at.is_some() && !self.write_shut && !self.pending() {
+            debug!("closing {:?} is flushed, shutting down writes", self.token);
+            self.write_shut = true;
+            self.sock.shutdown(Shutdown::Write).inspect_err(|_| {
+                self.count(|m| m.failed(Failure::WriteFailed));
+            })?;
+        }
+        Ok(())
+    }
+
+    fn queue(&mut self, band: Band) -> &mut SendQueue {
+        &mut self.send_queues[band as usize]
+    }
+
+    /// The number of frames waiting to be sent, in every band.
+    pub fn queued_frames(&self) -> usize {
+        self.send_queues.iter().map(|q| q.len()).sum()
+    }
+
+    /// Whether anything is waiting to be written, staged, still queued or spilled.
+    fn pending(&self) -> bool {
+        self.write_pos < self.write_buf.len() || self.queued_frames() > 0 || self.spilled.is_some()
+    }
+
+    /// Top up the staging buffer from the send queues and hand all of it to the socket in a
+    /// single write, instead of a write for every header and payload.
+    ///
+    /// Whatever the socket does not take stays in the buffer for next time, so a short write
+    /// never copies or reallocates the rest of a message.
+    ///
+    /// A shared frame too big to stage is written straight out of its own buffer instead, once
+    /// everything staged ahead of it is written.
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_buf.drain(..self.write_pos);
+        self.write_pos = 0;
+        self.stage();
+
+        let shared = if self.write_buf.is_empty() { self.next_shared() } else { None };
+        let buf = match shared {
+            Some((_, ref bytes)) => &bytes[self.write_offset..],
+            None => &self.write_buf[..],
+        };
+        if buf.is_empty() {
+            self.write_deadline = None;
+            return Ok(());
+        }
+
+        let len = buf.len();
+        match self.sock.write(buf) {
+            Ok(n) => {
+                debug!("CONN : we wrote {} of {} bytes", n, len);
+                self.traffic.bytes_written += n as u64;
+                self.count(|m| m.bytes_written.add(n as u64));
+                match shared {
+                    Some((band, _)) => self.wrote_shared(band, n),
+                    None => self.write_pos = n,
+                }
+                if !self.pending() {
+                    self.write_deadline = None;
+                }
+                Ok(())
+            }
+            Err(e) => {
+                if e.kind() == ErrorKind::WouldBlock {
+                    debug!("client flushing buf; WouldBlock");
+                    Ok(())
+                } else {
+                    error!("Failed to send buffer for {:?}, error: {}", self.token, e);
+                    self.count(|m| m.failed(Failure::WriteFailed));
+                    Err(e)
+                }
+            }
+        }
+    }
+
+    /// Move frames from the send queues into the staging buffer until it holds `write_batch`
+    /// bytes. The part staged frame goes first, then the bands in order.
+    ///
+    /// A frame too big for the room left is staged a piece at a time. It stays at the front of
+    /// its queue, with `write_offset` saying how much of it is already staged. A shared frame is
+    /// not, and staging stops in front of it instead, see `flush`.
+    fn stage(&mut self) {
+        while self.write_buf.len() < self.write_batch {
+            let band = match self.next_band() {
+                Some(band) => band,
+                None => break,
+            };
+
+            if let Some((_, bytes)) = self.next_shared() {
+                if bytes.len() - self.write_offset > self.write_batch - self.write_buf.len() {
+                    break;
+                }
+            }
+
+            let frame = self.queue(band).pop_front().unwrap();
+            let (kind, route) = (frame.kind, frame.route);
+
+            // Without headers there is no way to say what anything but a message is. A notice
+            // is text, so a line of text will do for it.
+            let line = self.framing == Framing::Text && kind == codec::NOTICE;
+            if self.framing != Framing::Length && kind != codec::DATA && !line {
+                trace!("dropping frame kind {} without headers; token={:?}", kind, self.token);
+                continue;
+            }
+
+            let buf = &frame.payload;
+            let before = self.write_buf.len();
+            let left = match self.framing {
+                Framing::Length => match frame.shared {
+                    Some(ref bytes) if self.write_endian == Endian::Big => {
+                        gather(&mut self.write_buf, self.write_batch, &[bytes], self.write_offset)
+                    }
+                    _ => {
+                        let header = self.write_endian.encode_routed_header(kind, route, buf.len());
+                        gather(&mut self.write_buf, self.write_batch, &[&header, buf],
+                               self.write_offset)
+                    }
+                },
+                Framing::Cobs => {
+                    let encoded = codec::encode_cobs(buf);
+                    gather(&mut self.write_buf, self.write_batch,
+                           &[&encoded, &[codec::COBS_DELIMITER]], self.write_offset)
+                }
+                Framing::Text => {
+                    let line = telnet::encode_line(buf);
+                    gather(&mut self.write_buf, self.write_batch, &[&line], self.write_offset)
+                }
+            };
+            let staged = self.write_buf.len() - before;
+
+            if staged < left {
+                self.queue(band).push_front(frame);
+                self.write_continuation = Some(band);
+                self.write_offset += staged;
+            } else {
+                self.finished(&frame);
+            }
+        }
+    }
+
+    /// The band the next frame is staged from: the one with a frame part staged or written, or
+    /// the first with anything in it.
+    fn next_band(&self) -> Option<Band> {
+        let queues = &self.send_queues;
+        self.write_continuation
+            .or_else(|| BANDS.iter().cloned().find(|&b| !queues[b as usize].is_empty()))
+    }
+
+    /// The next frame and its band, if it is shared and this connection can write it as it is.
+    fn next_shared(&self) -> Option<(Band, Payload)> {
+        if self.framing != Framing::Length || self.write_endian != Endian::Big {
+            return None;
+        }
+        let band = self.next_band()?;
+        let frame = self.send_queues[band as usize].front()?;
+        frame.shared.clone().map(|bytes| (band, bytes))
+    }
+
+    /// Account for `n` more bytes of the shared frame at the front of `band` written straight
+    /// to the socket.
+    fn wrote_shared(&mut self, band: Band, n: usize) {
+        self.write_offset += n;
+        let offset = self.write_offset;
+        let done = self.queue(band).front()
+            .and_then(|f| f.shared.as_ref())
+            .map(|bytes| offset >= bytes.len())
+            .unwrap_or(true);
+
+        if done {
+            let frame = self.queue(band).pop_front().unwrap();
+            self.finished(&frame);
+        } else {
+            self.write_continuation = Some(band);
+        }
+    }
+
+    /// Bookkeeping for a frame that is staged or written in full.
+    fn finished(&mut self, frame: &Queued) {
+        self.write_continuation = None;
+        self.write_offset = 0;
+        self.traffic.frames_written += 1;
+        self.count(|m| {
+            m.frames_written.inc();
+            m.frame_sizes_written.observe(frame.payload.len() as u64);
+        });
+
+        // Once it is staged, the PONG answers every PING received so far.
+        if frame.kind == codec::PONG {
+            self.pong_owed = false;
+        }
+
+        // The peer reads everything after our answer in the order it asked for.
+        if frame.kind == codec::ENDIAN {
+            self.write_endian = codec::decode_endian(&frame.payload)
+                .unwrap_or(self.write_endian);
+        }
+    }
+
+    /// Queue an outgoing message to the client.
+    ///
+    /// This will cause the connection to register interests in write events with the poller.
+    /// The connection can still safely have an interest in read events. The read and write buffers
+    /// operate independently of each other.
+    pub fn send_message<P: Into<Payload>>(&mut self, message: P) -> io::Result<()> {
+        self.send_frame(codec::DATA, message)
+    }
+
+    /// Queue an outgoing frame of the given kind, see `send_message`. Control and error frames
+    /// are queued ahead of messages.
+    ///
+    /// With coalescing on, a small message may be held back instead of written, see
+    /// `flush_held`.
+    pub fn send_frame<P: Into<Payload>>(&mut self, kind: u8, message: P) -> io::Result<()> {
+        self.send_routed(kind, Route::default(), message)
+    }
+
+    /// Queue an outgoing frame of the given kind on `route`, see `send_frame`. A message with a
+    /// priority goes out ahead of the ordinary ones queued before it, and is never held back.
+    /// The stream is only passed on in the header.
+    ///
+    /// A `Payload` shares its buffer with every connection it is queued for, and so does an
+    /// `Arc<Vec<u8>>`.
+    pub fn send_routed<P: Into<Payload>>(&mut self, kind: u8, route: Route, message: P)
+        -> io::Result<()>
+    {
+        self.push(Queued::new(kind, route, message.into()))
+    }
+
+    /// Queue a frame encoded for every connection a broadcast goes to, see `send_routed`.
+    pub fn send_shared(&mut self, frame: &SharedFrame) -> io::Result<()> {
+        let mut queued = Queued::new(frame.kind, frame.route, frame.payload());
+        queued.shared = Some(frame.bytes.clone());
+        self.push(queued)
+    }
+
+    /// Write a broadcast to disk instead of queueing it, to go out once everything queued ahead
+    /// of it has, see `spill`. The file is made in `spill.dir` the first time. Once a connection
+    /// has spilled, its broadcasts have to be spilled until it has caught up, or they would go
+    /// out ahead of those on disk.
+    pub fn spill_shared(&mut self, frame: &SharedFrame, spill: &Spill) -> io::Result<()> {
+        if self.closing_at.is_some() {
+            debug!("dropping frame for closing {:?}", self.token);
+            self.traffic.dropped += 1;
+            return Ok(());
+        }
+
+        let file = match self.spilled.take() {
+            Some(file) => file,
+            None => {
+                debug!("spilling broadcasts to disk; token={:?}", self.token);
+                SpillFile::create(&spill.dir)?
+            }
+        };
+        self.spilled.insert(file).push(&frame.bytes)?;
+        if is_message(frame.kind) {
+            self.credit = self.credit.map(|credit| credit.saturating_sub(1));
+        }
+
+        if !self.interest.is_writable() {
+            self.interest.insert(Ready::writable());
+        }
+        Ok(())
+    }
+
+    /// Whether broadcasts for the peer are being spilled to disk, see `spill_shared`.
+    pub fn is_spilling(&self) -> bool {
+        self.spilled.is_some()
+    }
+
+    /// Whether the spill file has room for more under `spill.max_bytes`, or there is none yet.
+    pub fn has_spill_room(&self, spill: &Spill) -> bool {
+        self.spilled.as_ref().map_or(0, SpillFile::size) < spill.max_bytes
+    }
+
+    /// Read spilled frames back into the send queues once every message queued ahead of them has
+    /// been staged, up to a batch at a time. The file goes once it has all been read.
+    fn unspill(&mut self) -> io::Result<()> {
+        if !self.queue(Band::Data).is_empty() {
+            return Ok(());
+        }
+
+        let mut read = 0;
+        while read < self.write_batch {
+            let bytes = match self.spilled {
+                Some(ref mut file) => file.pop()?,
+                None => break,
+            };
+            let bytes = match bytes {
+                Some(bytes) => Payload::from(bytes),
+                None => break,
+            };
+            read += bytes.len();
+
+            let kind = codec::decode_kind(&bytes);
+            let route = codec::decode_route(&bytes);
+            let mut queued = Queued::new(kind, route, bytes.slice(codec::HEADER_LEN..bytes.len()));
+            queued.shared = Some(bytes);
+            self.queue(Band::of(kind, route.priority)).push_back(queued);
+        }
+
+        if self.spilled.as_ref().is_some_and(|file| file.frames() == 0) {
+            debug!("caught up with spilled broadcasts; token={:?}", self.token);
+            self.spilled = None;
+        }
+        Ok(())
+    }
+
+    /// Throw away whatever was spilled to disk, counting it as dropped.
+    fn drop_spilled(&mut self) {
+        if let Some(file) = self.spilled.take() {
+            self.traffic.dropped += file.frames() as u64;
+        }
+    }
+
+    fn push(&mut self, frame: Queued) -> io::Result<()> {
+        let (kind, route) = (frame.kind, frame.route);
+        trace!("connection send_frame; kind={} priority={} stream={} token={:?}",
+               kind, route.priority, route.stream, self.token);
+
+        if self.closing_at.is_some() {
+            debug!("dropping frame for closing {:?}", self.token);
+            self.traffic.dropped += 1;
+            return Ok(());
+        }
+
+        // if the queues were empty then try and write. if we get WouldBlock the message stays
+        // queued up for later. if a queue already had items in it, then we know that we got
+        // WouldBlock from a previous write, so wait for the next write event. Messages being
+        // held back are not waiting on the socket, so they do not count.
+        let idle = !self.pending() || self.hold_until.is_some();
+        let band = Band::of(kind, route.priority);
+        let len = codec::HEADER_LEN + frame.payload.len();
+        if is_message(kind) {
+            self.credit = self.credit.map(|credit| credit.saturating_sub(1));
+        }
+        if band == Band::Data && self.missed > 0 {
+            self.queue_missed();
+        }
+        self.queue(band).push_back(frame);
+
+        let hold = match self.coalesce {
+            Some(coalesce) => band == Band::Data && self.held_bytes + len < coalesce.max_bytes,
+            None => false,
+        };
+
+        if idle && hold {
+            self.held_bytes += len;
+            if self.hold_until.is_none() {
+                self.hold_until = self.coalesce.map(|c| Instant::now() + c.delay);
+            }
+            return Ok(());
+        } else if idle {
+            self.release();
+            self.flush()?;
+        }
+
+        if self.pending() && !self.interest.is_writable() {
+            self.interest.insert(Ready::writable());
+        }
+
+        Ok(())
+    }
+
+    /// Skip a message instead of queueing it, because the peer is too far behind. The next
+    /// message, or the peer catching up, is preceded by a `MISSED` frame with the count.
+    pub fn skip_message(&mut self) {
+        self.missed += 1;
+        self.traffic.dropped += 1;
+    }
+
+    /// How many messages have been read from the peer, which is also the sequence number of the
+    /// last one. Control frames do not count.
+    pub fn messages_read(&self) -> u64 {
+        self.messages_read
+    }
+
+    /// How many skipped messages the peer has not been told about yet.
+    pub fn missed(&self) -> u64 {
+        self.missed
+    }
+
+    /// Queue a `MISSED` frame for the messages skipped so far.
+    fn queue_missed(&mut self) {
+        let count = Payload::from(self.missed.to_be_bytes().to_vec());
+        self.missed = 0;
+        self.queue(Band::Data).push_back(Queued::new(codec::MISSED, Route::default(), count));
+    }
+
+    /// Close the connection once everything already queued for the peer is written.
+    ///
+    /// Frames sent after this are dropped. With a `reason`, a `CLOSE` frame carrying it goes out
+    /// after everything else. Once the last frame is written our write side is shut down, and
+    /// anything the peer still sends is read and thrown away until it hangs up too. The server
+    /// then removes the connection, see `read_closed_at` and `closing_at`. Broadcasts spilled to
+    /// disk are dropped, rather than kept behind the `CLOSE` frame.
+    pub fn close_gracefully(&mut self, reason: Option<&str>) -> io::Result<()> {
+        if self.closing_at.is_some() {
+            return Ok(());
+        }
+        self.drop_spilled();
+
+        debug!("closing {:?} gracefully; reason={:?}", self.token, reason);
+        if let Some(reason) = reason {
+            let reason = Payload::from(reason.as_bytes().to_vec());
+            let close = Queued::new(codec::CLOSE, Route::default(), reason);
+            self.queue(Band::Data).push_back(close);
+        }
+        self.closing_at = Some(Instant::now());
+        self.recv.clear();
+
+        self.writable()?;
+        if self.pending() {
+            self.interest.insert(Ready::writable());
+        }
+        Ok(())
+    }
+
+    /// Throw away the queued messages that have not started to go out, and stop holding any
+    /// back. Control frames, urgent messages and a message that is part written are kept, so the
+    /// stream stays intact and alerts still get through. Anything spilled to disk goes too.
+    ///
+    /// Returns how many payload bytes were thrown away from memory.
+    pub fn shed_queue(&mut self) -> usize {
+        let keep = if self.write_continuation == Some(Band::Data) { 1 } else { 0 };
+        let queue = self.queue(Band::Data);
+        let start = cmp::min(keep, queue.len());
+        let frames = queue.len() - start;
+        let shed = queue.drain(start..).map(|f| f.payload.len()).sum();
+        self.traffic.dropped += frames as u64;
+        self.drop_spilled();
+        self.release();
+        shed
+    }
+
+    /// How many bytes are waiting to go to the peer, staged or queued. A payload shared with
+    /// other connections counts in full.
+    pub fn queued_bytes(&self) -> usize {
+        let queued: usize = self.queued_payloads().map(|buf| buf.len()).sum();
+        queued + self.write_buf.len() - self.write_pos
+    }
+
+    /// The payloads waiting in the send queues. Broadcasts share theirs with other connections.
+    pub fn queued_payloads(&self) -> impl Iterator<Item = &Payload> {
+        self.send_queues.iter().flat_map(|q| q.iter().map(|f| &f.payload))
+    }
+
+    /// How many bytes the connection holds that are not shared with any other: the staging
+    /// buffer and what has been read but not handed out.
+    pub fn owned_bytes(&self) -> usize {
+        self.write_buf.len() + self.recv.len() + self.read_buf.len()
+    }
+
+    /// Where the connection is up to, for a state dump. Times are how many milliseconds ago
+    /// something happened.
+    pub fn dump(&self) -> Json {
+        let now = Instant::now();
+        let ago = |at: Option<Instant>| {
+            at.map(|at| now.saturating_duration_since(at).as_millis() as u64)
+        };
+
+        let queues = BANDS.iter().map(|&band| {
+            let frames = self.send_queues[band as usize].iter().map(|f| {
+                Json::object(vec![
+                    ("kind", f.kind.into()),
+                    ("priority", f.route.priority.into()),
+                    ("stream", f.route.stream.into()),
+                    ("bytes", f.payload.len().into()),
+                    ("shared", f.shared.is_some().into()),
+                ])
+            });
+            Json::object(vec![
+                ("band", Json::debug(band)),
+                ("frames", Json::Array(frames.collect())),
+            ])
+        });
+        let continuation = self.write_continuation.map(|band| {
+            Json::object(vec![("band", Json::debug(band)), ("offset", self.write_offset.into())])
+        });
+
+        Json::object(vec![
+            ("token", self.token.0.into()),
+            ("peer", self.peer.into()),
+            ("interest", Json::debug(self.interest)),
+            ("registered", self.registered.map(Json::debug).into()),
+            ("framing", Json::debug(self.framing)),
+            ("read_endian", Json::debug(self.read_endian)),
+            ("write_endian", Json::debug(self.write_endian)),
+            ("read_buffered", (self.recv.len() + self.read_buf.len()).into()),
+            ("read_paused", self.read_paused.into()),
+            ("read_closed_ms", ago(self.read_closed_at).into()),
+            ("messages_read", self.messages_read.into()),
+            ("fragments", self.fragments.len().into()),
+            ("fragment_bytes", self.fragment_bytes.into()),
+            ("write_staged", (self.write_buf.len() - self.write_pos).into()),
+            ("spilled_frames", self.spilled.as_ref().map(SpillFile::frames).into()),
+            ("spilled_bytes", self.spilled.as_ref().map(SpillFile::unread_bytes).into()),
+            ("write_continuation", continuation.into()),
+            ("held_bytes", self.held_bytes.into()),
+            ("pong_owed", self.pong_owed.into()),
+            ("missed", self.missed.into()),
+            ("credit", self.credit.into()),
+            ("closing_ms", ago(self.closing_at).into()),
+            ("write_shut", self.write_shut.into()),
+            ("queues", Json::Array(queues.collect())),
+        ])
+    }
+
+    /// When we started closing the connection, if we have. See `close_gracefully`.
+    pub fn closing_at(&self) -> Option<Instant> {
+        self.closing_at
+    }
+
+    /// Count reads and writes in `metrics`, or stop with `None`. Not counted by default.
+    pub fn set_metrics(&mut self, metrics: Option<Arc<Metrics>>) {
+        self.metrics = metrics;
+    }
+
+    /// Update the metrics the connection is counted in, if there are any.
+    fn count<F: FnOnce(&Metrics)>(&self, f: F) {
+        if let Some(ref metrics) = self.metrics {
+            f(metrics);
+        }
+    }
+
+    /// Count a protocol error the peer made, and return the error that closes the connection.
+    fn fail(&self, failure: Failure, reason: &'static str) -> Error {
+        self.count(|m| m.failed(failure));
+        Error::new(ErrorKind::InvalidData, reason)
+    }
+
+    /// Mark the peer as another server federated with ours, or not. A federated server is sent
+    /// the frames clients are, such as `WELCOME`, so whatever it sends with a payload is read and
+    /// left to the server to make sense of.
+    pub fn set_peer(&mut self, peer: bool) {
+        self.peer = peer;
+    }
+
+    /// Whether the peer is another server federated with ours, see `set_peer`.
+    pub fn is_peer(&self) -> bool {
+        self.peer
+    }
+
+    /// Delimit frames this way, from the first one read or written. Length headers by default.
+    pub fn set_framing(&mut self, framing: Framing) {
+        self.framing = framing;
+    }
+
+    /// Remember who is at the other end, for the admin console to show.
+    pub fn set_addr(&mut self, addr: Option<SocketAddr>) {
+        self.addr = addr;
+    }
+
+    /// Who is at the other end, if we were told.
+    pub fn addr(&self) -> Option<SocketAddr> {
+        self.addr
+    }
+
+    /// When the connection was made.
+    pub fn connected_at(&self) -> Instant {
+        self.connected_at
+    }
+
+    /// What has gone through the connection so far.
+    pub fn traffic(&self) -> Traffic {
+        self.traffic
+    }
+
+    /// Register with the poller this way from now on. See `PollStrategy` for the default.
+    pub fn set_poll_strategy(&mut self, strategy: PollStrategy) {
+        self.poll_strategy = strategy;
+    }
+
+    /// Hold small messages back for up to `coalesce`, or write each one as soon as it is queued
+    /// if `None`. Anything already held is written at its original deadline.
+    pub fn set_coalesce(&mut self, coalesce: Option<Coalesce>) {
+        self.coalesce = coalesce;
+    }
+
+    /// When the messages being held back are due to be written, if any are.
+    pub fn hold_until(&self) -> Option<Instant> {
+        self.hold_until
+    }
+
+    /// Write the messages being held back if they are due at `now`.
+    ///
+    /// Returns whether the connection started waiting on a writable event, in which case it has
+    /// to be reregistered.
+    pub fn flush_held(&mut self, now: Instant) -> io::Result<bool> {
+        match self.hold_until {
+            Some(until) if until <= now => {},
+            _ => return Ok(false),
+        }
+
+        self.release();
+        self.flush()?;
+
+        if self.pending() && !self.interest.is_writable() {
+            self.interest.insert(Ready::writable());
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    /// Stop holding messages back. They are written along with everything else queued.
+    fn release(&mut self) {
+        self.hold_until = None;
+        self.held_bytes = 0;
+    }
+
+    /// Stop reading from the peer until `resume_reading`. What it sends waits in the socket, so
+    /// its TCP window fills and it is made to slow down. Hang ups are not watched for either,
+    /// since one would be reported again every time the connection is reregistered.
+    pub fn pause_reading(&mut self) {
+        self.read_paused = true;
+        self.interest.remove(Ready::readable());
+        self.interest.remove(UnixReady::hup());
+    }
+
+    /// Start reading from the peer again after `pause_reading`. The connection has to be
+    /// reregistered.
+    pub fn resume_reading(&mut self) {
+        if !self.read_paused {
+            return;
+        }
+
+        self.read_paused = false;
+        if self.read_closed_at.is_none() {
+            self.interest.insert(Ready::readable());
+            self.interest.insert(UnixReady::hup());
+        }
+    }
+
+    /// Whether reading from the peer is paused, see `pause_reading`.
+    pub fn is_read_paused(&self) -> bool {
+        self.read_paused
+    }
+
+    /// When the peer shut down its write side, if it has. Nothing more will be read from it,
+    /// but what is queued for it can still be written.
+    pub fn read_closed_at(&self) -> Option<Instant> {
+        self.read_closed_at
+    }
+
+    /// Whether everything queued for the peer has been written, including anything held back.
+    pub fn is_flushed(&self) -> bool {
+        !self.pending()
+    }
+
+    /// Whether the connection is waiting on a writable event to flush its send queue.
+    pub fn is_writable(&self) -> bool {
+        self.interest.is_writable()
+    }
+
+    /// Register interest in read events with poll.
+    ///
+    /// This will let our connection accept reads starting next poller tick.
+    pub fn register(&mut self, poll: &mut Poll) -> io::Result<()> {
+        trace!("connection register; token={:?}", self.token);
+
+        self.interest.insert(Ready::readable());
+
+        poll.register(
+            &self.sock,
+            self.token,
+            self.interest,
+            self.poll_strategy.opts()
+        ).map_err(|e| {
+            error!("Failed to reregister {:?}, {:?}", self.token, e);
+            e
+        })?;
+        self.registered = Some(self.interest);
+        Ok(())
+    }
+
+    /// Re-register interest in read events with poll. A registration that is not oneshot, and
+    /// is still for what we are interested in, is left as it is.
+    pub fn reregister(&mut self, poll: &mut Poll) -> io::Result<()> {
+        if !self.poll_strategy.oneshot && self.registered == Some(self.interest) {
+            return Ok(());
+        }
+        trace!("connection reregister; token={:?}", self.token);
+
+        poll.reregister(
+            &self.sock,
             self.token,
             self.interest,
-            PollOpt::edge() | PollOpt::oneshot()
-        ).or_else(|e| {
+            self.poll_strategy.opts()
+        ).map_err(|e| {
             error!("Failed to reregister {:?}, {:?}", self.token, e);
-            Err(e)
-        })
+            e
+        })?;
+        self.registered = Some(self.interest);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::ErrorKind;
+    use std::net::Shutdown;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    use mio::{Poll, PollOpt, Ready, Token};
+
+    use buffer::Payload;
+    use codec::{self, Route};
+    use metrics::{Failure, Metrics};
+    use transport::mock::{MockTransport, ReadStep, WriteStep};
+
+    use super::{Coalesce, Connection, Deadline, FragmentLimits, Framing, KeepAlive, Liveness,
+                Message, PollStrategy, SharedFrame, Traffic, HALF_CLOSE_TIMEOUT};
+
+    fn header(len: usize) -> Vec<u8> {
+        codec::encode_header(len).to_vec()
+    }
+
+    fn frame(payload: &[u8]) -> Vec<u8> {
+        codec::encode(payload)
+    }
+
+    #[test]
+    fn reads_a_whole_frame() {
+        let mut sock = MockTransport::new();
+        sock.push_read(ReadStep::Data(frame(b"hello")));
+
+        let mut conn = Connection::new(sock, Token(0));
+        assert_eq!(conn.readable().unwrap(), Some(Message::data(b"hello".to_vec())));
+        assert_eq!(conn.readable().unwrap(), None);
+    }
+
+    #[test]
+    fn reads_back_to_back_frames() {
+        let mut bytes = frame(b"one");
+        bytes.extend(frame(b"two"));
+
+        let mut sock = MockTransport::new();
+        sock.push_read(ReadStep::Data(bytes));
+
+        let mut conn = Connection::new(sock, Token(0));
+        assert_eq!(conn.readable().unwrap(), Some(Message::data(b"one".to_vec())));
+        assert_eq!(conn.readable().unwrap(), Some(Message::data(b"two".to_vec())));
+        assert_eq!(conn.readable().unwrap(), None);
+    }
+
+    #[test]
+    fn holds_fragments_until_the_last_piece() {
+        let mut conn = Connection::new(MockTransport::new(), Token(0));
+
+        let first: Payload = codec::encode_fragment(1, codec::DATA, false, b"hel").into();
+        let other: Payload = codec::encode_fragment(2, codec::DATA, true, b"other").into();
+        let last: Payload = codec::encode_fragment(1, codec::DATA, true, b"lo").into();
+        assert_eq!(conn.hold_fragment(first.clone()).unwrap(), None);
+        assert_eq!(conn.hold_fragment(other.clone()).unwrap(),
+                   Some((Message::data(b"other".to_vec()), vec![other])));
+        assert_eq!(conn.hold_fragment(last.clone()).unwrap(),
+                   Some((Message::data(b"hello".to_vec()), vec![first, last])));
+
+        let short = codec::encode_fragment(3, codec::REQUEST, true, b"id?");
+        assert_eq!(conn.hold_fragment(short.into()).unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn fragments_in_flight_are_limited() {
+        let mut conn = Connection::new(MockTransport::new(), Token(0));
+        conn.set_fragment_limits(FragmentLimits {
+            messages: 2,
+            bytes: 10,
+            timeout: Duration::from_secs(30),
+        });
+
+        let piece = |id, last, part: &[u8]| codec::encode_fragment(id, codec::DATA, last, part);
+
+        conn.pass_fragment(&piece(1, false, b"1234")).unwrap();
+        conn.pass_fragment(&piece(2, false, b"1234")).unwrap();
+        let e = conn.pass_fragment(&piece(3, false, b"1")).unwrap_err();
+        assert_eq!(e.to_string(), "Too many fragmented messages");
+
+        // Finishing one makes room for another, but not for more bytes than are allowed.
+        conn.pass_fragment(&piece(1, true, b"12")).unwrap();
+        conn.pass_fragment(&piece(3, false, b"1")).unwrap();
+        let e = conn.pass_fragment(&piece(2, false, b"123456")).unwrap_err();
+        assert_eq!(e.to_string(), "Fragmented messages too large");
+    }
+
+    #[test]
+    fn fragments_stall_without_their_next_piece() {
+        let mut conn = Connection::new(MockTransport::new(), Token(0));
+        let now = Instant::now();
+        assert!(!conn.has_stalled_fragment(now + Duration::from_secs(60)));
+
+        conn.hold_fragment(codec::encode_fragment(1, codec::DATA, false, b"hel").into()).unwrap();
+        assert!(!conn.has_stalled_fragment(now));
+        assert!(conn.has_stalled_fragment(now + Duration::from_secs(60)));
+
+        conn.hold_fragment(codec::encode_fragment(1, codec::DATA, true, b"lo").into()).unwrap();
+        assert!(!conn.has_stalled_fragment(now + Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn would_block_before_header_returns_none() {
+        let mut conn = Connection::new(MockTransport::new(), Token(0));
+        assert_eq!(conn.readable().unwrap(), None);
+    }
+
+    #[test]
+    fn resumes_payload_after_would_block() {
+        let mut sock = MockTransport::new();
+        sock.push_read(ReadStep::Data(header(5)))
+            .push_read(ReadStep::WouldBlock)
+            .push_read(ReadStep::Data(b"hello".to_vec()));
+
+        let mut conn = Connection::new(sock, Token(0));
+        assert_eq!(conn.readable().unwrap(), None);
+        assert_eq!(conn.readable().unwrap(), Some(Message::data(b"hello".to_vec())));
+    }
+
+    #[test]
+    fn resumes_header_after_would_block() {
+        let frame = frame(b"hello");
+
+        let mut sock = MockTransport::new();
+        sock.push_read(ReadStep::Data(frame[..3].to_vec()))
+            .push_read(ReadStep::WouldBlock)
+            .push_read(ReadStep::Data(frame[3..].to_vec()));
+
+        let mut conn = Connection::new(sock, Token(0));
+        assert_eq!(conn.readable().unwrap(), None);
+        assert_eq!(conn.readable().unwrap(), Some(Message::data(b"hello".to_vec())));
+    }
+
+    #[test]
+    fn resumes_payload_split_across_reads() {
+        let mut sock = MockTransport::new();
+        sock.push_read(ReadStep::Data(header(5)))
+            .push_read(ReadStep::Data(b"he".to_vec()))
+            .push_read(ReadStep::WouldBlock)
+            .push_read(ReadStep::Data(b"llo".to_vec()));
+
+        let mut conn = Connection::new(sock, Token(0));
+        assert_eq!(conn.readable().unwrap(), None);
+        assert_eq!(conn.readable().unwrap(), Some(Message::data(b"hello".to_vec())));
+    }
+
+    #[test]
+    fn eof_between_frames_stops_reading_but_not_writing() {
+        let mut sock = MockTransport::new();
+        sock.push_read(ReadStep::Data(frame(b"last")))
+            .push_read(ReadStep::Eof)
+            .push_write(WriteStep::WouldBlock);
+
+        let mut conn = Connection::new(sock, Token(0));
+        assert_eq!(conn.readable().unwrap(), Some(Message::data(b"last".to_vec())));
+        assert_eq!(conn.readable().unwrap(), None);
+        assert!(conn.read_closed_at().is_some());
+        assert!(!conn.interest.is_readable());
+
+        // Further reads do not touch the socket.
+        assert_eq!(conn.readable().unwrap(), None);
+
+        conn.send_message(Arc::new(b"still".to_vec())).unwrap();
+        assert!(!conn.is_flushed());
+        conn.writable().unwrap();
+        assert_eq!(conn.sock.written, frame(b"still"));
+        assert!(conn.is_flushed());
+    }
+
+    #[test]
+    fn eof_mid_header_is_an_error() {
+        let mut sock = MockTransport::new();
+        sock.push_read(ReadStep::Data(vec![0, 0, 0]))
+            .push_read(ReadStep::Eof);
+
+        let mut conn = Connection::new(sock, Token(0));
+        let e = conn.readable().unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn eof_mid_payload_is_an_error() {
+        let mut sock = MockTransport::new();
+        sock.push_read(ReadStep::Data(header(5)))
+            .push_read(ReadStep::Data(b"he".to_vec()))
+            .push_read(ReadStep::Eof);
+
+        let mut conn = Connection::new(sock, Token(0));
+        let e = conn.readable().unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn oversized_length_is_an_error() {
+        let mut sock = MockTransport::new();
+        sock.push_read(ReadStep::Data(header(codec::MAX_PAYLOAD_LEN + 1)));
+
+        let mut conn = Connection::new(sock, Token(0));
+        let e = conn.readable().unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn errors_are_counted_by_kind() {
+        let mut sock = MockTransport::new();
+        sock.push_read(ReadStep::Data(header(codec::MAX_PAYLOAD_LEN + 1)));
+
+        let metrics = Arc::new(Metrics::new());
+        let mut conn = Connection::new(sock, Token(0));
+        conn.set_metrics(Some(metrics.clone()));
+        conn.readable().unwrap_err();
+
+        assert_eq!(metrics.failures(Failure::FrameTooLarge), 1);
+        assert_eq!(metrics.failures(Failure::ReadFailed), 0);
+    }
+
+    #[test]
+    fn read_errors_are_returned() {
+        let mut sock = MockTransport::new();
+        sock.push_read(ReadStep::Error(ErrorKind::ConnectionReset));
+
+        let mut conn = Connection::new(sock, Token(0));
+        let e = conn.readable().unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::ConnectionReset);
+    }
+
+    #[test]
+    fn send_writes_immediately_when_queue_is_empty() {
+        let mut conn = Connection::new(MockTransport::new(), Token(0));
+        conn.send_message(Arc::new(b"hi".to_vec())).unwrap();
+
+        assert_eq!(conn.sock.written, frame(b"hi"));
+        assert!(!conn.pending());
+        assert!(!conn.interest.is_writable());
+    }
+
+    #[test]
+    fn reads_and_writes_are_counted() {
+        let mut sock = MockTransport::new();
+        sock.push_read(ReadStep::Data(frame(b"hello")));
+
+        let metrics = Arc::new(Metrics::new());
+        let mut conn = Connection::new(sock, Token(0));
+        conn.set_metrics(Some(metrics.clone()));
+        conn.readable().unwrap();
+        conn.send_message(Arc::new(b"hi".to_vec())).unwrap();
+
+        assert_eq!(metrics.frames_read.get(), 1);
+        assert_eq!(metrics.bytes_read.get(), frame(b"hello").len() as u64);
+        assert_eq!(metrics.frames_written.get(), 1);
+        assert_eq!(metrics.bytes_written.get(), frame(b"hi").len() as u64);
+        assert_eq!(metrics.frame_sizes_read.snapshot().sum, 5);
+        assert_eq!(metrics.frame_sizes_written.snapshot().sum, 2);
+    }
+
+    #[test]
+    fn would_block_queues_message_and_registers_write_interest() {
+        let mut sock = MockTransport::new();
+        sock.push_write(WriteStep::WouldBlock);
+
+        let mut conn = Connection::new(sock, Token(0));
+        conn.send_message(Arc::new(b"hi".to_vec())).unwrap();
+
+        assert!(conn.sock.written.is_empty());
+        assert!(conn.pending());
+        assert!(conn.interest.contains(Ready::writable()));
+
+        conn.writable().unwrap();
+        assert_eq!(conn.sock.written, frame(b"hi"));
+        assert!(!conn.pending());
+        assert!(!conn.interest.is_writable());
+    }
+
+    #[test]
+    fn persistent_registrations_are_only_renewed_when_interest_changes() {
+        let mut sock = MockTransport::new();
+        sock.push_write(WriteStep::WouldBlock);
+
+        let mut poll = Poll::new().unwrap();
+        let mut conn = Connection::new(sock, Token(0));
+        conn.set_poll_strategy(PollStrategy { edge: false, oneshot: false });
+        conn.register(&mut poll).unwrap();
+        conn.reregister(&mut poll).unwrap();
+        assert_eq!(conn.sock.registrations.borrow().len(), 1);
+
+        conn.send_message(Arc::new(b"hi".to_vec())).unwrap();
+        conn.reregister(&mut poll).unwrap();
+        conn.reregister(&mut poll).unwrap();
+        let registrations = conn.sock.registrations.borrow().clone();
+        assert_eq!(registrations.len(), 2);
+        assert!(registrations[1].0.is_writable());
+        assert_eq!(registrations[1].1, PollOpt::level());
+
+        // Oneshot ones are disarmed by every event, so they are always renewed.
+        conn.set_poll_strategy(PollStrategy::default());
+        conn.reregister(&mut poll).unwrap();
+        assert_eq!(conn.sock.registrations.borrow().len(), 3);
+    }
+
+    #[test]
+    fn partial_write_resumes_without_resending_header() {
+        let mut sock = MockTransport::new();
+        sock.push_write(WriteStep::Accept(8))
+            .push_write(WriteStep::Accept(2));
+
+        let mut conn = Connection::new(sock, Token(0));
+        conn.send_message(Arc::new(b"hello".to_vec())).unwrap();
+        assert!(conn.pending());
+
+        // One write per writable event.
+        conn.writable().unwrap();
+        assert!(conn.pending());
+        conn.writable().unwrap();
+        assert_eq!(conn.sock.written, frame(b"hello"));
+        assert!(!conn.pending());
+        assert!(!conn.interest.is_writable());
+    }
+
+    #[test]
+    fn partial_header_write_resumes() {
+        let mut sock = MockTransport::new();
+        sock.push_write(WriteStep::Accept(3));
+
+        let mut conn = Connection::new(sock, Token(0));
+        conn.send_message(Arc::new(b"hello".to_vec())).unwrap();
+        conn.writable().unwrap();
+
+        assert_eq!(conn.sock.written, frame(b"hello"));
+        assert!(!conn.interest.is_writable());
+    }
+
+    #[test]
+    fn queued_frames_are_written_together() {
+        let mut sock = MockTransport::new();
+        sock.push_write(WriteStep::WouldBlock)
+            .push_write(WriteStep::Accept(1024));
+
+        let mut conn = Connection::new(sock, Token(0));
+        for msg in [&b"one"[..], b"two", b"three"].iter() {
+            conn.send_message(Arc::new(msg.to_vec())).unwrap();
+        }
+
+        conn.writable().unwrap();
+
+        let mut expected = frame(b"one");
+        expected.extend(frame(b"two"));
+        expected.extend(frame(b"three"));
+        assert_eq!(conn.sock.written, expected);
+        assert!(!conn.interest.is_writable());
+    }
+
+    #[test]
+    fn shared_frames_too_big_to_stage_are_written_from_their_own_buffer() {
+        let payload = vec![7u8; 64];
+        let mut sock = MockTransport::new();
+        sock.push_write(WriteStep::WouldBlock)
+            .push_write(WriteStep::Accept(1024))
+            .push_write(WriteStep::Accept(10));
+
+        let mut conn = Connection::with_capacity(sock, Token(0), 4, 16);
+        conn.send_message(Arc::new(b"one".to_vec())).unwrap();
+        let shared = SharedFrame::new(codec::DATA, Route::default(), &payload);
+        conn.send_shared(&shared).unwrap();
+
+        // What fits is staged and written first, then the rest a piece at a time without ever
+        // going through the staging buffer.
+        conn.writable().unwrap();
+        assert_eq!(conn.sock.written, frame(b"one"));
+        conn.writable().unwrap();
+        assert!(conn.write_buf.is_empty());
+        assert!(conn.pending());
+        conn.writable().unwrap();
+
+        let mut expected = frame(b"one");
+        expected.extend(frame(&payload));
+        assert_eq!(conn.sock.written, expected);
+        assert_eq!(shared.payload(), Payload::from(payload));
+        assert!(!conn.pending());
+    }
+
+    #[test]
+    fn large_frames_are_written_a_batch_at_a_time() {
+        let payload = vec![7u8; super::DEFAULT_WRITE_BATCH * 2];
+        let mut sock = MockTransport::new();
+        sock.push_write(WriteStep::WouldBlock);
+
+        let mut conn = Connection::new(sock, Token(0));
+        conn.send_message(Arc::new(payload.clone())).unwrap();
+
+        let mut writes = 0;
+        while conn.is_writable() {
+            conn.writable().unwrap();
+            writes += 1;
+        }
+
+        assert_eq!(writes, 3);
+        assert_eq!(conn.sock.written, frame(&payload));
+    }
+
+    #[test]
+    fn messages_queue_behind_a_blocked_write() {
+        let mut sock = MockTransport::new();
+        sock.push_write(WriteStep::WouldBlock);
+
+        let mut conn = Connection::new(sock, Token(0));
+        conn.send_message(Arc::new(b"one".to_vec())).unwrap();
+        conn.send_message(Arc::new(b"two".to_vec())).unwrap();
+
+        // The first is staged for the blocked write, the second waits in the queue behind it.
+        assert_eq!(conn.queued_frames(), 1);
+
+        conn.writable().unwrap();
+        conn.writable().unwrap();
+
+        let mut expected = frame(b"one");
+        expected.extend(frame(b"two"));
+        assert_eq!(conn.sock.written, expected);
+    }
+
+    #[test]
+    fn write_errors_are_returned() {
+        let mut sock = MockTransport::new();
+        sock.push_write(WriteStep::Error(ErrorKind::BrokenPipe));
+
+        let mut conn = Connection::new(sock, Token(0));
+        let e = conn.send_message(Arc::new(b"hi".to_vec())).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::BrokenPipe);
+    }
+
+    #[test]
+    fn ping_is_answered_with_pong() {
+        let mut sock = MockTransport::new();
+        sock.push_read(ReadStep::Data(codec::encode_control(codec::PING).to_vec()));
+
+        let mut conn = Connection::new(sock, Token(0));
+        assert_eq!(conn.readable().unwrap(), None);
+        assert!(conn.interest.is_writable());
+
+        conn.writable().unwrap();
+        assert_eq!(conn.sock.written, codec::encode_control(codec::PONG));
+        assert!(!conn.interest.is_writable());
+    }
+
+    fn cobs(payload: &[u8]) -> Vec<u8> {
+        let mut frame = codec::encode_cobs(payload);
+        frame.push(codec::COBS_DELIMITER);
+        frame
+    }
+
+    #[test]
+    fn reads_cobs_frames_across_reads() {
+        let mut bytes = cobs(b"one\0");
+        bytes.extend(cobs(b""));
+        bytes.extend(cobs(b"two"));
+        let (first, rest) = bytes.split_at(9);
+
+        let mut sock = MockTransport::new();
+        sock.push_read(ReadStep::Data(first.to_vec()))
+            .push_read(ReadStep::Data(rest.to_vec()));
+
+        let mut conn = Connection::new(sock, Token(0));
+        conn.set_framing(Framing::Cobs);
+        assert_eq!(conn.readable().unwrap(), Some(Message::data(b"one\0".to_vec())));
+        assert_eq!(conn.readable().unwrap(), Some(Message::data(b"two".to_vec())));
+        assert_eq!(conn.readable().unwrap(), None);
+        assert_eq!(conn.messages_read(), 2);
+    }
+
+    #[test]
+    fn malformed_cobs_is_an_error() {
+        let mut sock = MockTransport::new();
+        sock.push_read(ReadStep::Data(vec![0x05, b'a', codec::COBS_DELIMITER]));
+
+        let mut conn = Connection::new(sock, Token(0));
+        conn.set_framing(Framing::Cobs);
+        assert_eq!(conn.readable().unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn cobs_writes_only_messages() {
+        let mut conn = Connection::new(MockTransport::new(), Token(0));
+        conn.set_framing(Framing::Cobs);
+        conn.send_frame(codec::ERROR, Arc::new(b"nope".to_vec())).unwrap();
+        conn.send_message(Arc::new(b"a\0b".to_vec())).unwrap();
+        conn.writable().unwrap();
+
+        assert_eq!(conn.sock.written, cobs(b"a\0b"));
+    }
+
+    #[test]
+    fn cobs_encodes_shared_frames_itself() {
+        let mut conn = Connection::new(MockTransport::new(), Token(0));
+        conn.set_framing(Framing::Cobs);
+        conn.send_shared(&SharedFrame::new(codec::DATA, Route::default(), b"a\0b")).unwrap();
+
+        assert_eq!(conn.sock.written, cobs(b"a\0b"));
+    }
+
+    #[test]
+    fn text_framing_reads_lines_without_telnet_commands() {
+        let mut sock = MockTransport::new();
+        sock.push_read(ReadStep::Data(vec![255, 251, 3, b'h', b'i', b'\r']))
+            .push_read(ReadStep::Data(b"\n\r\nbye\n".to_vec()));
+
+        let mut conn = Connection::new(sock, Token(0));
+        conn.set_framing(Framing::Text);
+        assert_eq!(conn.readable().unwrap(), Some(Message::data(b"hi".to_vec())));
+        assert_eq!(conn.readable().unwrap(), Some(Message::data(b"bye".to_vec())));
+        assert_eq!(conn.readable().unwrap(), None);
+
+        conn.send_message(Arc::new(b"back".to_vec())).unwrap();
+        assert_eq!(conn.sock.written, b"back\r\n".to_vec());
+    }
+
+    #[test]
+    fn byte_order_switches_once_asked() {
+        let little = codec::encode_endian(codec::Endian::Little);
+        let mut bytes = codec::encode_frame_header(codec::ENDIAN, 1).to_vec();
+        bytes.extend_from_slice(&little);
+        bytes.extend(codec::Endian::Little.encode(b"hi"));
+
+        let mut sock = MockTransport::new();
+        sock.push_read(ReadStep::Data(bytes));
+
+        // The switch is read past, and the frame after it read in the new order.
+        let mut conn = Connection::new(sock, Token(0));
+        assert_eq!(conn.readable().unwrap(), Some(Message::data(b"hi".to_vec())));
+
+        // The answer goes out in the old order, and everything after it in the new one.
+        conn.send_message(Arc::new(b"yo".to_vec())).unwrap();
+        conn.writable().unwrap();
+
+        let mut expected = codec::encode_frame_header(codec::ENDIAN, 1).to_vec();
+        expected.extend_from_slice(&little);
+        expected.extend(codec::Endian::Little.encode(b"yo"));
+        assert_eq!(conn.sock.written, expected);
+    }
+
+    #[test]
+    fn pong_waits_for_a_partly_written_message() {
+        let mut sock = MockTransport::new();
+        sock.push_read(ReadStep::Data(codec::encode_control(codec::PING).to_vec()))
+            .push_write(WriteStep::Accept(8))
+            .push_write(WriteStep::Accept(2));
+
+        let mut conn = Connection::new(sock, Token(0));
+        conn.send_message(Arc::new(b"hello".to_vec())).unwrap();
+        assert_eq!(conn.readable().unwrap(), None);
+
+        conn.writable().unwrap();
+        conn.writable().unwrap();
+
+        let mut expected = frame(b"hello");
+        expected.extend(&codec::encode_control(codec::PONG));
+        assert_eq!(conn.sock.written, expected);
+        assert!(!conn.interest.is_writable());
+    }
+
+    #[test]
+    fn pong_jumps_ahead_of_queued_messages() {
+        let mut sock = MockTransport::new();
+        sock.push_read(ReadStep::Data(codec::encode_control(codec::PING).to_vec()))
+            .push_write(WriteStep::WouldBlock)
+            .push_write(WriteStep::Accept(codec::HEADER_LEN + 3));
+
+        let mut conn = Connection::new(sock, Token(0));
+        conn.send_message(Arc::new(b"one".to_vec())).unwrap();
+        conn.send_message(Arc::new(b"two".to_vec())).unwrap();
+        conn.send_message(Arc::new(b"three".to_vec())).unwrap();
+
+        // The first message is already staged for the blocked write. The PONG can still get
+        // ahead of the two that are queued.
+        assert_eq!(conn.readable().unwrap(), None);
+        assert_eq!(conn.queued_frames(), 3);
+
+        while conn.is_writable() {
+            conn.writable().unwrap();
+        }
+
+        let mut expected = frame(b"one");
+        expected.extend(&codec::encode_control(codec::PONG));
+        expected.extend(frame(b"two"));
+        expected.extend(frame(b"three"));
+        assert_eq!(conn.sock.written, expected);
+        assert!(!conn.interest.is_writable());
+    }
+
+    #[test]
+    fn urgent_messages_jump_ahead_of_ordinary_ones() {
+        let route = Route { priority: 5, stream: 2 };
+        let mut urgent = codec::encode_routed_header(codec::DATA, route, 5).to_vec();
+        urgent.extend(b"alert");
+
+        let mut sock = MockTransport::new();
+        sock.push_read(ReadStep::Data(urgent.clone()))
+            .push_write(WriteStep::WouldBlock)
+            .push_write(WriteStep::Accept(codec::HEADER_LEN + 3));
+
+        let mut conn = Connection::new(sock, Token(0));
+        let message = conn.readable().unwrap().unwrap();
+        assert_eq!(message.route, route);
+
+        conn.send_message(Arc::new(b"one".to_vec())).unwrap();
+        conn.send_message(Arc::new(b"two".to_vec())).unwrap();
+        conn.send_frame(codec::PING, Arc::new(Vec::new())).unwrap();
+        conn.send_routed(message.kind, message.route, message.payload).unwrap();
+
+        while conn.is_writable() {
+            conn.writable().unwrap();
+        }
+
+        // Control frames still go first, and the alert keeps its route on the way out.
+        let mut expected = frame(b"one");
+        expected.extend(&codec::encode_frame_header(codec::PING, 0));
+        expected.extend(urgent);
+        expected.extend(frame(b"two"));
+        assert_eq!(conn.sock.written, expected);
+    }
+
+    #[test]
+    fn credit_is_used_up_by_messages_only() {
+        let mut grant = codec::encode_frame_header(codec::CREDIT, 8).to_vec();
+        grant.extend(codec::encode_credit(1));
+
+        let mut sock = MockTransport::new();
+        sock.push_read(ReadStep::Data(grant.clone()))
+            .push_read(ReadStep::WouldBlock)
+            .push_read(ReadStep::Data(grant));
+
+        // Without flow control there is always credit.
+        let mut conn = Connection::new(sock, Token(0));
+        assert!(conn.has_credit());
+        assert_eq!(conn.readable().unwrap(), None);
+
+        conn.send_frame(codec::NOTICE, Arc::new(b"hi".to_vec())).unwrap();
+        assert!(conn.has_credit());
+        conn.send_message(Arc::new(b"one".to_vec())).unwrap();
+        assert!(!conn.has_credit());
+
+        // More credit after messages were skipped says how many, without waiting for the next.
+        conn.skip_message();
+        conn.skip_message();
+        assert_eq!(conn.readable().unwrap(), None);
+        assert_eq!(conn.readable().unwrap(), None);
+        assert!(conn.has_credit());
+        while conn.is_writable() {
+            conn.writable().unwrap();
+        }
+
+        let mut expected = codec::encode_frame_header(codec::NOTICE, 2).to_vec();
+        expected.extend(b"hi");
+        expected.extend(frame(b"one"));
+        expected.extend(&codec::encode_frame_header(codec::MISSED, 8));
+        expected.extend(&2u64.to_be_bytes());
+        assert_eq!(conn.sock.written, expected);
+    }
+
+    #[test]
+    fn unknown_frame_kind_is_an_error() {
+        let mut sock = MockTransport::new();
+        sock.push_read(ReadStep::Data(codec::encode_control(0x7f).to_vec()));
+
+        let mut conn = Connection::new(sock, Token(0));
+        let e = conn.readable().unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn request_keeps_its_kind_when_sent_on() {
+        let frame = codec::encode_tagged(codec::REQUEST, 9, b"ask");
+        let mut sock = MockTransport::new();
+        sock.push_read(ReadStep::Data(frame.clone()));
+
+        let mut conn = Connection::new(sock, Token(0));
+        let message = conn.readable().unwrap().unwrap();
+        assert_eq!(message.kind, codec::REQUEST);
+        assert_eq!(&*message.payload, &frame[codec::HEADER_LEN..]);
+
+        conn.send_frame(message.kind, message.payload).unwrap();
+        assert_eq!(conn.sock.written, frame);
+    }
+
+    #[test]
+    fn request_without_correlation_id_is_an_error() {
+        let mut bytes = codec::encode_frame_header(codec::REQUEST, 3).to_vec();
+        bytes.extend_from_slice(b"abc");
+
+        let mut sock = MockTransport::new();
+        sock.push_read(ReadStep::Data(bytes));
+
+        let mut conn = Connection::new(sock, Token(0));
+        let e = conn.readable().unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn coalesced_messages_go_out_together_when_due() {
+        let mut conn = Connection::new(MockTransport::new(), Token(0));
+        conn.set_coalesce(Some(Coalesce { delay: Duration::from_millis(1), max_bytes: 1024 }));
+
+        conn.send_message(Arc::new(b"one".to_vec())).unwrap();
+        conn.send_message(Arc::new(b"two".to_vec())).unwrap();
+        assert!(conn.sock.written.is_empty());
+        assert!(!conn.interest.is_writable());
+
+        let due = conn.hold_until().unwrap();
+        assert!(!conn.flush_held(due - Duration::from_millis(1)).unwrap());
+        assert!(conn.sock.written.is_empty());
+
+        assert!(!conn.flush_held(due).unwrap());
+        let mut expected = frame(b"one");
+        expected.extend(frame(b"two"));
+        assert_eq!(conn.sock.written, expected);
+        assert_eq!(conn.hold_until(), None);
+    }
+
+    #[test]
+    fn coalescing_stops_at_max_bytes() {
+        let mut conn = Connection::new(MockTransport::new(), Token(0));
+        conn.set_coalesce(Some(Coalesce { delay: Duration::from_secs(60), max_bytes: 23 }));
+
+        conn.send_message(Arc::new(b"one".to_vec())).unwrap();
+        assert!(conn.sock.written.is_empty());
+
+        // Together they would be 23 bytes, so both are written now.
+        conn.send_message(Arc::new(b"five".to_vec())).unwrap();
+        let mut expected = frame(b"one");
+        expected.extend(frame(b"five"));
+        assert_eq!(conn.sock.written, expected);
+        assert_eq!(conn.hold_until(), None);
+    }
+
+    #[test]
+    fn control_frames_are_not_held() {
+        let mut conn = Connection::new(MockTransport::new(), Token(0));
+        conn.set_coalesce(Some(Coalesce { delay: Duration::from_secs(60), max_bytes: 1024 }));
+
+        conn.send_message(Arc::new(b"held".to_vec())).unwrap();
+        conn.send_frame(codec::ERROR, Arc::new(b"nope".to_vec())).unwrap();
+
+        let mut expected = codec::encode_frame_header(codec::ERROR, 4).to_vec();
+        expected.extend_from_slice(b"nope");
+        expected.extend(frame(b"held"));
+        assert_eq!(conn.sock.written, expected);
+    }
+
+    #[test]
+    fn held_messages_that_would_block_wait_for_writable() {
+        let mut sock = MockTransport::new();
+        sock.push_write(WriteStep::WouldBlock);
+
+        let mut conn = Connection::new(sock, Token(0));
+        conn.set_coalesce(Some(Coalesce { delay: Duration::from_millis(1), max_bytes: 1024 }));
+        conn.send_message(Arc::new(b"hi".to_vec())).unwrap();
+
+        assert!(conn.flush_held(Instant::now() + Duration::from_secs(1)).unwrap());
+        assert!(conn.interest.is_writable());
+
+        conn.writable().unwrap();
+        assert_eq!(conn.sock.written, frame(b"hi"));
+    }
+
+    #[test]
+    fn closing_flushes_the_queue_then_sends_close() {
+        let mut sock = MockTransport::new();
+        sock.push_write(WriteStep::WouldBlock).push_write(WriteStep::WouldBlock);
+
+        let mut conn = Connection::new(sock, Token(0));
+        conn.send_message(Arc::new(b"first".to_vec())).unwrap();
+        conn.close_gracefully(Some("bye")).unwrap();
+        conn.send_message(Arc::new(b"dropped".to_vec())).unwrap();
+        assert!(conn.interest.is_writable());
+        assert!(conn.sock.shutdowns.borrow().is_empty());
+
+        conn.writable().unwrap();
+
+        let mut expected = frame(b"first");
+        expected.extend_from_slice(&codec::encode_frame_header(codec::CLOSE, 3));
+        expected.extend_from_slice(b"bye");
+        assert_eq!(conn.sock.written, expected);
+        assert_eq!(*conn.sock.shutdowns.borrow(), vec![Shutdown::Write]);
+        assert!(conn.is_flushed());
+    }
+
+    #[test]
+    fn closing_discards_reads_until_the_peer_hangs_up() {
+        let mut sock = MockTransport::new();
+        sock.push_read(ReadStep::Data(frame(b"ignored")))
+            .push_read(ReadStep::WouldBlock)
+            .push_read(ReadStep::Data(vec![0xff; 3]))
+            .push_read(ReadStep::Eof);
+
+        let mut conn = Connection::new(sock, Token(0));
+        conn.close_gracefully(None).unwrap();
+        assert!(conn.sock.written.is_empty());
+        assert_eq!(*conn.sock.shutdowns.borrow(), vec![Shutdown::Write]);
+
+        assert_eq!(conn.readable().unwrap(), None);
+        assert!(conn.read_closed_at().is_none());
+        assert_eq!(conn.readable().unwrap(), None);
+        assert!(conn.read_closed_at().is_some());
+    }
+
+    #[test]
+    fn skipped_messages_are_counted_before_the_next_one() {
+        let mut conn = Connection::new(MockTransport::new(), Token(0));
+        conn.skip_message();
+        conn.skip_message();
+        assert_eq!(conn.missed(), 2);
+
+        conn.send_message(Arc::new(b"next".to_vec())).unwrap();
+
+        let mut expected = codec::encode_frame_header(codec::MISSED, 8).to_vec();
+        expected.extend_from_slice(&2u64.to_be_bytes());
+        expected.extend(frame(b"next"));
+        assert_eq!(conn.sock.written, expected);
+        assert_eq!(conn.missed(), 0);
+    }
+
+    #[test]
+    fn quiet_peers_are_pinged_until_they_miss_too_many() {
+        let mut sock = MockTransport::new();
+        sock.push_read(ReadStep::Data(codec::encode_control(codec::PONG).to_vec()));
+
+        let keep_alive = KeepAlive { interval: Duration::from_secs(10), misses: 1 };
+        let mut conn = Connection::new(sock, Token(0));
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        assert_eq!(conn.keep_alive(at(5), keep_alive).unwrap(), Liveness::Alive);
+        assert_eq!(conn.keep_alive(at(10), keep_alive).unwrap(), Liveness::Pinged);
+        assert_eq!(conn.sock.written, codec::encode_control(codec::PING));
+
+        // The answer starts the count again, so the next quiet interval is not yet one too many.
+        assert_eq!(conn.readable().unwrap(), None);
+        assert_eq!(conn.keep_alive(at(20), keep_alive).unwrap(), Liveness::Pinged);
+        assert_eq!(conn.keep_alive(at(25), keep_alive).unwrap(), Liveness::Alive);
+        assert_eq!(conn.keep_alive(at(30), keep_alive).unwrap(), Liveness::Dead);
+
+        conn.set_framing(Framing::Text);
+        assert_eq!(conn.keep_alive(at(60), keep_alive).unwrap(), Liveness::Alive);
+    }
+
+    #[test]
+    fn deadlines_are_met_by_a_message_and_by_catching_up() {
+        let mut sock = MockTransport::new();
+        sock.push_read(ReadStep::Data(frame(b"hello")))
+            .push_read(ReadStep::Eof)
+            .push_write(WriteStep::WouldBlock);
+
+        let mut conn = Connection::new(sock, Token(0));
+        let now = Instant::now();
+        conn.set_read_deadline(Some(now));
+        conn.set_write_deadline(Some(now));
+
+        // Nothing is queued, so only the read deadline can be missed.
+        assert_eq!(conn.missed_deadline(now), Some(Deadline::Read));
+        assert_eq!(conn.readable().unwrap(), Some(Message::data(b"hello".to_vec())));
+        assert_eq!(conn.read_deadline(), None);
+        assert_eq!(conn.missed_deadline(now), None);
+
+        conn.send_message(Arc::new(b"slow".to_vec())).unwrap();
+        assert_eq!(conn.missed_deadline(now), Some(Deadline::Write));
+        conn.writable().unwrap();
+        assert_eq!(conn.write_deadline(), None);
+
+        // Hanging up gives the peer `HALF_CLOSE_TIMEOUT` to read the rest.
+        assert_eq!(conn.readable().unwrap(), None);
+        let deadline = conn.write_deadline().unwrap();
+        assert!(deadline > now && deadline <= Instant::now() + HALF_CLOSE_TIMEOUT);
+    }
+
+    #[test]
+    fn traffic_counts_bytes_frames_and_drops() {
+        let mut sock = MockTransport::new();
+        sock.push_read(ReadStep::Data(frame(b"in")));
+
+        let mut conn = Connection::new(sock, Token(0));
+        assert_eq!(conn.readable().unwrap(), Some(Message::data(b"in".to_vec())));
+        conn.skip_message();
+        conn.send_message(Arc::new(b"out".to_vec())).unwrap();
+        conn.close_gracefully(None).unwrap();
+        conn.send_message(Arc::new(b"late".to_vec())).unwrap();
+
+        assert_eq!(conn.traffic(), Traffic {
+            bytes_read: frame(b"in").len() as u64,
+            bytes_written: conn.sock.written.len() as u64,
+            frames_written: 2,
+            dropped: 2,
+        });
+    }
+
+    #[test]
+    fn skipped_messages_are_counted_once_caught_up() {
+        let mut sock = MockTransport::new();
+        sock.push_write(WriteStep::WouldBlock);
+
+        let mut conn = Connection::new(sock, Token(0));
+        conn.send_message(Arc::new(b"slow".to_vec())).unwrap();
+        conn.skip_message();
+
+        conn.writable().unwrap();
+
+        let mut expected = frame(b"slow");
+        expected.extend_from_slice(&codec::encode_frame_header(codec::MISSED, 8));
+        expected.extend_from_slice(&1u64.to_be_bytes());
+        assert_eq!(conn.sock.written, expected);
+        assert!(!conn.interest.is_writable());
     }
 }
@@ -0,0 +1,178 @@
+//! State dumps, for working out afterwards why a connection got stuck.
+//!
+//! Once `dump_on_sigquit` has been called, `SIGQUIT` no longer stops the process. Instead, every
+//! server given a dump directory writes what it knows about itself and each of its connections
+//! to a JSON file there at its next tick, and carries on. See `Server::set_dump_dir`.
+//!
+//! Only what it takes to see where a connection is stuck goes in: interests, what is part read
+//! and part written, and the kind, stream and size of every queued frame. Payloads stay out.
+
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use libc;
+
+use logging::json_string;
+
+/// How many dumps have been asked for since the process started.
+static REQUESTS: AtomicUsize = AtomicUsize::new(0);
+
+/// A JSON value, built up by whatever is being dumped.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(u64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(&'static str, Json)>),
+}
+
+impl Json {
+    pub fn object(fields: Vec<(&'static str, Json)>) -> Json {
+        Json::Object(fields)
+    }
+
+    /// `value` as a string, by its `Debug` formatting.
+    pub fn debug<T: fmt::Debug>(value: T) -> Json {
+        Json::String(format!("{:?}", value))
+    }
+}
+
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Json::Null => f.write_str("null"),
+            Json::Bool(b) => write!(f, "{}", b),
+            Json::Number(n) => write!(f, "{}", n),
+            Json::String(ref s) => f.write_str(&json_string(s)),
+            Json::Array(ref values) => {
+                f.write_str("[")?;
+                for (i, value) in values.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(",")?;
+                    }
+                    write!(f, "{}", value)?;
+                }
+                f.write_str("]")
+            }
+            Json::Object(ref fields) => {
+                f.write_str("{")?;
+                for (i, &(name, ref value)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(",")?;
+                    }
+                    write!(f, "{}:{}", json_string(name), value)?;
+                }
+                f.write_str("}")
+            }
+        }
+    }
+}
+
+impl From<bool> for Json {
+    fn from(b: bool) -> Json {
+        Json::Bool(b)
+    }
+}
+
+impl From<u64> for Json {
+    fn from(n: u64) -> Json {
+        Json::Number(n)
+    }
+}
+
+impl From<usize> for Json {
+    fn from(n: usize) -> Json {
+        Json::Number(n as u64)
+    }
+}
+
+impl From<u16> for Json {
+    fn from(n: u16) -> Json {
+        Json::Number(n.into())
+    }
+}
+
+impl From<u8> for Json {
+    fn from(n: u8) -> Json {
+        Json::Number(n.into())
+    }
+}
+
+impl From<String> for Json {
+    fn from(s: String) -> Json {
+        Json::String(s)
+    }
+}
+
+impl<T: Into<Json>> From<Option<T>> for Json {
+    fn from(value: Option<T>) -> Json {
+        value.map(Into::into).unwrap_or(Json::Null)
+    }
+}
+
+impl<T: Into<Json>> From<Vec<T>> for Json {
+    fn from(values: Vec<T>) -> Json {
+        Json::Array(values.into_iter().map(Into::into).collect())
+    }
+}
+
+/// Ask every server with a dump directory to write a dump, as `SIGQUIT` does.
+pub fn request() {
+    REQUESTS.fetch_add(1, Ordering::SeqCst);
+}
+
+/// How many dumps have been asked for so far. A server dumps when this has gone up since it last
+/// looked.
+pub fn requests() -> usize {
+    REQUESTS.load(Ordering::SeqCst)
+}
+
+extern "C" fn request_dump(_: libc::c_int) {
+    request();
+}
+
+/// Have `SIGQUIT` ask for a dump, instead of stopping the process.
+pub fn dump_on_sigquit() -> io::Result<()> {
+    let handler = request_dump as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    if unsafe { libc::signal(libc::SIGQUIT, handler) } == libc::SIG_ERR {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Write `state` to the file `name` in `dir`, and return its path. The file only appears once it
+/// is whole, so nothing watching the directory reads half a dump.
+pub fn write(dir: &Path, name: &str, state: &Json) -> io::Result<PathBuf> {
+    let path = dir.join(name);
+    let partial = dir.join(format!(".{}.partial", name));
+
+    let mut file = File::create(&partial)?;
+    writeln!(file, "{}", state)?;
+    file.sync_all()?;
+    fs::rename(&partial, &path)?;
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Json;
+
+    #[test]
+    fn values_are_written_as_compact_json() {
+        let state = Json::object(vec![
+            ("token", 3usize.into()),
+            ("interest", Json::debug("a \"quoted\"\n")),
+            ("credit", None::<u64>.into()),
+            ("queued", vec![Json::object(vec![("kind", 1u8.into()), ("shared", true.into())])]
+                .into()),
+        ]);
+        assert_eq!(state.to_string(),
+                   r#"{"token":3,"interest":"\"a \\\"quoted\\\"\\n\"","credit":null,"#.to_string()
+                   + r#""queued":[{"kind":1,"shared":true}]}"#);
+    }
+}
@@ -0,0 +1,86 @@
+//! The process's limit on open file descriptors, and how much of it is in use.
+
+use std::fs;
+use std::io;
+
+use libc;
+
+/// How many file descriptors the process has open, and how many it may have.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FdUsage {
+    pub open: u64,
+
+    /// The soft limit, which is the one that makes `accept` fail.
+    pub limit: u64,
+}
+
+impl FdUsage {
+    /// Whether the process is close enough to its limit to start worrying about it.
+    pub fn is_near_limit(&self) -> bool {
+        self.open.saturating_mul(10) >= self.limit.saturating_mul(9)
+    }
+}
+
+/// The soft and hard limits on open file descriptors.
+pub fn limits() -> io::Result<(u64, u64)> {
+    let mut rlim = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut rlim) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok((rlim.rlim_cur, rlim.rlim_max))
+}
+
+/// Raise the soft limit to `want`, or as close to it as the hard limit allows. A soft limit that
+/// is already high enough is left alone.
+///
+/// Returns the soft limit afterwards.
+pub fn raise_limit(want: u64) -> io::Result<u64> {
+    let (soft, hard) = limits()?;
+    if soft >= want {
+        return Ok(soft);
+    }
+
+    let rlim = libc::rlimit {
+        rlim_cur: want.min(hard) as libc::rlim_t,
+        rlim_max: hard as libc::rlim_t,
+    };
+    if unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &rlim) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(rlim.rlim_cur)
+}
+
+/// How many file descriptors the process has open, counting the one used to find out.
+pub fn open_count() -> io::Result<u64> {
+    Ok(fs::read_dir("/proc/self/fd")?.count() as u64)
+}
+
+/// How many file descriptors are open, against the soft limit.
+pub fn usage() -> io::Result<FdUsage> {
+    Ok(FdUsage { open: open_count()?, limit: limits()?.0 })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{limits, raise_limit, usage, FdUsage};
+
+    #[test]
+    fn reports_usage_against_the_soft_limit() {
+        let usage = usage().unwrap();
+        assert!(usage.open >= 3);
+        assert_eq!(usage.limit, limits().unwrap().0);
+    }
+
+    #[test]
+    fn a_high_enough_limit_is_left_alone() {
+        let (soft, _) = limits().unwrap();
+        assert_eq!(raise_limit(soft / 2).unwrap(), soft);
+        assert_eq!(limits().unwrap().0, soft);
+    }
+
+    #[test]
+    fn near_limit_from_ninety_percent() {
+        assert!(!FdUsage { open: 89, limit: 100 }.is_near_limit());
+        assert!(FdUsage { open: 90, limit: 100 }.is_near_limit());
+    }
+}
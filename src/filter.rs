@@ -0,0 +1,185 @@
+//! Inbound filters that stop messages before they are broadcast.
+
+use std::str::{self, FromStr};
+
+use regex::bytes::Regex;
+
+/// What happens to a message a filter catches.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum Action {
+    /// Throw it away without telling anyone.
+    #[default]
+    Drop,
+
+    /// Throw it away and send the sender an `ERROR` frame saying why.
+    Reject,
+}
+
+impl FromStr for Action {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Action, String> {
+        match s {
+            "drop" => Ok(Action::Drop),
+            "reject" => Ok(Action::Reject),
+            _ => Err(format!("unknown filter action {}, expected drop or reject", s)),
+        }
+    }
+}
+
+/// The kind of payload a server accepts.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Content {
+    /// Valid UTF-8 only.
+    Text,
+
+    /// Anything that is not valid UTF-8.
+    Binary,
+}
+
+impl FromStr for Content {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Content, String> {
+        match s {
+            "text" => Ok(Content::Text),
+            "binary" => Ok(Content::Binary),
+            _ => Err(format!("unknown content {}, expected text or binary", s)),
+        }
+    }
+}
+
+/// How many messages each filter has caught.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FilterCounts {
+    pub too_large: u64,
+    pub denied: u64,
+    pub wrong_content: u64,
+}
+
+impl FilterCounts {
+    pub fn total(&self) -> u64 {
+        self.too_large + self.denied + self.wrong_content
+    }
+}
+
+/// A set of rules a message has to pass before it is broadcast. With no rules set, everything
+/// passes.
+///
+/// The rules look at the body of a message. The correlation id of a request or reply is not part
+/// of it.
+#[derive(Clone, Debug, Default)]
+pub struct Filters {
+    /// Bodies longer than this are caught. This is on top of the frame size limit.
+    pub max_len: Option<usize>,
+
+    /// Bodies containing any of these are caught.
+    pub deny_substrings: Vec<Vec<u8>>,
+
+    /// Bodies matching any of these are caught.
+    pub deny_patterns: Vec<Regex>,
+
+    /// Bodies of any other kind are caught.
+    pub content: Option<Content>,
+
+    /// What to do with a caught message.
+    pub action: Action,
+
+    counts: FilterCounts,
+}
+
+impl Filters {
+    pub fn new() -> Filters {
+        Filters::default()
+    }
+
+    /// Carry on counting from `old`'s counts, for rules that replace it.
+    pub fn carry_counts(&mut self, old: &Filters) {
+        self.counts = old.counts;
+    }
+
+    /// Whether there are no rules, so that every message passes.
+    pub fn is_empty(&self) -> bool {
+        self.max_len.is_none() && self.deny_substrings.is_empty() && self.deny_patterns.is_empty()
+            && self.content.is_none()
+    }
+
+    /// Check a message body against every rule, counting it if one catches it.
+    ///
+    /// Returns the reason the message was caught, suitable for an `ERROR` frame.
+    pub fn check(&mut self, body: &[u8]) -> Result<(), &'static str> {
+        if self.max_len.map(|max| body.len() > max).unwrap_or(false) {
+            self.counts.too_large += 1;
+            return Err("Payload too large");
+        }
+
+        let denied = self.deny_substrings.iter().any(|s| contains(body, s))
+            || self.deny_patterns.iter().any(|p| p.is_match(body));
+        if denied {
+            self.counts.denied += 1;
+            return Err("Payload denied");
+        }
+
+        let reason = match self.content {
+            Some(Content::Text) if !is_text(body) => "Payload is not valid UTF-8",
+            Some(Content::Binary) if is_text(body) => "Payload is not binary",
+            _ => return Ok(()),
+        };
+        self.counts.wrong_content += 1;
+        Err(reason)
+    }
+
+    /// How many messages have been caught so far.
+    pub fn counts(&self) -> FilterCounts {
+        self.counts
+    }
+}
+
+/// Whether `body` is valid UTF-8.
+pub fn is_text(body: &[u8]) -> bool {
+    str::from_utf8(body).is_ok()
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    needle.is_empty() || haystack.windows(needle.len()).any(|w| w == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use regex::bytes::Regex;
+
+    use super::{Content, FilterCounts, Filters};
+
+    #[test]
+    fn passes_everything_without_rules() {
+        let mut filters = Filters::new();
+        assert_eq!(filters.check(b"anything \xff"), Ok(()));
+        assert_eq!(filters.counts().total(), 0);
+    }
+
+    #[test]
+    fn catches_and_counts_by_rule() {
+        let mut filters = Filters::new();
+        filters.max_len = Some(8);
+        filters.deny_substrings.push(b"spam".to_vec());
+        filters.deny_patterns.push(Regex::new(r"^\d+$").unwrap());
+        filters.content = Some(Content::Text);
+
+        assert_eq!(filters.check(b"hello"), Ok(()));
+        assert_eq!(filters.check(b"much too long"), Err("Payload too large"));
+        assert_eq!(filters.check(b"eat spam"), Err("Payload denied"));
+        assert_eq!(filters.check(b"12345"), Err("Payload denied"));
+        assert_eq!(filters.check(b"\xff\xfe"), Err("Payload is not valid UTF-8"));
+
+        assert_eq!(filters.counts(), FilterCounts { too_large: 1, denied: 2, wrong_content: 1 });
+    }
+
+    #[test]
+    fn binary_content_catches_text() {
+        let mut filters = Filters::new();
+        filters.content = Some(Content::Binary);
+
+        assert_eq!(filters.check(b"\x00\xff"), Ok(()));
+        assert_eq!(filters.check(b"plain"), Err("Payload is not binary"));
+    }
+}
@@ -0,0 +1,59 @@
+//! mob-grpc: let gRPC services publish and subscribe through a running mob server.
+//!
+//! Every `Chat` call on the gateway becomes a client of the server at `--addr`. Built only with
+//! the `grpc` feature.
+//!
+//! ```text
+//! mob-grpc --listen 127.0.0.1:50051 --addr 127.0.0.1:8000
+//! ```
+
+extern crate mob_grpc;
+
+use std::env;
+use std::net::{SocketAddr, TcpListener};
+use std::process;
+
+struct Options {
+    listen: SocketAddr,
+    addr: SocketAddr,
+}
+
+fn usage() -> ! {
+    eprintln!("usage: mob-grpc [--listen host:port] [--addr host:port]");
+    process::exit(2);
+}
+
+fn parse_options() -> Options {
+    let mut opts = Options {
+        listen: "127.0.0.1:50051".parse().unwrap(),
+        addr: "127.0.0.1:8000".parse().unwrap(),
+    };
+
+    let mut args = env::args().skip(1);
+    while let Some(flag) = args.next() {
+        let value = args.next().unwrap_or_else(|| usage());
+        match flag.as_str() {
+            "--listen" => opts.listen = value.parse().unwrap_or_else(|_| usage()),
+            "--addr" => opts.addr = value.parse().unwrap_or_else(|_| usage()),
+            _ => usage(),
+        }
+    }
+
+    opts
+}
+
+fn main() {
+    let opts = parse_options();
+
+    let listener = TcpListener::bind(opts.listen).unwrap_or_else(|e| {
+        eprintln!("Failed to bind {}: {}", opts.listen, e);
+        process::exit(1);
+    });
+
+    println!("gRPC gateway on {}, bridging to mob at {}", opts.listen, opts.addr);
+
+    if let Err(e) = mob_grpc::serve(listener, opts.addr) {
+        eprintln!("Gateway failed: {}", e);
+        process::exit(1);
+    }
+}
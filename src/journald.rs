@@ -0,0 +1,146 @@
+//! Sending log records straight to the systemd journal, with fields to filter them by.
+//!
+//! Records are sent over the journal's native protocol, one datagram each. Besides the message,
+//! its priority and where it was logged from, fields are taken from the message itself, for the
+//! messages written the way most of mob's are: what happened, a `;`, then `key=value` pairs.
+//!
+//! ```text
+//! reset connection; token=Token(5) peer=127.0.0.1:50412
+//! ```
+//!
+//! gives `EVENT=reset connection`, `TOKEN=5` and `PEER=127.0.0.1:50412`, so
+//! `journalctl TOKEN=5` shows what happened to that connection.
+
+use std::env;
+use std::io;
+use std::mem;
+use std::os::unix::net::UnixDatagram;
+use std::path::Path;
+
+use libc;
+use log::LogLevel;
+
+/// Where journald listens for native messages.
+pub const DEFAULT_SOCKET: &str = "/run/systemd/journal/socket";
+
+/// Whether stderr is connected to the journal, which is what systemd does for services that do
+/// not say otherwise.
+pub fn is_stderr_journal() -> bool {
+    let stream = match env::var("JOURNAL_STREAM") {
+        Ok(stream) => stream,
+        Err(_) => return false,
+    };
+
+    // It names the device and inode of the stream, which stderr may since have been moved off.
+    let mut stat: libc::stat = unsafe { mem::zeroed() };
+    if unsafe { libc::fstat(libc::STDERR_FILENO, &mut stat) } != 0 {
+        return false;
+    }
+    stream == format!("{}:{}", stat.st_dev, stat.st_ino)
+}
+
+/// The syslog priority of a log level, which the journal uses too.
+fn priority(level: LogLevel) -> u8 {
+    match level {
+        LogLevel::Error => 3,
+        LogLevel::Warn => 4,
+        LogLevel::Info => 6,
+        LogLevel::Debug | LogLevel::Trace => 7,
+    }
+}
+
+/// A connection to journald.
+pub struct Journald {
+    socket: UnixDatagram,
+    identifier: String,
+}
+
+impl Journald {
+    /// Send to the journal listening at `path`, usually `DEFAULT_SOCKET`.
+    pub fn connect<P: AsRef<Path>>(path: P) -> io::Result<Journald> {
+        let socket = UnixDatagram::unbound()?;
+        socket.connect(path)?;
+        Ok(Journald { socket, identifier: "mob".to_string() })
+    }
+
+    /// Send one message at `level`, logged from `module` at `file` and `line`.
+    pub fn send(&self, level: LogLevel, module: &str, file: &str, line: u32, message: &str)
+        -> io::Result<()>
+    {
+        let mut fields = vec![
+            ("MESSAGE", message.to_string()),
+            ("PRIORITY", priority(level).to_string()),
+            ("SYSLOG_IDENTIFIER", self.identifier.clone()),
+            ("CODE_MODULE", module.to_string()),
+            ("CODE_FILE", file.to_string()),
+            ("CODE_LINE", line.to_string()),
+        ];
+        fields.extend(message_fields(message));
+
+        self.socket.send(&encode(&fields)).map(|_| ())
+    }
+}
+
+/// The fields a message written as `event; key=value ...` carries. Only `token`, `peer` and the
+/// event are taken, the rest stay in the message.
+fn message_fields(message: &str) -> Vec<(&'static str, String)> {
+    let (event, pairs) = match message.find("; ") {
+        Some(at) => (&message[..at], &message[at + 2..]),
+        None => return Vec::new(),
+    };
+
+    let mut fields = vec![("EVENT", event.to_string())];
+    for pair in pairs.split_whitespace() {
+        let mut parts = pair.splitn(2, '=');
+        match (parts.next(), parts.next()) {
+            (Some("token"), Some(value)) => {
+                let token = value.trim_start_matches("Token(").trim_end_matches(')');
+                fields.push(("TOKEN", token.to_string()));
+            }
+            (Some("peer"), Some(value)) => fields.push(("PEER", value.to_string())),
+            _ => {}
+        }
+    }
+    fields
+}
+
+/// Fields in the native protocol. Values with a newline in them are sent with their length
+/// instead of ending at the newline.
+fn encode(fields: &[(&str, String)]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    for &(name, ref value) in fields {
+        buf.extend_from_slice(name.as_bytes());
+        if value.contains('\n') {
+            buf.push(b'\n');
+            buf.extend_from_slice(&(value.len() as u64).to_le_bytes());
+        } else {
+            buf.push(b'=');
+        }
+        buf.extend_from_slice(value.as_bytes());
+        buf.push(b'\n');
+    }
+    buf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{encode, message_fields};
+
+    #[test]
+    fn fields_are_taken_from_the_message() {
+        let fields = message_fields("accepted connection; token=Token(5) peer=127.0.0.1:9 x=1");
+        assert_eq!(fields, vec![
+            ("EVENT", "accepted connection".to_string()),
+            ("TOKEN", "5".to_string()),
+            ("PEER", "127.0.0.1:9".to_string()),
+        ]);
+
+        assert!(message_fields("Server run loop starting...").is_empty());
+    }
+
+    #[test]
+    fn multiline_values_are_sent_with_their_length() {
+        let buf = encode(&[("PRIORITY", "4".to_string()), ("MESSAGE", "a\nb".to_string())]);
+        assert_eq!(buf, b"PRIORITY=4\nMESSAGE\n\x03\0\0\0\0\0\0\0a\nb\n".to_vec());
+    }
+}
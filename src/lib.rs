@@ -0,0 +1,40 @@
+//! mob is a multi-echo server built on top of the mio async-io library.
+//!
+//! The `mob-server` binary is a thin wrapper around `Server`. Programs that want to embed mob, or
+//! set up the listening socket themselves, can construct a `Server` directly.
+
+extern crate crossbeam_queue;
+extern crate libc;
+extern crate mio;
+extern crate mob_client;
+extern crate regex;
+extern crate slab;
+
+#[macro_use] extern crate log;
+
+pub mod server;
+pub mod connection;
+pub mod buffer;
+pub mod transport;
+pub mod limit;
+pub mod filter;
+pub mod fd;
+pub mod telnet;
+pub mod shard;
+pub mod metrics;
+pub mod logging;
+pub mod syslog;
+pub mod journald;
+pub mod admin;
+pub mod watchdog;
+pub mod supervisor;
+pub mod dump;
+pub mod resolve;
+pub mod push;
+pub mod spill;
+
+pub use mob_client::codec;
+pub use mob_client::format;
+
+#[cfg(test)]
+mod sim;
@@ -0,0 +1,251 @@
+//! Per source IP limits on how fast new connections are accepted, and a server wide limit on
+//! how fast messages are broadcast.
+
+use std::cmp;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+/// How long connection attempts are counted over.
+const WINDOW: Duration = Duration::from_secs(60);
+
+/// How fast one source IP may connect, and what happens when it connects faster.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AcceptLimit {
+    /// Connection attempts allowed from one IP per minute.
+    pub per_minute: u32,
+
+    /// How long an IP that went over the limit has its new connections closed for.
+    pub ban: Duration,
+}
+
+struct Attempts {
+    // when the current one minute window started
+    window_start: Instant,
+
+    // attempts seen in the current window
+    count: u32,
+
+    // when the ban ends, if the IP is banned
+    banned_until: Option<Instant>,
+}
+
+/// Counts connection attempts per source IP and decides which ones to accept.
+pub struct AcceptLimiter {
+    limit: AcceptLimit,
+    attempts: HashMap<IpAddr, Attempts>,
+}
+
+impl AcceptLimiter {
+    pub fn new(limit: AcceptLimit) -> AcceptLimiter {
+        AcceptLimiter {
+            limit,
+            attempts: HashMap::new(),
+        }
+    }
+
+    /// The limit this enforces.
+    pub fn limit(&self) -> AcceptLimit {
+        self.limit
+    }
+
+    /// Record a connection attempt from `ip` and return whether it should be accepted.
+    ///
+    /// Attempts made while banned are not counted, so a ban ends on time however hard the IP
+    /// keeps trying.
+    pub fn allow(&mut self, ip: IpAddr, now: Instant) -> bool {
+        let limit = self.limit;
+        let attempts = self.attempts.entry(ip).or_insert(Attempts {
+            window_start: now,
+            count: 0,
+            banned_until: None,
+        });
+
+        match attempts.banned_until {
+            Some(until) if now < until => return false,
+            Some(_) => {
+                attempts.banned_until = None;
+                attempts.window_start = now;
+                attempts.count = 0;
+            }
+            None => {},
+        }
+
+        if now.duration_since(attempts.window_start) >= WINDOW {
+            attempts.window_start = now;
+            attempts.count = 0;
+        }
+
+        attempts.count += 1;
+        if attempts.count > limit.per_minute {
+            warn!("{} connected more than {} times a minute, banning it for {:?}",
+                  ip, limit.per_minute, limit.ban);
+            attempts.banned_until = Some(now + limit.ban);
+            return false;
+        }
+
+        true
+    }
+
+    /// Forget the IPs whose window and ban are both over, so a flood from many addresses does
+    /// not grow the map forever. The server calls this from its maintenance tick.
+    pub fn sweep(&mut self, now: Instant) {
+        self.attempts.retain(|_, a| {
+            a.banned_until.map(|until| now < until).unwrap_or(false)
+                || now.duration_since(a.window_start) < WINDOW
+        });
+    }
+}
+
+/// Limits how many bytes the server broadcasts per second, across every connection.
+///
+/// Up to a second's worth may go out in a burst. A message that takes more than is left still
+/// goes out, and the debt is paid back before anything else can.
+pub struct ThroughputLimiter {
+    // bytes allowed per second, which is also the most that can be saved up
+    per_second: u64,
+
+    // bytes that can go out now. Negative when the last message overdrew it
+    available: i64,
+
+    // when `available` was last topped up
+    refilled_at: Instant,
+}
+
+impl ThroughputLimiter {
+    pub fn new(per_second: u64, now: Instant) -> ThroughputLimiter {
+        ThroughputLimiter {
+            per_second,
+            available: per_second as i64,
+            refilled_at: now,
+        }
+    }
+
+    /// Whether anything more may go out at `now`.
+    pub fn has_room(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        self.available > 0
+    }
+
+    /// Count `bytes` that went out at `now` against the limit.
+    pub fn take(&mut self, bytes: u64, now: Instant) {
+        self.refill(now);
+        self.available = self.available.saturating_sub(bytes as i64);
+    }
+
+    /// When there will next be room, if there is none now.
+    pub fn room_at(&self) -> Option<Instant> {
+        if self.available > 0 || self.per_second == 0 {
+            return None;
+        }
+
+        let short = (1 - self.available) as u64;
+        let nanos = short.saturating_mul(1_000_000_000) / self.per_second;
+        Some(self.refilled_at + Duration::from_nanos(nanos))
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.refilled_at);
+        let earned = (elapsed.as_nanos() * self.per_second as u128 / 1_000_000_000) as i64;
+        if earned == 0 {
+            return;
+        }
+
+        // Only move the clock on by the time that was paid for, so fractions are not lost.
+        let paid = earned as u128 * 1_000_000_000 / self.per_second as u128;
+        self.refilled_at += Duration::from_nanos(paid as u64);
+        self.available = cmp::min(self.available.saturating_add(earned), self.per_second as i64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+    use std::time::{Duration, Instant};
+
+    use super::{AcceptLimit, AcceptLimiter, ThroughputLimiter, WINDOW};
+
+    fn limiter() -> AcceptLimiter {
+        AcceptLimiter::new(AcceptLimit { per_minute: 3, ban: Duration::from_secs(10) })
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn bans_an_ip_over_the_limit() {
+        let mut limiter = limiter();
+        let now = Instant::now();
+
+        for _ in 0..3 {
+            assert!(limiter.allow(ip("10.0.0.1"), now));
+        }
+        assert!(!limiter.allow(ip("10.0.0.1"), now));
+
+        // Other addresses are not affected.
+        assert!(limiter.allow(ip("10.0.0.2"), now));
+
+        // Still banned just before the ban ends, however often it tries.
+        let almost = now + Duration::from_secs(9);
+        for _ in 0..10 {
+            assert!(!limiter.allow(ip("10.0.0.1"), almost));
+        }
+
+        assert!(limiter.allow(ip("10.0.0.1"), now + Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn counts_start_again_each_window() {
+        let mut limiter = limiter();
+        let now = Instant::now();
+
+        for _ in 0..3 {
+            assert!(limiter.allow(ip("10.0.0.1"), now));
+        }
+        for _ in 0..3 {
+            assert!(limiter.allow(ip("10.0.0.1"), now + WINDOW));
+        }
+    }
+
+    #[test]
+    fn forgets_quiet_ips() {
+        let mut limiter = limiter();
+        let now = Instant::now();
+
+        limiter.allow(ip("10.0.0.1"), now);
+        limiter.allow(ip("10.0.0.2"), now + WINDOW);
+        limiter.sweep(now + WINDOW);
+
+        assert_eq!(limiter.attempts.len(), 1);
+    }
+
+    #[test]
+    fn throughput_allows_a_burst_then_waits() {
+        let now = Instant::now();
+        let mut limiter = ThroughputLimiter::new(1000, now);
+
+        assert!(limiter.has_room(now));
+        limiter.take(600, now);
+        assert!(limiter.has_room(now));
+        limiter.take(600, now);
+        assert!(!limiter.has_room(now));
+
+        // 200 bytes overdrawn, so there is room again once 201 more are earned.
+        let at = limiter.room_at().unwrap();
+        assert_eq!(at, now + Duration::from_millis(201));
+        assert!(!limiter.has_room(now + Duration::from_millis(200)));
+        assert!(limiter.has_room(at));
+        assert_eq!(limiter.room_at(), None);
+    }
+
+    #[test]
+    fn throughput_saves_up_at_most_a_second() {
+        let now = Instant::now();
+        let mut limiter = ThroughputLimiter::new(1000, now);
+
+        let later = now + Duration::from_secs(10);
+        limiter.take(1000, later);
+        assert!(!limiter.has_room(later));
+    }
+}
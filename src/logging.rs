@@ -0,0 +1,549 @@
+//! The logger `mob-server` installs, whose filter can be changed while it runs.
+//!
+//! Filters are written the way `RUST_LOG` is for `env_logger`: a comma separated list of
+//! `module=level` directives, where a bare level applies to every module and a bare module turns
+//! on everything for it. A record is let through by the directive with the longest module that
+//! it falls under. Without any directives, only errors are logged.
+//!
+//! Records go to stderr, to a `LogFile` that rotates itself when it gets too big or too old and
+//! can be reopened on `SIGHUP` after something else has moved it, to syslog, or to the systemd
+//! journal. What goes to stderr or a file is formatted as `Format` says: plain lines like
+//! `env_logger` writes, JSON objects for log pipelines, or aligned and colored for people.
+
+use std::cmp::Reverse;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use libc;
+use log::{self, Log, LogLevel, LogLevelFilter, LogMetadata, LogRecord, MaxLogLevelFilter};
+
+use journald::Journald;
+use syslog::Syslog;
+
+/// How many times the process has had `SIGHUP`, once `reopen_on_sighup` is called. A log file
+/// reopens itself when this has gone up since it last looked.
+static SIGHUPS: AtomicUsize = AtomicUsize::new(0);
+
+/// Which records are logged.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Filter {
+    // the level for each module, and for every module if there is no module. Longest module first
+    directives: Vec<(Option<String>, LogLevelFilter)>,
+}
+
+impl Filter {
+    /// Log `module`, and the modules under it, up to `level` from now on. Without a module, this
+    /// is the level for every module that has none of its own.
+    pub fn set(&mut self, module: Option<&str>, level: LogLevelFilter) {
+        let module = module.map(|m| m.to_string());
+        self.directives.retain(|d| d.0 != module);
+        self.directives.push((module, level));
+        self.directives.sort_by_key(|d| Reverse(d.0.as_ref().map_or(0, |m| m.len())));
+    }
+
+    /// Whether a record at `level` from `target` is logged.
+    pub fn enabled(&self, level: log::LogLevel, target: &str) -> bool {
+        let directive = self.directives.iter().find(|d| match d.0 {
+            Some(ref module) => {
+                target.starts_with(module.as_str())
+                    && (target.len() == module.len() || target[module.len()..].starts_with("::"))
+            }
+            None => true,
+        });
+        level <= directive.map_or(LogLevelFilter::Error, |d| d.1)
+    }
+
+    /// The most verbose level any module is logged at.
+    pub fn max_level(&self) -> LogLevelFilter {
+        self.directives.iter().map(|d| d.1).max().unwrap_or(LogLevelFilter::Error)
+    }
+}
+
+impl FromStr for Filter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Filter, String> {
+        let mut filter = Filter::default();
+        for directive in s.split(',').map(|d| d.trim()).filter(|d| !d.is_empty()) {
+            let mut parts = directive.splitn(2, '=');
+            let first = parts.next().unwrap();
+            match parts.next() {
+                Some(level) => {
+                    let level = level.parse()
+                        .map_err(|_| format!("unknown log level {}", level))?;
+                    filter.set(Some(first), level);
+                }
+                None => match first.parse() {
+                    Ok(level) => filter.set(None, level),
+                    Err(_) => filter.set(Some(first), LogLevelFilter::Trace),
+                },
+            }
+        }
+        Ok(filter)
+    }
+}
+
+impl fmt::Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let directives = self.directives.iter().rev().map(|d| match d.0 {
+            Some(ref module) => format!("{}={}", module, d.1.to_string().to_lowercase()),
+            None => d.1.to_string().to_lowercase(),
+        }).collect::<Vec<_>>();
+        if directives.is_empty() {
+            return write!(f, "error");
+        }
+        write!(f, "{}", directives.join(","))
+    }
+}
+
+/// When a log file is moved aside and a new one started.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Rotation {
+    /// Rotate before the file grows past this many bytes.
+    pub max_bytes: Option<u64>,
+
+    /// Rotate once the file has been written to for this long.
+    pub max_age: Option<Duration>,
+
+    /// How many rotated files to keep, as `<path>.1` for the newest up to `<path>.<keep>`. Older
+    /// ones are deleted. With none kept, rotating starts the file over.
+    pub keep: usize,
+}
+
+/// A file records are appended to, which rotates itself as `Rotation` says.
+pub struct LogFile {
+    path: PathBuf,
+    rotation: Rotation,
+    file: File,
+
+    // bytes in the file, when we opened it, and `SIGHUPS` as it was then
+    len: u64,
+    opened_at: Instant,
+    sighups: usize,
+}
+
+impl LogFile {
+    /// Append to the file at `path`, creating it if there is none.
+    pub fn open<P: AsRef<Path>>(path: P, rotation: Rotation) -> io::Result<LogFile> {
+        let path = path.as_ref().to_path_buf();
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let len = file.metadata()?.len();
+        let sighups = SIGHUPS.load(Ordering::SeqCst);
+        Ok(LogFile { path, rotation, file, len, opened_at: Instant::now(), sighups })
+    }
+
+    /// Write `line`, first reopening the file if `SIGHUP` asked for it and rotating it if it is
+    /// due.
+    pub fn write_line(&mut self, line: &str) -> io::Result<()> {
+        if SIGHUPS.load(Ordering::SeqCst) != self.sighups {
+            self.reopen()?;
+        }
+
+        let too_big = self.rotation.max_bytes
+            .map(|max| self.len > 0 && self.len + line.len() as u64 > max)
+            .unwrap_or(false);
+        let too_old = self.rotation.max_age
+            .map(|max| self.opened_at.elapsed() >= max)
+            .unwrap_or(false);
+        if too_big || too_old {
+            self.rotate()?;
+        }
+
+        self.file.write_all(line.as_bytes())?;
+        self.len += line.len() as u64;
+        Ok(())
+    }
+
+    /// Open the file at our path again, for when it was moved or deleted from under us.
+    pub fn reopen(&mut self) -> io::Result<()> {
+        *self = LogFile::open(&self.path, self.rotation)?;
+        Ok(())
+    }
+
+    /// Shift the rotated files up by one, dropping the oldest, and start a new file.
+    fn rotate(&mut self) -> io::Result<()> {
+        if self.rotation.keep == 0 {
+            fs::remove_file(&self.path).or_else(ignore_not_found)?;
+        }
+        for n in (1..self.rotation.keep + 1).rev() {
+            let from = if n == 1 { self.path.clone() } else { self.rotated(n - 1) };
+            fs::rename(&from, self.rotated(n)).or_else(ignore_not_found)?;
+        }
+        self.reopen()
+    }
+
+    fn rotated(&self, n: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", n));
+        PathBuf::from(path)
+    }
+}
+
+fn ignore_not_found(e: io::Error) -> io::Result<()> {
+    if e.kind() == ErrorKind::NotFound { Ok(()) } else { Err(e) }
+}
+
+extern "C" fn request_reopen(_: libc::c_int) {
+    SIGHUPS.fetch_add(1, Ordering::SeqCst);
+}
+
+/// Have log files reopen themselves when the process gets `SIGHUP`, which is how `logrotate` and
+/// the like say they have moved the file.
+pub fn reopen_on_sighup() -> io::Result<()> {
+    let handler = request_reopen as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    if unsafe { libc::signal(libc::SIGHUP, handler) } == libc::SIG_ERR {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// How records written to stderr or a file look.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum Format {
+    /// `LEVEL:module: message`, as `env_logger` writes them.
+    #[default]
+    Plain,
+
+    /// One JSON object a line, with `time`, `level`, `module` and `message`.
+    Json,
+
+    /// The time of day, level and module in columns, then the connection the message is about,
+    /// if it says, as `#<token>`. Levels are colored when the output is a terminal.
+    Pretty,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Format, String> {
+        match s {
+            "plain" => Ok(Format::Plain),
+            "json" => Ok(Format::Json),
+            "pretty" => Ok(Format::Pretty),
+            _ => Err(format!("unknown log format {}, expected plain, json or pretty", s)),
+        }
+    }
+}
+
+impl Format {
+    /// A record as a line of this format, ending in a newline.
+    fn line(self, level: LogLevel, module: &str, message: &fmt::Arguments, at: SystemTime,
+            color: bool)
+        -> String
+    {
+        match self {
+            Format::Plain => format!("{}:{}: {}\n", level, module, message),
+            Format::Json => {
+                format!(r#"{{"time":"{}","level":"{}","module":{},"message":{}}}"#,
+                        timestamp(at), level, json_string(module),
+                        json_string(&message.to_string())) + "\n"
+            }
+            Format::Pretty => {
+                let message = message.to_string();
+                let connection = connection_of(&message).map(|t| format!("#{}", t));
+                let (on, dim, off) = if color {
+                    (level_color(level), "\x1b[2m", "\x1b[0m")
+                } else {
+                    ("", "", "")
+                };
+                format!("{} {}{:<5}{} {}{:<20}{} {:>4} {}\n",
+                        &timestamp(at)[11..23], on, level, off, dim, module, off,
+                        connection.unwrap_or_default(), message)
+            }
+        }
+    }
+}
+
+/// The token of the connection a message of the form `what happened; token=Token(5) ...` is
+/// about.
+fn connection_of(message: &str) -> Option<&str> {
+    let at = message.find("token=Token(")? + "token=Token(".len();
+    message[at..].split(')').next()
+}
+
+fn level_color(level: LogLevel) -> &'static str {
+    match level {
+        LogLevel::Error => "\x1b[31m",
+        LogLevel::Warn => "\x1b[33m",
+        LogLevel::Info => "\x1b[32m",
+        LogLevel::Debug => "\x1b[34m",
+        LogLevel::Trace => "\x1b[35m",
+    }
+}
+
+/// `s` as a quoted JSON string.
+pub(crate) fn json_string(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// `at` as an RFC 3339 timestamp in UTC, to the microsecond.
+pub fn timestamp(at: SystemTime) -> String {
+    let since_epoch = match at.duration_since(UNIX_EPOCH) {
+        Ok(since) => since,
+        Err(_) => return "-".to_string(),
+    };
+    let secs = since_epoch.as_secs();
+    let (year, month, day) = civil_from_days((secs / 86_400) as i64);
+    let secs_of_day = secs % 86_400;
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:06}Z",
+            year, month, day, secs_of_day / 3600, secs_of_day / 60 % 60, secs_of_day % 60,
+            since_epoch.subsec_micros())
+}
+
+/// The year, month and day of the proleptic Gregorian calendar that is `days` after 1970-01-01.
+/// This is Howard Hinnant's `civil_from_days`.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = (if mp < 10 { mp + 3 } else { mp - 9 }) as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// Where records are written.
+pub enum Output {
+    Stderr,
+    File(LogFile),
+    Syslog(Syslog),
+    Journald(Journald),
+}
+
+impl Output {
+    fn write(&mut self, record: &LogRecord, format: Format) -> io::Result<()> {
+        let module = record.location().module_path();
+        match *self {
+            Output::Syslog(ref syslog) => {
+                return syslog.send(record.level(), module, record.args());
+            }
+            Output::Journald(ref journald) => {
+                let location = record.location();
+                let message = record.args().to_string();
+                return journald.send(record.level(), module, location.file(), location.line(),
+                                     &message);
+            }
+            _ => {}
+        }
+
+        let now = SystemTime::now();
+        match *self {
+            Output::File(ref mut file) => {
+                file.write_line(&format.line(record.level(), module, record.args(), now, false))
+            }
+            _ => {
+                let color = unsafe { libc::isatty(libc::STDERR_FILENO) } == 1;
+                let line = format.line(record.level(), module, record.args(), now, color);
+                io::stderr().write_all(line.as_bytes())
+            }
+        }
+    }
+}
+
+// what the installed logger and every handle to it share
+struct Shared {
+    filter: RwLock<Filter>,
+    max_level: MaxLogLevelFilter,
+}
+
+/// Writes records that pass the filter to its output, the same way `env_logger` does.
+struct Logger {
+    shared: Arc<Shared>,
+    output: Mutex<Output>,
+    format: Format,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &LogMetadata) -> bool {
+        self.shared.filter.read().unwrap().enabled(metadata.level(), metadata.target())
+    }
+
+    fn log(&self, record: &LogRecord) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        if let Err(e) = self.output.lock().unwrap().write(record, self.format) {
+            // There is nowhere else to say so.
+            let _ = writeln!(io::stderr(), "Failed to write log, {:?}: {}:{}: {}", e,
+                             record.level(), record.location().module_path(), record.args());
+        }
+    }
+}
+
+/// Changes the filter of the installed logger.
+#[derive(Clone)]
+pub struct LogHandle {
+    shared: Arc<Shared>,
+}
+
+impl LogHandle {
+    /// The filter records are logged through now.
+    pub fn filter(&self) -> Filter {
+        self.shared.filter.read().unwrap().clone()
+    }
+
+    /// Log `module` up to `level` from now on, or every module without a level of its own if
+    /// `module` is `None`. Returns the filter as it now is.
+    pub fn set_level(&self, module: Option<&str>, level: LogLevelFilter) -> Filter {
+        let mut filter = self.shared.filter.write().unwrap();
+        filter.set(module, level);
+        self.shared.max_level.set(filter.max_level());
+        filter.clone()
+    }
+
+    /// Log through `filter` from now on.
+    pub fn set_filter(&self, filter: Filter) {
+        self.shared.max_level.set(filter.max_level());
+        *self.shared.filter.write().unwrap() = filter;
+    }
+}
+
+/// Install the logger with `filter`, writing to `output` in `format`. This fails if a logger is
+/// already installed.
+pub fn init(filter: Filter, output: Output, format: Format)
+    -> Result<LogHandle, log::SetLoggerError>
+{
+    let mut handle = None;
+    log::set_logger(|max_level| {
+        max_level.set(filter.max_level());
+        let shared = Arc::new(Shared { filter: RwLock::new(filter), max_level });
+        handle = Some(LogHandle { shared: shared.clone() });
+        Box::new(Logger { shared, output: Mutex::new(output), format })
+    })?;
+    Ok(handle.unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::process;
+    use std::sync::atomic::Ordering;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use log::{LogLevel, LogLevelFilter};
+
+    use super::{timestamp, Filter, Format, LogFile, Rotation, SIGHUPS};
+
+    /// An empty directory of its own for a test to put log files in.
+    fn log_dir(test: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("mob-logging-{}-{}", process::id(), test));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn the_longest_matching_module_decides() {
+        let filter: Filter = "info,mob::connection=trace,mob=warn".parse().unwrap();
+
+        assert!(filter.enabled(LogLevel::Trace, "mob::connection"));
+        assert!(filter.enabled(LogLevel::Trace, "mob::connection::inner"));
+        assert!(!filter.enabled(LogLevel::Info, "mob::server"));
+        assert!(filter.enabled(LogLevel::Warn, "mob::server"));
+        assert!(!filter.enabled(LogLevel::Info, "mob::connections"));
+        assert!(filter.enabled(LogLevel::Info, "mio::poll"));
+        assert_eq!(filter.max_level(), LogLevelFilter::Trace);
+    }
+
+    #[test]
+    fn only_errors_are_logged_without_directives() {
+        let filter: Filter = "".parse().unwrap();
+        assert!(filter.enabled(LogLevel::Error, "mob"));
+        assert!(!filter.enabled(LogLevel::Warn, "mob"));
+        assert_eq!(filter.to_string(), "error");
+    }
+
+    #[test]
+    fn setting_a_level_replaces_the_one_for_that_module() {
+        let mut filter: Filter = "mob=debug".parse().unwrap();
+        filter.set(Some("mob"), LogLevelFilter::Trace);
+        filter.set(None, LogLevelFilter::Warn);
+        assert_eq!(filter.to_string(), "warn,mob=trace");
+
+        // A bare module is everything from it.
+        assert_eq!("mob".parse::<Filter>().unwrap().to_string(), "mob=trace");
+        assert!("mob=loud".parse::<Filter>().is_err());
+    }
+
+    #[test]
+    fn timestamps_are_rfc3339_in_utc() {
+        let at = UNIX_EPOCH + Duration::new(951_827_696, 5_000);
+        assert_eq!(timestamp(at), "2000-02-29T12:34:56.000005Z");
+        assert_eq!(timestamp(UNIX_EPOCH), "1970-01-01T00:00:00.000000Z");
+    }
+
+    #[test]
+    fn records_are_formatted_as_asked() {
+        let at = UNIX_EPOCH + Duration::new(951_827_696, 5_000_000);
+        let line = |format: Format, color| {
+            let message = format_args!("slow event; token=Token(5) took=\"{}\"", 1);
+            format.line(LogLevel::Warn, "mob::server", &message, at, color)
+        };
+
+        assert_eq!(line(Format::Plain, false),
+                   "WARN:mob::server: slow event; token=Token(5) took=\"1\"\n");
+        assert_eq!(line(Format::Json, false),
+                   concat!(r#"{"time":"2000-02-29T12:34:56.005000Z","level":"WARN","#,
+                           r#""module":"mob::server","#,
+                           r#""message":"slow event; token=Token(5) took=\"1\""}"#, "\n"));
+        assert_eq!(line(Format::Pretty, false),
+                   concat!("12:34:56.005 WARN  mob::server            #5 ",
+                           "slow event; token=Token(5) took=\"1\"\n"));
+        assert!(line(Format::Pretty, true).contains("\x1b[33mWARN \x1b[0m"));
+    }
+
+    #[test]
+    fn files_rotate_when_they_would_get_too_big() {
+        let path = log_dir("rotate").join("mob.log");
+        let rotation = Rotation { max_bytes: Some(10), max_age: None, keep: 2 };
+        let mut file = LogFile::open(&path, rotation).unwrap();
+        for line in &["first\n", "second\n", "third\n", "fourth\n"] {
+            file.write_line(line).unwrap();
+        }
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "fourth\n");
+        assert_eq!(fs::read_to_string(path.with_extension("log.1")).unwrap(), "third\n");
+        assert_eq!(fs::read_to_string(path.with_extension("log.2")).unwrap(), "second\n");
+        assert!(!path.with_extension("log.3").exists());
+    }
+
+    #[test]
+    fn files_are_reopened_when_asked() {
+        let path = log_dir("reopen").join("mob.log");
+        let mut file = LogFile::open(&path, Rotation::default()).unwrap();
+        file.write_line("before\n").unwrap();
+
+        // As logrotate would, move the file away and then signal.
+        fs::rename(&path, path.with_extension("old")).unwrap();
+        SIGHUPS.fetch_add(1, Ordering::SeqCst);
+        file.write_line("after\n").unwrap();
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "after\n");
+        assert_eq!(fs::read_to_string(path.with_extension("old")).unwrap(), "before\n");
+    }
+}
@@ -1,30 +1,895 @@
-extern crate byteorder;
 extern crate mio;
-extern crate slab;
+extern crate mob;
 
-#[macro_use] extern crate log;
-extern crate env_logger;
+use std::cmp;
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::net::{SocketAddr, TcpListener, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
-mod server;
-mod connection;
+use mio::Poll;
 
-use std::net::SocketAddr;
+use mob::admin::{self, Admin, Role};
+use mob::codec::{Endian, Welcome, MAX_PAYLOAD_LEN};
+use mob::connection::{Coalesce, FragmentLimits, Framing, KeepAlive};
+use mob::dump;
+use mob::fd;
+use mob::filter::Filters;
+use mob::format;
+use mob::journald::{self, Journald};
+use mob::limit::AcceptLimit;
+use mob::logging::{self, Filter, Format, LogFile, Output, Rotation};
+use mob::push::{self, Target};
+use mob::resolve::{resolve, Family};
+use mob::server::*;
+use mob::shard;
+use mob::spill::Spill;
+use mob::supervisor::{self, Backoff};
+use mob::syslog::{self, Facility, Syslog};
+use mob::transport::Listener;
 
-use mio::Poll;
-use mio::net::TcpListener;
+const USAGE: &str = "\
+usage: mob-server [options]
+
+options:
+    --bind <addr>      listen on host:port, the host a name or an address, resolved once at
+                       startup [default: 127.0.0.1:8000]
+    --family <family>  any, ipv4 or ipv6, the addresses of --bind to use [default: any]
+    --mode <mode>      broadcast sends each message to every client, echo only back to
+                       its sender [default: broadcast]
+    --max-accepts <n>  connections one IP may open per minute before it is banned
+                       [default: unlimited]
+    --ban <secs>       how long a banned IP has its connections closed for [default: 60]
+    --text             reject payloads that are not valid UTF-8
+    --payload-format <fmt>
+                       reject payloads that are not envelopes in raw, json or msgpack
+    --receipts         tell the sender of each message how many clients it was queued for
+    --greeting         tell every client what the server supports as it connects
+    --endian <order>   big or little, the byte order of length headers until a client asks
+                       for another [default: big]
+    --framing <kind>   length for length headers, cobs for zero delimited COBS frames or
+                       text for lines, which telnet can talk. The last two carry messages
+                       only [default: length]
+    --coalesce <ms>    hold small messages back this long so several go out in one write
+    --coalesce-bytes <n>
+                       write held messages as soon as this many bytes are waiting
+                       [default: 1400]
+    --raise-fd-limit   raise the open file limit if it is too low for --max-connections
+    --memory-limit <n> bytes connections may buffer between them before the ones furthest
+                       behind are closed [default: unlimited]
+    --high-watermark <n>
+                       bytes queued for a client before broadcasts skip it
+                       [default: unlimited]
+    --spill-dir <path> spill broadcasts for a client over --high-watermark to a file in path
+                       and send them as it catches up, instead of skipping them
+    --spill-max-bytes <n>
+                       bytes a client's spill file may grow to before broadcasts skip it
+                       [default: 1073741824]
+    --max-throughput <n>
+                       bytes broadcast per second, counted once per recipient, before
+                       reads are paused [default: unlimited]
+    --read-budget <n>  messages read from a client per event before the others get a turn
+                       [default: unlimited]
+    --max-fragmented <n>
+                       fragmented messages a client may be part way through sending
+                       before it is closed [default: 16]
+    --max-fragmented-bytes <n>
+                       bytes of those messages a client may have sent before it is closed
+                       [default: 268435456]
+    --fragment-timeout <secs>
+                       how long a fragmented message may wait for its next piece before
+                       its sender is closed [default: 30]
+    --peer <addr>      federate with the mob server at host:port, passing messages both
+                       ways, may be repeated
+    --peer-secret-file <path>
+                       federate only with servers that know the secret in path, which
+                       --peer needs
+    --relay <addr>     send every message on to the server at host:port instead of
+                       broadcasting it
+    --relay-connections <n>
+                       connections kept open to the --relay server [default: 4]
+    --slow-event <percent>
+                       warn when one connection takes more than this share of a poll's
+                       handling [default: never]
+    --watchdog <secs>  log an error when the poll loop goes this long without ticking
+                       [default: never]
+    --keep-alive <secs>
+                       ping connections that have been quiet this long, and reset those that
+                       miss too many pings in a row [default: never]
+    --keep-alive-misses <n>
+                       how many pings in a row a connection may miss [default: 3]
+    --handshake-timeout <secs>
+                       close new connections that send no message this long [default: never]
+    --metrics-push <url>
+                       push the metrics in the Prometheus text format to this http:// URL,
+                       such as a Pushgateway's /metrics/job/mob
+    --metrics-push-interval <secs>
+                       how often to push the metrics [default: 15]
+    --admin <addr>     answer admin commands, such as loglevel, on host:port
+    --admin-http <addr>
+                       serve per-connection figures over HTTP on host:port, at
+                       /connections.csv and /connections.json
+    --admin-secret-file <path>
+                       require the secret in path before admin commands are run
+    --admin-read-secret-file <path>
+                       take the secret in path too, for commands that only look
+    --dump-dir <path>  on SIGQUIT, write the state of the server and its connections to a
+                       JSON file in path, and carry on
+    --supervise        run the server as a child process, and restart it with the same
+                       listening socket if it crashes
+    --check-config     check these options, and what they need such as the log file, then
+                       exit with 1 if the server would not start
+
+logging, to stderr unless a file is given:
+    --log-format <fmt>     plain, json, or pretty for aligned and colored lines, on stderr or
+                           in the file [default: plain]
+    --log-file <path>      append to path instead, and reopen it on SIGHUP
+    --log-max-bytes <n>    rotate the file before it grows past n bytes [default: never]
+    --log-max-age <secs>   rotate the file once it is this old [default: never]
+    --log-keep <n>         rotated files to keep as path.1 to path.n [default: 5]
+    --syslog               send to the local syslog daemon instead
+    --syslog-addr <addr>   send to the syslog collector at host:port over UDP instead
+    --syslog-facility <name>
+                           daemon, user, local0 to local7 and so on [default: daemon]
+    --journald             send to the systemd journal instead, which is the default when
+                           stderr is connected to it
+
+welcome, a frame sent to every client as it connects:
+    --welcome          send one, even if none of the options below are given
+    --name <name>      the server name it gives [default: mob]
+    --motd <text>      a message of the day
+    --heartbeat <ms>   how often clients should ping when quiet [default: never]
+
+capacities, allocated up front, and for each shard:
+    --shards <n>           servers to run on threads of their own, each with its share of
+                           the connections [default: 1]
+    --max-connections <n>  connections accepted at once [default: 128]
+    --events <n>           events handled per poll [default: 1024]
+    --queue-capacity <n>   messages each connection has room to queue [default: 32]
+    --write-batch <n>      most bytes written to a connection at a time [default: 65536]
+
+filters, which drop matching messages unless --filter-action is reject:
+    --max-payload <n>         payloads longer than n bytes
+    --deny <text>             payloads containing text, may be repeated
+    --deny-pattern <regex>    payloads matching regex, may be repeated
+    --content <text|binary>   payloads of the other kind
+    --filter-action <action>  drop, or reject with an error to the sender [default: drop]";
 
-use server::*;
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    process::exit(2);
+}
+
+/// The flags `reload` on the admin socket may give, which are those `Settings` covers. The
+/// others only take effect with a restart.
+const RELOADABLE: &[&str] = &[
+    "--max-accepts", "--ban", "--text", "--payload-format", "--receipts", "--greeting",
+    "--coalesce", "--coalesce-bytes", "--memory-limit", "--high-watermark", "--max-throughput",
+    "--read-budget", "--max-fragmented", "--max-fragmented-bytes", "--fragment-timeout",
+    "--slow-event", "--welcome", "--name", "--motd", "--heartbeat", "--max-payload", "--deny",
+    "--deny-pattern", "--content", "--filter-action",
+];
+
+fn parse<T: ::std::str::FromStr>(flag: &str, value: Option<String>) -> Result<T, String> {
+    let value = value.ok_or_else(|| format!("{} needs a value", flag))?;
+    value.parse().map_err(|_| format!("invalid value for {}: {}", flag, value))
+}
+
+/// What the command line asked for.
+struct Options {
+    bind: String,
+    family: Family,
+    mode: Mode,
+    settings: Settings,
+    endian: Endian,
+    framing: Framing,
+    capacities: Capacities,
+    raise_fd_limit: bool,
+    peers: Vec<SocketAddr>,
+    peer_secret_file: Option<PathBuf>,
+    relay: Option<Relay>,
+    shards: usize,
+    watchdog: Option<Duration>,
+    keep_alive: Option<Duration>,
+    keep_alive_misses: u32,
+    handshake_timeout: Option<Duration>,
+    spill_dir: Option<PathBuf>,
+    spill_max_bytes: u64,
+    metrics_push: Option<Target>,
+    metrics_push_interval: Duration,
+    admin: Option<SocketAddr>,
+    admin_http: Option<SocketAddr>,
+    admin_secret_file: Option<PathBuf>,
+    admin_read_secret_file: Option<PathBuf>,
+    dump_dir: Option<PathBuf>,
+    supervise: bool,
+    check_config: bool,
+    log_file: Option<PathBuf>,
+    log_rotation: Rotation,
+    syslog: Option<Option<SocketAddr>>,
+    syslog_facility: Facility,
+    journald: bool,
+    log_format: Format,
+
+    // every flag given, in order
+    flags: Vec<String>,
+}
+
+/// Parse `args` and check they make sense together, returning every problem found rather than
+/// stopping at the first.
+fn parse_options<I: IntoIterator<Item = String>>(args: I) -> Result<Options, Vec<String>> {
+    let mut bind = "127.0.0.1:8000".to_string();
+    let mut family = Family::default();
+    let mut mode = Mode::default();
+    let mut max_accepts = None;
+    let mut ban = Duration::from_secs(60);
+    let mut text_only = false;
+    let mut payload_format = None;
+    let mut receipts = false;
+    let mut greeting = false;
+    let mut endian = Endian::default();
+    let mut framing = Framing::default();
+    let mut filters = Filters::new();
+    let mut coalesce_delay = None;
+    let mut coalesce_bytes = 1400;
+    let mut capacities = Capacities::default();
+    let mut raise_fd_limit = false;
+    let mut memory_limit = None;
+    let mut throughput_limit = None;
+    let mut high_watermark = None;
+    let mut read_budget = None;
+    let mut fragment_limits = FragmentLimits::default();
+    let mut welcome = Welcome::default();
+    let mut send_welcome = false;
+    let mut peers = Vec::new();
+    let mut peer_secret_file = None;
+    let mut relay_addr = None;
+    let mut relay_connections = 4;
+    let mut shards = 1;
+    let mut slow_event_share = None;
+    let mut watchdog = None;
+    let mut keep_alive = None;
+    let mut keep_alive_misses = 3;
+    let mut handshake_timeout = None;
+    let mut spill_dir = None;
+    let mut spill_max_bytes = 1 << 30;
+    let mut metrics_push = None;
+    let mut metrics_push_interval = Duration::from_secs(15);
+    let mut admin = None;
+    let mut admin_http = None;
+    let mut admin_secret_file = None;
+    let mut admin_read_secret_file = None;
+    let mut dump_dir = None;
+    let mut supervise = false;
+    let mut check_config = false;
+    let mut log_file = None;
+    let mut log_rotation = Rotation { keep: 5, ..Rotation::default() };
+    let mut syslog = None;
+    let mut syslog_facility = Facility::default();
+    let mut journald = false;
+    let mut log_format = Format::default();
+    let mut flags = Vec::new();
+    let mut errors = Vec::new();
+
+    // Flags may be given their value after an `=` too, as in `--log-format=pretty`.
+    let mut args = args.into_iter().flat_map(|arg| {
+        match arg.find('=') {
+            Some(at) if arg.starts_with("--") => {
+                vec![arg[..at].to_string(), arg[at + 1..].to_string()]
+            }
+            _ => vec![arg],
+        }
+    });
+    while let Some(arg) = args.next() {
+        let parsed = match arg.as_str() {
+            "--bind" => parse(&arg, args.next()).map(|addr| bind = addr),
+            "--family" => parse(&arg, args.next()).map(|f| family = f),
+            "--mode" => parse(&arg, args.next()).map(|m| mode = m),
+            "--max-accepts" => parse(&arg, args.next()).map(|n| max_accepts = Some(n)),
+            "--ban" => parse(&arg, args.next()).map(|secs| ban = Duration::from_secs(secs)),
+            "--text" => {
+                text_only = true;
+                Ok(())
+            }
+            "--payload-format" => {
+                parse::<String>(&arg, args.next()).and_then(|name| {
+                    let format = format::by_name(&name).ok_or_else(|| {
+                        format!("unknown payload format {}, expected raw, json or msgpack", name)
+                    })?;
+                    payload_format = Some(format);
+                    Ok(())
+                })
+            }
+            "--receipts" => {
+                receipts = true;
+                Ok(())
+            }
+            "--greeting" => {
+                greeting = true;
+                Ok(())
+            }
+            "--endian" => parse(&arg, args.next()).map(|e| endian = e),
+            "--framing" => parse(&arg, args.next()).map(|f| framing = f),
+            "--coalesce" => {
+                parse(&arg, args.next()).map(|ms| coalesce_delay = Some(Duration::from_millis(ms)))
+            }
+            "--coalesce-bytes" => parse(&arg, args.next()).map(|n| coalesce_bytes = n),
+            "--raise-fd-limit" => {
+                raise_fd_limit = true;
+                Ok(())
+            }
+            "--memory-limit" => parse(&arg, args.next()).map(|n| memory_limit = Some(n)),
+            "--high-watermark" => parse(&arg, args.next()).map(|n| high_watermark = Some(n)),
+            "--spill-dir" => parse(&arg, args.next()).map(|path| spill_dir = Some(path)),
+            "--spill-max-bytes" => parse(&arg, args.next()).map(|n| spill_max_bytes = n),
+            "--max-throughput" => parse(&arg, args.next()).map(|n| throughput_limit = Some(n)),
+            "--read-budget" => parse(&arg, args.next()).map(|n| read_budget = Some(n)),
+            "--max-fragmented" => parse(&arg, args.next()).map(|n| fragment_limits.messages = n),
+            "--max-fragmented-bytes" => {
+                parse(&arg, args.next()).map(|n| fragment_limits.bytes = n)
+            }
+            "--fragment-timeout" => {
+                parse(&arg, args.next())
+                    .map(|secs| fragment_limits.timeout = Duration::from_secs(secs))
+            }
+            "--peer" => parse(&arg, args.next()).map(|addr| peers.push(addr)),
+            "--peer-secret-file" => {
+                parse(&arg, args.next()).map(|path| peer_secret_file = Some(path))
+            }
+            "--relay" => parse(&arg, args.next()).map(|addr| relay_addr = Some(addr)),
+            "--relay-connections" => parse(&arg, args.next()).map(|n| relay_connections = n),
+            "--shards" => parse(&arg, args.next()).map(|n| shards = n),
+            "--slow-event" => parse(&arg, args.next()).map(|n| slow_event_share = Some(n)),
+            "--watchdog" => {
+                parse(&arg, args.next()).map(|secs| watchdog = Some(Duration::from_secs(secs)))
+            }
+            "--keep-alive" => {
+                parse(&arg, args.next()).map(|secs| keep_alive = Some(Duration::from_secs(secs)))
+            }
+            "--keep-alive-misses" => parse(&arg, args.next()).map(|n| keep_alive_misses = n),
+            "--handshake-timeout" => {
+                parse(&arg, args.next())
+                    .map(|secs| handshake_timeout = Some(Duration::from_secs(secs)))
+            }
+            "--metrics-push" => {
+                parse::<String>(&arg, args.next()).and_then(|url| url.parse())
+                    .map(|target| metrics_push = Some(target))
+            }
+            "--metrics-push-interval" => {
+                parse(&arg, args.next())
+                    .map(|secs| metrics_push_interval = Duration::from_secs(secs))
+            }
+            "--admin" => parse(&arg, args.next()).map(|addr| admin = Some(addr)),
+            "--admin-http" => parse(&arg, args.next()).map(|addr| admin_http = Some(addr)),
+            "--admin-secret-file" => {
+                parse(&arg, args.next()).map(|path| admin_secret_file = Some(path))
+            }
+            "--admin-read-secret-file" => {
+                parse(&arg, args.next()).map(|path| admin_read_secret_file = Some(path))
+            }
+            "--dump-dir" => parse(&arg, args.next()).map(|path| dump_dir = Some(path)),
+            "--supervise" => {
+                supervise = true;
+                Ok(())
+            }
+            "--check-config" => {
+                check_config = true;
+                Ok(())
+            }
+            "--log-file" => parse(&arg, args.next()).map(|path| log_file = Some(path)),
+            "--log-max-bytes" => {
+                parse(&arg, args.next()).map(|n| log_rotation.max_bytes = Some(n))
+            }
+            "--log-max-age" => {
+                parse(&arg, args.next())
+                    .map(|secs| log_rotation.max_age = Some(Duration::from_secs(secs)))
+            }
+            "--log-keep" => parse(&arg, args.next()).map(|n| log_rotation.keep = n),
+            "--syslog" => {
+                syslog = Some(None);
+                Ok(())
+            }
+            "--syslog-addr" => parse(&arg, args.next()).map(|addr| syslog = Some(Some(addr))),
+            "--syslog-facility" => parse(&arg, args.next()).map(|f| syslog_facility = f),
+            "--journald" => {
+                journald = true;
+                Ok(())
+            }
+            "--log-format" => parse(&arg, args.next()).map(|f| log_format = f),
+            "--welcome" => {
+                send_welcome = true;
+                Ok(())
+            }
+            "--name" => {
+                parse(&arg, args.next()).map(|name| {
+                    welcome.name = name;
+                    send_welcome = true;
+                })
+            }
+            "--motd" => {
+                parse(&arg, args.next()).map(|motd| {
+                    welcome.motd = motd;
+                    send_welcome = true;
+                })
+            }
+            "--heartbeat" => {
+                parse(&arg, args.next()).map(|ms| {
+                    welcome.heartbeat = Some(Duration::from_millis(ms));
+                    send_welcome = true;
+                })
+            }
+            "--max-connections" => parse(&arg, args.next()).map(|n| capacities.connections = n),
+            "--events" => parse(&arg, args.next()).map(|n| capacities.events = n),
+            "--queue-capacity" => parse(&arg, args.next()).map(|n| capacities.send_queue = n),
+            "--write-batch" => parse(&arg, args.next()).map(|n| capacities.write_batch = n),
+            "--max-payload" => parse(&arg, args.next()).map(|n| filters.max_len = Some(n)),
+            "--deny" => {
+                parse::<String>(&arg, args.next())
+                    .map(|text| filters.deny_substrings.push(text.into_bytes()))
+            }
+            "--deny-pattern" => {
+                parse(&arg, args.next()).map(|pattern| filters.deny_patterns.push(pattern))
+            }
+            "--content" => parse(&arg, args.next()).map(|c| filters.content = Some(c)),
+            "--filter-action" => parse(&arg, args.next()).map(|a| filters.action = a),
+            _ => Err(format!("unknown argument {}", arg)),
+        };
+        if let Err(e) = parsed {
+            errors.push(e);
+        }
+        flags.push(arg);
+    }
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    // Clients should hear about the limit the filters enforce, if it is the lower one.
+    let max_payload = filters.max_len.map_or(MAX_PAYLOAD_LEN, |max| max.min(MAX_PAYLOAD_LEN));
+    welcome.max_payload = max_payload as u64;
+
+    let opts = Options {
+        bind,
+        family,
+        mode,
+        settings: Settings {
+            accept_limit: max_accepts.map(|per_minute| AcceptLimit { per_minute, ban }),
+            text_only,
+            payload_format,
+            receipts,
+            greeting,
+            welcome: if send_welcome { Some(welcome) } else { None },
+            filters,
+            coalesce: coalesce_delay.map(|delay| Coalesce { delay, max_bytes: coalesce_bytes }),
+            memory_limit,
+            throughput_limit,
+            high_watermark,
+            read_budget,
+            fragment_limits,
+            slow_event_share,
+        },
+        endian,
+        framing,
+        capacities,
+        raise_fd_limit,
+        peers,
+        peer_secret_file,
+        relay: relay_addr.map(|addr| Relay { addr, connections: relay_connections }),
+        shards,
+        watchdog,
+        keep_alive,
+        keep_alive_misses,
+        handshake_timeout,
+        spill_dir,
+        spill_max_bytes,
+        metrics_push,
+        metrics_push_interval,
+        admin,
+        admin_http,
+        admin_secret_file,
+        admin_read_secret_file,
+        dump_dir,
+        supervise,
+        check_config,
+        log_file,
+        log_rotation,
+        syslog,
+        syslog_facility,
+        journald,
+        log_format,
+        flags,
+    };
+
+    let problems = check(&opts);
+    if problems.is_empty() {
+        Ok(opts)
+    } else {
+        Err(problems)
+    }
+}
+
+/// What is wrong with options that each parsed, but do not make sense together.
+fn check(opts: &Options) -> Vec<String> {
+    let mut problems = Vec::new();
+    let settings = &opts.settings;
+    let capacities = opts.capacities;
+
+    if capacities.connections == 0 || capacities.events == 0 || capacities.write_batch == 0 {
+        problems.push("--max-connections, --events and --write-batch must be more than 0"
+            .to_string());
+    }
+
+    if opts.admin.is_none() && opts.admin_http.is_none()
+        && (opts.admin_secret_file.is_some() || opts.admin_read_secret_file.is_some())
+    {
+        problems.push("admin secrets need --admin or --admin-http".to_string());
+    }
+    if !opts.peers.is_empty() && opts.peer_secret_file.is_none() {
+        problems.push("--peer needs --peer-secret-file, with the secret the peers share"
+            .to_string());
+    }
+
+    if settings.read_budget == Some(0) {
+        problems.push("--read-budget must be at least 1".to_string());
+    }
+    if opts.keep_alive == Some(Duration::from_secs(0)) || opts.keep_alive_misses == 0 {
+        problems.push("--keep-alive and --keep-alive-misses must be at least 1".to_string());
+    }
+    if opts.handshake_timeout == Some(Duration::from_secs(0)) {
+        problems.push("--handshake-timeout must be at least 1".to_string());
+    }
+    if opts.spill_dir.is_some() && settings.high_watermark.is_none() {
+        problems.push("--spill-dir needs --high-watermark".to_string());
+    }
+    if opts.metrics_push_interval == Duration::from_secs(0) {
+        problems.push("--metrics-push-interval must be at least 1".to_string());
+    }
+    if settings.throughput_limit == Some(0) {
+        problems.push("--max-throughput must be more than 0".to_string());
+    }
+    if settings.coalesce.is_some_and(|c| c.max_bytes == 0) {
+        problems.push("--coalesce-bytes must be more than 0".to_string());
+    }
+    if let (Some(high), Some(limit)) = (settings.high_watermark, settings.memory_limit) {
+        if high > limit {
+            problems.push(format!("--high-watermark {} is over --memory-limit {}, so it never \
+                                   takes effect", high, limit));
+        }
+    }
+    problems
+}
+
+fn parse_args() -> Options {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.iter().any(|arg| arg == "-h" || arg == "--help") {
+        println!("{}", USAGE);
+        process::exit(0);
+    }
+
+    let check_only = args.iter().any(|arg| arg == "--check-config");
+    parse_options(args).unwrap_or_else(|errors| {
+        if check_only {
+            for e in errors {
+                eprintln!("error: {}", e);
+            }
+            process::exit(1);
+        }
+        for e in errors {
+            eprintln!("{}", e);
+        }
+        usage();
+    })
+}
+
+/// Whether `path` can be appended to, or created if it does not exist yet.
+fn check_writable(path: &Path) -> io::Result<()> {
+    match OpenOptions::new().append(true).open(path) {
+        Ok(_) => Ok(()),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+            let dir = match path.parent() {
+                Some(dir) if !dir.as_os_str().is_empty() => dir,
+                _ => Path::new("."),
+            };
+            if fs::metadata(dir)?.permissions().readonly() {
+                return Err(io::ErrorKind::PermissionDenied.into());
+            }
+            Ok(())
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// The admin secrets in the files `opts` names, each with the role it grants.
+fn admin_secrets(opts: &Options) -> Result<Vec<(Role, String)>, String> {
+    let files = [(Role::Operator, &opts.admin_secret_file),
+                 (Role::ReadOnly, &opts.admin_read_secret_file)];
+    let mut secrets = Vec::new();
+    for &(role, path) in &files {
+        let path = match *path {
+            Some(ref path) => path,
+            None => continue,
+        };
+        secrets.push((role, read_secret(path, "admin")?));
+    }
+    Ok(secrets)
+}
+
+/// The secret federated servers share, from the file `opts` names, if it names one.
+fn peer_secret(opts: &Options) -> Result<Option<String>, String> {
+    opts.peer_secret_file.as_ref().map(|path| read_secret(path, "peer")).transpose()
+}
+
+/// The secret in the file at `path`, the `what` secret in errors.
+fn read_secret(path: &Path, what: &str) -> Result<String, String> {
+    let secret = fs::read_to_string(path)
+        .map_err(|e| format!("cannot read {} secret {}: {}", what, path.display(), e))?;
+
+    // Editors leave a newline at the end.
+    let secret = secret.trim_end_matches(['\r', '\n']);
+    if secret.is_empty() {
+        return Err(format!("{} secret {} is empty", what, path.display()));
+    }
+    Ok(secret.to_string())
+}
+
+/// Check what the options need from outside the process, for `--check-config`, printing what is
+/// wrong to stderr. Returns whether the server would start.
+fn check_config(opts: &Options) -> bool {
+    let mut errors = Vec::new();
+    let mut warnings = Vec::new();
+
+    if let Err(e) = env::var("RUST_LOG").unwrap_or_default().parse::<Filter>() {
+        errors.push(format!("invalid RUST_LOG: {}", e));
+    }
+    if let Some(ref path) = opts.log_file {
+        if let Err(e) = check_writable(path) {
+            errors.push(format!("cannot write to log file {}: {}", path.display(), e));
+        }
+    }
+    let connected = match opts.syslog {
+        Some(Some(addr)) => Syslog::udp(addr, opts.syslog_facility).map(|_| ()),
+        Some(None) => Syslog::unix(syslog::DEFAULT_SOCKET, opts.syslog_facility).map(|_| ()),
+        None if opts.journald => Journald::connect(journald::DEFAULT_SOCKET).map(|_| ()),
+        None => Ok(()),
+    };
+    if let Some(ref dir) = opts.dump_dir {
+        match fs::metadata(dir) {
+            Ok(ref meta) if meta.is_dir() && !meta.permissions().readonly() => {}
+            Ok(_) => errors.push(format!("dump directory {} is not a writable directory",
+                                         dir.display())),
+            Err(e) => errors.push(format!("cannot use dump directory {}: {}", dir.display(), e)),
+        }
+    }
+    if let Some(ref dir) = opts.spill_dir {
+        match fs::metadata(dir) {
+            Ok(ref meta) if meta.is_dir() && !meta.permissions().readonly() => {}
+            Ok(_) => errors.push(format!("spill directory {} is not a writable directory",
+                                         dir.display())),
+            Err(e) => errors.push(format!("cannot use spill directory {}: {}", dir.display(), e)),
+        }
+    }
+    if let Err(e) = connected {
+        errors.push(format!("cannot connect to the log collector: {}", e));
+    }
+    if let Err(e) = resolve(&opts.bind, opts.family) {
+        errors.push(e.to_string());
+    }
+    if let Some(ref target) = opts.metrics_push {
+        if let Err(e) = (target.host.as_str(), target.port).to_socket_addrs() {
+            errors.push(format!("cannot resolve the metrics push host {}: {}", target.host, e));
+        }
+    }
+    match admin_secrets(opts) {
+        Ok(ref secrets) if secrets.is_empty() => {
+            if let Some(addr) = opts.admin.filter(|addr| !addr.ip().is_loopback()) {
+                warnings.push(format!("anyone who can reach the admin socket on {} may run \
+                                       every command, see --admin-secret-file", addr));
+            }
+            if let Some(addr) = opts.admin_http.filter(|addr| !addr.ip().is_loopback()) {
+                warnings.push(format!("anyone who can reach {} may see every connection, see \
+                                       --admin-secret-file", addr));
+            }
+        }
+        Ok(_) => {}
+        Err(e) => errors.push(e),
+    }
+    if let Err(e) = peer_secret(opts) {
+        errors.push(e);
+    }
+
+    let capacities = opts.capacities;
+    let needed = (capacities.connections * cmp::max(opts.shards, 1)) as u64;
+    match fd::limits() {
+        Ok((_, hard)) if needed > hard => {
+            warnings.push(format!("{} connections need more file descriptors than the hard \
+                                   limit of {}", needed, hard));
+        }
+        Ok((soft, _)) if needed > soft && !opts.raise_fd_limit => {
+            warnings.push(format!("{} connections need more file descriptors than the limit of \
+                                   {}, see --raise-fd-limit", needed, soft));
+        }
+        Ok(_) => {}
+        Err(e) => warnings.push(format!("cannot read the file descriptor limit: {}", e)),
+    }
+    if capacities.events < capacities.connections {
+        warnings.push(format!("{} events per poll is fewer than the {} connections allowed",
+                              capacities.events, capacities.connections));
+    }
+
+    for warning in &warnings {
+        eprintln!("warning: {}", warning);
+    }
+    for e in &errors {
+        eprintln!("error: {}", e);
+    }
+    errors.is_empty()
+}
+
+/// Settings for `reload` on the admin socket, from flags given as on the command line. Those
+/// left out go back to their defaults.
+fn reload(args: Vec<String>) -> Result<Settings, Vec<String>> {
+    let opts = parse_options(args)?;
+    let fixed: Vec<_> = opts.flags.iter()
+        .filter(|flag| !RELOADABLE.contains(&flag.as_str()))
+        .map(|flag| format!("{} cannot change without a restart", flag))
+        .collect();
+    if !fixed.is_empty() {
+        return Err(fixed);
+    }
+    Ok(opts.settings)
+}
+
+/// Apply the command line to a server, or to each shard of one, with the peer secret read from
+/// the file it names.
+fn configure<L: Listener>(server: &mut Server<L>, opts: &Options, peer_secret: &Option<String>) {
+    if let Err(e) = server.set_capacities(opts.capacities) {
+        eprintln!("{}", e);
+        usage();
+    }
+    server.set_mode(opts.mode);
+    server.apply_settings(opts.settings.clone());
+    server.set_raise_fd_limit(opts.raise_fd_limit);
+    server.set_endian(opts.endian);
+    server.set_framing(opts.framing);
+    server.set_peers(opts.peers.clone());
+    server.set_peer_secret(peer_secret.clone());
+    server.set_relay(opts.relay);
+    server.set_watchdog(opts.watchdog);
+    server.set_keep_alive(opts.keep_alive.map(|interval| {
+        KeepAlive { interval, misses: opts.keep_alive_misses }
+    }));
+    server.set_handshake_timeout(opts.handshake_timeout);
+    server.set_spill(opts.spill_dir.clone().map(|dir| {
+        Spill { dir, max_bytes: opts.spill_max_bytes }
+    }));
+    server.set_dump_dir(opts.dump_dir.clone());
+}
+
+/// Push what `server` counts to the target in `push`, if it has not been taken yet. Shards share
+/// one registry, so the first of them to get here pushes for all of them.
+fn start_push<L: Listener>(server: &Server<L>, push: &Mutex<Option<Target>>, every: Duration) {
+    let target = push.lock().unwrap_or_else(|e| e.into_inner()).take();
+    if let Some(target) = target {
+        push::spawn(server.metrics(), target, every).expect("Failed to start pushing metrics");
+    }
+}
 
 fn main() {
+    let opts = parse_args();
+    if opts.check_config {
+        if !check_config(&opts) {
+            process::exit(1);
+        }
+        println!("configuration ok");
+        return;
+    }
 
     // Before doing anything, let us register a logger. The mio library has really good logging
     // at the _trace_ and _debug_ levels. Having a logger setup is invaluable when trying to
     // figure out why something is not working correctly.
-    env_logger::init().expect("Failed to init logger");
+    let filter: Filter = env::var("RUST_LOG").unwrap_or_default().parse().unwrap_or_else(|e| {
+        eprintln!("invalid RUST_LOG: {}", e);
+        process::exit(2);
+    });
+    let output = match (&opts.log_file, opts.syslog) {
+        (_, Some(addr)) => {
+            let syslog = match addr {
+                Some(addr) => Syslog::udp(addr, opts.syslog_facility),
+                None => Syslog::unix(syslog::DEFAULT_SOCKET, opts.syslog_facility),
+            };
+            Output::Syslog(syslog.unwrap_or_else(|e| {
+                eprintln!("Failed to connect to syslog: {}", e);
+                process::exit(1);
+            }))
+        }
+        (Some(path), None) => {
+            let file = LogFile::open(path, opts.log_rotation).unwrap_or_else(|e| {
+                eprintln!("Failed to open {}: {}", path.display(), e);
+                process::exit(1);
+            });
+            logging::reopen_on_sighup().expect("Failed to handle SIGHUP");
+            Output::File(file)
+        }
+        (None, None) if opts.journald || journald::is_stderr_journal() => {
+            Output::Journald(Journald::connect(journald::DEFAULT_SOCKET).unwrap_or_else(|e| {
+                eprintln!("Failed to connect to the journal: {}", e);
+                process::exit(1);
+            }))
+        }
+        (None, None) => Output::Stderr,
+    };
+    let log = logging::init(filter, output, opts.log_format).expect("Failed to init logger");
+
+    let inherited = supervisor::inherited_listener().expect("Failed to inherit listening socket");
+    let sock = inherited.unwrap_or_else(|| {
+        let addrs = resolve(&opts.bind, opts.family).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            process::exit(1);
+        });
+        TcpListener::bind(&addrs[..]).unwrap_or_else(|e| {
+            eprintln!("Failed to bind {}: {}", opts.bind, e);
+            process::exit(1);
+        })
+    });
+
+    // The child does everything else, given the same command line.
+    if opts.supervise {
+        let args: Vec<_> = env::args_os().skip(1).filter(|arg| arg != "--supervise").collect();
+        let status = supervisor::supervise(&sock, &args, Backoff::default())
+            .expect("Failed to supervise server");
+        process::exit(status.code().unwrap_or(1));
+    }
+
+    if opts.dump_dir.is_some() {
+        dump::dump_on_sigquit().expect("Failed to handle SIGQUIT");
+    }
+
+    // Every server hands the admin socket an `Announcer`, for `announce` to reach it.
+    let admin = if opts.admin.is_some() || opts.admin_http.is_some() {
+        let mut admin = Admin::new();
+        let secrets = admin_secrets(&opts).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            process::exit(1);
+        });
+        for (role, secret) in secrets {
+            admin.set_secret(role, Some(secret));
+        }
+        admin.set_log(Some(log));
+        let reload: Arc<admin::Reload> = Arc::new(reload);
+        admin.set_reload(Some(reload));
+        if let Some(addr) = opts.admin {
+            let listener = TcpListener::bind(addr).expect("Failed to bind admin address");
+            admin::spawn(listener, admin.clone()).expect("Failed to start admin socket");
+        }
+        if let Some(addr) = opts.admin_http {
+            let listener = TcpListener::bind(addr).expect("Failed to bind admin HTTP address");
+            admin::spawn_http(listener, admin.clone()).expect("Failed to start admin HTTP");
+        }
+        Some(admin)
+    } else {
+        None
+    };
+
+    let peer_secret = peer_secret(&opts).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1);
+    });
 
-    let addr = "127.0.0.1:8000".parse::<SocketAddr>()
-        .expect("Failed to parse host:port string");
-    let sock = TcpListener::bind(&addr).expect("Failed to bind address");
+    let metrics_push = Mutex::new(opts.metrics_push.clone());
+    if opts.shards > 1 {
+        let shards = opts.shards;
+        shard::run(sock, shards, move |server| {
+            configure(server, &opts, &peer_secret);
+            if let Some(ref admin) = admin {
+                admin.add_announcer(server.announcer());
+            }
+            start_push(server, &metrics_push, opts.metrics_push_interval);
+        }).expect("Failed to run server");
+        return;
+    }
 
     // Create a polling object that will be used by the server to receive events
     let mut poll = Poll::new().expect("Failed to create Poll");
@@ -33,6 +898,11 @@ fn main() {
     // the details of how registering works inside of the `Server` object. One reason I
     // really like this is to get around having to have `const SERVER = Token(0)` at the top of my
     // file. It also keeps our polling options inside `Server`.
-    let mut server = Server::new(sock);
+    let mut server = Server::from_listener(sock).expect("Failed to create server");
+    configure(&mut server, &opts, &peer_secret);
+    if let Some(ref admin) = admin {
+        admin.add_announcer(server.announcer());
+    }
+    start_push(&server, &metrics_push, opts.metrics_push_interval);
     server.run(&mut poll).expect("Failed to run server");
 }
@@ -0,0 +1,494 @@
+//! Counters, gauges and histograms describing what the server is doing.
+//!
+//! A `Server` owns a `Metrics` registry behind an `Arc`, and shares it with its connections and
+//! with anything else that reports on it, such as the shards of a sharded server. Everything is
+//! atomic, so the registry can be read from other threads while the server runs. Exporters read
+//! it through `Metrics::samples`, which lists every metric with its name and a line of help.
+
+use std::cmp;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+
+/// The bucket bounds for frame sizes, in bytes, from a small chat line up to the largest payload
+/// a frame may have.
+pub const FRAME_SIZE_BOUNDS: &[u64] = &[
+    64, 256, 1024, 4096, 16 * 1024, 64 * 1024, 256 * 1024, 1024 * 1024, 4 * 1024 * 1024,
+    16 * 1024 * 1024,
+];
+
+/// A count that only goes up.
+#[derive(Debug, Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// A value that goes up and down. Several servers may share one, so each changes it by how much
+/// its own part changed, instead of setting it.
+#[derive(Debug, Default)]
+pub struct Gauge(AtomicI64);
+
+impl Gauge {
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn dec(&self) {
+        self.add(-1);
+    }
+
+    pub fn add(&self, n: i64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> i64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Counts of values falling at or below each of a fixed set of bounds, with a last bucket for
+/// everything above them, plus their sum.
+#[derive(Debug)]
+pub struct Histogram {
+    bounds: &'static [u64],
+    buckets: Vec<AtomicU64>,
+    sum: AtomicU64,
+}
+
+/// What a `Histogram` held when it was read.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HistogramSnapshot {
+    /// The upper bound of each bucket but the last, which has none.
+    pub bounds: &'static [u64],
+
+    /// How many values fell in each bucket, one more than there are bounds.
+    pub counts: Vec<u64>,
+
+    /// The sum of every value.
+    pub sum: u64,
+}
+
+impl HistogramSnapshot {
+    /// How many values there were in all.
+    pub fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+}
+
+impl Histogram {
+    /// A histogram with buckets up to each of `bounds`, which have to be in increasing order.
+    pub fn new(bounds: &'static [u64]) -> Histogram {
+        Histogram {
+            bounds,
+            buckets: (0..bounds.len() + 1).map(|_| AtomicU64::new(0)).collect(),
+            sum: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, value: u64) {
+        let bucket = self.bounds.iter().position(|&b| value <= b).unwrap_or(self.bounds.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(value, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> HistogramSnapshot {
+        HistogramSnapshot {
+            bounds: self.bounds,
+            counts: self.buckets.iter().map(|b| b.load(Ordering::Relaxed)).collect(),
+            sum: self.sum.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// What went wrong, so errors can be counted by what an operator might do about them. The first
+/// few are protocol abuse or broken clients, the rest trouble with the network or the host.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Failure {
+    /// A frame longer than the server takes.
+    FrameTooLarge,
+
+    /// A frame whose length does not fit its kind, such as a request too short for its
+    /// correlation id.
+    InvalidLength,
+
+    /// A frame of a kind the server does not know.
+    UnknownKind,
+
+    /// A frame whose payload could not be decoded.
+    Malformed,
+
+    /// A peer that hung up part way through a frame.
+    Truncated,
+
+    /// A client skipped by a broadcast, or closed, for having too much queued.
+    QueueOverflow,
+
+    /// A read from a socket that failed.
+    ReadFailed,
+
+    /// A write to a socket that failed.
+    WriteFailed,
+
+    /// An error the poller reported on a socket.
+    SocketError,
+
+    /// A connection that could not be accepted.
+    AcceptFailed,
+
+    /// A client closed for having too much of its fragmented messages in flight, or for leaving
+    /// one unfinished too long.
+    FragmentLimit,
+
+    /// An event whose handling panicked. The connection it was for is closed.
+    Panicked,
+
+    /// A connection closed because its peer left too many heartbeats unanswered.
+    MissedHeartbeats,
+
+    /// A connection closed because it missed a read or write deadline, see
+    /// `Server::set_handshake_timeout` and `Connection::set_write_deadline`.
+    MissedDeadline,
+}
+
+impl Failure {
+    pub const ALL: [Failure; 14] = [
+        Failure::FrameTooLarge,
+        Failure::InvalidLength,
+        Failure::UnknownKind,
+        Failure::Malformed,
+        Failure::Truncated,
+        Failure::QueueOverflow,
+        Failure::ReadFailed,
+        Failure::WriteFailed,
+        Failure::SocketError,
+        Failure::AcceptFailed,
+        Failure::FragmentLimit,
+        Failure::Panicked,
+        Failure::MissedHeartbeats,
+        Failure::MissedDeadline,
+    ];
+
+    /// The name exporters label its count with.
+    pub fn name(self) -> &'static str {
+        match self {
+            Failure::FrameTooLarge => "frame_too_large",
+            Failure::InvalidLength => "invalid_length",
+            Failure::UnknownKind => "unknown_kind",
+            Failure::Malformed => "malformed",
+            Failure::Truncated => "truncated",
+            Failure::QueueOverflow => "queue_overflow",
+            Failure::ReadFailed => "read_failed",
+            Failure::WriteFailed => "write_failed",
+            Failure::SocketError => "socket_error",
+            Failure::AcceptFailed => "accept_failed",
+            Failure::FragmentLimit => "fragment_limit",
+            Failure::Panicked => "panicked",
+            Failure::MissedHeartbeats => "missed_heartbeats",
+            Failure::MissedDeadline => "missed_deadline",
+        }
+    }
+}
+
+/// How much is queued for one connection, as of the last time its server looked, and the most
+/// there has been since it was opened.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct QueueDepth {
+    pub frames: usize,
+    pub bytes: usize,
+    pub max_frames: usize,
+    pub max_bytes: usize,
+}
+
+/// The value of a metric when it was read.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Value {
+    Counter(u64),
+    Gauge(i64),
+    Histogram(HistogramSnapshot),
+}
+
+/// One metric as exporters see it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Sample {
+    /// The name, in `snake_case` and without a prefix. Exporters add their own.
+    pub name: &'static str,
+    pub help: &'static str,
+
+    /// What tells this sample apart from others with the same name, as label names and values.
+    pub labels: Vec<(&'static str, String)>,
+
+    pub value: Value,
+}
+
+/// Every metric the server keeps.
+#[derive(Debug)]
+pub struct Metrics {
+    /// Connections accepted, whether or not they were kept.
+    pub accepts: Counter,
+
+    /// Connections closed, for whatever reason.
+    pub closes: Counter,
+
+    /// Connections open now.
+    pub connections: Gauge,
+
+    /// Messages read from connections, and frames of every kind written to them.
+    pub frames_read: Counter,
+    pub frames_written: Counter,
+
+    /// Bytes read from and written to connections, headers included.
+    pub bytes_read: Counter,
+    pub bytes_written: Counter,
+
+    /// The payload sizes of the frames counted in `frames_read` and `frames_written`, in
+    /// `FRAME_SIZE_BOUNDS` buckets.
+    pub frame_sizes_read: Histogram,
+    pub frame_sizes_written: Histogram,
+
+    /// Frames and bytes queued for connections and not yet written, as of the last tick.
+    pub queued_frames: Gauge,
+    pub queued_bytes: Gauge,
+
+    /// Times a poll loop went longer than its watchdog allows without ticking.
+    pub loop_stalls: Counter,
+
+    // what is queued for each open connection, by the id of its server and its token
+    queues: Mutex<BTreeMap<(u64, usize), QueueDepth>>,
+
+    // errors, one count for each `Failure` in the order of `Failure::ALL`
+    failures: [Counter; 14],
+}
+
+impl Default for Metrics {
+    fn default() -> Metrics {
+        Metrics {
+            accepts: Counter::default(),
+            closes: Counter::default(),
+            connections: Gauge::default(),
+            frames_read: Counter::default(),
+            frames_written: Counter::default(),
+            bytes_read: Counter::default(),
+            bytes_written: Counter::default(),
+            frame_sizes_read: Histogram::new(FRAME_SIZE_BOUNDS),
+            frame_sizes_written: Histogram::new(FRAME_SIZE_BOUNDS),
+            queued_frames: Gauge::default(),
+            queued_bytes: Gauge::default(),
+            loop_stalls: Counter::default(),
+            queues: Mutex::new(BTreeMap::new()),
+            failures: Default::default(),
+        }
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics::default()
+    }
+
+    /// Count an error.
+    pub fn failed(&self, failure: Failure) {
+        self.failures[failure as usize].inc();
+    }
+
+    /// How many errors of a kind there have been.
+    pub fn failures(&self, failure: Failure) -> u64 {
+        self.failures[failure as usize].get()
+    }
+
+    /// Record what is queued for the connections of server `server`, as their tokens with the
+    /// frames and bytes queued for each. Connections of that server left out are forgotten.
+    pub fn report_queues<I>(&self, server: u64, depths: I)
+        where I: IntoIterator<Item = (usize, usize, usize)>
+    {
+        let mut queues = self.queues.lock().unwrap();
+        let reported = depths.into_iter().map(|(token, frames, bytes)| {
+            let last = queues.get(&(server, token)).cloned().unwrap_or_default();
+            let depth = QueueDepth {
+                frames,
+                bytes,
+                max_frames: cmp::max(last.max_frames, frames),
+                max_bytes: cmp::max(last.max_bytes, bytes),
+            };
+            ((server, token), depth)
+        }).collect::<Vec<_>>();
+
+        queues.retain(|&(s, _), _| s != server);
+        queues.extend(reported);
+    }
+
+    /// Forget the queue of a connection that closed, so whatever takes its token next starts
+    /// from nothing.
+    pub fn forget_queue(&self, server: u64, token: usize) {
+        self.queues.lock().unwrap().remove(&(server, token));
+    }
+
+    /// What is queued for a connection, as last reported.
+    pub fn queue_depth(&self, server: u64, token: usize) -> Option<QueueDepth> {
+        self.queues.lock().unwrap().get(&(server, token)).cloned()
+    }
+
+    /// Read every metric.
+    pub fn samples(&self) -> Vec<Sample> {
+        fn counter(name: &'static str, help: &'static str, c: &Counter) -> Sample {
+            Sample { name, help, labels: Vec::new(), value: Value::Counter(c.get()) }
+        }
+        fn gauge(name: &'static str, help: &'static str, g: &Gauge) -> Sample {
+            Sample { name, help, labels: Vec::new(), value: Value::Gauge(g.get()) }
+        }
+
+        let mut samples = vec![
+            counter("accepts", "Connections accepted", &self.accepts),
+            counter("closes", "Connections closed", &self.closes),
+            gauge("connections", "Connections open", &self.connections),
+            counter("frames_read", "Messages read from connections", &self.frames_read),
+            counter("frames_written", "Frames written to connections", &self.frames_written),
+            counter("bytes_read", "Bytes read from connections", &self.bytes_read),
+            counter("bytes_written", "Bytes written to connections", &self.bytes_written),
+            gauge("queued_frames", "Frames waiting to be written", &self.queued_frames),
+            gauge("queued_bytes", "Bytes waiting to be written", &self.queued_bytes),
+            counter("loop_stalls", "Times a poll loop stopped ticking for too long",
+                    &self.loop_stalls),
+        ];
+
+        let largest = self.queues.lock().unwrap().iter()
+            .fold((0, 0), |(f, b), (_, d)| (cmp::max(f, d.frames), cmp::max(b, d.bytes)));
+        samples.push(Sample {
+            name: "largest_queued_frames",
+            help: "Frames waiting to be written to the connection with the most",
+            labels: Vec::new(),
+            value: Value::Gauge(largest.0 as i64),
+        });
+        samples.push(Sample {
+            name: "largest_queued_bytes",
+            help: "Bytes waiting to be written to the connection with the most",
+            labels: Vec::new(),
+            value: Value::Gauge(largest.1 as i64),
+        });
+
+        for (&(server, token), depth) in self.queues.lock().unwrap().iter() {
+            let labels = vec![("server", format!("{:016x}", server)),
+                              ("connection", token.to_string())];
+            let per_connection = [
+                ("connection_queued_frames", "Frames waiting to be written to a connection",
+                 depth.frames),
+                ("connection_queued_bytes", "Bytes waiting to be written to a connection",
+                 depth.bytes),
+                ("connection_max_queued_frames", "Most frames ever waiting for a connection",
+                 depth.max_frames),
+                ("connection_max_queued_bytes", "Most bytes ever waiting for a connection",
+                 depth.max_bytes),
+            ];
+            for &(name, help, value) in &per_connection {
+                samples.push(Sample {
+                    name,
+                    help,
+                    labels: labels.clone(),
+                    value: Value::Gauge(value as i64),
+                });
+            }
+        }
+
+        for &(direction, histogram) in &[("in", &self.frame_sizes_read),
+                                         ("out", &self.frame_sizes_written)] {
+            samples.push(Sample {
+                name: "frame_bytes",
+                help: "Payload sizes of frames read and written",
+                labels: vec![("direction", direction.to_string())],
+                value: Value::Histogram(histogram.snapshot()),
+            });
+        }
+
+        for &failure in Failure::ALL.iter() {
+            let mut sample = counter("errors", "Errors by kind", &self.failures[failure as usize]);
+            sample.labels.push(("kind", failure.name().to_string()));
+            samples.push(sample);
+        }
+        samples
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Failure, Histogram, Metrics, QueueDepth, Value};
+
+    #[test]
+    fn histogram_values_land_in_the_first_bucket_that_holds_them() {
+        let histogram = Histogram::new(&[10, 100]);
+        for &value in &[0, 10, 11, 100, 1000] {
+            histogram.observe(value);
+        }
+
+        let snapshot = histogram.snapshot();
+        assert_eq!(snapshot.counts, vec![2, 2, 1]);
+        assert_eq!(snapshot.sum, 1121);
+        assert_eq!(snapshot.count(), 5);
+    }
+
+    #[test]
+    fn samples_read_the_current_values() {
+        let metrics = Metrics::new();
+        metrics.accepts.add(3);
+        metrics.connections.inc();
+        metrics.connections.inc();
+        metrics.connections.dec();
+
+        let samples = metrics.samples();
+        let value = |name: &str| samples.iter().find(|s| s.name == name).unwrap().value.clone();
+        assert_eq!(value("accepts"), Value::Counter(3));
+        assert_eq!(value("connections"), Value::Gauge(1));
+    }
+
+    #[test]
+    fn queue_depths_keep_their_maximum_until_the_connection_goes() {
+        let metrics = Metrics::new();
+        metrics.report_queues(1, vec![(0, 4, 400), (1, 1, 10)]);
+        metrics.report_queues(2, vec![(0, 9, 900)]);
+        metrics.report_queues(1, vec![(0, 2, 200)]);
+
+        let depth = QueueDepth { frames: 2, bytes: 200, max_frames: 4, max_bytes: 400 };
+        assert_eq!(metrics.queue_depth(1, 0), Some(depth));
+        assert_eq!(metrics.queue_depth(1, 1), None);
+        assert_eq!(metrics.queue_depth(2, 0).map(|d| d.frames), Some(9));
+
+        metrics.forget_queue(2, 0);
+        assert_eq!(metrics.queue_depth(2, 0), None);
+
+        let samples = metrics.samples();
+        let largest = samples.iter().find(|s| s.name == "largest_queued_bytes").unwrap();
+        assert_eq!(largest.value, Value::Gauge(200));
+        let max = samples.iter()
+            .find(|s| s.name == "connection_max_queued_bytes")
+            .unwrap();
+        assert_eq!(max.labels[1], ("connection", "0".to_string()));
+        assert_eq!(max.value, Value::Gauge(400));
+    }
+
+    #[test]
+    fn errors_are_counted_by_kind() {
+        let metrics = Metrics::new();
+        metrics.failed(Failure::FrameTooLarge);
+        metrics.failed(Failure::FrameTooLarge);
+        metrics.failed(Failure::AcceptFailed);
+
+        assert_eq!(metrics.failures(Failure::FrameTooLarge), 2);
+        assert_eq!(metrics.failures(Failure::WriteFailed), 0);
+
+        let samples = metrics.samples();
+        let accept = samples.iter()
+            .find(|s| s.name == "errors" && s.labels == [("kind", "accept_failed".to_string())])
+            .unwrap();
+        assert_eq!(accept.value, Value::Counter(1));
+    }
+}
@@ -0,0 +1,321 @@
+//! mob-pollbench: compare the ways the server can register connections with the poller.
+//!
+//! The server registers connections edge triggered and oneshot, so every event is followed by a
+//! `reregister` call. This runs a server in this process once for each combination of edge or
+//! level triggering and oneshot or persistent registration, and has the same clients broadcast
+//! the same messages through each. The server's connections are wrapped to count the socket
+//! calls it makes on them, and its polls are counted too, so the report says what each strategy
+//! costs in system calls as well as how many messages it delivered per second.
+//!
+//! Clients send at a steady rate by default, so most messages arrive in an event of their own,
+//! as they would from interactive clients. With `--rate 0` they send as fast as they can, and
+//! many messages arrive in each read instead.
+//!
+//! ```text
+//! mob-pollbench --clients 20 --messages 2000 --size 128 --rate 1000
+//! ```
+
+extern crate mio;
+extern crate mob;
+
+use std::env;
+use std::io::{self, Read, Write};
+use std::net::{self, Shutdown, SocketAddr};
+use std::process;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use mio::{Evented, Poll, PollOpt, Ready, Token};
+use mio::net::{TcpListener, TcpStream};
+
+use mob::codec::{self, FrameReader};
+use mob::connection::PollStrategy;
+use mob::metrics::Metrics;
+use mob::server::Server;
+use mob::transport::{Listener, Transport};
+
+/// Every strategy, in the order they are reported, the default first.
+const STRATEGIES: &[(&str, PollStrategy)] = &[
+    ("edge oneshot", PollStrategy { edge: true, oneshot: true }),
+    ("edge", PollStrategy { edge: true, oneshot: false }),
+    ("level oneshot", PollStrategy { edge: false, oneshot: true }),
+    ("level", PollStrategy { edge: false, oneshot: false }),
+];
+
+struct Options {
+    clients: usize,
+    messages: usize,
+    size: usize,
+
+    // messages per second per client, 0 means as fast as possible
+    rate: u64,
+    strategy: Option<String>,
+}
+
+fn usage() -> ! {
+    eprintln!("usage: mob-pollbench [--clients N] [--messages N] [--size BYTES] [--rate N] \
+               [--strategy \"edge oneshot\"|edge|\"level oneshot\"|level]");
+    process::exit(2);
+}
+
+fn parse_options() -> Options {
+    let mut opts = Options { clients: 20, messages: 1000, size: 128, rate: 1000, strategy: None };
+
+    let mut args = env::args().skip(1);
+    while let Some(flag) = args.next() {
+        let value = args.next().unwrap_or_else(|| usage());
+        match flag.as_str() {
+            "--clients" => opts.clients = value.parse().unwrap_or_else(|_| usage()),
+            "--messages" => opts.messages = value.parse().unwrap_or_else(|_| usage()),
+            "--size" => opts.size = value.parse().unwrap_or_else(|_| usage()),
+            "--rate" => opts.rate = value.parse().unwrap_or_else(|_| usage()),
+            "--strategy" => {
+                if !STRATEGIES.iter().any(|&(name, _)| name == value) {
+                    usage();
+                }
+                opts.strategy = Some(value);
+            }
+            _ => usage(),
+        }
+    }
+
+    opts
+}
+
+/// The calls the server made while it ran.
+#[derive(Default)]
+struct Counts {
+    polls: AtomicU64,
+    reads: AtomicU64,
+    writes: AtomicU64,
+    registrations: AtomicU64,
+}
+
+impl Counts {
+    fn inc(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// A connection that counts the calls made on it.
+struct Counted {
+    inner: TcpStream,
+    counts: Arc<Counts>,
+}
+
+impl Read for Counted {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        Counts::inc(&self.counts.reads);
+        self.inner.read(buf)
+    }
+}
+
+impl Write for Counted {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        Counts::inc(&self.counts.writes);
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl Evented for Counted {
+    fn register(&self, poll: &Poll, token: Token, interest: Ready, opts: PollOpt)
+        -> io::Result<()>
+    {
+        Counts::inc(&self.counts.registrations);
+        self.inner.register(poll, token, interest, opts)
+    }
+
+    fn reregister(&self, poll: &Poll, token: Token, interest: Ready, opts: PollOpt)
+        -> io::Result<()>
+    {
+        Counts::inc(&self.counts.registrations);
+        self.inner.reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        self.inner.deregister(poll)
+    }
+}
+
+impl Transport for Counted {
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        self.inner.shutdown(how)
+    }
+}
+
+/// A listener whose connections count the calls made on them.
+struct CountingListener {
+    inner: TcpListener,
+    counts: Arc<Counts>,
+}
+
+impl Evented for CountingListener {
+    fn register(&self, poll: &Poll, token: Token, interest: Ready, opts: PollOpt)
+        -> io::Result<()>
+    {
+        self.inner.register(poll, token, interest, opts)
+    }
+
+    fn reregister(&self, poll: &Poll, token: Token, interest: Ready, opts: PollOpt)
+        -> io::Result<()>
+    {
+        self.inner.reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        self.inner.deregister(poll)
+    }
+}
+
+impl Listener for CountingListener {
+    type Stream = Counted;
+
+    fn accept(&self) -> io::Result<(Counted, SocketAddr)> {
+        let (inner, addr) = self.inner.accept()?;
+        Ok((Counted { inner, counts: self.counts.clone() }, addr))
+    }
+}
+
+/// What one run measured.
+struct Run {
+    elapsed: Duration,
+    delivered: u64,
+    counts: Arc<Counts>,
+}
+
+/// Run a server with `strategy` on a thread of its own until `stop` is set, counting its calls
+/// in `counts`. Returns its address and metrics once it is listening.
+fn start_server(strategy: PollStrategy, counts: Arc<Counts>, stop: Arc<AtomicBool>)
+    -> (SocketAddr, Arc<Metrics>, thread::JoinHandle<()>)
+{
+    let listener = net::TcpListener::bind("127.0.0.1:0").expect("Failed to bind");
+    let addr = listener.local_addr().expect("Failed to read address");
+    let (tx, rx) = mpsc::channel();
+
+    let handle = thread::spawn(move || {
+        let inner = TcpListener::from_listener(listener, &addr)
+            .expect("Failed to make listener non-blocking");
+        let mut server = Server::new(CountingListener { inner, counts: counts.clone() });
+        server.set_poll_strategy(strategy);
+        tx.send(server.metrics()).unwrap();
+
+        let mut poll = Poll::new().expect("Failed to create Poll");
+        server.register(&mut poll).expect("Failed to register server");
+        while !stop.load(Ordering::Relaxed) {
+            Counts::inc(&counts.polls);
+            server.run_once(&mut poll, Some(Duration::from_millis(100))).expect("Poll failed");
+        }
+    });
+    (addr, rx.recv().unwrap(), handle)
+}
+
+/// Write `frame` `messages` times at `rate` a second, or all at once if `rate` is zero.
+fn send(mut stream: net::TcpStream, frame: &[u8], messages: usize, rate: u64) -> io::Result<()> {
+    let interval = match 1_000_000_000u64.checked_div(rate) {
+        Some(nanos) => Duration::from_nanos(nanos),
+        None => return stream.write_all(&frame.repeat(messages)),
+    };
+
+    // Pace against a fixed schedule, so slow writes do not drag the rate down.
+    let mut next = Instant::now();
+    for _ in 0..messages {
+        stream.write_all(frame)?;
+        next += interval;
+        let now = Instant::now();
+        if next > now {
+            thread::sleep(next - now);
+        }
+    }
+    Ok(())
+}
+
+/// Broadcast `opts.messages` messages from each of `opts.clients` clients through a server
+/// registering connections with `strategy`, and count what it took.
+fn run(strategy: PollStrategy, opts: &Options) -> io::Result<Run> {
+    let counts = Arc::new(Counts::default());
+    let stop = Arc::new(AtomicBool::new(false));
+    let (addr, metrics, server) = start_server(strategy, counts.clone(), stop.clone());
+
+    let mut streams = Vec::new();
+    for _ in 0..opts.clients {
+        let stream = net::TcpStream::connect(addr)?;
+        stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+        streams.push(stream);
+    }
+
+    // Messages sent before everyone is accepted would not reach everyone.
+    while metrics.connections.get() < opts.clients as i64 {
+        thread::sleep(Duration::from_millis(1));
+    }
+
+    let payload = vec![b'x'; opts.size];
+    let expected = opts.clients * opts.messages;
+    let started = Instant::now();
+    let mut clients = Vec::new();
+    for stream in streams {
+        let writer = stream.try_clone()?;
+        let frame = codec::encode(&payload);
+        let (messages, rate) = (opts.messages, opts.rate);
+        clients.push(thread::spawn(move || -> io::Result<u64> {
+            let sender = thread::spawn(move || send(writer, &frame, messages, rate));
+            let mut reader = FrameReader::new(stream);
+            let mut received = 0;
+            while received < expected as u64 {
+                match reader.read_frame() {
+                    Ok(Some(_)) => received += 1,
+                    Ok(None) | Err(_) => break,
+                }
+            }
+            sender.join().expect("sender panicked")?;
+            Ok(received)
+        }));
+    }
+
+    let mut delivered = 0;
+    for client in clients {
+        delivered += client.join().expect("client panicked")?;
+    }
+    let elapsed = started.elapsed();
+
+    stop.store(true, Ordering::Relaxed);
+    server.join().expect("server panicked");
+    Ok(Run { elapsed, delivered, counts })
+}
+
+fn main() {
+    let opts = parse_options();
+    let sent = (opts.clients * opts.messages) as f64;
+
+    println!("{} clients each broadcasting {} messages of {} bytes, {} a second", opts.clients,
+             opts.messages, opts.size, opts.rate);
+    println!("{:<14} {:>12} {:>8} {:>9} {:>9} {:>9} {:>9} {:>10}", "strategy", "delivered/s",
+             "missed", "polls", "reads", "writes", "regs", "calls/msg");
+
+    for &(name, strategy) in STRATEGIES {
+        if opts.strategy.as_ref().is_some_and(|only| only != name) {
+            continue;
+        }
+
+        let run = run(strategy, &opts).unwrap_or_else(|e| {
+            eprintln!("{} failed: {}", name, e);
+            process::exit(1);
+        });
+        let counts = &run.counts;
+        let calls = [&counts.polls, &counts.reads, &counts.writes, &counts.registrations]
+            .iter().map(|counter| counter.load(Ordering::Relaxed)).collect::<Vec<_>>();
+        let expected = (opts.clients * opts.clients * opts.messages) as u64;
+        println!("{:<14} {:>12.0} {:>8} {:>9} {:>9} {:>9} {:>9} {:>10.2}",
+                 name,
+                 run.delivered as f64 / run.elapsed.as_secs_f64(),
+                 expected.saturating_sub(run.delivered),
+                 calls[0], calls[1], calls[2], calls[3],
+                 calls.iter().sum::<u64>() as f64 / sent);
+    }
+}
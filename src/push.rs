@@ -0,0 +1,283 @@
+//! Pushing the metrics to a Prometheus Pushgateway, or any HTTP endpoint that takes the
+//! Prometheus text format, for where nothing can scrape the server.
+//!
+//! A thread of its own wakes every interval, reads the registry through `Metrics::samples`, and
+//! `POST`s the lot to the URL it was given, so a slow endpoint never holds up the poll loop. For
+//! a Pushgateway, the URL is its `/metrics/job/<job>` path, with any grouping labels after it.
+//! A failed push is logged and dropped; the next push carries every counter as it is by then,
+//! so nothing is lost but resolution. Only plain `http` is spoken, since mob has no TLS.
+
+use std::collections::BTreeMap;
+use std::fmt::{self, Write as FmtWrite};
+use std::io::{self, BufRead, BufReader, Error, ErrorKind, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use metrics::{Metrics, Sample, Value};
+
+/// What every metric's name starts with once pushed.
+const PREFIX: &str = "mob_";
+
+/// How long connecting to the endpoint, and each read and write, may take.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Where to push to, from an `http://host[:port][/path]` URL.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Target {
+    pub host: String,
+    pub port: u16,
+    pub path: String,
+}
+
+impl FromStr for Target {
+    type Err = String;
+
+    fn from_str(url: &str) -> Result<Target, String> {
+        let rest = if let Some(rest) = url.strip_prefix("http://") {
+            rest
+        } else if url.starts_with("https://") {
+            return Err(format!("cannot push to {}, mob does not speak TLS", url));
+        } else {
+            return Err(format!("cannot push to {}, expected an http:// URL", url));
+        };
+
+        let (authority, path) = match rest.find('/') {
+            Some(slash) => (&rest[..slash], &rest[slash..]),
+            None => (rest, "/"),
+        };
+        // The port follows the last colon, unless that is inside an IPv6 address's brackets.
+        let (host, port) = match authority.rfind(':') {
+            Some(colon) if !authority[colon..].contains(']') => {
+                let port = authority[colon + 1..].parse()
+                    .map_err(|_| format!("cannot push to {}, the port is not a number", url))?;
+                (&authority[..colon], port)
+            }
+            _ => (authority, 80),
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty() {
+            return Err(format!("cannot push to {}, there is no host", url));
+        }
+        Ok(Target { host: host.to_string(), port, path: path.to_string() })
+    }
+}
+
+/// `samples` in the Prometheus text format, each name with `PREFIX` in front of it. Samples that
+/// share a name are written together, under the help of the first, wherever they were in the
+/// list.
+pub fn render(samples: &[Sample]) -> String {
+    let mut order = Vec::new();
+    let mut families: BTreeMap<&str, Vec<&Sample>> = BTreeMap::new();
+    for sample in samples {
+        let family = families.entry(sample.name).or_default();
+        if family.is_empty() {
+            order.push(sample.name);
+        }
+        family.push(sample);
+    }
+
+    let mut text = String::new();
+    for name in order {
+        let family = &families[name];
+        let (kind, suffix) = match family[0].value {
+            Value::Counter(_) => ("counter", "_total"),
+            Value::Gauge(_) => ("gauge", ""),
+            Value::Histogram(_) => ("histogram", ""),
+        };
+        let name = format!("{}{}{}", PREFIX, name, suffix);
+        let _ = writeln!(text, "# HELP {} {}", name, family[0].help);
+        let _ = writeln!(text, "# TYPE {} {}", name, kind);
+
+        for sample in family {
+            match sample.value {
+                Value::Counter(n) => line(&mut text, &name, &sample.labels, None, n),
+                Value::Gauge(n) => line(&mut text, &name, &sample.labels, None, n),
+                Value::Histogram(ref h) => {
+                    let bucket = format!("{}_bucket", name);
+                    let mut count = 0;
+                    for (i, &n) in h.counts.iter().enumerate() {
+                        count += n;
+                        let le = h.bounds.get(i).map_or_else(|| "+Inf".to_string(),
+                                                             |bound| bound.to_string());
+                        line(&mut text, &bucket, &sample.labels, Some(&le), count);
+                    }
+                    line(&mut text, &format!("{}_sum", name), &sample.labels, None, h.sum);
+                    line(&mut text, &format!("{}_count", name), &sample.labels, None, count);
+                }
+            }
+        }
+    }
+    text
+}
+
+/// Write one sample line, with `le` as a last label if there is one.
+fn line<V: fmt::Display>(text: &mut String, name: &str, labels: &[(&'static str, String)],
+                         le: Option<&str>, value: V) {
+    let mut labels: Vec<(&str, &str)> = labels.iter().map(|(k, v)| (*k, v.as_str())).collect();
+    labels.extend(le.map(|le| ("le", le)));
+
+    text.push_str(name);
+    if !labels.is_empty() {
+        let labels: Vec<String> = labels.iter().map(|&(key, value)| {
+            let value = value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n");
+            format!("{}=\"{}\"", key, value)
+        }).collect();
+        let _ = write!(text, "{{{}}}", labels.join(","));
+    }
+    let _ = writeln!(text, " {}", value);
+}
+
+/// `POST` `body` to `target`, and fail unless it answers with a 2xx status.
+pub fn push(target: &Target, body: &str) -> io::Result<()> {
+    let mut last_err = Error::new(ErrorKind::NotFound,
+                                  format!("{} resolved to no addresses", target.host));
+    let mut stream = None;
+    for addr in (target.host.as_str(), target.port).to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, TIMEOUT) {
+            Ok(s) => {
+                stream = Some(s);
+                break;
+            }
+            Err(e) => last_err = e,
+        }
+    }
+    let mut stream = stream.ok_or(last_err)?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+
+    // An IPv6 address goes in brackets, as it did in the URL.
+    let host = if target.host.contains(':') {
+        format!("[{}]", target.host)
+    } else {
+        target.host.clone()
+    };
+    write!(stream, "POST {} HTTP/1.1\r\nHost: {}:{}\r\n\
+                    Content-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\n\
+                    Connection: close\r\n\r\n{}",
+           target.path, host, target.port, body.len(), body)?;
+    stream.flush()?;
+
+    let mut status = String::new();
+    BufReader::new(stream).read_line(&mut status)?;
+    match status.split_whitespace().nth(1) {
+        Some(code) if code.starts_with('2') => Ok(()),
+        Some(_) => Err(Error::other(format!("the endpoint answered {}", status.trim()))),
+        None => Err(Error::new(ErrorKind::InvalidData, "the endpoint did not answer HTTP")),
+    }
+}
+
+/// Push `metrics` to `target` every `interval`, on a thread of its own, for as long as the
+/// process runs. The first push is one interval in. A failure is logged when pushing starts to
+/// fail, and again when it works once more, rather than at every interval.
+pub fn spawn(metrics: Arc<Metrics>, target: Target, interval: Duration)
+    -> io::Result<thread::JoinHandle<()>>
+{
+    thread::Builder::new().name("mob-metrics-push".to_string()).spawn(move || {
+        let mut failing = false;
+        loop {
+            thread::sleep(interval);
+            match push(&target, &render(&metrics.samples())) {
+                Ok(()) if failing => {
+                    info!("pushing metrics works again; host={}", target.host);
+                    failing = false;
+                }
+                Ok(()) => {}
+                Err(e) if !failing => {
+                    warn!("Failed to push metrics to {}, {}", target.host, e);
+                    failing = true;
+                }
+                Err(e) => debug!("pushing metrics still fails, {}", e),
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    use metrics::{Histogram, Sample, Value};
+
+    use super::{push, render, Target};
+
+    #[test]
+    fn urls_are_split_into_host_port_and_path() {
+        assert_eq!("http://gateway:9091/metrics/job/mob".parse(),
+                   Ok(Target { host: "gateway".to_string(), port: 9091,
+                               path: "/metrics/job/mob".to_string() }));
+        assert_eq!("http://[::1]".parse(),
+                   Ok(Target { host: "::1".to_string(), port: 80, path: "/".to_string() }));
+        assert_eq!("https://gateway/".parse::<Target>(),
+                   Err("cannot push to https://gateway/, mob does not speak TLS".to_string()));
+        assert!("gateway:9091".parse::<Target>().is_err());
+        assert!("http://gateway:port/".parse::<Target>().is_err());
+    }
+
+    #[test]
+    fn samples_are_grouped_by_name_in_the_text_format() {
+        let histogram = Histogram::new(&[10]);
+        histogram.observe(3);
+        histogram.observe(30);
+        let samples = vec![
+            Sample { name: "queued", help: "Queued", labels: vec![("conn", "1".to_string())],
+                     value: Value::Gauge(2) },
+            Sample { name: "accepts", help: "Accepts", labels: Vec::new(),
+                     value: Value::Counter(7) },
+            Sample { name: "queued", help: "Queued", labels: vec![("conn", "a\"b".to_string())],
+                     value: Value::Gauge(-1) },
+            Sample { name: "sizes", help: "Sizes", labels: Vec::new(),
+                     value: Value::Histogram(histogram.snapshot()) },
+        ];
+        assert_eq!(render(&samples), "\
+# HELP mob_queued Queued
+# TYPE mob_queued gauge
+mob_queued{conn=\"1\"} 2
+mob_queued{conn=\"a\\\"b\"} -1
+# HELP mob_accepts_total Accepts
+# TYPE mob_accepts_total counter
+mob_accepts_total 7
+# HELP mob_sizes Sizes
+# TYPE mob_sizes histogram
+mob_sizes_bucket{le=\"10\"} 1
+mob_sizes_bucket{le=\"+Inf\"} 2
+mob_sizes_sum 33
+mob_sizes_count 2
+");
+    }
+
+    #[test]
+    fn pushes_are_posted_and_need_a_2xx_answer() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let mut requests = Vec::new();
+            for status in &["202 Accepted", "400 Bad Request"] {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut head = String::new();
+                while !head.ends_with("\r\n\r\n") {
+                    reader.read_line(&mut head).unwrap();
+                }
+                let mut body = vec![0; 4];
+                reader.read_exact(&mut body).unwrap();
+                requests.push((head.lines().next().unwrap().to_string(), body));
+                write!(stream, "HTTP/1.1 {}\r\nContent-Length: 0\r\n\r\n", status).unwrap();
+            }
+            requests
+        });
+
+        let target = Target { host: "127.0.0.1".to_string(), port, path: "/metrics/job/mob"
+                              .to_string() };
+        push(&target, "m 1\n").unwrap();
+        assert_eq!(push(&target, "m 2\n").unwrap_err().to_string(),
+                   "the endpoint answered HTTP/1.1 400 Bad Request");
+        assert_eq!(server.join().unwrap(),
+                   vec![("POST /metrics/job/mob HTTP/1.1".to_string(), b"m 1\n".to_vec()),
+                        ("POST /metrics/job/mob HTTP/1.1".to_string(), b"m 2\n".to_vec())]);
+    }
+}
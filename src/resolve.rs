@@ -0,0 +1,80 @@
+//! Turning the `host:port` addresses given on the command line into socket addresses, once, at
+//! startup. The host may be a name or a literal address, with IPv6 ones in brackets.
+
+use std::io::{self, Error, ErrorKind};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::str::FromStr;
+
+/// Which addresses of a name to use.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Family {
+    /// Both, in the order the resolver gives them.
+    #[default]
+    Any,
+    V4,
+    V6,
+}
+
+impl Family {
+    /// Whether `addr` is one of ours.
+    pub fn allows(self, addr: &SocketAddr) -> bool {
+        match self {
+            Family::Any => true,
+            Family::V4 => addr.is_ipv4(),
+            Family::V6 => addr.is_ipv6(),
+        }
+    }
+}
+
+impl FromStr for Family {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Family, String> {
+        match s {
+            "any" => Ok(Family::Any),
+            "ipv4" => Ok(Family::V4),
+            "ipv6" => Ok(Family::V6),
+            _ => Err(format!("unknown address family {}, expected any, ipv4 or ipv6", s)),
+        }
+    }
+}
+
+/// The addresses of `family` that `addr` resolves to, in the order the resolver gave them. Fails
+/// with an error naming `addr` if it cannot be resolved, or has no address of `family`.
+pub fn resolve(addr: &str, family: Family) -> io::Result<Vec<SocketAddr>> {
+    let resolved = addr.to_socket_addrs().map_err(|e| {
+        let hint = if addr.contains(':') { "" } else { ", which needs a :port" };
+        Error::new(e.kind(), format!("cannot resolve {}{}: {}", addr, hint, e))
+    })?;
+
+    let addrs: Vec<SocketAddr> = resolved.filter(|a| family.allows(a)).collect();
+    if addrs.is_empty() {
+        let message = match family {
+            Family::Any => format!("{} resolved to no addresses", addr),
+            Family::V4 => format!("{} has no IPv4 address", addr),
+            Family::V6 => format!("{} has no IPv6 address", addr),
+        };
+        return Err(Error::new(ErrorKind::NotFound, message));
+    }
+    Ok(addrs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{resolve, Family};
+
+    #[test]
+    fn literals_resolve_to_themselves_if_the_family_allows() {
+        let addrs = resolve("127.0.0.1:8000", Family::Any).unwrap();
+        assert_eq!(addrs, vec!["127.0.0.1:8000".parse().unwrap()]);
+        assert_eq!(resolve("[::1]:8000", Family::V6).unwrap(),
+                   vec!["[::1]:8000".parse().unwrap()]);
+
+        assert_eq!(resolve("[::1]:8000", Family::V4).unwrap_err().to_string(),
+                   "[::1]:8000 has no IPv4 address");
+        assert!(resolve("localhost", Family::Any).unwrap_err().to_string()
+            .starts_with("cannot resolve localhost, which needs a :port: "));
+        assert_eq!("ipv5".parse::<Family>().unwrap_err(),
+                   "unknown address family ipv5, expected any, ipv4 or ipv6");
+    }
+}
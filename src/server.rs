@@ -1,32 +1,493 @@
-use std::io::{self, ErrorKind};
-use std::rc::Rc;
+use std::cmp;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::fs::File;
+use std::io::{self, Error, ErrorKind};
+use std::net::{self, SocketAddr};
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::PathBuf;
+use std::process;
+use std::sync::Arc;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+use std::str::FromStr;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use mio::{Events, Poll, PollOpt, Ready, Token};
+use mio::{Events, Poll, PollOpt, Ready, Registration, SetReadiness, Token};
 use mio::net::TcpListener;
 use mio::unix::UnixReady;
 
-use slab;
+use libc;
+use slab::{Slab, VacantEntry};
 
-use connection::Connection;
+use admin;
+use buffer::Payload;
+use codec::{self, Capabilities, Endian, Origin, Route, Welcome};
+use format::PayloadFormat;
+use connection::{Coalesce, Connection, Deadline, FragmentLimits, Framing, KeepAlive, Liveness,
+                 Message, PollStrategy, SharedFrame, Traffic, DEFAULT_QUEUE_CAPACITY,
+                 DEFAULT_WRITE_BATCH};
+use dump::{self, Json};
+use fd::{self, FdUsage};
+use filter::{self, Action, FilterCounts, Filters};
+use limit::{AcceptLimit, AcceptLimiter, ThroughputLimiter};
+use metrics::{Failure, Metrics};
+use shard::{self, Shard};
+use spill::Spill;
+use transport::Listener;
+use watchdog::{self, Heartbeat};
 
-type Slab<T> = slab::Slab<T, Token>;
+/// Room for one more connection in `conns`, unless it already holds `capacity`. The slab grows
+/// as it fills, so the limit on connections is kept here.
+fn vacant_entry<T>(conns: &mut Slab<T>, capacity: usize) -> Option<VacantEntry<'_, T>> {
+    if conns.len() < capacity {
+        Some(conns.vacant_entry())
+    } else {
+        None
+    }
+}
+
+/// How often `tick` runs its periodic maintenance, even when no events arrive.
+const TICK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long a connection we are closing gracefully has to take what is queued for it and hang up
+/// before we close it anyway.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// File descriptors the server needs besides one per connection: stdio, the listener, the poller,
+/// the spare kept for `shed_backlog`, and a few to spare for logging and the like.
+const RESERVED_FDS: u64 = 16;
+
+/// How often memory use is checked against the limit, if there is one.
+const MEMORY_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long accepting stops for after the process runs out of file descriptors.
+const ACCEPT_PAUSE: Duration = Duration::from_millis(100);
+
+/// How many polls in a row may fail before `run` gives up. Interrupted polls do not count.
+const MAX_POLL_FAILURES: u32 = 10;
+
+/// How long `run` waits after the first failed poll. Each further failure in a row doubles it.
+const MIN_POLL_BACKOFF: Duration = Duration::from_millis(10);
+
+/// The longest `run` waits between failed polls.
+const MAX_POLL_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Polls handled faster than this are never reported as slow, whatever share of them a single
+/// event took.
+const MIN_SLOW_POLL: Duration = Duration::from_millis(5);
+
+/// Decides what `run` does about a failed poll.
+#[derive(Default)]
+struct PollRetry {
+    // polls in a row that failed with something other than an interruption
+    failures: u32,
+}
+
+impl PollRetry {
+    fn succeeded(&mut self) {
+        self.failures = 0;
+    }
+
+    /// Returns how long to wait before polling again, or the error back if we should give up.
+    ///
+    /// An interrupted poll is retried straight away. Anything else might be the system running
+    /// short of something for a moment, so we back off and try again, up to a point.
+    fn failed(&mut self, e: io::Error) -> io::Result<Duration> {
+        if e.kind() == ErrorKind::Interrupted {
+            return Ok(Duration::from_secs(0));
+        }
+
+        self.failures += 1;
+        if self.failures >= MAX_POLL_FAILURES {
+            return Err(e);
+        }
+
+        Ok(MIN_POLL_BACKOFF.checked_mul(1 << cmp::min(self.failures - 1, 16))
+            .map(|b| cmp::min(b, MAX_POLL_BACKOFF))
+            .unwrap_or(MAX_POLL_BACKOFF))
+    }
+}
+
+/// Who a message is sent back to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum Mode {
+    /// Every connected client, including the sender.
+    #[default]
+    Broadcast,
+
+    /// Only the sender. This turns mob into a plain framed echo server, which is handy for
+    /// testing a protocol implementation against.
+    Echo,
+}
+
+impl FromStr for Mode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Mode, String> {
+        match s {
+            "broadcast" => Ok(Mode::Broadcast),
+            "echo" => Ok(Mode::Echo),
+            _ => Err(format!("unknown mode {}, expected broadcast or echo", s)),
+        }
+    }
+}
+
+/// Where messages are relayed to, instead of being broadcast.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Relay {
+    /// The downstream server, which has to speak mob's framing.
+    pub addr: SocketAddr,
+
+    /// How many connections to keep open to it. Each sender's messages all go down the same one,
+    /// so they arrive in the order they were sent.
+    pub connections: usize,
+}
+
+/// How much the server allocates up front.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Capacities {
+    /// The most connections accepted at once. Any more are closed straight away.
+    pub connections: usize,
+
+    /// The most events handled per poll. Fewer than `connections` still works, but a busy server
+    /// then needs several polls to get round every connection.
+    pub events: usize,
+
+    /// How many messages each connection has room to queue before its queue has to grow.
+    pub send_queue: usize,
+
+    /// The most bytes written to a connection at a time.
+    pub write_batch: usize,
+}
+
+impl Default for Capacities {
+    fn default() -> Capacities {
+        Capacities {
+            connections: 128,
+            events: 1024,
+            send_queue: DEFAULT_QUEUE_CAPACITY,
+            write_batch: DEFAULT_WRITE_BATCH,
+        }
+    }
+}
+
+/// What became of a broadcast, or of every broadcast so far, counted once per connection.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Delivery {
+    /// Connections the message was queued for.
+    pub queued: u64,
+
+    /// Connections that were skipped because they were over the high watermark, or out of
+    /// credit.
+    pub skipped: u64,
+
+    /// Connections that failed while the message was being queued for them.
+    pub failed: u64,
+}
+
+impl Delivery {
+    fn add(&mut self, other: Delivery) {
+        self.queued += other.queued;
+        self.skipped += other.skipped;
+        self.failed += other.failed;
+    }
+}
+
+/// What a running server can be reconfigured with all at once, see `Server::apply_settings`.
+/// Each field is what the setter of the same name takes, and the default is the server's own.
+#[derive(Clone, Default)]
+pub struct Settings {
+    pub accept_limit: Option<AcceptLimit>,
+    pub text_only: bool,
+    pub payload_format: Option<Arc<dyn PayloadFormat>>,
+    pub receipts: bool,
+    pub greeting: bool,
+    pub welcome: Option<Welcome>,
+    pub filters: Filters,
+    pub coalesce: Option<Coalesce>,
+    pub memory_limit: Option<usize>,
+    pub throughput_limit: Option<u64>,
+    pub high_watermark: Option<usize>,
+    pub read_budget: Option<usize>,
+    pub fragment_limits: FragmentLimits,
+    pub slow_event_share: Option<u32>,
+}
+
+/// What the admin console can ask of a running server, see `Server::answer`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Request {
+    /// A line of figures about the server.
+    Stats,
+
+    /// A line for each connection.
+    List,
 
-pub struct Server {
+    /// Close a connection once what is queued for it is written.
+    Kick(Token),
+
+    /// Turn new clients away, and close the ones there are, see `Server::drain`.
+    Drain,
+}
+
+/// A server's answer to a `Request`, as lines of text.
+pub type Answer = Result<Vec<String>, String>;
+
+/// Figures about one connection, for the admin console to export, see
+/// `Server::connection_stats`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConnectionStats {
+    pub token: Token,
+    pub addr: Option<SocketAddr>,
+
+    /// Whether the other end is a federated server rather than a client.
+    pub peer: bool,
+
+    /// How long the connection has been open.
+    pub uptime: Duration,
+
+    pub traffic: Traffic,
+    pub messages_read: u64,
+    pub queued_frames: usize,
+    pub queued_bytes: usize,
+}
+
+/// What other threads ask a running server to do.
+enum Control {
+    Announce(String),
+    Reconfigure(Box<Settings>),
+    Request(Request, Sender<Answer>),
+    Export(Sender<Vec<ConnectionStats>>),
+}
+
+/// Asks a running server to send its clients a `NOTICE`, or to change its settings, from any
+/// thread, see `Server::announcer`.
+#[derive(Clone)]
+pub struct Announcer {
+    sender: Sender<Control>,
+    readiness: SetReadiness,
+}
+
+impl Announcer {
+    /// Have the server `announce` `text` the next time it polls. Fails if the server is gone.
+    pub fn announce(&self, text: &str) -> io::Result<()> {
+        self.send(Control::Announce(text.to_string()))
+    }
+
+    /// Have the server `apply_settings` the next time it polls. Fails if the server is gone.
+    pub fn reconfigure(&self, settings: Settings) -> io::Result<()> {
+        self.send(Control::Reconfigure(Box::new(settings)))
+    }
+
+    /// Have the server `answer` `request` the next time it polls. The answer arrives on the
+    /// receiver returned. Fails if the server is gone.
+    pub fn ask(&self, request: Request) -> io::Result<Receiver<Answer>> {
+        let (sender, answer) = mpsc::channel();
+        self.send(Control::Request(request, sender))?;
+        Ok(answer)
+    }
+
+    /// Have the server send its `connection_stats` the next time it polls, on the receiver
+    /// returned. Fails if the server is gone.
+    pub fn export(&self) -> io::Result<Receiver<Vec<ConnectionStats>>> {
+        let (sender, stats) = mpsc::channel();
+        self.send(Control::Export(sender))?;
+        Ok(stats)
+    }
+
+    fn send(&self, control: Control) -> io::Result<()> {
+        self.sender.send(control)
+            .map_err(|_| Error::new(ErrorKind::BrokenPipe, "Server stopped"))?;
+        self.readiness.set_readiness(Ready::readable())
+    }
+}
+
+/// The server's end of its `Announcer`s.
+struct Announcements {
+    sender: Sender<Control>,
+    inbox: Receiver<Control>,
+    registration: Registration,
+    readiness: SetReadiness,
+}
+
+pub struct Server<L: Listener = TcpListener> {
     // main socket for our server
-    sock: TcpListener,
+    sock: L,
 
     // token of our server. we keep track of it here instead of doing `const SERVER = Token(_)`.
     token: Token,
 
     // a list of connections _accepted_ by our server
-    conns: Slab<Connection>,
+    conns: Slab<Connection<L::Stream>>,
 
     // a list of events to process
     events: Events,
+
+    // how much is allocated up front
+    capacities: Capacities,
+
+    // who messages are sent back to
+    mode: Mode,
+
+    // turns away source IPs that connect too often, if a limit is set
+    limiter: Option<AcceptLimiter>,
+
+    // only pass on payloads that are valid UTF-8
+    text_only: bool,
+
+    // only pass on payloads that decode in this format
+    payload_format: Option<Arc<dyn PayloadFormat>>,
+
+    // tell the sender of each message how many connections it was queued for
+    receipts: bool,
+
+    // rules a message has to pass before it is broadcast
+    filters: Filters,
+
+    // how long connections hold small messages back, if at all
+    coalesce: Option<Coalesce>,
+
+    // how new connections register with the poller
+    poll_strategy: PollStrategy,
+
+    // how much of their fragmented messages clients may have in flight
+    fragment_limits: FragmentLimits,
+
+    // when `tick` next runs its periodic maintenance
+    next_tick: Instant,
+
+    // a file descriptor held in reserve, so there is one to accept with when the process has run
+    // out, and when accepting starts again if it is paused for want of them
+    spare_fd: Option<File>,
+    accept_paused_until: Option<Instant>,
+
+    // raise the soft file descriptor limit at startup if it is too low for `capacities`
+    raise_fd_limit: bool,
+
+    // whether the last tick found the process close to its file descriptor limit
+    fds_near_limit: bool,
+
+    // the most bytes connections may buffer between them, if there is a limit, and when it is
+    // next checked
+    memory_limit: Option<usize>,
+    next_memory_check: Instant,
+
+    // connections with at least this many bytes queued are skipped by broadcasts, if set
+    high_watermark: Option<usize>,
+
+    // where broadcasts for connections over the high watermark are spilled instead, if anywhere
+    spill: Option<Spill>,
+
+    // the percentage of a poll's handling one event may take before it is logged, if set
+    slow_event_share: Option<u32>,
+
+    // how long the loop may go without ticking before the watchdog says so, and what it watches,
+    // if there is one
+    watchdog: Option<(Duration, Arc<Heartbeat>)>,
+
+    // how often quiet connections are pinged, and how many pings they may miss, if they are
+    keep_alive: Option<KeepAlive>,
+
+    // how long new clients have to send their first message, if there is a limit
+    handshake_timeout: Option<Duration>,
+
+    // limits how many bytes are broadcast per second, if there is a limit, and the connections
+    // whose reads are paused until there is room again, longest paused first
+    throughput: Option<ThroughputLimiter>,
+    paused_readers: VecDeque<Token>,
+
+    // the most messages read from one connection per event, if there is a limit, and the
+    // connections that hit it with more still to read, to be read from again next tick
+    read_budget: Option<usize>,
+    unfinished_readers: VecDeque<Token>,
+
+    // what became of every broadcast so far, and the totals as of the last tick that logged them
+    delivery: Delivery,
+    delivery_logged: Delivery,
+
+    // the encoded `WELCOME` frame sent to every new connection, if there is one
+    welcome: Option<Arc<Vec<u8>>>,
+
+    // open every new connection with a `GREETING` frame
+    greeting: bool,
+
+    // the byte order of length headers on new connections, until their clients ask for another
+    endian: Endian,
+
+    // how frames are delimited on new connections
+    framing: Framing,
+
+    // polls that failed, including interrupted ones
+    poll_errors: u64,
+
+    // identifies this server to federated ones
+    id: u64,
+
+    // the upstream servers we federate with, and our connection to each if there is one
+    peers: Vec<(SocketAddr, Option<Token>)>,
+
+    // what federated servers introduce themselves with, and so do we
+    peer_secret: Option<String>,
+
+    // how many messages we have forwarded to federated servers, and the latest sequence number
+    // forwarded to us from each origin
+    forwarded: u64,
+    seen: HashMap<u64, u64>,
+
+    // where messages are relayed to, if anywhere, and our connections there if they are up
+    relay: Option<Relay>,
+    relay_links: Vec<Option<Token>>,
+
+    // how broadcasts are traded with the other shards, if the server is one, and its token
+    shard: Option<Shard>,
+    shard_token: Token,
+
+    // where announcements from other threads arrive, once anyone has asked for an `Announcer`,
+    // and its token
+    announcements: Option<Announcements>,
+    announce_token: Token,
+
+    // what the server counts, and the queue depths it last added to the gauges there
+    metrics: Arc<Metrics>,
+    reported_queue: (usize, usize),
+
+    // new clients are turned away and the ones there are closed, see `drain`
+    draining: bool,
+
+    // where state dumps are written, if anywhere, and how many had been asked for when we last
+    // looked
+    dump_dir: Option<PathBuf>,
+    dumps_requested: usize,
 }
 
-impl Server {
-    pub fn new(sock: TcpListener) -> Server {
+impl Server<TcpListener> {
+    /// Create a Server from a listener that was bound and configured by the caller.
+    ///
+    /// This is useful when socket options need to be set before mob takes over the socket. The
+    /// listener is switched into non-blocking mode.
+    pub fn from_listener(listener: net::TcpListener) -> io::Result<Server> {
+        let addr = listener.local_addr()?;
+        let sock = TcpListener::from_listener(listener, &addr)?;
+        Ok(Server::new(sock))
+    }
+
+    /// Create a Server from a raw file descriptor of a listening socket.
+    ///
+    /// This lets a supervisor process hand an inherited socket down to mob.
+    ///
+    /// # Safety
+    ///
+    /// The file descriptor must be an open, bound and listening TCP socket. The Server takes
+    /// ownership of it and will close it when dropped.
+    pub unsafe fn from_raw_fd(fd: RawFd) -> io::Result<Server> {
+        Server::from_listener(net::TcpListener::from_raw_fd(fd))
+    }
+}
+
+impl<L: Listener> Server<L> {
+    pub fn new(sock: L) -> Server<L> {
         Server {
             sock,
 
@@ -34,36 +495,1050 @@ impl Server {
             // track an internal offset, but does not anymore.
             token: Token(10_000_000),
 
-            // We will handle a max of 128 connections
-            conns: Slab::with_capacity(128),
+            // We will handle a max of 128 connections, unless `set_capacities` says otherwise
+            conns: Slab::with_capacity(Capacities::default().connections),
 
             // list of events from the poller that the server needs to process
-            events: Events::with_capacity(1024),
+            events: Events::with_capacity(Capacities::default().events),
+
+            capacities: Capacities::default(),
+
+            mode: Mode::default(),
+
+            limiter: None,
+
+            text_only: false,
+
+            payload_format: None,
+
+            receipts: false,
+
+            filters: Filters::new(),
+
+            coalesce: None,
+
+            poll_strategy: PollStrategy::default(),
+
+            fragment_limits: FragmentLimits::default(),
+
+            next_tick: Instant::now() + TICK_INTERVAL,
+
+            spare_fd: reserve_fd(),
+            accept_paused_until: None,
+
+            raise_fd_limit: false,
+
+            fds_near_limit: false,
+
+            memory_limit: None,
+            next_memory_check: Instant::now(),
+
+            high_watermark: None,
+
+            spill: None,
+
+            slow_event_share: None,
+
+            watchdog: None,
+
+            keep_alive: None,
+
+            handshake_timeout: None,
+
+            throughput: None,
+            paused_readers: VecDeque::new(),
+
+            read_budget: None,
+            unfinished_readers: VecDeque::new(),
+
+            delivery: Delivery::default(),
+            delivery_logged: Delivery::default(),
+
+            welcome: None,
+
+            greeting: false,
+
+            endian: Endian::default(),
+
+            framing: Framing::default(),
+
+            poll_errors: 0,
+
+            id: random_id(),
+
+            peers: Vec::new(),
+
+            peer_secret: None,
+
+            forwarded: 0,
+            seen: HashMap::new(),
+
+            relay: None,
+            relay_links: Vec::new(),
+
+            shard: None,
+            shard_token: Token(10_000_001),
+
+            announcements: None,
+            announce_token: Token(10_000_002),
+
+            metrics: Arc::new(Metrics::new()),
+            reported_queue: (0, 0),
+
+            draining: false,
+
+            dump_dir: None,
+            dumps_requested: dump::requests(),
+        }
+    }
+
+    /// Choose who messages are sent back to. The default is to broadcast.
+    pub fn set_mode(&mut self, mode: Mode) {
+        self.mode = mode;
+    }
+
+    /// Only pass on payloads that are valid UTF-8. Anything else is answered with an `ERROR`
+    /// frame to its sender, and goes no further. The correlation id of a request or reply is
+    /// not part of its text.
+    pub fn set_text_only(&mut self, text_only: bool) {
+        self.text_only = text_only;
+    }
+
+    /// Only pass on payloads that decode as an envelope in `format`, so every client of this
+    /// listener can count on it. Anything else is answered with an `ERROR` frame to its sender,
+    /// like a payload that is not text. Payloads are not checked by default.
+    pub fn set_payload_format(&mut self, format: Option<Arc<dyn PayloadFormat>>) {
+        self.payload_format = format;
+    }
+
+    /// Send the sender of each message a `RECEIPT` frame once it has been broadcast, saying which
+    /// of its messages it was and how many connections it was queued for. Messages that are
+    /// rejected or filtered out get no receipt, but still count towards the sequence. Off by
+    /// default.
+    ///
+    /// A fragmented message gets one receipt, counting the connections every piece of it was
+    /// queued for. Its pieces are held back until the last has arrived, and broadcast together.
+    pub fn set_receipts(&mut self, receipts: bool) {
+        self.receipts = receipts;
+    }
+
+    /// Open every new connection with a `WELCOME` frame, or stop with `None`. Off by default.
+    ///
+    /// The server sends it as given, so its limits should match how the server is configured.
+    pub fn set_welcome(&mut self, welcome: Option<Welcome>) {
+        self.welcome = welcome.map(|w| Arc::new(codec::encode_welcome(&w)));
+    }
+
+    /// Open every new connection with a `GREETING` frame listing what the server supports, ahead
+    /// of the `WELCOME` frame if there is one. Off by default.
+    pub fn set_greeting(&mut self, greeting: bool) {
+        self.greeting = greeting;
+    }
+
+    /// Read and write length headers in this byte order on new connections, for legacy clients
+    /// that cannot ask for it with an `ENDIAN` frame. Big endian by default.
+    pub fn set_endian(&mut self, endian: Endian) {
+        self.endian = endian;
+    }
+
+    /// Delimit frames this way on new connections. Length headers by default.
+    ///
+    /// Other framings only carry messages. Control frames, errors, receipts and the like are
+    /// not sent, and requests and replies cannot be told apart from messages.
+    pub fn set_framing(&mut self, framing: Framing) {
+        self.framing = framing;
+    }
+
+    /// Federate with the mob servers at `addrs`. The server connects to each as a client and
+    /// introduces itself with a `PEER` frame, then passes on everything broadcast here and
+    /// broadcasts everything passed on from there. A connection that fails is tried again every
+    /// tick. None by default.
+    ///
+    /// Only one end has to be told about the other, but both need the same secret, see
+    /// `set_peer_secret`. Federated connections use big endian length headers, whatever new
+    /// connections default to.
+    ///
+    /// Messages forwarded by federated servers are checked against this server's own rules,
+    /// like those of its clients, but a fragmented message can only be checked whole. A server
+    /// with rules of its own drops the pieces of those forwarded to it.
+    pub fn set_peers(&mut self, addrs: Vec<SocketAddr>) {
+        self.peers = addrs.into_iter().map(|addr| (addr, None)).collect();
+    }
+
+    /// Take federated connections from servers that introduce themselves with `secret`, and
+    /// introduce this one with it to the servers in `set_peers`. Without a secret, only the
+    /// connections this server makes itself are federated, and a connection that says it is a
+    /// server is closed. None by default.
+    pub fn set_peer_secret(&mut self, secret: Option<String>) {
+        self.peer_secret = secret;
+    }
+
+    /// Identify this server to federated ones by `id`, instead of a random one picked when it was
+    /// created. Messages forwarded back to the server they came from are dropped by this id, so
+    /// every server in a federation needs its own. A server that restarts should get a new one,
+    /// or its messages are dropped until it has sent as many as before.
+    pub fn set_server_id(&mut self, id: u64) {
+        self.id = id;
+    }
+
+    /// Relay every message to a downstream server instead of broadcasting it, so that mob fans
+    /// clients in to another service. Frames go on as they came, over a pool of
+    /// `relay.connections` connections that are connected again every tick if they fail. What
+    /// the downstream server sends back is ignored. Off by default, and `set_mode` does not
+    /// apply while it is on.
+    ///
+    /// A message whose connection is down is dropped, and counts as a failed delivery.
+    pub fn set_relay(&mut self, relay: Option<Relay>) {
+        self.relay = relay;
+        self.relay_links = match relay {
+            Some(relay) => vec![None; cmp::max(relay.connections, 1)],
+            None => Vec::new(),
+        };
+    }
+
+    /// Make the server one of several shards, see `shard::run`, which calls this. What it
+    /// broadcasts is passed to the other shards, and what they broadcast it broadcasts too.
+    pub fn set_shard(&mut self, shard: Option<Shard>) {
+        self.shard = shard;
+    }
+
+    /// Count into `metrics` instead of a registry of the server's own, for instance to share one
+    /// between shards. This has to happen before the first connection is accepted.
+    pub fn set_metrics(&mut self, metrics: Arc<Metrics>) {
+        self.metrics = metrics;
+    }
+
+    /// What the server has counted so far, for exporters to read from.
+    pub fn metrics(&self) -> Arc<Metrics> {
+        self.metrics.clone()
+    }
+
+    /// What the server supports as it is configured now.
+    pub fn capabilities(&self) -> Capabilities {
+        let mut features =
+            vec!["ping", "request", "who", "endian", "fragments", "priority", "notice", "credit",
+                 "streams"];
+        if self.receipts {
+            features.push("receipts");
+        }
+        if self.high_watermark.is_some() {
+            features.push("missed");
+        }
+        if self.welcome.is_some() {
+            features.push("welcome");
+        }
+
+        Capabilities {
+            versions: vec![codec::PROTOCOL_VERSION],
+            codecs: vec!["length".to_string()],
+            features: features.into_iter().map(String::from).collect(),
+        }
+    }
+
+    /// Set the rules a message has to pass before it is broadcast. Their counts start from zero.
+    pub fn set_filters(&mut self, filters: Filters) {
+        self.filters = filters;
+    }
+
+    /// How many messages the filters have caught.
+    pub fn filter_counts(&self) -> FilterCounts {
+        self.filters.counts()
+    }
+
+    /// Change how much the server allocates up front. This has to happen before the first
+    /// connection is accepted.
+    ///
+    /// Capacities of zero, or more connections than the server can tell apart from its own token,
+    /// are an `InvalidInput` error. Combinations that work but are probably a mistake are logged.
+    pub fn set_capacities(&mut self, capacities: Capacities) -> io::Result<()> {
+        if capacities.connections == 0 || capacities.events == 0 || capacities.write_batch == 0 {
+            return Err(Error::new(ErrorKind::InvalidInput, "Capacities must not be zero"));
+        }
+        if capacities.connections >= self.token.0 {
+            return Err(Error::new(ErrorKind::InvalidInput, "Too many connections"));
+        }
+        if !self.conns.is_empty() {
+            return Err(Error::new(ErrorKind::InvalidInput,
+                                  "Capacities can only change before connections are accepted"));
+        }
+
+        if capacities.events < capacities.connections {
+            warn!("{} events per poll is fewer than the {} connections allowed",
+                  capacities.events, capacities.connections);
+        }
+        if capacities.write_batch < codec::HEADER_LEN {
+            warn!("a write batch of {} bytes cannot hold a whole frame header",
+                  capacities.write_batch);
+        }
+
+        self.conns = Slab::with_capacity(capacities.connections);
+        self.events = Events::with_capacity(capacities.events);
+        self.capacities = capacities;
+        Ok(())
+    }
+
+    /// Write the server's state to a JSON file in `dir` whenever a dump is asked for, see the
+    /// `dump` module, then carry on. Off by default.
+    pub fn set_dump_dir(&mut self, dir: Option<PathBuf>) {
+        self.dump_dir = dir;
+        self.dumps_requested = dump::requests();
+    }
+
+    /// What the server and each of its connections are doing, as written by a state dump.
+    pub fn dump(&self) -> Json {
+        let tokens = |tokens: &VecDeque<Token>| {
+            Json::Array(tokens.iter().map(|t| t.0.into()).collect())
+        };
+        let links = |links: &[Option<Token>]| {
+            Json::Array(links.iter().map(|link| link.map(|t| t.0).into()).collect())
+        };
+        let peers = self.peers.iter().map(|&(addr, token)| {
+            Json::object(vec![
+                ("addr", addr.to_string().into()),
+                ("token", token.map(|t| t.0).into()),
+            ])
+        });
+
+        Json::object(vec![
+            ("server", format!("{:016x}", self.id).into()),
+            ("pid", (process::id() as u64).into()),
+            ("mode", Json::debug(self.mode)),
+            ("connections", self.conns.len().into()),
+            ("capacity", self.capacities.connections.into()),
+            ("accept_paused", self.accept_paused_until.is_some().into()),
+            ("paused_readers", tokens(&self.paused_readers)),
+            ("unfinished_readers", tokens(&self.unfinished_readers)),
+            ("memory_usage", self.memory_usage().into()),
+            ("peers", Json::Array(peers.collect())),
+            ("relay_links", links(&self.relay_links)),
+            ("delivered", self.delivery.queued.into()),
+            ("skipped", self.delivery.skipped.into()),
+            ("failed", self.delivery.failed.into()),
+            ("poll_errors", self.poll_errors.into()),
+            ("conns", Json::Array(self.conns.iter().map(|(_, c)| c.dump()).collect())),
+        ])
+    }
+
+    /// Answer `request` from the admin console.
+    pub fn answer(&mut self, poll: &mut Poll, request: Request) -> Answer {
+        match request {
+            Request::Stats => {
+                let queued: usize = self.conns.iter().map(|(_, c)| c.queued_bytes()).sum();
+                Ok(vec![format!("connections={}/{} queued_bytes={} memory={} delivered={} \
+                                 skipped={} failed={} {}",
+                                self.conns.len(), self.capacities.connections, queued,
+                                self.memory_usage(), self.delivery.queued, self.delivery.skipped,
+                                self.delivery.failed,
+                                if self.draining { "draining" } else { "accepting" })])
+            }
+            Request::List => {
+                Ok(self.conns.iter().map(|(_, c)| {
+                    let state = if c.closing_at().is_some() {
+                        "closing"
+                    } else if c.read_closed_at().is_some() {
+                        "half-closed"
+                    } else if c.is_read_paused() {
+                        "paused"
+                    } else {
+                        "open"
+                    };
+                    format!("{} {} {}{} read={} queued={}/{}",
+                            c.token.0,
+                            c.addr().map_or_else(|| "-".to_string(), |addr| addr.to_string()),
+                            state,
+                            if c.is_peer() { " peer" } else { "" },
+                            c.messages_read(), c.queued_frames(), c.queued_bytes())
+                }).collect())
+            }
+            Request::Kick(token) => {
+                if !self.conns.contains(token.0) {
+                    return Err(format!("no connection {}", token.0));
+                }
+                info!("kicking; token={:?}", token);
+                self.close(poll, token, "Kicked");
+                Ok(Vec::new())
+            }
+            Request::Drain => {
+                self.drain(poll);
+                Ok(Vec::new())
+            }
+        }
+    }
+
+    /// Figures about each connection, in token order.
+    pub fn connection_stats(&self) -> Vec<ConnectionStats> {
+        let now = Instant::now();
+        self.conns.iter().map(|(_, c)| ConnectionStats {
+            token: c.token,
+            addr: c.addr(),
+            peer: c.is_peer(),
+            uptime: now.saturating_duration_since(c.connected_at()),
+            traffic: c.traffic(),
+            messages_read: c.messages_read(),
+            queued_frames: c.queued_frames(),
+            queued_bytes: c.queued_bytes(),
+        }).collect()
+    }
+
+    /// Turn new clients away from now on, and close every client there is with a reason once
+    /// what is queued for it is written. Federated servers and relay links are kept. There is no
+    /// undoing it short of a restart.
+    pub fn drain(&mut self, poll: &mut Poll) {
+        info!("draining; connections={}", self.conns.len());
+        self.draining = true;
+
+        let clients: Vec<Token> = self.conns.iter().map(|(_, c)| c)
+            .filter(|c| !c.is_peer() && c.closing_at().is_none())
+            .map(|c| c.token)
+            .collect();
+        for token in clients {
+            self.close(poll, token, "Server draining");
+        }
+    }
+
+    /// Close `token` gracefully with `reason`, or right away if that fails.
+    fn close(&mut self, poll: &mut Poll, token: Token, reason: &str) {
+        let c = self.connection(token);
+        let result = c.close_gracefully(Some(reason)).and_then(|_| c.reregister(poll));
+        if let Err(e) = result {
+            warn!("Closing {:?} failed, {:?}", token, e);
+            self.remove_token(token);
+        }
+    }
+
+    /// Register new connections with the poller this way. Edge triggered and oneshot by default,
+    /// which `mob-pollbench` compares with the alternatives.
+    ///
+    /// Level triggered registrations report a connection for as long as it has something to
+    /// read, so a connection holding more than the read budget allows is reported on every poll.
+    pub fn set_poll_strategy(&mut self, strategy: PollStrategy) {
+        self.poll_strategy = strategy;
+    }
+
+    /// Hold small messages back for a moment, so that several bound for the same connection go
+    /// out in one write. This trades up to `coalesce.delay` of latency for fewer packets when
+    /// messages arrive quickly. Off by default.
+    pub fn set_coalesce(&mut self, coalesce: Option<Coalesce>) {
+        self.coalesce = coalesce;
+        for (_, c) in self.conns.iter_mut() {
+            c.set_coalesce(coalesce);
+        }
+    }
+
+    /// Limit how much of their fragmented messages clients may have in flight, and how long one
+    /// may wait for its next piece. See `FragmentLimits` for the defaults.
+    pub fn set_fragment_limits(&mut self, limits: FragmentLimits) {
+        self.fragment_limits = limits;
+        for (_, c) in self.conns.iter_mut() {
+            c.set_fragment_limits(limits);
+        }
+    }
+
+    /// Limit how often one source IP may connect. Connections from an IP over the limit are
+    /// closed as soon as they are accepted, until its ban is over. There is no limit by default.
+    pub fn set_accept_limit(&mut self, limit: Option<AcceptLimit>) {
+        self.limiter = limit.map(AcceptLimiter::new);
+    }
+
+    /// Raise the soft limit on open file descriptors when `run` starts, if it is too low for the
+    /// number of connections allowed. It cannot go above the hard limit. Off by default, in which
+    /// case a limit that is too low is only logged.
+    pub fn set_raise_fd_limit(&mut self, raise: bool) {
+        self.raise_fd_limit = raise;
+    }
+
+    /// How many file descriptors the process has open, against its limit. Once they are all in
+    /// use, new connections are closed as soon as they are accepted.
+    pub fn fd_usage(&self) -> io::Result<FdUsage> {
+        fd::usage()
+    }
+
+    /// Limit how many bytes connections may buffer between them. Once they buffer more, the
+    /// connections with the most queued for them are treated as too slow: what is queued for
+    /// them is thrown away, and they are closed with a reason. There is no limit by default.
+    pub fn set_memory_limit(&mut self, limit: Option<usize>) {
+        self.memory_limit = limit;
+        self.next_memory_check = Instant::now();
+    }
+
+    /// How many bytes connections buffer between them, in messages waiting to be written and
+    /// messages part read. A broadcast waiting for several connections counts once.
+    pub fn memory_usage(&self) -> usize {
+        let mut seen = HashSet::new();
+        let mut total = 0;
+        for (_, c) in self.conns.iter() {
+            total += c.owned_bytes();
+            for payload in c.queued_payloads() {
+                if seen.insert(payload.as_ptr()) {
+                    total += payload.len();
+                }
+            }
+        }
+        total
+    }
+
+    /// Skip connections with at least `bytes` queued for them when broadcasting, instead of
+    /// queueing more. A client that was skipped is sent a `MISSED` frame with the count before
+    /// its next message, or as soon as it catches up. This bounds how much a slow client can
+    /// cost without slowing anyone else down. Off by default.
+    pub fn set_high_watermark(&mut self, bytes: Option<usize>) {
+        self.high_watermark = bytes;
+    }
+
+    /// Spill the broadcasts a connection over the high watermark would have skipped to a file of
+    /// its own in `spill.dir`, and write them out in order as it catches up, instead of skipping
+    /// them. Memory stays bounded by the watermark, and a client that is only behind for a while
+    /// gets every message. Once its file has grown to `spill.max_bytes`, broadcasts skip it as
+    /// they would otherwise. Needs a high watermark to take effect. Off by default.
+    pub fn set_spill(&mut self, spill: Option<Spill>) {
+        self.spill = spill;
+    }
+
+    /// Log a warning whenever handling one event takes more than `percent` of the time taken to
+    /// handle everything a poll returned, `tick` included. That one connection held up every
+    /// other, which is worth knowing in a single threaded loop. Polls handled in less than a few
+    /// milliseconds are not worth a warning. Off by default.
+    pub fn set_slow_event_share(&mut self, percent: Option<u32>) {
+        self.slow_event_share = percent;
+    }
+
+    /// Watch the loop from another thread, which logs an error naming the event being handled
+    /// and counts a stall whenever the loop goes `threshold` without ticking. The loop ticks at
+    /// least once a second, so the threshold should be well over that. The watchdog starts with
+    /// `run`. Off by default.
+    pub fn set_watchdog(&mut self, threshold: Option<Duration>) {
+        self.watchdog = threshold.map(|t| (t, Arc::new(Heartbeat::new())));
+    }
+
+    /// Send a `PING` to every connection that has been quiet for `keep_alive.interval`, and
+    /// reset those that leave `keep_alive.misses` of them in a row unanswered, so a peer that
+    /// vanished behind a NAT without a word does not hold a connection until the kernel gives
+    /// up on it, which can take hours. Anything a peer sends counts as an answer. Connections
+    /// are looked at once a tick, so intervals shorter than a second are rounded up to one.
+    /// Off by default.
+    ///
+    /// Peers only answer while they read, so clients that only send, and send nothing for
+    /// longer than this allows, are cut off too.
+    pub fn set_keep_alive(&mut self, keep_alive: Option<KeepAlive>) {
+        self.keep_alive = keep_alive;
+    }
+
+    /// Give every new client `timeout` to send its first message, and close those that do not
+    /// with "Read timed out", so a client that connects and says nothing does not hold a slot.
+    /// `ENDIAN` and `CREDIT` frames do not count as messages. Connections are looked at once a
+    /// tick, so timeouts shorter than a second are rounded up to one. Only clients accepted
+    /// after this is set get the timeout. Off by default.
+    pub fn set_handshake_timeout(&mut self, timeout: Option<Duration>) {
+        self.handshake_timeout = timeout;
+    }
+
+    /// Limit how many bytes are broadcast per second, counting a message once for every
+    /// connection it is queued for. Up to a second's worth may go out in a burst.
+    ///
+    /// Once the limit is reached, the server stops reading from whichever connection it would read
+    /// from next, which pushes back on the sender instead of letting queues grow. When there is
+    /// room again, paused connections are read from in the order they were paused, so one busy
+    /// sender cannot starve the rest. There is no limit by default.
+    pub fn set_throughput_limit(&mut self, bytes_per_second: Option<u64>) {
+        self.throughput = bytes_per_second.map(|n| ThroughputLimiter::new(n, Instant::now()));
+    }
+
+    /// Read at most `messages` messages from a connection for each event, then move on to the
+    /// next. Whatever one has left is read from next tick, behind any others that hit the limit
+    /// first, and the poll that follows does not wait. That does not depend on the poller: a
+    /// connection registered edge triggered is not reported again for what it has left, and one
+    /// registered level triggered may be, which only has it read from again that much sooner.
+    /// This keeps one busy sender from holding up everyone else's events. There is no limit by
+    /// default.
+    pub fn set_read_budget(&mut self, messages: Option<usize>) {
+        self.read_budget = messages.map(|n| cmp::max(n, 1));
+    }
+
+    /// Change everything in `settings` at once, as if each of its setters had been called.
+    ///
+    /// Connections already open keep going. An accept limit that has not changed keeps its bans,
+    /// and the filters carry on counting from where the old ones left off.
+    pub fn apply_settings(&mut self, settings: Settings) {
+        let limit = self.limiter.as_ref().map(|l| l.limit());
+        if settings.accept_limit != limit {
+            self.set_accept_limit(settings.accept_limit);
+        }
+        let mut filters = settings.filters;
+        filters.carry_counts(&self.filters);
+
+        self.set_text_only(settings.text_only);
+        self.set_payload_format(settings.payload_format);
+        self.set_receipts(settings.receipts);
+        self.set_greeting(settings.greeting);
+        self.set_welcome(settings.welcome);
+        self.set_filters(filters);
+        self.set_coalesce(settings.coalesce);
+        self.set_memory_limit(settings.memory_limit);
+        self.set_throughput_limit(settings.throughput_limit);
+        self.set_high_watermark(settings.high_watermark);
+        self.set_read_budget(settings.read_budget);
+        self.set_fragment_limits(settings.fragment_limits);
+        self.set_slow_event_share(settings.slow_event_share);
+    }
+
+    /// A handle other threads can `announce` or `reconfigure` through, for instance the admin
+    /// socket. It has to be asked for before the server is registered with its poller, which
+    /// `run` does first.
+    pub fn announcer(&mut self) -> Announcer {
+        let announcements = self.announcements.get_or_insert_with(|| {
+            let (sender, inbox) = mpsc::channel();
+            let (registration, readiness) = Registration::new2();
+            Announcements { sender, inbox, registration, readiness }
+        });
+        Announcer {
+            sender: announcements.sender.clone(),
+            readiness: announcements.readiness.clone(),
         }
     }
 
+    /// Send every client a `NOTICE` carrying `text`, such as a warning that the server is about
+    /// to shut down.
+    ///
+    /// It goes out ahead of everything queued for each client, without waiting for coalesced
+    /// messages, and reaches clients over the high watermark too. Only clients being closed miss
+    /// it. Federated servers, relay connections and the other shards are not sent it.
+    pub fn announce(&mut self, poll: &mut Poll, text: &str) -> Delivery {
+        info!("announcing {:?}", text);
+        let notice = Arc::new(text.as_bytes().to_vec());
+        let mut delivery = Delivery::default();
+        let mut failed = Vec::new();
+
+        let relay_links = &self.relay_links;
+        let open = self.conns.iter_mut().map(|(_, c)| c)
+            .filter(|c| !c.is_peer() && c.closing_at().is_none());
+        for c in open {
+            if relay_links.contains(&Some(c.token)) {
+                continue;
+            }
+
+            let was_writable = c.is_writable();
+            let mut result = c.send_frame(codec::NOTICE, notice.clone());
+            if result.is_ok() && !was_writable && c.is_writable() {
+                result = c.reregister(poll);
+            }
+
+            match result {
+                Ok(()) => delivery.queued += 1,
+                Err(e) => {
+                    warn!("Announcing to {:?} failed, {:?}", c.token, e);
+                    delivery.failed += 1;
+                    failed.push(c.token);
+                }
+            }
+        }
+
+        for token in failed {
+            self.remove_token(token);
+        }
+        delivery
+    }
+
     pub fn run(&mut self, poll: &mut Poll) -> io::Result<()> {
 
         self.register(poll)?;
+        self.check_fd_budget();
+        self.connect_links(poll);
+
+        if let Some((threshold, ref heartbeat)) = self.watchdog {
+            heartbeat.beat();
+            watchdog::spawn(heartbeat, threshold, self.metrics.clone(), self.id)?;
+        }
 
         info!("Server run loop starting...");
+        let mut retry = PollRetry::default();
         loop {
-            let cnt = poll.poll(&mut self.events, None)?;
+            let timeout = self.poll_timeout();
+            match self.run_once(poll, Some(timeout)) {
+                Ok(_) => retry.succeeded(),
+                Err(e) => {
+                    self.poll_errors += 1;
+                    warn!("Poll failed, {:?}", e);
 
-            trace!("processing events... cnt={}; len={}", cnt, self.events.len());
+                    let backoff = retry.failed(e).inspect_err(|_| {
+                        error!("Giving up after {} failed polls in a row", MAX_POLL_FAILURES);
+                    })?;
+                    thread::sleep(backoff);
+                }
+            }
+        }
+    }
 
-            // Iterate over the notifications. Each event provides the token
-            // it was registered with (which usually represents, at least, the
-            // handle that the event is about) as well as information about
-            // what kind of event occurred (readable, writable, signal, etc.)
-            for i in 0..cnt {
-                let event = self.events.get(i).ok_or_else(|| {
-                    io::Error::new(ErrorKind::Other, "Failed to get event")
-                })?;
+    /// Poll once, process all of the events that were returned, then `tick`.
+    ///
+    /// `run` calls this in a loop. It is exposed separately so tests can step the server one
+    /// poll at a time. The server must already be registered with the poller.
+    pub fn run_once(&mut self, poll: &mut Poll, timeout: Option<Duration>) -> io::Result<usize> {
+        let cnt = poll.poll(&mut self.events, timeout)?;
+
+        trace!("processing events... cnt={}; len={}", cnt, self.events.len());
+
+        // the event that took longest to handle, when we are watching for slow ones
+        let started = Instant::now();
+        let mut slowest: Option<(Token, Duration)> = None;
+
+        // Iterate over the notifications. Each event provides the token
+        // it was registered with (which usually represents, at least, the
+        // handle that the event is about) as well as information about
+        // what kind of event occurred (readable, writable, signal, etc.)
+        for i in 0..cnt {
+            let event = self.events.get(i).ok_or_else(|| {
+                io::Error::other("Failed to get event")
+            })?;
 
-                trace!("event={:?}; idx={:?}", event, i);
+            trace!("event={:?}; idx={:?}", event, i);
+            if let Some((_, ref heartbeat)) = self.watchdog {
+                heartbeat.handling(Some(event.token()));
+            }
+            if self.slow_event_share.is_none() {
                 self.ready(poll, event.token(), event.readiness());
+                continue;
+            }
+
+            let at = Instant::now();
+            self.ready(poll, event.token(), event.readiness());
+            let took = at.elapsed();
+            if slowest.map(|(_, longest)| took > longest).unwrap_or(true) {
+                slowest = Some((event.token(), took));
+            }
+        }
+
+        if let Some((_, ref heartbeat)) = self.watchdog {
+            heartbeat.handling(None);
+        }
+        self.tick(poll);
+
+        if let (Some(percent), Some((token, took))) = (self.slow_event_share, slowest) {
+            let total = started.elapsed();
+            if is_slow(took, total, percent) {
+                warn!("slow event; token={:?} took={:?} poll={:?} events={}",
+                      token, took, total, cnt);
+            }
+        }
+
+        Ok(cnt)
+    }
+
+    /// Write a state dump if one has been asked for since we last looked.
+    fn dump_if_requested(&mut self) {
+        let requested = dump::requests();
+        if requested == self.dumps_requested {
+            return;
+        }
+        self.dumps_requested = requested;
+
+        let dir = match self.dump_dir {
+            Some(ref dir) => dir,
+            None => return,
+        };
+        let at = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis();
+        let name = format!("mob-{}-{:016x}-{}.json", process::id(), self.id, at);
+        match dump::write(dir, &name, &self.dump()) {
+            Ok(path) => info!("dumped state to {}", path.display()),
+            Err(e) => error!("Failed to dump state to {}, {:?}", dir.display(), e),
+        }
+    }
+
+    /// Make sure the file descriptor limit leaves room for every connection allowed, raising it
+    /// if we were asked to and logging a warning if it is still too low.
+    fn check_fd_budget(&self) {
+        let needed = self.capacities.connections as u64 + RESERVED_FDS;
+        let limit = if self.raise_fd_limit {
+            fd::raise_limit(needed)
+        } else {
+            fd::limits().map(|(soft, _)| soft)
+        };
+        match limit {
+            Ok(limit) if limit < needed => {
+                warn!("{} connections need about {} file descriptors, but the limit is {}",
+                      self.capacities.connections, needed, limit);
+            }
+            Ok(limit) => debug!("file descriptor limit is {}, {} needed", limit, needed),
+            Err(e) => warn!("Failed to check the file descriptor limit, {:?}", e),
+        }
+    }
+
+    /// How long the next poll may wait before `tick` has something to do.
+    fn poll_timeout(&self) -> Duration {
+        if !self.unfinished_readers.is_empty() {
+            return Duration::from_secs(0);
+        }
+
+        let room_at = match self.throughput {
+            Some(ref t) if !self.paused_readers.is_empty() => t.room_at(),
+            _ => None,
+        };
+        let next = self.conns.iter().map(|(_, c)| c)
+            .filter_map(|c| c.hold_until())
+            .chain(self.accept_paused_until)
+            .chain(room_at)
+            .fold(self.next_tick, cmp::min);
+        next.saturating_duration_since(Instant::now())
+    }
+
+    /// Time based maintenance, run after every poll whether or not any events arrived.
+    ///
+    /// Held messages are written, and paused accepting and reading start again, as soon as they
+    /// are due. Everything else runs at most once every `TICK_INTERVAL`.
+    fn tick(&mut self, poll: &mut Poll) {
+        if let Some((_, ref heartbeat)) = self.watchdog {
+            heartbeat.beat();
+        }
+        self.dump_if_requested();
+        self.flush_held(poll);
+        self.resume_readers(poll);
+        self.finish_reads(poll);
+
+        let now = Instant::now();
+        if self.accept_paused_until.map(|until| until <= now).unwrap_or(false) {
+            debug!("accepting again");
+            self.accept_paused_until = None;
+            self.accept(poll);
+        }
+
+        if let Some(limit) = self.memory_limit {
+            if now >= self.next_memory_check {
+                self.next_memory_check = now + MEMORY_CHECK_INTERVAL;
+                self.enforce_memory_limit(poll, limit);
+            }
+        }
+
+        if now < self.next_tick {
+            return;
+        }
+        self.next_tick = now + TICK_INTERVAL;
+
+        if let Some(ref mut limiter) = self.limiter {
+            limiter.sweep(now);
+        }
+
+        self.connect_links(poll);
+
+        // Say how many deliveries were lost since the last time, if any were.
+        let skipped = self.delivery.skipped - self.delivery_logged.skipped;
+        let failed = self.delivery.failed - self.delivery_logged.failed;
+        if skipped > 0 || failed > 0 {
+            warn!("{} deliveries skipped for slow clients and {} failed since the last tick",
+                  skipped, failed);
+        }
+        self.delivery_logged = self.delivery;
+
+        self.report_queue_depth();
+
+        // Say so once when file descriptors start to run out, and once when they recover.
+        if let Ok(usage) = fd::usage() {
+            if usage.is_near_limit() != self.fds_near_limit {
+                self.fds_near_limit = usage.is_near_limit();
+                if self.fds_near_limit {
+                    warn!("{} of {} file descriptors in use", usage.open, usage.limit);
+                } else {
+                    info!("{} of {} file descriptors in use", usage.open, usage.limit);
+                }
+            }
+        }
+
+        // Close the connections whose peers finished sending and have either read everything
+        // queued for them or missed the write deadline that set, and those we have been closing
+        // for too long.
+        let finished: Vec<Token> = self.conns.iter().map(|(_, c)| c)
+            .filter(|c| {
+                let half_closed = c.read_closed_at().map(|_| {
+                    c.is_flushed() || c.missed_deadline(now) == Some(Deadline::Write)
+                });
+                let closing = c.closing_at().map(|at| now.duration_since(at) >= CLOSE_TIMEOUT);
+                half_closed.unwrap_or(false) || closing.unwrap_or(false)
+            })
+            .map(|c| c.token)
+            .collect();
+        for token in finished {
+            debug!("closing finished {:?}", token);
+            self.remove_token(token);
+        }
+
+        // Close the connections that started a fragmented message and stopped sending it.
+        let stalled: Vec<Token> = self.conns.iter().map(|(_, c)| c)
+            .filter(|c| c.closing_at().is_none() && c.has_stalled_fragment(now))
+            .map(|c| c.token)
+            .collect();
+        for token in stalled {
+            warn!("fragmented message timed out; token={:?}", token);
+            self.metrics.failed(Failure::FragmentLimit);
+            let c = self.connection(token);
+            let result = c.close_gracefully(Some("Fragmented message timed out"))
+                .and_then(|_| c.reregister(poll));
+            if let Err(e) = result {
+                warn!("Closing {:?} failed, {:?}", token, e);
+                self.remove_token(token);
+            }
+        }
+
+        self.enforce_deadlines(poll, now);
+
+        if let Some(keep_alive) = self.keep_alive {
+            self.keep_alive(poll, now, keep_alive);
+        }
+    }
+
+    /// Close the connections that missed a deadline. One whose peer did not send in time is
+    /// closed gracefully, and told why. One that could not write what was queued in time is
+    /// reset, since a graceful close would only queue more.
+    fn enforce_deadlines(&mut self, poll: &mut Poll, now: Instant) {
+        let missed: Vec<(Token, Deadline)> = self.conns.iter().map(|(_, c)| c)
+            .filter_map(|c| c.missed_deadline(now).map(|deadline| (c.token, deadline)))
+            .collect();
+        for (token, deadline) in missed {
+            warn!("missed {:?} deadline; token={:?}", deadline, token);
+            self.metrics.failed(Failure::MissedDeadline);
+            if deadline == Deadline::Write {
+                self.remove_token(token);
+                continue;
+            }
+
+            let c = self.connection(token);
+            let result = c.close_gracefully(Some("Read timed out"))
+                .and_then(|_| c.reregister(poll));
+            if let Err(e) = result {
+                warn!("Closing {:?} failed, {:?}", token, e);
+                self.remove_token(token);
+            }
+        }
+    }
+
+    /// Ping the connections that have gone quiet, and reset those whose peers are gone. There is
+    /// no closing them gracefully, since nothing written to them would be read.
+    fn keep_alive(&mut self, poll: &mut Poll, now: Instant, keep_alive: KeepAlive) {
+        let mut dead = Vec::new();
+        for (_, c) in self.conns.iter_mut() {
+            match c.keep_alive(now, keep_alive).and_then(|liveness| {
+                if liveness == Liveness::Pinged {
+                    c.reregister(poll)?;
+                }
+                Ok(liveness)
+            }) {
+                Ok(Liveness::Dead) => {
+                    warn!("peer missed {} heartbeats, closing; token={:?} addr={:?}",
+                          keep_alive.misses, c.token, c.addr());
+                    self.metrics.failed(Failure::MissedHeartbeats);
+                    dead.push(c.token);
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    warn!("Pinging {:?} failed, {:?}", c.token, e);
+                    dead.push(c.token);
+                }
+            }
+        }
+        for token in dead {
+            self.remove_token(token);
+        }
+    }
+
+    /// Bring the queue depth gauges up to date with what is queued for our connections, in all
+    /// and for each one.
+    fn report_queue_depth(&mut self) {
+        let depths = self.conns.iter().map(|(_, c)| c)
+            .map(|c| (c.token.0, c.queued_frames(), c.queued_bytes()))
+            .collect::<Vec<_>>();
+        let frames = depths.iter().map(|d| d.1).sum::<usize>();
+        let bytes = depths.iter().map(|d| d.2).sum::<usize>();
+        self.metrics.report_queues(self.id, depths);
+
+        let (last_frames, last_bytes) = self.reported_queue;
+        self.metrics.queued_frames.add(frames as i64 - last_frames as i64);
+        self.metrics.queued_bytes.add(bytes as i64 - last_bytes as i64);
+        self.reported_queue = (frames, bytes);
+    }
+
+    /// Close the connections with the most queued for them until the rest fit in `limit`.
+    fn enforce_memory_limit(&mut self, poll: &mut Poll, limit: usize) {
+        let mut usage = self.memory_usage();
+        if usage <= limit {
+            return;
+        }
+
+        let mut worst: Vec<(usize, Token)> = self.conns.iter().map(|(_, c)| c)
+            .filter(|c| c.closing_at().is_none())
+            .map(|c| (c.queued_bytes(), c.token))
+            .collect();
+        worst.sort_by(|a, b| b.cmp(a));
+
+        for (queued, token) in worst {
+            if usage <= limit || queued == 0 {
+                break;
             }
+
+            warn!("{} bytes buffered is over the limit of {}, closing {:?} with {} bytes queued",
+                  usage, limit, token, queued);
+            self.metrics.failed(Failure::QueueOverflow);
+            let c = self.connection(token);
+            c.shed_queue();
+            let result = c.close_gracefully(Some("Too slow")).and_then(|_| c.reregister(poll));
+            if let Err(e) = result {
+                warn!("Closing {:?} failed, {:?}", token, e);
+                self.remove_token(token);
+            }
+
+            // The payloads it let go of may still be queued for others.
+            usage = self.memory_usage();
+        }
+    }
+
+    /// Read from the connections paused by the throughput limit, longest paused first, for as
+    /// long as there is room. One that runs out of room again goes to the back of the line.
+    fn resume_readers(&mut self, poll: &mut Poll) {
+        while !self.paused_readers.is_empty() && !self.throughput_exhausted() {
+            let token = self.paused_readers.pop_front().unwrap();
+            debug!("resuming reads from {:?}", token);
+            self.connection(token).resume_reading();
+            self.ready(poll, token, Ready::readable());
+        }
+    }
+
+    /// Read from the connections that used up their read budget last time, in the order they did.
+    /// One that uses it up again goes to the back of the line for the next tick.
+    fn finish_reads(&mut self, poll: &mut Poll) {
+        for _ in 0..self.unfinished_readers.len() {
+            let token = match self.unfinished_readers.pop_front() {
+                Some(token) => token,
+                None => return,
+            };
+            trace!("reading on from {:?}", token);
+            self.ready(poll, token, Ready::readable());
+        }
+    }
+
+    /// Whether the throughput limit leaves no room to broadcast anything more for now.
+    fn throughput_exhausted(&mut self) -> bool {
+        match self.throughput {
+            Some(ref mut t) => !t.has_room(Instant::now()),
+            None => false,
+        }
+    }
+
+    /// Write the messages every connection has held back for long enough.
+    fn flush_held(&mut self, poll: &mut Poll) {
+        if self.coalesce.is_none() {
+            return;
+        }
+
+        let now = Instant::now();
+        let mut failed = Vec::new();
+        for (_, c) in self.conns.iter_mut() {
+            let result = c.flush_held(now).and_then(|reregister| {
+                if reregister { c.reregister(poll) } else { Ok(()) }
+            });
+            if let Err(e) = result {
+                warn!("Flushing held messages failed for {:?}, {:?}", c.token, e);
+                failed.push(c.token);
+            }
+        }
+
+        for token in failed {
+            self.remove_token(token);
         }
     }
 
@@ -76,17 +1551,60 @@ impl Server {
             self.token,
             Ready::readable(),
             PollOpt::edge()
-        ).or_else(|e| {
+        ).map_err(|e| {
             error!("Failed to register server {:?}, {:?}", self.token, e);
-            Err(e)
-        })
+            e
+        })?;
+
+        if let Some(ref shard) = self.shard {
+            poll.register(shard, self.shard_token, Ready::readable(), PollOpt::edge())
+                .inspect_err(|e| {
+                    error!("Failed to register shard {:?}, {:?}", self.shard_token, e);
+                })?;
+        }
+
+        if let Some(ref announcements) = self.announcements {
+            let token = self.announce_token;
+            poll.register(&announcements.registration, token, Ready::readable(), PollOpt::edge())
+                .inspect_err(|e| {
+                    error!("Failed to register announcements {:?}, {:?}", token, e);
+                })?;
+        }
+        Ok(())
+    }
+
+    /// What became of every broadcast so far. Each message counts once for every connection,
+    /// so a client that is skipped or fails shows up however many others got the message.
+    pub fn delivery_counts(&self) -> Delivery {
+        self.delivery
+    }
+
+    /// How many polls have failed, including the interrupted ones `run` shrugs off.
+    pub fn poll_errors(&self) -> u64 {
+        self.poll_errors
+    }
+
+    /// The number of connections currently accepted by the server.
+    pub fn connection_count(&self) -> usize {
+        self.conns.len()
     }
 
     /// Remove a token from the slab
     fn remove_token(&mut self, token: Token) {
-        match self.conns.remove(token) {
+        self.paused_readers.retain(|&t| t != token);
+        self.unfinished_readers.retain(|&t| t != token);
+        for peer in self.peers.iter_mut().filter(|p| p.1 == Some(token)) {
+            peer.1 = None;
+        }
+        for link in self.relay_links.iter_mut().filter(|l| **l == Some(token)) {
+            *link = None;
+        }
+        match self.conns.try_remove(token.0) {
             Some(_c) => {
                 debug!("reset connection; token={:?}", token);
+                self.metrics.forget_queue(self.id, token.0);
+                self.metrics.closes.inc();
+                self.metrics.connections.dec();
             }
             None => {
                 warn!("Unable to remove connection for {:?}", token);
@@ -94,10 +1612,40 @@ impl Server {
         }
     }
 
+    /// Handle an event, see `handle`. A panic while handling it is caught, so that only the
+    /// connection it was for is lost: that connection is closed, and every other one carries on.
     fn ready(&mut self, poll: &mut Poll, token: Token, event: Ready) {
+        let caught = panic::catch_unwind(AssertUnwindSafe(|| self.handle(poll, token, event)));
+        let cause = match caught {
+            Ok(()) => return,
+            Err(cause) => cause,
+        };
+
+        let message = cause.downcast_ref::<&str>().cloned()
+            .or_else(|| cause.downcast_ref::<String>().map(|s| s.as_str()))
+            .unwrap_or("unknown cause");
+        error!("event handler panicked, closing the connection; token={:?} panic={:?}",
+               token, message);
+        self.metrics.failed(Failure::Panicked);
+        if self.conns.contains(token.0) {
+            self.remove_token(token);
+        }
+    }
+
+    fn handle(&mut self, poll: &mut Poll, token: Token, event: Ready) {
         debug!("{:?} event = {:?}", token, event);
 
-        if self.token != token && !self.conns.contains(token) {
+        if token == self.shard_token {
+            self.read_shards(poll);
+            return;
+        }
+
+        if token == self.announce_token {
+            self.read_announcements(poll);
+            return;
+        }
+
+        if self.token != token && !self.conns.contains(token.0) {
             debug!("Failed to find connection for {:?}", token);
             return;
         }
@@ -106,14 +1654,22 @@ impl Server {
 
         if event.is_error() {
             warn!("Error event for {:?}", token);
+            self.metrics.failed(Failure::SocketError);
             self.remove_token(token);
             return;
         }
 
-        if event.is_hup() {
+        // A hang up may only mean the peer shut down its write side. There may still be frames
+        // to read before the end of the stream, and it may still be reading, so we carry on
+        // until the read side reports the end. Once that has happened we no longer ask about
+        // hang ups, so another one means the peer is gone for good.
+        let hup = event.is_hup();
+        if hup {
             trace!("Hup event for {:?}", token);
-            self.remove_token(token);
-            return;
+            if self.token == token || self.connection(token).read_closed_at().is_some() {
+                self.remove_token(token);
+                return;
+            }
         }
 
         let event = Ready::from(event);
@@ -136,29 +1692,45 @@ impl Server {
 
         // A read event for our `Server` token means we are establishing a new connection. A read
         // event for any other token should be handed off to that connection.
-        if event.is_readable() {
+        if event.is_readable() || hup {
             trace!("Read event for {:?}", token);
             if self.token == token {
-                self.accept(poll);
+                // A paused server picks the backlog up in `tick`.
+                if self.accept_paused_until.is_none() {
+                    self.accept(poll);
+                }
             } else {
-                match self.readable(token) {
+                match self.readable(poll, token) {
                     Ok(()) => {},
                     Err(e) => {
                         warn!("Read event failed for {:?}: {:?}", token, e);
-                        self.remove_token(token);
-                        return;
+
+                        // The peer broke the protocol, so tell it why before hanging up.
+                        let reason = e.to_string();
+                        let graceful = e.kind() == ErrorKind::InvalidData
+                            && self.connection(token).close_gracefully(Some(&reason)).is_ok();
+                        if !graceful {
+                            self.remove_token(token);
+                            return;
+                        }
                     }
                 }
             }
         }
 
         if self.token != token {
+            let c = self.connection(token);
+            if c.read_closed_at().is_some() && c.is_flushed() {
+                debug!("peer finished and everything is written, closing {:?}", token);
+                self.remove_token(token);
+                return;
+            }
+
             match self.connection(token).reregister(poll) {
                 Ok(()) => {},
                 Err(e) => {
                     warn!("Reregister failed {:?}", e);
                     self.remove_token(token);
-                    return;
                 }
             }
         }
@@ -174,28 +1746,69 @@ impl Server {
         loop {
             // Log an error if there is no socket, but otherwise move on so we do not tear down the
             // entire server.
-            let sock = match self.sock.accept() {
-                Ok((sock, _)) => sock,
+            let (sock, addr) = match self.sock.accept() {
+                Ok(accepted) => {
+                    self.metrics.accepts.inc();
+                    accepted
+                }
                 Err(e) => {
                     if e.kind() == ErrorKind::WouldBlock {
                         debug!("accept encountered WouldBlock");
+                    } else if is_out_of_fds(&e) {
+                        self.metrics.failed(Failure::AcceptFailed);
+                        self.shed_backlog();
                     } else {
                         error!("Failed to accept new socket, {:?}", e);
+                        self.metrics.failed(Failure::AcceptFailed);
                     }
                     return;
                 }
             };
 
-            let token = match self.conns.vacant_entry() {
+            // Closing it right away keeps a connect flood from filling the slab.
+            if let Some(ref mut limiter) = self.limiter {
+                if !limiter.allow(addr.ip(), Instant::now()) {
+                    debug!("closing connection from banned {}", addr);
+                    continue;
+                }
+            }
+            if self.draining {
+                debug!("closing connection from {}, draining", addr);
+                continue;
+            }
+
+            let token = match vacant_entry(&mut self.conns, self.capacities.connections) {
                 Some(entry) => {
-                    let c = Connection::new(sock, entry.index());
-                    entry.insert(c).index()
+                    let token = Token(entry.key());
+                    let mut c = Connection::with_capacity(sock,
+                                                          token,
+                                                          self.capacities.send_queue,
+                                                          self.capacities.write_batch);
+                    c.set_addr(Some(addr));
+                    c.set_poll_strategy(self.poll_strategy);
+                    c.set_coalesce(self.coalesce);
+                    c.set_fragment_limits(self.fragment_limits);
+                    c.set_endian(self.endian);
+                    c.set_framing(self.framing);
+                    c.set_metrics(Some(self.metrics.clone()));
+                    c.set_read_deadline(self.handshake_timeout.map(|t| Instant::now() + t));
+                    self.metrics.connections.inc();
+                    entry.insert(c);
+                    token
                 }
                 None => {
                     error!("Failed to insert connection into slab");
                     return;
                 }
             };
+            debug!("accepted connection; token={:?} peer={}", token, addr);
+
+            // Queued before registering, so the first writable event finds them waiting.
+            if let Err(e) = self.greet(token) {
+                debug!("Failed to greet {:?}, {:?}", token, e);
+                self.remove_token(token);
+                continue;
+            }
 
             debug!("registering {:?} with poller", token);
             match self.connection(token).register(poll) {
@@ -208,31 +1821,735 @@ impl Server {
         }
     }
 
+    /// Send a new connection the `GREETING` and `WELCOME` frames, if they are turned on.
+    fn greet(&mut self, token: Token) -> io::Result<()> {
+        if self.greeting {
+            let greeting = Arc::new(codec::encode_greeting(&self.capabilities()));
+            self.connection(token).send_frame(codec::GREETING, greeting)?;
+        }
+
+        if let Some(welcome) = self.welcome.clone() {
+            self.connection(token).send_frame(codec::WELCOME, welcome)?;
+        }
+
+        Ok(())
+    }
+
+    /// Connect to every federated server, and fill the relay pool, where we are not connected.
+    fn connect_links(&mut self, poll: &mut Poll) {
+        for i in 0..self.peers.len() {
+            let (addr, token) = self.peers[i];
+            if token.is_some() {
+                continue;
+            }
+
+            match self.connect_out(poll, &addr, true) {
+                Ok(token) => {
+                    debug!("federating with {} as {:?}", addr, token);
+                    self.peers[i].1 = Some(token);
+                }
+                Err(e) => warn!("Failed to connect to peer {}, {:?}", addr, e),
+            }
+        }
+
+        let addr = match self.relay {
+            Some(relay) => relay.addr,
+            None => return,
+        };
+        for i in 0..self.relay_links.len() {
+            if self.relay_links[i].is_some() {
+                continue;
+            }
+
+            match self.connect_out(poll, &addr, false) {
+                Ok(token) => {
+                    debug!("relaying to {} through {:?}", addr, token);
+                    self.relay_links[i] = Some(token);
+                }
+                Err(e) => warn!("Failed to connect to relay {}, {:?}", addr, e),
+            }
+        }
+    }
+
+    /// Start connecting to another server. A federated one has a `PEER` frame queued to
+    /// introduce us.
+    fn connect_out(&mut self, poll: &mut Poll, addr: &SocketAddr, peer: bool)
+        -> io::Result<Token>
+    {
+        let sock = self.sock.connect(addr)?;
+        let token = match vacant_entry(&mut self.conns, self.capacities.connections) {
+            Some(entry) => {
+                let token = Token(entry.key());
+                let mut c = Connection::with_capacity(sock,
+                                                      token,
+                                                      self.capacities.send_queue,
+                                                      self.capacities.write_batch);
+                c.set_peer(peer);
+                c.set_addr(Some(*addr));
+                c.set_poll_strategy(self.poll_strategy);
+                c.set_metrics(Some(self.metrics.clone()));
+                self.metrics.connections.inc();
+                entry.insert(c);
+                token
+            }
+            None => return Err(Error::other("No room for another connection")),
+        };
+
+        let result = if peer {
+            let secret = self.peer_secret.as_ref().map(String::as_bytes).unwrap_or_default();
+            let hello = Arc::new(codec::encode_peer(self.id, secret));
+            self.connection(token).send_frame(codec::PEER, hello)
+        } else {
+            Ok(())
+        };
+        let result = result.and_then(|_| self.connection(token).register(poll));
+        if let Err(e) = result {
+            self.remove_token(token);
+            return Err(e);
+        }
+        Ok(token)
+    }
+
+    /// Close every connection waiting to be accepted, because there is no file descriptor to
+    /// keep them with, then pause accepting for `ACCEPT_PAUSE`.
+    ///
+    /// Left in the backlog, they would hang until their clients gave up, and the listener would
+    /// not report them again. The spare file descriptor is given up so that each one can be
+    /// accepted and closed, and taken back once the backlog is empty.
+    fn shed_backlog(&mut self) {
+        warn!("out of file descriptors, closing new connections for {:?}", ACCEPT_PAUSE);
+
+        self.spare_fd = None;
+        loop {
+            match self.sock.accept() {
+                Ok((_sock, addr)) => debug!("closing connection from {}, out of fds", addr),
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    warn!("Failed to shed the accept backlog, {:?}", e);
+                    break;
+                }
+            }
+        }
+        self.spare_fd = reserve_fd();
+
+        self.accept_paused_until = Some(Instant::now() + ACCEPT_PAUSE);
+    }
+
     /// Forward a readable event to an established connection.
     ///
     /// Connections are identified by the token provided to us from the poller. Once a read has
     /// finished, push the receive buffer into the all the existing connections so we can
-    /// broadcast.
-    fn readable(&mut self, token: Token) -> io::Result<()> {
+    /// broadcast. In echo mode it only goes back to the connection it came from.
+    fn readable(&mut self, poll: &mut Poll, token: Token) -> io::Result<()> {
         debug!("server conn readable; token={:?}", token);
 
-        while let Some(message) = self.connection(token).readable()? {
+        let mut budget = self.read_budget;
+        loop {
+            // Come back for the rest next tick, so everyone else is read from first.
+            if budget == Some(0) {
+                if !self.unfinished_readers.contains(&token) {
+                    debug!("read budget used up, reading on from {:?} next tick", token);
+                    self.unfinished_readers.push_back(token);
+                }
+                return Ok(());
+            }
+
+            // Leave the rest in the socket until there is room, behind anyone paused before.
+            if self.throughput_exhausted() && self.connection(token).closing_at().is_none() {
+                let c = self.connection(token);
+                if !c.is_read_paused() {
+                    debug!("over the throughput limit, pausing reads from {:?}", token);
+                    c.pause_reading();
+                    self.paused_readers.push_back(token);
+                }
+                return Ok(());
+            }
+
+            let message = match self.connection(token).readable()? {
+                Some(message) => message,
+                None => break,
+            };
+            budget = budget.map(|n| n - 1);
+
+            if self.relay_links.contains(&Some(token)) {
+                trace!("ignoring frame kind {} from the relay {:?}", message.kind, token);
+                continue;
+            }
+
+            if self.connection(token).is_peer() {
+                self.read_from_peer(poll, token, message)?;
+                continue;
+            }
+
+            if message.kind == codec::PEER {
+                self.accept_peer(token, &message.payload)?;
+                continue;
+            }
+
+            if message.kind == codec::WHO {
+                self.answer_who(token)?;
+                continue;
+            }
+
+            // The pieces of a fragmented message can only be checked, or given one receipt,
+            // together, so none of them are broadcast before the last one has arrived.
+            let route = message.route;
+            let holds = self.inspects_payloads() || self.receipts;
+            let frames = if message.kind == codec::FRAGMENT && holds {
+                let (whole, pieces) = match self.connection(token).hold_fragment(message.payload)? {
+                    Some(held) => held,
+                    None => continue,
+                };
+                if !self.admit(token, &whole)? {
+                    continue;
+                }
+                pieces.into_iter()
+                    .map(|payload| Message { kind: codec::FRAGMENT, route, payload })
+                    .collect()
+            } else {
+                if message.kind == codec::FRAGMENT {
+                    self.connection(token).pass_fragment(&message.payload)?;
+                }
+                if !self.admit(token, &message)? {
+                    continue;
+                }
+                vec![message]
+            };
+
+            // A fragmented message has only reached the connections every piece of it was
+            // queued for, so the pieces are tallied by who they were queued for.
+            let pieces = frames.len();
+            let mut tally: HashMap<Token, usize> = HashMap::new();
+            let mut queued = 0;
+            for message in frames {
+                let kind = message.kind;
+                let payload = message.payload;
+                let mut recipients = Vec::new();
+                let collect = if pieces > 1 { Some(&mut recipients) } else { None };
 
-            let rc_message = Rc::new(message);
-            // Echo the message too all connected clients.
-            for c in self.conns.iter_mut() {
-                c.send_message(rc_message.clone())?;
+                queued = if self.relay.is_some() {
+                    self.relay(poll, token, kind, route, payload, collect)
+                } else if self.mode == Mode::Echo {
+                    let len = payload.len();
+                    let c = self.connection(token);
+                    if c.has_credit() {
+                        // The connection we are reading from is reregistered once we are done
+                        // with it.
+                        c.send_routed(kind, route, payload)?;
+                        self.count_throughput(len);
+                        if let Some(recipients) = collect {
+                            recipients.push(token);
+                        }
+                        1
+                    } else {
+                        c.skip_message();
+                        0
+                    }
+                } else {
+                    self.forwarded += 1;
+                    let origin = Origin { server: self.id, seq: self.forwarded };
+                    self.forward(poll, token, origin, kind, route, &payload);
+                    let frame = SharedFrame::new(kind, route, &payload);
+                    if let Some(ref shard) = self.shard {
+                        shard.share(&frame);
+                    }
+                    self.broadcast(poll, token, &frame, collect)?.queued
+                };
+
+                for recipient in recipients {
+                    *tally.entry(recipient).or_insert(0) += 1;
+                }
+            }
+            if pieces > 1 {
+                queued = tally.values().filter(|&&n| n == pieces).count() as u64;
+            }
+
+            if self.receipts {
+                let c = self.connection(token);
+                let receipt = codec::encode_receipt(c.messages_read(), queued);
+                c.send_frame(codec::RECEIPT, Arc::new(receipt))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Whether messages have to be seen whole to be checked before they are broadcast.
+    fn inspects_payloads(&self) -> bool {
+        self.text_only || self.payload_format.is_some() || !self.filters.is_empty()
+    }
+
+    /// Check a message read from `token` against the server's rules, telling the sender why if
+    /// it is rejected and the rules say to. Returns whether it may be broadcast.
+    fn admit(&mut self, token: Token, message: &Message) -> io::Result<bool> {
+        if self.text_only && !filter::is_text(message.body()) {
+            debug!("rejecting binary message from {:?}", token);
+            self.reject(token, b"Payload is not valid UTF-8".to_vec())?;
+            return Ok(false);
+        }
+
+        let malformed = match self.payload_format {
+            Some(ref format) => format.decode(message.body()).err().map(|e| e.to_string()),
+            None => None,
+        };
+        if let Some(reason) = malformed {
+            debug!("rejecting malformed message from {:?}: {}", token, reason);
+            self.reject(token, reason.into_bytes())?;
+            return Ok(false);
+        }
+
+        if let Err(reason) = self.filters.check(message.body()) {
+            debug!("filtered message from {:?}: {}", token, reason);
+            if self.filters.action == Action::Reject {
+                self.reject(token, reason.as_bytes().to_vec())?;
             }
+            return Ok(false);
+        }
+
+        Ok(true)
+    }
+
+    /// Tell `token` why its message was rejected, unless it is a federated server, which has no
+    /// one to tell.
+    fn reject(&mut self, token: Token, reason: Vec<u8>) -> io::Result<()> {
+        let c = self.connection(token);
+        if c.is_peer() {
+            return Ok(());
+        }
+        c.send_frame(codec::ERROR, reason)
+    }
+
+    /// Treat the connection as a federated server that introduced itself with a `PEER` frame,
+    /// if it knows the peer secret.
+    fn accept_peer(&mut self, token: Token, payload: &[u8]) -> io::Result<()> {
+        let (id, secret) = codec::decode_peer(payload).ok_or_else(|| {
+            self.metrics.failed(Failure::Malformed);
+            Error::new(ErrorKind::InvalidData, "Malformed peer id")
+        })?;
+        let refused = match self.peer_secret {
+            Some(ref ours) if admin::same(ours.as_bytes(), secret) => None,
+            Some(_) => Some("Wrong peer secret"),
+            None => Some("Not taking federated servers"),
+        };
+        if let Some(reason) = refused {
+            warn!("refusing {:?} as a federated server: {}", token, reason);
+            return Err(Error::new(ErrorKind::InvalidData, reason));
+        }
+        if id == self.id {
+            return Err(Error::new(ErrorKind::InvalidData, "Peer has our server id"));
         }
 
+        debug!("{:?} is federated server {:x}", token, id);
+        self.connection(token).set_peer(true);
         Ok(())
     }
 
+    /// Handle a message from a federated server. Only `FORWARD` frames mean anything here, the
+    /// rest is what it sends its clients.
+    ///
+    /// A forwarded message is broadcast and passed on to the other federated servers, unless it
+    /// started here, has been seen before or breaks our rules. The server it was first sent to
+    /// gets no receipt. It keeps the route of the `FORWARD` frame it came in.
+    fn read_from_peer(&mut self, poll: &mut Poll, token: Token, message: Message)
+        -> io::Result<()>
+    {
+        if message.kind != codec::FORWARD || self.mode == Mode::Echo {
+            trace!("ignoring frame kind {} from peer {:?}", message.kind, token);
+            return Ok(());
+        }
+
+        let (origin, kind, skip) = match codec::decode_forward(&message.payload) {
+            Some((origin, kind @ (codec::DATA | codec::FRAGMENT), body)) => {
+                (origin, kind, message.payload.len() - body.len())
+            }
+            Some((origin, kind @ (codec::REQUEST | codec::REPLY), body))
+                if body.len() >= codec::CORRELATION_ID_LEN =>
+            {
+                (origin, kind, message.payload.len() - body.len())
+            }
+            _ => {
+                self.metrics.failed(Failure::Malformed);
+                return Err(Error::new(ErrorKind::InvalidData, "Malformed forward"));
+            }
+        };
+
+        if !self.first_sighting(origin) {
+            trace!("dropping forwarded message {:?} seen before", origin);
+            return Ok(());
+        }
+
+        // Pieces from different clients of the same server may be interleaved, so they cannot be
+        // put back together here to be checked.
+        if kind == codec::FRAGMENT && self.inspects_payloads() {
+            debug!("dropping forwarded fragment {:?}, it cannot be checked alone", origin);
+            return Ok(());
+        }
+
+        // The body is the end of the forward, so it is broadcast without copying it out.
+        let body = Message {
+            kind,
+            route: message.route,
+            payload: message.payload.slice(skip..message.payload.len()),
+        };
+        if !self.admit(token, &body)? {
+            return Ok(());
+        }
+
+        let (route, body) = (body.route, body.payload);
+        self.forward(poll, token, origin, kind, route, &body);
+        let frame = SharedFrame::new(kind, route, &body);
+        if let Some(ref shard) = self.shard {
+            shard.share(&frame);
+        }
+        self.broadcast(poll, token, &frame, None).map(|_| ())
+    }
+
+    /// Broadcast what the other shards have broadcast since we last looked.
+    fn read_shards(&mut self, poll: &mut Poll) {
+        loop {
+            let received = match self.shard {
+                Some(ref shard) => shard.recv(),
+                None => return,
+            };
+
+            match received {
+                Ok(Some(frame)) => {
+                    // No connection has the server's token, so none is treated as the sender.
+                    let token = self.token;
+                    if let Err(e) = self.broadcast(poll, token, &frame, None) {
+                        warn!("Broadcast from another shard failed, {:?}", e);
+                    }
+                }
+                Ok(None) => return,
+                Err(e) => {
+                    warn!("Failed to hear from the other shards, {:?}", e);
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Announce, or reconfigure, as other threads have asked to since we last looked.
+    fn read_announcements(&mut self, poll: &mut Poll) {
+        loop {
+            let received = match self.announcements {
+                Some(ref a) => shard::recv_or_clear(&a.inbox, &a.readiness),
+                None => return,
+            };
+
+            match received {
+                Ok(Some(Control::Announce(text))) => {
+                    let delivery = self.announce(poll, &text);
+                    debug!("announced; delivery={:?}", delivery);
+                }
+                Ok(Some(Control::Reconfigure(settings))) => {
+                    self.apply_settings(*settings);
+                    info!("settings reloaded; server={:016x}", self.id);
+                }
+                Ok(Some(Control::Request(request, answer))) => {
+                    // Whoever asked may have given up waiting.
+                    let _ = answer.send(self.answer(poll, request));
+                }
+                Ok(Some(Control::Export(stats))) => {
+                    let _ = stats.send(self.connection_stats());
+                }
+                Ok(None) => return,
+                Err(e) => {
+                    warn!("Failed to read announcements, {:?}", e);
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Whether a forwarded message is new to us, remembering it if it is.
+    fn first_sighting(&mut self, origin: Origin) -> bool {
+        if origin.server == self.id {
+            return false;
+        }
+
+        let last = self.seen.entry(origin.server).or_insert(0);
+        if origin.seq <= *last {
+            return false;
+        }
+        *last = origin.seq;
+        true
+    }
+
+    /// Pass a message on to every federated server except `from`, in a `FORWARD` frame sent on
+    /// the message's route.
+    ///
+    /// A federated server that fails along the way is closed, and connected to again next tick.
+    fn forward(&mut self, poll: &mut Poll, from: Token, origin: Origin, kind: u8, route: Route,
+               message: &[u8])
+    {
+        let mut payload = None;
+        let mut failed = Vec::new();
+
+        let peers = self.conns.iter_mut().map(|(_, c)| c)
+            .filter(|c| c.is_peer() && c.token != from);
+        for c in peers {
+            let frame = payload.get_or_insert_with(|| {
+                Arc::new(codec::encode_forward(origin, kind, message))
+            });
+
+            let was_writable = c.is_writable();
+            let mut result = c.send_routed(codec::FORWARD, route, frame.clone());
+            if result.is_ok() && !was_writable && c.is_writable() {
+                result = c.reregister(poll);
+            }
+
+            if let Err(e) = result {
+                warn!("Forwarding to {:?} failed, {:?}", c.token, e);
+                failed.push(c.token);
+            }
+        }
+
+        for token in failed {
+            self.remove_token(token);
+        }
+    }
+
+    /// Queue a message for the relay downstream, on the connection `from` is assigned to.
+    /// Returns how many connections it was queued for, which is one unless that one is down, and
+    /// adds that one to `recipients` if given.
+    fn relay(&mut self, poll: &mut Poll, from: Token, kind: u8, route: Route, message: Payload,
+             recipients: Option<&mut Vec<Token>>)
+        -> u64
+    {
+        let link = match self.relay_links[from.0 % self.relay_links.len()] {
+            Some(link) => link,
+            None => {
+                debug!("relay connection for {:?} is down, dropping its message", from);
+                self.delivery.failed += 1;
+                return 0;
+            }
+        };
+
+        let len = message.len();
+        let c = self.connection(link);
+        let was_writable = c.is_writable();
+        let mut result = c.send_routed(kind, route, message);
+        if result.is_ok() && !was_writable && c.is_writable() {
+            result = c.reregister(poll);
+        }
+
+        match result {
+            Ok(()) => {
+                self.count_throughput(len);
+                self.delivery.queued += 1;
+                if let Some(recipients) = recipients {
+                    recipients.push(link);
+                }
+                1
+            }
+            Err(e) => {
+                warn!("Relaying through {:?} failed, {:?}", link, e);
+                self.delivery.failed += 1;
+                self.remove_token(link);
+                0
+            }
+        }
+    }
+
+    /// Queue a frame for every connected client, except those too far behind to take it. Every
+    /// connection writes it out of the same buffer. Federated servers are sent it by `forward`
+    /// instead, and relay connections not at all.
+    ///
+    /// A client that fails along the way is closed, and the others still get the message. If the
+    /// sender fails, its error is returned once everyone else has been dealt with. Every client it
+    /// was queued for is added to `recipients`, if given.
+    fn broadcast(&mut self, poll: &mut Poll, from: Token, frame: &SharedFrame,
+                 mut recipients: Option<&mut Vec<Token>>)
+        -> io::Result<Delivery>
+    {
+        let high_watermark = self.high_watermark;
+        let spill = self.spill.as_ref();
+        let metrics = &self.metrics;
+        let mut delivery = Delivery::default();
+        let mut failed = Vec::new();
+        let mut sender_error = None;
+
+        let relay_links = &self.relay_links;
+        for c in self.conns.iter_mut().map(|(_, c)| c).filter(|c| !c.is_peer()) {
+            if relay_links.contains(&Some(c.token)) {
+                continue;
+            }
+
+            // Once a connection spills, the rest go after what is on disk until it catches up.
+            let behind = c.is_spilling()
+                || high_watermark.map(|mark| c.queued_bytes() >= mark).unwrap_or(false);
+            let spill_to = spill.filter(|spill| behind && c.has_spill_room(spill));
+            if behind && spill_to.is_none() {
+                trace!("skipping {:?}, over the high watermark", c.token);
+                metrics.failed(Failure::QueueOverflow);
+                c.skip_message();
+                delivery.skipped += 1;
+                continue;
+            }
+
+            if !c.has_credit() {
+                trace!("skipping {:?}, out of credit", c.token);
+                c.skip_message();
+                delivery.skipped += 1;
+                continue;
+            }
+
+            let was_writable = c.is_writable();
+            let mut result = match spill_to {
+                Some(spill) => c.spill_shared(frame, spill),
+                None => c.send_shared(frame),
+            };
+
+            // A connection that just started waiting on a writable event has to be
+            // reregistered, otherwise the poller never tells us when it can be written to.
+            // The connection we are reading from is reregistered once we are done with it.
+            if result.is_ok() && c.token != from && !was_writable && c.is_writable() {
+                result = c.reregister(poll);
+            }
+
+            match result {
+                Ok(()) => {
+                    delivery.queued += 1;
+                    if let Some(ref mut recipients) = recipients {
+                        recipients.push(c.token);
+                    }
+                }
+                Err(e) => {
+                    delivery.failed += 1;
+                    if c.token == from {
+                        sender_error = Some(e);
+                    } else {
+                        warn!("Broadcast to {:?} failed, {:?}", c.token, e);
+                        failed.push(c.token);
+                    }
+                }
+            }
+        }
+
+        for token in failed {
+            self.remove_token(token);
+        }
+
+        self.count_throughput(frame.payload().len() * delivery.queued as usize);
+        self.delivery.add(delivery);
+        if delivery.skipped > 0 || delivery.failed > 0 {
+            debug!("broadcast from {:?} was not delivered everywhere, {:?}", from, delivery);
+        }
+
+        match sender_error {
+            Some(e) => Err(e),
+            None => Ok(delivery),
+        }
+    }
+
+    /// Tell `token` the id of every connection, its own first. Connections that are closing,
+    /// federated servers and relay connections are left out.
+    fn answer_who(&mut self, token: Token) -> io::Result<()> {
+        let mut ids = vec![token.0 as u64];
+        let relay_links = &self.relay_links;
+        ids.extend(self.conns.iter().map(|(_, c)| c)
+            .filter(|c| c.token != token && c.closing_at().is_none() && !c.is_peer())
+            .filter(|c| !relay_links.contains(&Some(c.token)))
+            .map(|c| c.token.0 as u64));
+
+        let who = Arc::new(codec::encode_who(&ids));
+        self.connection(token).send_frame(codec::WHO, who)
+    }
+
+    /// Count `bytes` queued for connections against the throughput limit, if there is one.
+    fn count_throughput(&mut self, bytes: usize) {
+        if let Some(ref mut t) = self.throughput {
+            t.take(bytes as u64, Instant::now());
+        }
+    }
+
     /// Find a connection in the slab using the given token.
     ///
-    /// This function will panic if the token does not exist. Use self.conns.contains(token)
+    /// This function will panic if the token does not exist. Use self.conns.contains(token.0)
     /// before using this function.
-    fn connection(&mut self, token: Token) -> &mut Connection {
-        &mut self.conns[token]
+    fn connection(&mut self, token: Token) -> &mut Connection<L::Stream> {
+        &mut self.conns[token.0]
+    }
+}
+
+/// Open a file descriptor to hold in reserve for `Server::shed_backlog`.
+fn reserve_fd() -> Option<File> {
+    match File::open("/dev/null") {
+        Ok(file) => Some(file),
+        Err(e) => {
+            warn!("Failed to reserve a spare file descriptor, {:?}", e);
+            None
+        }
+    }
+}
+
+/// Pick a server id that is unlikely to be anyone else's.
+fn random_id() -> u64 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u32(::std::process::id());
+    hasher.finish()
+}
+
+/// Whether an event that took `took` to handle, out of a poll that took `total`, took more than
+/// `percent` of it. Short polls never count.
+fn is_slow(took: Duration, total: Duration, percent: u32) -> bool {
+    total >= MIN_SLOW_POLL && took.as_secs_f64() * 100.0 > total.as_secs_f64() * percent as f64
+}
+
+/// Whether `e` means the process or the whole system has run out of file descriptors.
+fn is_out_of_fds(e: &io::Error) -> bool {
+    matches!(e.raw_os_error(), Some(libc::EMFILE) | Some(libc::ENFILE))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Error, ErrorKind};
+    use std::time::Duration;
+
+    use super::{is_slow, PollRetry, MAX_POLL_BACKOFF, MAX_POLL_FAILURES, MIN_POLL_BACKOFF,
+                MIN_SLOW_POLL};
+
+    #[test]
+    fn events_are_slow_when_they_take_more_than_their_share() {
+        let ms = Duration::from_millis;
+        assert!(is_slow(ms(60), ms(100), 50));
+        assert!(!is_slow(ms(50), ms(100), 50));
+        assert!(!is_slow(ms(40), ms(100), 50));
+
+        // A poll this short is fine, however it was spent.
+        assert!(!is_slow(MIN_SLOW_POLL / 2, MIN_SLOW_POLL / 2, 50));
+    }
+
+    #[test]
+    fn interrupted_polls_are_retried_straight_away() {
+        let mut retry = PollRetry::default();
+        for _ in 0..MAX_POLL_FAILURES * 2 {
+            let wait = retry.failed(Error::from(ErrorKind::Interrupted)).unwrap();
+            assert_eq!(wait, Duration::from_secs(0));
+        }
+    }
+
+    #[test]
+    fn failed_polls_back_off_then_give_up() {
+        let mut retry = PollRetry::default();
+        assert_eq!(retry.failed(Error::other("boom")).unwrap(), MIN_POLL_BACKOFF);
+        assert_eq!(retry.failed(Error::other("boom")).unwrap(), MIN_POLL_BACKOFF * 2);
+
+        for _ in 2..MAX_POLL_FAILURES - 1 {
+            assert!(retry.failed(Error::other("boom")).unwrap() <= MAX_POLL_BACKOFF);
+        }
+        assert!(retry.failed(Error::other("boom")).is_err());
+    }
+
+    #[test]
+    fn a_good_poll_resets_the_backoff() {
+        let mut retry = PollRetry::default();
+        for _ in 0..MAX_POLL_FAILURES - 1 {
+            retry.failed(Error::other("boom")).unwrap();
+        }
+
+        retry.succeeded();
+        assert_eq!(retry.failed(Error::other("boom")).unwrap(), MIN_POLL_BACKOFF);
     }
 }
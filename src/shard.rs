@@ -0,0 +1,331 @@
+//! Several servers sharing one listener, each on its own thread with its own poll loop.
+//!
+//! `run` accepts on the calling thread and hands each new connection to a shard picked by hashing
+//! its peer address. A shard owns the connections it is handed for as long as they are open, so
+//! nothing about a connection is ever shared between threads. What one shard broadcasts, it also
+//! pushes onto a lock-free queue into every other shard, frame and all, and wakes that shard's
+//! poll loop to queue the same frame for its own clients. A broadcast is encoded once, and never
+//! copied on its way between shards.
+//!
+//! Everything else stays per shard. Capacities and limits apply to each shard separately, a
+//! `WHO` frame only lists the clients of the asker's shard, and a receipt only counts them.
+
+use std::cmp;
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::io::{self, Error, ErrorKind};
+use std::net::{self, SocketAddr};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::thread;
+use std::time::Duration;
+
+use crossbeam_queue::SegQueue;
+use mio::{Evented, Poll, PollOpt, Ready, Registration, SetReadiness, Token};
+use mio::net::TcpStream;
+
+use connection::SharedFrame;
+use metrics::Metrics;
+use server::Server;
+use transport::Listener;
+
+/// How long accepting waits after it fails, for instance for want of file descriptors.
+const ACCEPT_RETRY: Duration = Duration::from_millis(100);
+
+/// A lock-free queue into a shard, and whether the shard has stopped taking from it.
+struct Queue<T> {
+    items: SegQueue<T>,
+    closed: AtomicBool,
+}
+
+/// The sending end of a queue into a shard, and what wakes its poll loop when there is
+/// something in it.
+struct Waker<T> {
+    queue: Arc<Queue<T>>,
+    readiness: SetReadiness,
+}
+
+impl<T> Waker<T> {
+    fn send(&self, item: T) -> io::Result<()> {
+        if self.queue.closed.load(Ordering::Acquire) {
+            return Err(Error::new(ErrorKind::BrokenPipe, "Shard stopped"));
+        }
+
+        self.queue.items.push(item);
+        self.readiness.set_readiness(Ready::readable())
+    }
+}
+
+impl<T> Clone for Waker<T> {
+    fn clone(&self) -> Waker<T> {
+        Waker { queue: self.queue.clone(), readiness: self.readiness.clone() }
+    }
+}
+
+/// The receiving end of a queue into a shard. The queue is closed once it is dropped, so
+/// whoever sends on it next finds out the shard has stopped.
+struct Inbox<T> {
+    queue: Arc<Queue<T>>,
+    readiness: SetReadiness,
+}
+
+impl<T> Inbox<T> {
+    /// Take the next item off the queue. Once it is empty, the readiness is cleared, so the next
+    /// item sent wakes the poll loop again.
+    fn recv(&self) -> io::Result<Option<T>> {
+        if let Some(item) = self.queue.items.pop() {
+            return Ok(Some(item));
+        }
+
+        self.readiness.set_readiness(Ready::empty())?;
+
+        // Anything sent before the readiness was cleared did not wake us, so look once more.
+        Ok(self.queue.items.pop())
+    }
+}
+
+impl<T> Drop for Inbox<T> {
+    fn drop(&mut self) {
+        self.queue.closed.store(true, Ordering::Release);
+    }
+}
+
+/// A queue into a shard, woken through `readiness`.
+fn queue<T>(readiness: SetReadiness) -> (Waker<T>, Inbox<T>) {
+    let queue = Arc::new(Queue { items: SegQueue::new(), closed: AtomicBool::new(false) });
+    (Waker { queue: queue.clone(), readiness: readiness.clone() }, Inbox { queue, readiness })
+}
+
+/// Take the next item off a server's announcements. Once they are empty, the readiness is
+/// cleared, so the next item sent wakes the poll loop again.
+pub(crate) fn recv_or_clear<T>(receiver: &Receiver<T>, readiness: &SetReadiness)
+    -> io::Result<Option<T>>
+{
+    match receiver.try_recv() {
+        Ok(item) => return Ok(Some(item)),
+        Err(TryRecvError::Disconnected) => {
+            return Err(Error::new(ErrorKind::BrokenPipe, "Channel closed"));
+        }
+        Err(TryRecvError::Empty) => {},
+    }
+
+    readiness.set_readiness(Ready::empty())?;
+
+    // Anything sent before the readiness was cleared did not wake us, so look once more.
+    match receiver.try_recv() {
+        Ok(item) => Ok(Some(item)),
+        Err(TryRecvError::Empty) => Ok(None),
+        Err(TryRecvError::Disconnected) => Err(Error::new(ErrorKind::BrokenPipe, "Channel closed")),
+    }
+}
+
+/// The listener of a shard, which accepts the connections `run` hands it.
+pub struct ShardListener {
+    accepted: Inbox<(net::TcpStream, SocketAddr)>,
+    registration: Registration,
+}
+
+impl Listener for ShardListener {
+    type Stream = TcpStream;
+
+    fn accept(&self) -> io::Result<(TcpStream, SocketAddr)> {
+        match self.accepted.recv()? {
+            Some((stream, addr)) => Ok((TcpStream::from_stream(stream)?, addr)),
+            None => Err(ErrorKind::WouldBlock.into()),
+        }
+    }
+
+    fn connect(&self, addr: &SocketAddr) -> io::Result<TcpStream> {
+        TcpStream::connect(addr)
+    }
+}
+
+impl Evented for ShardListener {
+    fn register(&self, poll: &Poll, token: Token, interest: Ready, opts: PollOpt)
+        -> io::Result<()>
+    {
+        self.registration.register(poll, token, interest, opts)
+    }
+
+    fn reregister(&self, poll: &Poll, token: Token, interest: Ready, opts: PollOpt)
+        -> io::Result<()>
+    {
+        self.registration.reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        Evented::deregister(&self.registration, poll)
+    }
+}
+
+/// How a shard's server trades broadcasts with the other shards. The server registers it with
+/// its poller, and is woken through it when another shard has broadcast something.
+pub struct Shard {
+    inbox: Inbox<SharedFrame>,
+    registration: Registration,
+    others: Vec<Waker<SharedFrame>>,
+}
+
+impl Shard {
+    /// Pass a frame this shard broadcast to every other shard. They all share its buffer.
+    pub fn share(&self, frame: &SharedFrame) {
+        for other in &self.others {
+            if let Err(e) = other.send(frame.clone()) {
+                warn!("Failed to pass a broadcast to another shard, {:?}", e);
+            }
+        }
+    }
+
+    /// The next frame another shard broadcast, if there is one.
+    pub fn recv(&self) -> io::Result<Option<SharedFrame>> {
+        self.inbox.recv()
+    }
+}
+
+impl Evented for Shard {
+    fn register(&self, poll: &Poll, token: Token, interest: Ready, opts: PollOpt)
+        -> io::Result<()>
+    {
+        self.registration.register(poll, token, interest, opts)
+    }
+
+    fn reregister(&self, poll: &Poll, token: Token, interest: Ready, opts: PollOpt)
+        -> io::Result<()>
+    {
+        self.registration.reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        Evented::deregister(&self.registration, poll)
+    }
+}
+
+/// What a shard thread sends back once it has made its registrations: the wakers for its
+/// accepted connections and for broadcasts from other shards.
+type Setup = (Waker<(net::TcpStream, SocketAddr)>, Waker<SharedFrame>);
+
+/// Run `shards` servers on threads of their own, accepting connections from `listener` on this
+/// thread and handing each to a shard. Each server is set up by `configure` before it runs.
+/// They all count into one metrics registry, which `Server::metrics` returns on any of them.
+///
+/// This only returns if accepting fails for good, or a shard stops.
+pub fn run<F>(listener: net::TcpListener, shards: usize, configure: F) -> io::Result<()>
+    where F: Fn(&mut Server<ShardListener>) + Send + Sync + 'static
+{
+    let shards = cmp::max(shards, 1);
+    let configure = Arc::new(configure);
+    let metrics = Arc::new(Metrics::new());
+
+    // A shard has to register with its own poller before anyone can wake it, and needs everyone
+    // else's wakers before it can run, so each one sends its wakers back and waits for the rest.
+    let (setup_tx, setup_rx) = mpsc::channel();
+    let mut others_txs = Vec::with_capacity(shards);
+    for i in 0..shards {
+        let (others_tx, others_rx) = mpsc::channel();
+        others_txs.push(others_tx);
+
+        let setup_tx = setup_tx.clone();
+        let configure = configure.clone();
+        let metrics = metrics.clone();
+        thread::Builder::new().name(format!("mob-shard-{}", i)).spawn(move || {
+            if let Err(e) = run_shard(i, setup_tx, others_rx, metrics, &*configure) {
+                error!("Shard {} stopped, {:?}", i, e);
+            }
+        })?;
+    }
+    drop(setup_tx);
+
+    let mut setups: Vec<Option<Setup>> = (0..shards).map(|_| None).collect();
+    for _ in 0..shards {
+        let (i, setup) = setup_rx.recv()
+            .map_err(|_| Error::other("A shard stopped before it started"))?;
+        setups[i] = Some(setup);
+    }
+    let (acceptors, broadcasters): (Vec<_>, Vec<_>) =
+        setups.into_iter().map(Option::unwrap).unzip();
+
+    for (i, others_tx) in others_txs.into_iter().enumerate() {
+        let others = broadcasters.iter().enumerate()
+            .filter(|&(j, _)| j != i)
+            .map(|(_, w)| w.clone())
+            .collect::<Vec<_>>();
+        others_tx.send(others).map_err(|_| Error::other("A shard stopped before it started"))?;
+    }
+
+    // File descriptors are handed out lowest first, so they would pile connections onto the
+    // first few shards. Peer addresses spread them out, and keyed at random, nobody can aim
+    // theirs at one shard.
+    let hasher = RandomState::new();
+    info!("accepting for {} shards", shards);
+    loop {
+        let (stream, addr) = match listener.accept() {
+            Ok(accepted) => accepted,
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => {
+                // Running out of file descriptors passes once connections close.
+                warn!("Failed to accept new socket, {:?}", e);
+                thread::sleep(ACCEPT_RETRY);
+                continue;
+            }
+        };
+
+        let shard = (hasher.hash_one(addr) % shards as u64) as usize;
+        debug!("handing connection from {} to shard {}", addr, shard);
+        acceptors[shard].send((stream, addr))?;
+    }
+}
+
+/// Set up and run the server of shard `i`.
+fn run_shard<F>(i: usize,
+                setup_tx: Sender<(usize, Setup)>,
+                others_rx: Receiver<Vec<Waker<SharedFrame>>>,
+                metrics: Arc<Metrics>,
+                configure: &F)
+    -> io::Result<()>
+    where F: Fn(&mut Server<ShardListener>)
+{
+    let (registration, readiness) = Registration::new2();
+    let (acceptor, accepted) = queue(readiness);
+    let listener = ShardListener { accepted, registration };
+
+    let (registration, readiness) = Registration::new2();
+    let (broadcaster, inbox) = queue(readiness);
+
+    setup_tx.send((i, (acceptor, broadcaster))).map_err(|_| Error::other("Setup abandoned"))?;
+    let others = others_rx.recv().map_err(|_| Error::other("Setup abandoned"))?;
+
+    let mut poll = Poll::new()?;
+    let mut server = Server::new(listener);
+    server.set_metrics(metrics);
+    configure(&mut server);
+    server.set_shard(Some(Shard { inbox, registration, others }));
+    server.run(&mut poll)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::ErrorKind;
+
+    use mio::Registration;
+
+    use codec::{self, Route};
+    use connection::SharedFrame;
+
+    use super::queue;
+
+    #[test]
+    fn frames_cross_over_in_the_same_buffer_until_the_shard_stops() {
+        let (_registration, readiness) = Registration::new2();
+        let (waker, inbox) = queue(readiness);
+
+        let frame = SharedFrame::new(codec::DATA, Route::default(), b"hello");
+        waker.send(frame.clone()).unwrap();
+        let received = inbox.recv().unwrap().unwrap();
+        assert_eq!(received.payload().as_ptr(), frame.payload().as_ptr());
+        assert!(inbox.recv().unwrap().is_none());
+
+        drop(inbox);
+        assert_eq!(waker.send(frame).unwrap_err().kind(), ErrorKind::BrokenPipe);
+    }
+}
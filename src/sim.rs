@@ -0,0 +1,811 @@
+//! A deterministic, in-memory network for driving the whole `Server` in tests.
+//!
+//! `SimNet` stands in for the kernel. It hands out a `SimListener` for the server and a
+//! `SimClient` for each simulated peer. Nothing touches a real socket: bytes travel through
+//! in-memory links and readiness is delivered to the real `Poll` through mio `Registration`s.
+//!
+//! Time only moves when the test calls `SimNet::advance`, so latency, partial writes and
+//! disconnects happen at exactly the same point on every run.
+
+use std::cell::RefCell;
+use std::cmp;
+use std::collections::VecDeque;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{Shutdown, SocketAddr};
+use std::rc::Rc;
+
+use mio::{Evented, Poll, PollOpt, Ready, Registration, SetReadiness, Token};
+use mio::unix::UnixReady;
+
+use codec;
+use transport::{Listener, Transport};
+
+/// Knobs for the simulated network.
+#[derive(Clone, Copy, Debug)]
+pub struct SimConfig {
+    /// One-way delay, in virtual milliseconds, for bytes in either direction.
+    pub latency: u64,
+
+    /// The largest number of bytes a single server-side `write` accepts.
+    pub max_write: usize,
+
+    /// How many bytes may be in flight to, or sitting unread at, a client before server-side
+    /// writes return `WouldBlock`.
+    pub window: usize,
+}
+
+impl Default for SimConfig {
+    fn default() -> SimConfig {
+        SimConfig {
+            latency: 0,
+            max_write: usize::MAX,
+            window: 64 * 1024,
+        }
+    }
+}
+
+/// The state shared between the two ends of one simulated TCP connection.
+struct Link {
+    config: SimConfig,
+    now: u64,
+
+    // bytes travelling towards the server, with the time they arrive
+    to_server: VecDeque<(u64, Vec<u8>)>,
+
+    // bytes that have arrived and can be read by the server
+    inbound: VecDeque<u8>,
+
+    // bytes travelling towards the client, with the time they arrive
+    to_client: VecDeque<(u64, Vec<u8>)>,
+
+    // bytes that have arrived and can be read by the client
+    received: Vec<u8>,
+
+    // the client disconnected
+    client_closed: bool,
+
+    // the server dropped its end of the connection
+    server_closed: bool,
+
+    // readiness handle for the server side registration
+    readiness: SetReadiness,
+}
+
+impl Link {
+    fn in_flight_to_client(&self) -> usize {
+        self.to_client.iter().map(|(_, b)| b.len()).sum::<usize>() + self.received.len()
+    }
+
+    /// Move every segment that is due at the current time to the receiving side.
+    fn deliver(&mut self) {
+        while self.to_server.front().map(|&(at, _)| at <= self.now).unwrap_or(false) {
+            let (_, bytes) = self.to_server.pop_front().unwrap();
+            self.inbound.extend(bytes);
+        }
+
+        while self.to_client.front().map(|&(at, _)| at <= self.now).unwrap_or(false) {
+            let (_, bytes) = self.to_client.pop_front().unwrap();
+            self.received.extend(bytes);
+        }
+
+        self.update();
+    }
+
+    /// Recompute the server side readiness, emulating level-triggered socket readiness.
+    fn update(&self) {
+        if self.server_closed {
+            return;
+        }
+
+        let mut ready = Ready::empty();
+        let fin_arrived = self.client_closed && self.to_server.is_empty();
+
+        if !self.inbound.is_empty() || fin_arrived {
+            ready.insert(Ready::readable());
+        }
+
+        if fin_arrived {
+            ready.insert(UnixReady::hup());
+        }
+
+        if self.in_flight_to_client() < self.config.window {
+            ready.insert(Ready::writable());
+        }
+
+        let _ = self.readiness.set_readiness(ready);
+    }
+}
+
+struct NetInner {
+    config: SimConfig,
+    now: u64,
+    links: Vec<Rc<RefCell<Link>>>,
+    backlog: VecDeque<SimStream>,
+    next_port: u16,
+    listener: SetReadiness,
+}
+
+/// The simulated network.
+pub struct SimNet {
+    inner: Rc<RefCell<NetInner>>,
+}
+
+impl SimNet {
+    /// Create a network and the listener the server should be built with.
+    pub fn new(config: SimConfig) -> (SimNet, SimListener) {
+        let (registration, readiness) = Registration::new2();
+
+        let net = SimNet {
+            inner: Rc::new(RefCell::new(NetInner {
+                config,
+                now: 0,
+                links: Vec::new(),
+                backlog: VecDeque::new(),
+                next_port: 40_000,
+                listener: readiness,
+            })),
+        };
+
+        let listener = SimListener {
+            net: net.inner.clone(),
+            registration,
+        };
+
+        (net, listener)
+    }
+
+    /// Open a new client connection. The server sees it on its next poll.
+    pub fn connect(&self) -> SimClient {
+        let mut net = self.inner.borrow_mut();
+        let (registration, readiness) = Registration::new2();
+
+        let link = Rc::new(RefCell::new(Link {
+            config: net.config,
+            now: net.now,
+            to_server: VecDeque::new(),
+            inbound: VecDeque::new(),
+            to_client: VecDeque::new(),
+            received: Vec::new(),
+            client_closed: false,
+            server_closed: false,
+            readiness,
+        }));
+
+        let port = net.next_port;
+        net.next_port += 1;
+
+        net.links.push(link.clone());
+        net.backlog.push_back(SimStream {
+            link: link.clone(),
+            registration,
+            peer: SocketAddr::from(([10, 0, 0, 1], port)),
+        });
+        let _ = net.listener.set_readiness(Ready::readable());
+
+        SimClient {
+            link,
+            buf: RefCell::new(Vec::new()),
+        }
+    }
+
+    /// Move the virtual clock forward and deliver everything that has arrived by then.
+    pub fn advance(&self, ms: u64) {
+        let mut net = self.inner.borrow_mut();
+        net.now += ms;
+
+        let now = net.now;
+        for link in &net.links {
+            let mut link = link.borrow_mut();
+            link.now = now;
+            link.deliver();
+        }
+    }
+}
+
+/// The server side of the simulated network.
+pub struct SimListener {
+    net: Rc<RefCell<NetInner>>,
+    registration: Registration,
+}
+
+impl Listener for SimListener {
+    type Stream = SimStream;
+
+    fn accept(&self) -> io::Result<(SimStream, SocketAddr)> {
+        let mut net = self.net.borrow_mut();
+        match net.backlog.pop_front() {
+            Some(stream) => {
+                stream.link.borrow().update();
+                let peer = stream.peer;
+                Ok((stream, peer))
+            }
+            None => {
+                let _ = net.listener.set_readiness(Ready::empty());
+                Err(ErrorKind::WouldBlock.into())
+            }
+        }
+    }
+}
+
+impl Evented for SimListener {
+    fn register(&self, poll: &Poll, token: Token, interest: Ready, opts: PollOpt)
+        -> io::Result<()>
+    {
+        self.registration.register(poll, token, interest, opts)
+    }
+
+    fn reregister(&self, poll: &Poll, token: Token, interest: Ready, opts: PollOpt)
+        -> io::Result<()>
+    {
+        self.registration.reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        Evented::deregister(&self.registration, poll)
+    }
+}
+
+/// The server side of one simulated connection.
+pub struct SimStream {
+    link: Rc<RefCell<Link>>,
+    registration: Registration,
+    peer: SocketAddr,
+}
+
+impl Read for SimStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut link = self.link.borrow_mut();
+
+        if link.inbound.is_empty() {
+            if link.client_closed && link.to_server.is_empty() {
+                return Ok(0);
+            }
+            return Err(ErrorKind::WouldBlock.into());
+        }
+
+        let n = cmp::min(buf.len(), link.inbound.len());
+        for (i, b) in link.inbound.drain(..n).enumerate() {
+            buf[i] = b;
+        }
+
+        link.update();
+        Ok(n)
+    }
+}
+
+impl Write for SimStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut link = self.link.borrow_mut();
+
+        if link.client_closed {
+            return Err(ErrorKind::BrokenPipe.into());
+        }
+
+        let room = link.config.window.saturating_sub(link.in_flight_to_client());
+        if room == 0 {
+            link.update();
+            return Err(ErrorKind::WouldBlock.into());
+        }
+
+        let n = cmp::min(cmp::min(buf.len(), room), link.config.max_write);
+        let at = link.now + link.config.latency;
+        link.to_client.push_back((at, buf[..n].to_vec()));
+        link.deliver();
+
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Evented for SimStream {
+    fn register(&self, poll: &Poll, token: Token, interest: Ready, opts: PollOpt)
+        -> io::Result<()>
+    {
+        self.registration.register(poll, token, interest, opts)
+    }
+
+    fn reregister(&self, poll: &Poll, token: Token, interest: Ready, opts: PollOpt)
+        -> io::Result<()>
+    {
+        self.registration.reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        Evented::deregister(&self.registration, poll)
+    }
+}
+
+impl Transport for SimStream {
+    fn shutdown(&self, _how: Shutdown) -> io::Result<()> {
+        self.link.borrow_mut().server_closed = true;
+        Ok(())
+    }
+}
+
+impl Drop for SimStream {
+    fn drop(&mut self) {
+        self.link.borrow_mut().server_closed = true;
+    }
+}
+
+/// The test's handle on one simulated peer.
+pub struct SimClient {
+    link: Rc<RefCell<Link>>,
+
+    // bytes read off the link that do not yet form a complete frame
+    buf: RefCell<Vec<u8>>,
+}
+
+impl SimClient {
+    /// Send raw bytes to the server.
+    pub fn send(&self, bytes: &[u8]) {
+        let mut link = self.link.borrow_mut();
+        let at = link.now + link.config.latency;
+        link.to_server.push_back((at, bytes.to_vec()));
+        link.deliver();
+    }
+
+    /// Send a length prefixed frame to the server.
+    pub fn send_frame(&self, payload: &[u8]) {
+        self.send(&codec::encode(payload));
+    }
+
+    /// Take every complete frame the client has received, leaving any partial frame behind.
+    pub fn recv_frames(&self) -> Vec<Vec<u8>> {
+        let mut buf = self.buf.borrow_mut();
+        {
+            // Reading frees up window space, which may make the server side writable again.
+            let mut link = self.link.borrow_mut();
+            buf.append(&mut link.received);
+            link.update();
+        }
+
+        let mut frames = Vec::new();
+        while let Some((payload, used)) = codec::decode(&buf).map(|(p, n)| (p.to_vec(), n)) {
+            frames.push(payload);
+            buf.drain(..used);
+        }
+
+        frames
+    }
+
+    /// Disconnect abruptly. Bytes already sent still arrive before the FIN.
+    pub fn disconnect(&self) {
+        let mut link = self.link.borrow_mut();
+        link.client_closed = true;
+        link.update();
+    }
+
+    /// Whether the server has dropped this connection.
+    pub fn is_closed(&self) -> bool {
+        self.link.borrow().server_closed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::thread;
+    use std::time::Duration;
+
+    use mio::Poll;
+
+    use codec;
+    use server::{Capacities, Server};
+    use spill::Spill;
+
+    use super::{SimClient, SimConfig, SimListener, SimNet};
+
+    struct Sim {
+        net: SimNet,
+        server: Server<SimListener>,
+        poll: Poll,
+    }
+
+    impl Sim {
+        fn new(config: SimConfig) -> Sim {
+            let (net, listener) = SimNet::new(config);
+            let mut poll = Poll::new().unwrap();
+            let mut server = Server::new(listener);
+            server.register(&mut poll).unwrap();
+
+            Sim { net, server, poll }
+        }
+
+        /// Step the server until it has no more events to process.
+        fn settle(&mut self) {
+            for _ in 0..1000 {
+                let cnt = self.server.run_once(&mut self.poll, Some(Duration::from_millis(0)))
+                    .unwrap();
+                if cnt == 0 {
+                    return;
+                }
+            }
+            panic!("server did not settle");
+        }
+
+        fn connect(&mut self) -> SimClient {
+            let client = self.net.connect();
+            self.settle();
+            client
+        }
+
+        fn advance(&mut self, ms: u64) {
+            self.net.advance(ms);
+            self.settle();
+        }
+    }
+
+    #[test]
+    fn broadcast_reaches_every_client() {
+        let mut sim = Sim::new(SimConfig::default());
+        let a = sim.connect();
+        let b = sim.connect();
+        let c = sim.connect();
+        assert_eq!(sim.server.connection_count(), 3);
+
+        a.send_frame(b"hello");
+        sim.settle();
+
+        for client in &[&a, &b, &c] {
+            assert_eq!(client.recv_frames(), vec![b"hello".to_vec()]);
+        }
+    }
+
+    #[test]
+    fn latency_delays_delivery() {
+        let mut sim = Sim::new(SimConfig { latency: 10, ..SimConfig::default() });
+        let a = sim.connect();
+        let b = sim.connect();
+
+        a.send_frame(b"hello");
+        sim.advance(9);
+        assert!(b.recv_frames().is_empty());
+
+        // The frame reaches the server at 10ms and the broadcast reaches b at 20ms.
+        sim.advance(1);
+        assert!(b.recv_frames().is_empty());
+        sim.advance(10);
+        assert_eq!(b.recv_frames(), vec![b"hello".to_vec()]);
+    }
+
+    #[test]
+    fn partial_writes_deliver_intact_frames() {
+        // A short write of the 8 byte length header is still treated as an error by
+        // `Connection`, so only split payloads here.
+        let mut sim = Sim::new(SimConfig { max_write: 10, ..SimConfig::default() });
+        let a = sim.connect();
+        let b = sim.connect();
+
+        a.send_frame(b"the answer is 42");
+        a.send_frame(b"again");
+        sim.settle();
+
+        assert_eq!(b.recv_frames(), vec![b"the answer is 42".to_vec(), b"again".to_vec()]);
+    }
+
+    #[test]
+    fn slow_reader_receives_backlog_once_it_drains() {
+        let mut sim = Sim::new(SimConfig { window: 16, ..SimConfig::default() });
+        let a = sim.connect();
+        let b = sim.connect();
+
+        for i in 0..5 {
+            a.send_frame(format!("message {}", i).as_bytes());
+        }
+        sim.settle();
+
+        let mut got = Vec::new();
+        for _ in 0..20 {
+            got.extend(b.recv_frames());
+            sim.settle();
+        }
+
+        let expected: Vec<Vec<u8>> = (0..5)
+            .map(|i| format!("message {}", i).into_bytes())
+            .collect();
+        assert_eq!(got, expected);
+    }
+
+    #[test]
+    fn disconnect_removes_the_connection() {
+        let mut sim = Sim::new(SimConfig::default());
+        let a = sim.connect();
+        let b = sim.connect();
+
+        a.disconnect();
+        sim.settle();
+
+        assert!(a.is_closed());
+        assert!(!b.is_closed());
+        assert_eq!(sim.server.connection_count(), 1);
+    }
+
+    #[test]
+    fn disconnect_mid_frame_does_not_broadcast() {
+        let mut sim = Sim::new(SimConfig::default());
+        let a = sim.connect();
+        let b = sim.connect();
+
+        let mut partial = vec![0, 0, 0, 0, 0, 0, 0, 10];
+        partial.extend_from_slice(b"abc");
+        a.send(&partial);
+        a.disconnect();
+        sim.settle();
+
+        assert!(a.is_closed());
+        assert!(b.recv_frames().is_empty());
+
+        // The server keeps serving everyone else.
+        let c = sim.connect();
+        b.send_frame(b"still here");
+        sim.settle();
+        assert_eq!(c.recv_frames(), vec![b"still here".to_vec()]);
+    }
+
+    #[test]
+    fn slow_reader_is_closed_over_the_memory_limit() {
+        let mut sim = Sim::new(SimConfig { window: 16, ..SimConfig::default() });
+        let capacities = Capacities { write_batch: 64, ..Capacities::default() };
+        sim.server.set_capacities(capacities).unwrap();
+        sim.server.set_memory_limit(Some(1000));
+        let a = sim.connect();
+        let b = sim.connect();
+
+        // a keeps up, b reads nothing.
+        for _ in 0..20 {
+            a.send_frame(&[b'x'; 100]);
+            let mut got = Vec::new();
+            while got.is_empty() {
+                sim.settle();
+                got = a.recv_frames();
+            }
+        }
+        assert!(sim.server.memory_usage() > 1000);
+
+        thread::sleep(Duration::from_millis(150));
+        sim.settle();
+        assert!(sim.server.memory_usage() <= 1000);
+
+        // b gets what was already on its way, then the reason, and nothing more.
+        let mut got = Vec::new();
+        while !b.is_closed() {
+            got.extend(b.recv_frames());
+            sim.settle();
+        }
+        got.extend(b.recv_frames());
+        assert!(got.len() < 20);
+        assert_eq!(got.last().unwrap(), b"Too slow");
+
+        a.send_frame(b"still here");
+        let mut got = Vec::new();
+        while got.last().map(|f: &Vec<u8>| f != b"still here").unwrap_or(true) {
+            sim.settle();
+            got.extend(a.recv_frames());
+        }
+        assert!(!a.is_closed());
+    }
+
+    #[test]
+    fn senders_over_the_throughput_limit_wait_their_turn() {
+        let mut sim = Sim::new(SimConfig::default());
+        sim.server.set_throughput_limit(Some(1000));
+        let a = sim.connect();
+        let b = sim.connect();
+        let c = sim.connect();
+
+        // Each message is queued for three clients, so two fit in the first second.
+        for _ in 0..3 {
+            a.send_frame(&[b'a'; 200]);
+            b.send_frame(&[b'b'; 200]);
+        }
+        sim.settle();
+        assert_eq!(c.recv_frames().len(), 2);
+
+        // Once there is room again, whoever was paused first goes first.
+        let mut got = Vec::new();
+        while got.len() < 4 {
+            thread::sleep(Duration::from_millis(100));
+            sim.settle();
+            got.extend(c.recv_frames());
+        }
+        let senders: Vec<u8> = got.iter().map(|f| f[0]).collect();
+        assert_eq!(&senders[..2], b"ab");
+    }
+
+    #[test]
+    fn clients_over_the_high_watermark_are_told_what_they_missed() {
+        let mut sim = Sim::new(SimConfig { window: 16, ..SimConfig::default() });
+        sim.server.set_high_watermark(Some(300));
+        let a = sim.connect();
+        let b = sim.connect();
+
+        for _ in 0..10 {
+            a.send_frame(&[b'x'; 100]);
+        }
+        sim.settle();
+
+        let mut got = Vec::new();
+        for _ in 0..100 {
+            got.extend(b.recv_frames());
+            sim.settle();
+        }
+
+        // a reads nothing either, so it misses as many as b.
+        let delivery = sim.server.delivery_counts();
+        assert_eq!(delivery.queued + delivery.skipped, 20);
+        assert_eq!(delivery.failed, 0);
+
+        // What was queued, then the count of what was not.
+        let count = got.pop().unwrap();
+        assert_eq!(count.len(), 8);
+        let missed = u64::from_be_bytes([count[0], count[1], count[2], count[3],
+                                         count[4], count[5], count[6], count[7]]);
+        assert!(missed > 0);
+        assert_eq!(got.len() as u64 + missed, 10);
+        assert_eq!(delivery.skipped, missed * 2);
+        assert!(got.iter().all(|f| f == &[b'x'; 100]));
+
+        // Caught up, so it gets messages again.
+        a.send_frame(b"after");
+        let mut got = Vec::new();
+        for _ in 0..10 {
+            sim.settle();
+            got.extend(b.recv_frames());
+        }
+        assert_eq!(got, vec![b"after".to_vec()]);
+    }
+
+    #[test]
+    fn clients_over_the_high_watermark_spill_and_miss_nothing() {
+        let mut sim = Sim::new(SimConfig { window: 16, ..SimConfig::default() });
+        sim.server.set_high_watermark(Some(300));
+        sim.server.set_spill(Some(Spill { dir: env::temp_dir(), max_bytes: 1 << 20 }));
+        let a = sim.connect();
+        let b = sim.connect();
+
+        for i in 0..10u8 {
+            a.send_frame(&[i; 100]);
+        }
+        sim.settle();
+
+        let mut got = Vec::new();
+        for _ in 0..100 {
+            got.extend(b.recv_frames());
+            sim.settle();
+        }
+
+        // Everything arrives, in order, with no count of missed messages.
+        let delivery = sim.server.delivery_counts();
+        assert_eq!((delivery.queued, delivery.skipped, delivery.failed), (20, 0, 0));
+        assert_eq!(got, (0..10u8).map(|i| vec![i; 100]).collect::<Vec<_>>());
+
+        // Caught up, so the file is gone and what follows goes straight out.
+        a.send_frame(b"after");
+        let mut got = Vec::new();
+        for _ in 0..10 {
+            sim.settle();
+            got.extend(b.recv_frames());
+        }
+        assert_eq!(got, vec![b"after".to_vec()]);
+    }
+
+    #[test]
+    fn senders_get_a_receipt_for_each_message() {
+        let mut sim = Sim::new(SimConfig::default());
+        sim.server.set_receipts(true);
+        let a = sim.connect();
+        let b = sim.connect();
+        let c = sim.connect();
+
+        a.send_frame(b"one");
+        a.send_frame(b"two");
+        sim.settle();
+
+        let receipts: Vec<Vec<u8>> = a.recv_frames().into_iter()
+            .filter(|f| f.len() == 16)
+            .collect();
+        assert_eq!(receipts, vec![codec::encode_receipt(1, 3), codec::encode_receipt(2, 3)]);
+
+        // Nobody else gets them.
+        for client in &[&b, &c] {
+            assert_eq!(client.recv_frames(), vec![b"one".to_vec(), b"two".to_vec()]);
+        }
+    }
+
+    #[test]
+    fn a_fragmented_message_gets_one_receipt() {
+        let mut sim = Sim::new(SimConfig::default());
+        sim.server.set_receipts(true);
+        let a = sim.connect();
+        let b = sim.connect();
+        let _c = sim.connect();
+
+        let route = codec::Route::default();
+        let frames = codec::Endian::Big.encode_fragments(1, codec::DATA, route,
+                                                         b"hello, world", 14);
+        assert_eq!(frames.len(), 3);
+        for frame in &frames[..2] {
+            a.send(frame);
+        }
+        sim.settle();
+        assert!(a.recv_frames().is_empty());
+        assert!(b.recv_frames().is_empty());
+
+        a.send(&frames[2]);
+        sim.settle();
+
+        let receipts: Vec<Vec<u8>> = a.recv_frames().into_iter()
+            .filter(|f| f.len() == 16)
+            .collect();
+        assert_eq!(receipts, vec![codec::encode_receipt(3, 3)]);
+        assert_eq!(b.recv_frames().len(), 3);
+    }
+
+    #[test]
+    fn who_lists_every_client_asker_first() {
+        let mut sim = Sim::new(SimConfig::default());
+        let a = sim.connect();
+        let b = sim.connect();
+        let _c = sim.connect();
+
+        a.send(&codec::encode_control(codec::WHO));
+        b.send(&codec::encode_control(codec::WHO));
+        sim.settle();
+
+        let mut answers = Vec::new();
+        for client in &[&a, &b] {
+            let frames = client.recv_frames();
+            assert_eq!(frames.len(), 1);
+            assert_eq!(frames[0].len(), 3 * 8);
+            answers.push(frames[0].chunks(8).map(|id| id.to_vec()).collect::<Vec<_>>());
+        }
+
+        // Both see the same three clients, each listed first in its own answer.
+        assert_eq!(answers[0][1], answers[1][0]);
+        assert_eq!(answers[1][1], answers[0][0]);
+        assert_eq!(answers[0][2], answers[1][2]);
+    }
+
+    #[test]
+    fn new_clients_are_welcomed_first() {
+        let mut sim = Sim::new(SimConfig::default());
+        let welcome = codec::Welcome { motd: "hello".to_string(), ..codec::Welcome::default() };
+        sim.server.set_welcome(Some(welcome.clone()));
+
+        let a = sim.connect();
+        a.send_frame(b"one");
+        sim.settle();
+
+        assert_eq!(a.recv_frames(), vec![codec::encode_welcome(&welcome), b"one".to_vec()]);
+
+        // Connections made while it was off are not welcomed.
+        sim.server.set_welcome(None);
+        let b = sim.connect();
+        a.send_frame(b"two");
+        sim.settle();
+        assert_eq!(b.recv_frames(), vec![b"two".to_vec()]);
+    }
+
+    #[test]
+    fn greeting_lists_what_is_turned_on() {
+        let mut sim = Sim::new(SimConfig::default());
+        sim.server.set_greeting(true);
+        sim.server.set_receipts(true);
+        let welcome = codec::Welcome::default();
+        sim.server.set_welcome(Some(welcome.clone()));
+
+        let a = sim.connect();
+        sim.settle();
+
+        let capabilities = sim.server.capabilities();
+        assert!(capabilities.has_feature("receipts"));
+        assert!(capabilities.has_feature("welcome"));
+        assert!(!capabilities.has_feature("missed"));
+        assert_eq!(a.recv_frames(),
+                   vec![codec::encode_greeting(&capabilities), codec::encode_welcome(&welcome)]);
+    }
+}
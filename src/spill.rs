@@ -0,0 +1,127 @@
+//! Spilling broadcasts for a client that has fallen behind to disk, instead of skipping them.
+//!
+//! With a spill directory set, a client over the high watermark has the broadcasts it would
+//! have missed written to a file of its own, and read back in order as its socket drains. What
+//! it costs in memory stays bounded by the watermark, and it still gets every message, as long as
+//! it catches up before its file grows to `Spill::max_bytes`. See `Server::set_spill`.
+//!
+//! Each file is unlinked as soon as it is made, so nothing is left behind if the process dies,
+//! and is gone once it has all been read back.
+
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use codec;
+
+/// How many spill files have been made since the process started, to name the next one.
+static FILES: AtomicUsize = AtomicUsize::new(0);
+
+/// Where clients over the high watermark spill to, and how far.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Spill {
+    /// The directory spill files are made in.
+    pub dir: PathBuf,
+
+    /// The most bytes a client's file may grow to. Broadcasts that do not fit are skipped, as
+    /// they would be without a spill directory.
+    pub max_bytes: u64,
+}
+
+/// Frames spilled for one connection, in the order they were written.
+///
+/// Frames are kept as they go on the wire with big endian length headers, so they come back as
+/// the shared frames they went in as.
+pub struct SpillFile {
+    file: File,
+
+    // where the next frame is read from, and where the one after the last is written
+    read_pos: u64,
+    write_pos: u64,
+
+    // frames written and not yet read back
+    frames: usize,
+}
+
+impl SpillFile {
+    /// Make a new, empty spill file in `dir`.
+    pub fn create(dir: &Path) -> io::Result<SpillFile> {
+        let n = FILES.fetch_add(1, Ordering::SeqCst);
+        let path = dir.join(format!(".mob-spill-{}-{}", ::std::process::id(), n));
+        let file = OpenOptions::new().read(true).write(true).create_new(true).open(&path)?;
+        fs::remove_file(&path)?;
+        Ok(SpillFile { file, read_pos: 0, write_pos: 0, frames: 0 })
+    }
+
+    /// Write `frame`, header and all, after those already in the file.
+    pub fn push(&mut self, frame: &[u8]) -> io::Result<()> {
+        self.file.write_all_at(frame, self.write_pos)?;
+        self.write_pos += frame.len() as u64;
+        self.frames += 1;
+        Ok(())
+    }
+
+    /// Read back the frame written longest ago, if any are left.
+    pub fn pop(&mut self) -> io::Result<Option<Vec<u8>>> {
+        if self.frames == 0 {
+            return Ok(None);
+        }
+
+        let mut header = [0u8; codec::HEADER_LEN];
+        self.file.read_exact_at(&mut header, self.read_pos)?;
+        let mut frame = vec![0; codec::HEADER_LEN + codec::decode_len(&header) as usize];
+        self.file.read_exact_at(&mut frame, self.read_pos)?;
+        self.read_pos += frame.len() as u64;
+        self.frames -= 1;
+        Ok(Some(frame))
+    }
+
+    /// How many frames are waiting to be read back.
+    pub fn frames(&self) -> usize {
+        self.frames
+    }
+
+    /// How many bytes of frames are waiting to be read back.
+    pub fn unread_bytes(&self) -> u64 {
+        self.write_pos - self.read_pos
+    }
+
+    /// How big the file has grown, counting what has been read back already.
+    pub fn size(&self) -> u64 {
+        self.write_pos
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+
+    use codec;
+
+    use super::SpillFile;
+
+    #[test]
+    fn frames_come_back_in_order_and_leave_nothing_behind() {
+        let dir = env::temp_dir().join(format!("mob-spill-test-{}", ::std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let mut file = SpillFile::create(&dir).unwrap();
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+
+        let (first, second) = (codec::encode(b"first"), codec::encode(b"second"));
+        file.push(&first).unwrap();
+        file.push(&second).unwrap();
+        assert_eq!((file.frames(), file.size()), (2, (first.len() + second.len()) as u64));
+
+        assert_eq!(file.pop().unwrap(), Some(first));
+        assert_eq!(file.unread_bytes(), second.len() as u64);
+        assert_eq!(file.pop().unwrap(), Some(second));
+        assert_eq!(file.pop().unwrap(), None);
+        assert_eq!(file.frames(), 0);
+
+        fs::remove_dir(&dir).unwrap();
+    }
+}
@@ -0,0 +1,192 @@
+//! Supervisor mode, where one process runs the server as its child and restarts it if it dies.
+//!
+//! The supervisor binds the listening socket itself, and hands it down to every child it starts,
+//! with its file descriptor in `MOB_LISTEN_FD`. While a child that crashed is being replaced,
+//! new connections wait in the socket's backlog instead of being refused, so clients see a pause
+//! rather than an error. Connections the dead child had accepted are lost either way.
+//!
+//! A child that exits cleanly is not started again. Neither is one the supervisor was asked to
+//! stop: `SIGTERM` and `SIGINT` are passed on to the child, and the supervisor exits after it.
+//! A child that crashes is started again after a pause that doubles with each crash in a row, up
+//! to a limit, and goes back to the start once a child stays up long enough.
+
+use std::cmp;
+use std::env;
+use std::ffi::OsString;
+use std::io;
+use std::net::TcpListener;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::process::CommandExt;
+use std::process::{Command, ExitStatus};
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use libc;
+
+/// The environment variable a child finds the listening socket's file descriptor in.
+pub const LISTEN_FD: &str = "MOB_LISTEN_FD";
+
+/// The running child's process id, or zero between children, for signals to be passed on to.
+static CHILD: AtomicI32 = AtomicI32::new(0);
+
+/// Whether the supervisor was asked to stop, so the child is not started again.
+static STOPPING: AtomicBool = AtomicBool::new(false);
+
+/// How long to wait before starting a child that crashed again.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Backoff {
+    /// The wait after the first crash in a row.
+    pub initial: Duration,
+
+    /// The longest wait, however many crashes in a row there have been.
+    pub max: Duration,
+
+    /// How long a child has to stay up for its crash to count as the first in a row.
+    pub stable: Duration,
+}
+
+impl Default for Backoff {
+    fn default() -> Backoff {
+        Backoff {
+            initial: Duration::from_millis(100),
+            max: Duration::from_secs(30),
+            stable: Duration::from_secs(60),
+        }
+    }
+}
+
+/// The wait before each restart, given how long the child was up.
+struct Restarts {
+    backoff: Backoff,
+    next: Duration,
+}
+
+impl Restarts {
+    fn new(backoff: Backoff) -> Restarts {
+        Restarts { backoff, next: backoff.initial }
+    }
+
+    fn after(&mut self, up: Duration) -> Duration {
+        if up >= self.backoff.stable {
+            self.next = self.backoff.initial;
+        }
+        let wait = self.next;
+        self.next = cmp::min(self.next * 2, self.backoff.max);
+        wait
+    }
+}
+
+/// The listening socket a supervisor handed down, if this process is its child.
+pub fn inherited_listener() -> io::Result<Option<TcpListener>> {
+    let fd = match env::var(LISTEN_FD) {
+        Ok(fd) => fd,
+        Err(_) => return Ok(None),
+    };
+    let fd: RawFd = fd.parse().map_err(|_| {
+        let message = format!("{} is not a file descriptor", LISTEN_FD);
+        io::Error::new(io::ErrorKind::InvalidInput, message)
+    })?;
+
+    // Only one listener may own it.
+    env::remove_var(LISTEN_FD);
+    Ok(Some(unsafe { TcpListener::from_raw_fd(fd) }))
+}
+
+extern "C" fn pass_on(signal: libc::c_int) {
+    STOPPING.store(true, Ordering::SeqCst);
+    let child = CHILD.load(Ordering::SeqCst);
+    if child > 0 {
+        unsafe { libc::kill(child, signal) };
+    }
+}
+
+/// Pass `SIGTERM` and `SIGINT` on to the child, and stop supervising once it has gone.
+fn pass_signals_on() -> io::Result<()> {
+    let handler = pass_on as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    for &signal in &[libc::SIGTERM, libc::SIGINT] {
+        if unsafe { libc::signal(signal, handler) } == libc::SIG_ERR {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Start this program again with `args`, handing it `listener`.
+fn start_child(listener: &TcpListener, args: &[OsString]) -> io::Result<::std::process::Child> {
+    let fd = listener.as_raw_fd();
+    let mut command = Command::new(env::current_exe()?);
+    command.args(args).env(LISTEN_FD, fd.to_string());
+
+    // The socket is closed on exec unless the child is told otherwise.
+    unsafe {
+        command.pre_exec(move || {
+            if libc::fcntl(fd, libc::F_SETFD, 0) == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    command.spawn()
+}
+
+/// Run this program with `args` as a child that serves `listener`, and start it again whenever
+/// it crashes, waiting longer each time as `backoff` says.
+///
+/// Returns how the last child exited, once one exits cleanly or the supervisor is asked to stop.
+pub fn supervise(listener: &TcpListener, args: &[OsString], backoff: Backoff)
+    -> io::Result<ExitStatus>
+{
+    pass_signals_on()?;
+    let mut restarts = Restarts::new(backoff);
+
+    loop {
+        let mut child = start_child(listener, args)?;
+        CHILD.store(child.id() as i32, Ordering::SeqCst);
+        info!("supervising server; pid={}", child.id());
+
+        // Asked to stop before there was a child to pass it on to.
+        if STOPPING.load(Ordering::SeqCst) {
+            unsafe { libc::kill(child.id() as i32, libc::SIGTERM) };
+        }
+
+        let started = Instant::now();
+        let status = child.wait()?;
+        CHILD.store(0, Ordering::SeqCst);
+
+        if status.success() || STOPPING.load(Ordering::SeqCst) {
+            info!("server exited; status={}", status);
+            return Ok(status);
+        }
+
+        let wait = restarts.after(started.elapsed());
+        warn!("server crashed, starting it again; status={} wait={:?}", status, wait);
+        thread::sleep(wait);
+        if STOPPING.load(Ordering::SeqCst) {
+            return Ok(status);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{Backoff, Restarts};
+
+    #[test]
+    fn restarts_back_off_until_a_child_stays_up() {
+        let backoff = Backoff {
+            initial: Duration::from_millis(100),
+            max: Duration::from_millis(350),
+            stable: Duration::from_secs(10),
+        };
+        let mut restarts = Restarts::new(backoff);
+        let crash = Duration::from_secs(1);
+
+        let waits: Vec<_> = (0..4).map(|_| restarts.after(crash).as_millis()).collect();
+        assert_eq!(waits, vec![100, 200, 350, 350]);
+        assert_eq!(restarts.after(Duration::from_secs(10)), Duration::from_millis(100));
+        assert_eq!(restarts.after(crash), Duration::from_millis(200));
+    }
+}
@@ -0,0 +1,227 @@
+//! Sending log records to syslog, as RFC 5424 messages.
+//!
+//! Messages go to the local daemon over `/dev/log`, or over UDP to a collector. Either way each
+//! record is one datagram, so nothing is buffered and a daemon that is down only loses records.
+
+use std::ffi::CStr;
+use std::fmt;
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::os::unix::net::UnixDatagram;
+use std::path::Path;
+use std::process;
+use std::str::FromStr;
+use std::time::SystemTime;
+
+use libc;
+use log::LogLevel;
+
+use logging::timestamp;
+
+/// Where the local syslog daemon listens.
+pub const DEFAULT_SOCKET: &str = "/dev/log";
+
+/// What kind of program a message comes from, as syslog sorts them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum Facility {
+    Kern,
+    User,
+    Mail,
+    #[default]
+    Daemon,
+    Auth,
+    Syslog,
+    Lpr,
+    News,
+    Uucp,
+    Cron,
+    Authpriv,
+    Ftp,
+    Local(u8),
+}
+
+impl Facility {
+    fn code(self) -> u8 {
+        match self {
+            Facility::Kern => 0,
+            Facility::User => 1,
+            Facility::Mail => 2,
+            Facility::Daemon => 3,
+            Facility::Auth => 4,
+            Facility::Syslog => 5,
+            Facility::Lpr => 6,
+            Facility::News => 7,
+            Facility::Uucp => 8,
+            Facility::Cron => 9,
+            Facility::Authpriv => 10,
+            Facility::Ftp => 11,
+            Facility::Local(n) => 16 + n,
+        }
+    }
+}
+
+impl FromStr for Facility {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Facility, String> {
+        let facility = match s {
+            "kern" => Facility::Kern,
+            "user" => Facility::User,
+            "mail" => Facility::Mail,
+            "daemon" => Facility::Daemon,
+            "auth" => Facility::Auth,
+            "syslog" => Facility::Syslog,
+            "lpr" => Facility::Lpr,
+            "news" => Facility::News,
+            "uucp" => Facility::Uucp,
+            "cron" => Facility::Cron,
+            "authpriv" => Facility::Authpriv,
+            "ftp" => Facility::Ftp,
+            _ => match s.strip_prefix("local").and_then(|n| n.parse().ok()) {
+                Some(n) if n < 8 => Facility::Local(n),
+                _ => return Err(format!("unknown syslog facility {}", s)),
+            },
+        };
+        Ok(facility)
+    }
+}
+
+/// The syslog severity of a log level. Syslog has nothing below debug, so trace is debug too.
+fn severity(level: LogLevel) -> u8 {
+    match level {
+        LogLevel::Error => 3,
+        LogLevel::Warn => 4,
+        LogLevel::Info => 6,
+        LogLevel::Debug | LogLevel::Trace => 7,
+    }
+}
+
+enum Socket {
+    Unix(UnixDatagram),
+    Udp(UdpSocket),
+}
+
+/// A connection to a syslog daemon or collector.
+pub struct Syslog {
+    socket: Socket,
+    facility: Facility,
+
+    // the header fields that are the same for every message
+    hostname: String,
+    app_name: String,
+    pid: u32,
+}
+
+impl Syslog {
+    /// Send to the daemon listening on the unix datagram socket at `path`, usually
+    /// `DEFAULT_SOCKET`.
+    pub fn unix<P: AsRef<Path>>(path: P, facility: Facility) -> io::Result<Syslog> {
+        let socket = UnixDatagram::unbound()?;
+        socket.connect(path)?;
+        Ok(Syslog::new(Socket::Unix(socket), facility))
+    }
+
+    /// Send to the collector listening for UDP at `addr`.
+    pub fn udp(addr: SocketAddr, facility: Facility) -> io::Result<Syslog> {
+        let local = if addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+        let socket = UdpSocket::bind(local)?;
+        socket.connect(addr)?;
+        Ok(Syslog::new(Socket::Udp(socket), facility))
+    }
+
+    fn new(socket: Socket, facility: Facility) -> Syslog {
+        Syslog {
+            socket,
+            facility,
+            hostname: hostname().unwrap_or_else(|| "-".to_string()),
+            app_name: "mob".to_string(),
+            pid: process::id(),
+        }
+    }
+
+    /// Name the program messages come from something other than `mob`.
+    pub fn set_app_name(&mut self, name: &str) {
+        self.app_name = name.to_string();
+    }
+
+    /// Send one message at `level`, with the module it came from as its message id.
+    pub fn send(&self, level: LogLevel, module: &str, message: &fmt::Arguments)
+        -> io::Result<()>
+    {
+        let line = self.format(level, module, message, SystemTime::now());
+        match self.socket {
+            Socket::Unix(ref socket) => socket.send(line.as_bytes()),
+            Socket::Udp(ref socket) => socket.send(line.as_bytes()),
+        }.map(|_| ())
+    }
+
+    fn format(&self, level: LogLevel, module: &str, message: &fmt::Arguments, at: SystemTime)
+        -> String
+    {
+        let priority = self.facility.code() as u32 * 8 + severity(level) as u32;
+
+        // A message id is at most 32 printable characters.
+        let msgid: String = module.chars().filter(|c| c.is_ascii_graphic()).take(32).collect();
+        let msgid = if msgid.is_empty() { "-".to_string() } else { msgid };
+
+        format!("<{}>1 {} {} {} {} {} - {}",
+                priority, timestamp(at), self.hostname, self.app_name, self.pid, msgid, message)
+    }
+}
+
+/// The name of this host, if it has one.
+fn hostname() -> Option<String> {
+    let mut buf = [0 as libc::c_char; 256];
+    if unsafe { libc::gethostname(buf.as_mut_ptr(), buf.len()) } != 0 {
+        return None;
+    }
+    buf[buf.len() - 1] = 0;
+    let name = unsafe { CStr::from_ptr(buf.as_ptr()) }.to_string_lossy().into_owned();
+    if name.is_empty() { None } else { Some(name) }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::os::unix::net::UnixDatagram;
+    use std::process;
+
+    use log::LogLevel;
+
+    use super::{Facility, Syslog};
+
+    #[test]
+    fn facilities_parse_by_name() {
+        assert_eq!("daemon".parse(), Ok(Facility::Daemon));
+        assert_eq!("local7".parse(), Ok(Facility::Local(7)));
+        assert!("local8".parse::<Facility>().is_err());
+        assert!("kernel".parse::<Facility>().is_err());
+    }
+
+    #[test]
+    fn messages_are_rfc5424() {
+        let path = env::temp_dir().join(format!("mob-syslog-{}.sock", process::id()));
+        let _ = fs::remove_file(&path);
+        let daemon = UnixDatagram::bind(&path).unwrap();
+
+        let syslog = Syslog::unix(&path, Facility::Local(0)).unwrap();
+        syslog.send(LogLevel::Warn, "mob::server", &format_args!("slow {}", 1)).unwrap();
+
+        let mut buf = [0u8; 1024];
+        let n = daemon.recv(&mut buf).unwrap();
+        let message = String::from_utf8_lossy(&buf[..n]).into_owned();
+        let fields: Vec<&str> = message.splitn(8, ' ').collect();
+
+        // local0 is 16, and warning is 4.
+        assert_eq!(fields[0], "<132>1");
+        assert!(fields[1].ends_with('Z'));
+        assert_eq!(fields[3], "mob");
+        assert_eq!(fields[4], process::id().to_string());
+        assert_eq!(fields[5], "mob::server");
+        assert_eq!(fields[6], "-");
+        assert_eq!(fields[7], "slow 1");
+
+        let _ = fs::remove_file(&path);
+    }
+}
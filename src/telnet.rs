@@ -0,0 +1,125 @@
+//! Just enough of telnet to let `telnet host 8000` talk to a server in text framing.
+//!
+//! Telnet clients mix option negotiation into the byte stream, each command starting with an
+//! `IAC` byte. The server does not negotiate anything, so the commands are stripped out and the
+//! client carries on with its defaults. A literal 255 is sent as two `IAC` bytes.
+
+/// Interpret As Command, the byte every telnet command starts with.
+pub const IAC: u8 = 255;
+
+// the commands that are followed by an option byte
+const WILL: u8 = 251;
+const DONT: u8 = 254;
+
+// the start and end of a subnegotiation, which can run to any length
+const SB: u8 = 250;
+const SE: u8 = 240;
+
+/// Where the filter is in a command that may be split across reads.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum State {
+    Data,
+    Iac,
+    Option,
+    Sub,
+    SubIac,
+}
+
+/// Strips telnet commands out of a byte stream.
+#[derive(Debug)]
+pub struct Filter {
+    state: State,
+}
+
+impl Default for Filter {
+    fn default() -> Filter {
+        Filter { state: State::Data }
+    }
+}
+
+impl Filter {
+    pub fn new() -> Filter {
+        Filter::default()
+    }
+
+    /// Append the data bytes in `input` to `out`, leaving out any commands. A command cut off at
+    /// the end of `input` is finished off by the next call.
+    pub fn filter(&mut self, input: &[u8], out: &mut Vec<u8>) {
+        for &byte in input {
+            self.state = match (self.state, byte) {
+                (State::Data, IAC) => State::Iac,
+                (State::Data, _) => {
+                    out.push(byte);
+                    State::Data
+                }
+                (State::Iac, IAC) => {
+                    out.push(IAC);
+                    State::Data
+                }
+                (State::Iac, WILL..=DONT) => State::Option,
+                (State::Iac, SB) => State::Sub,
+                (State::Iac, _) | (State::Option, _) => State::Data,
+                (State::Sub, IAC) => State::SubIac,
+                (State::Sub, _) => State::Sub,
+                (State::SubIac, SE) => State::Data,
+                (State::SubIac, _) => State::Sub,
+            };
+        }
+    }
+}
+
+/// Encode `line` to be sent to a telnet client: any 255 byte doubled, and a CRLF on the end.
+pub fn encode_line(line: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(line.len() + 2);
+    for &byte in line {
+        if byte == IAC {
+            out.push(IAC);
+        }
+        out.push(byte);
+    }
+    out.extend_from_slice(b"\r\n");
+    out
+}
+
+/// The line a telnet client sent, without its line ending. Clients end lines with CRLF, but a
+/// plain LF is accepted too.
+pub fn decode_line(line: &[u8]) -> &[u8] {
+    let line = line.strip_suffix(b"\n").unwrap_or(line);
+    line.strip_suffix(b"\r").unwrap_or(line)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode_line, encode_line, Filter, IAC};
+
+    #[test]
+    fn strips_negotiation_split_across_reads() {
+        let mut filter = Filter::new();
+        let mut out = Vec::new();
+
+        // IAC DO ECHO, a subnegotiation with a doubled IAC inside it, and an escaped 255
+        filter.filter(&[b'h', IAC, 253], &mut out);
+        filter.filter(&[1, b'i', IAC, 250, 24, IAC, IAC, 0], &mut out);
+        filter.filter(&[IAC, 240, IAC, IAC, b'!'], &mut out);
+
+        assert_eq!(out, vec![b'h', b'i', IAC, b'!']);
+    }
+
+    #[test]
+    fn strips_two_byte_commands() {
+        let mut filter = Filter::new();
+        let mut out = Vec::new();
+
+        // IAC NOP and IAC AYT
+        filter.filter(&[IAC, 241, b'o', IAC, 246, b'k'], &mut out);
+        assert_eq!(out, b"ok".to_vec());
+    }
+
+    #[test]
+    fn lines_are_escaped_and_terminated() {
+        assert_eq!(encode_line(&[b'a', IAC]), vec![b'a', IAC, IAC, b'\r', b'\n']);
+        assert_eq!(decode_line(b"hello\r\n"), b"hello");
+        assert_eq!(decode_line(b"hello\n"), b"hello");
+        assert_eq!(decode_line(b"\r\n"), b"");
+    }
+}
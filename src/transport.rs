@@ -0,0 +1,228 @@
+//! The socket operations a `Connection` and `Server` depend on.
+//!
+//! `Connection` only needs to read, write, shutdown and register with the poller. `Server` only
+//! needs to accept from its listener. Keeping those operations behind traits lets the state
+//! machines run against something other than real sockets, such as the scripted `MockTransport`
+//! and the simulated network used in the tests.
+
+use std::io::{self, Error, ErrorKind, Read, Write};
+use std::net::{Shutdown, SocketAddr};
+
+use mio::Evented;
+use mio::net::{TcpListener, TcpStream};
+
+/// A non-blocking byte stream that can be registered with the poller.
+///
+/// Reads and writes are expected to follow the mio conventions: return `WouldBlock` when the
+/// operation cannot make progress and wait for the next readiness event.
+pub trait Transport: Read + Write + Evented {
+    /// Shut down the read, write, or both halves of the transport.
+    fn shutdown(&self, how: Shutdown) -> io::Result<()>;
+}
+
+impl Transport for TcpStream {
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        TcpStream::shutdown(self, how)
+    }
+}
+
+/// A non-blocking listener that hands out new transports.
+pub trait Listener: Evented {
+    /// The transport type of accepted connections.
+    type Stream: Transport;
+
+    /// Accept a new connection, returning `WouldBlock` when there is nothing left to accept.
+    fn accept(&self) -> io::Result<(Self::Stream, SocketAddr)>;
+
+    /// Start connecting to `addr` with the same kind of transport, for a server to federate
+    /// with. The connection completes in the background, like an accepted one that is not
+    /// writable yet. Listeners that cannot connect out say so.
+    fn connect(&self, addr: &SocketAddr) -> io::Result<Self::Stream> {
+        let _ = addr;
+        Err(Error::new(ErrorKind::Unsupported, "Listener cannot connect out"))
+    }
+}
+
+impl Listener for TcpListener {
+    type Stream = TcpStream;
+
+    fn accept(&self) -> io::Result<(TcpStream, SocketAddr)> {
+        TcpListener::accept(self)
+    }
+
+    fn connect(&self, addr: &SocketAddr) -> io::Result<TcpStream> {
+        TcpStream::connect(addr)
+    }
+}
+
+#[cfg(any(test, feature = "mock"))]
+pub mod mock {
+    //! A scripted transport for tests and benchmarks.
+    //!
+    //! Each call to `read` or `write` consumes the next scripted step, so tests can inject
+    //! `WouldBlock`, short reads/writes and errors at exactly the point they care about.
+
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+    use std::io::{self, ErrorKind, Read, Write};
+    use std::net::Shutdown;
+
+    use mio::{Evented, Poll, PollOpt, Ready, Token};
+
+    use super::Transport;
+
+    /// What the next `read` call should do.
+    pub enum ReadStep {
+        /// Return these bytes. If the caller's buffer is smaller, the rest is kept for the next
+        /// read.
+        Data(Vec<u8>),
+        /// Return `WouldBlock`.
+        WouldBlock,
+        /// Return end of stream.
+        Eof,
+        /// Return an error of the given kind.
+        Error(ErrorKind),
+    }
+
+    /// What the next `write` call should do.
+    pub enum WriteStep {
+        /// Accept up to this many bytes.
+        Accept(usize),
+        /// Return `WouldBlock`.
+        WouldBlock,
+        /// Return an error of the given kind.
+        Error(ErrorKind),
+    }
+
+    /// A transport that plays back scripted reads and writes, and records everything else done
+    /// to it.
+    #[derive(Default)]
+    pub struct MockTransport {
+        reads: VecDeque<ReadStep>,
+        writes: VecDeque<WriteStep>,
+
+        // every byte successfully written to the transport
+        pub written: Vec<u8>,
+
+        // shutdown calls in the order they were made
+        pub shutdowns: RefCell<Vec<Shutdown>>,
+
+        // the interest and options of every register and reregister call, in order
+        pub registrations: RefCell<Vec<(Ready, PollOpt)>>,
+    }
+
+    impl MockTransport {
+        pub fn new() -> MockTransport {
+            MockTransport::default()
+        }
+
+        pub fn push_read(&mut self, step: ReadStep) -> &mut MockTransport {
+            self.reads.push_back(step);
+            self
+        }
+
+        pub fn push_write(&mut self, step: WriteStep) -> &mut MockTransport {
+            self.writes.push_back(step);
+            self
+        }
+    }
+
+    impl Read for MockTransport {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.reads.pop_front() {
+                None | Some(ReadStep::WouldBlock) => Err(ErrorKind::WouldBlock.into()),
+                Some(ReadStep::Eof) => Ok(0),
+                Some(ReadStep::Error(kind)) => Err(kind.into()),
+                Some(ReadStep::Data(mut data)) => {
+                    let n = ::std::cmp::min(buf.len(), data.len());
+                    buf[..n].copy_from_slice(&data[..n]);
+                    if n < data.len() {
+                        let rest = data.split_off(n);
+                        self.reads.push_front(ReadStep::Data(rest));
+                    }
+                    Ok(n)
+                }
+            }
+        }
+    }
+
+    impl Write for MockTransport {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            // With nothing scripted the transport accepts everything.
+            match self.writes.pop_front() {
+                None => {
+                    self.written.extend_from_slice(buf);
+                    Ok(buf.len())
+                }
+                Some(WriteStep::Accept(max)) => {
+                    let n = ::std::cmp::min(max, buf.len());
+                    self.written.extend_from_slice(&buf[..n]);
+                    Ok(n)
+                }
+                Some(WriteStep::WouldBlock) => Err(ErrorKind::WouldBlock.into()),
+                Some(WriteStep::Error(kind)) => Err(kind.into()),
+            }
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Evented for MockTransport {
+        fn register(&self, _poll: &Poll, _token: Token, interest: Ready, opts: PollOpt)
+            -> io::Result<()>
+        {
+            self.registrations.borrow_mut().push((interest, opts));
+            Ok(())
+        }
+
+        fn reregister(&self, _poll: &Poll, _token: Token, interest: Ready, opts: PollOpt)
+            -> io::Result<()>
+        {
+            self.registrations.borrow_mut().push((interest, opts));
+            Ok(())
+        }
+
+        fn deregister(&self, _poll: &Poll) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Transport for MockTransport {
+        fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+            self.shutdowns.borrow_mut().push(how);
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use std::net::{self, SocketAddr};
+    use std::time::Duration;
+
+    use mio::net::TcpListener;
+
+    use super::Listener;
+
+    #[test]
+    fn outbound_connects_reach_either_family() {
+        for bind in &["127.0.0.1:0", "[::1]:0"] {
+            let listener = match net::TcpListener::bind(bind) {
+                Ok(listener) => listener,
+                // No IPv6 on this host.
+                Err(_) => continue,
+            };
+            let addr: SocketAddr = listener.local_addr().unwrap();
+            let connector = TcpListener::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+
+            let stream = connector.connect(&addr).unwrap();
+            let (mut accepted, _) = listener.accept().unwrap();
+            drop(stream);
+            accepted.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            assert_eq!(accepted.read(&mut [0u8; 1]).unwrap(), 0);
+        }
+    }
+}
@@ -0,0 +1,191 @@
+//! A watchdog for the poll loop.
+//!
+//! The loop is single threaded, so a handler that blocks or spins holds up every connection, and
+//! nothing in the loop is left to notice. With a watchdog, the loop beats a `Heartbeat` every
+//! tick and notes which event it is handling, and a thread of its own looks at it several times
+//! per threshold. When the loop has gone longer than the threshold without ticking, the thread
+//! logs an error saying which event it is stuck on and counts a stall in the metrics, once for
+//! each stall, and logs again once the loop is back.
+//!
+//! The loop ticks at least once a second even when there is nothing to do, so any threshold well
+//! over that only fires when something is wrong.
+
+use std::cmp;
+use std::io;
+use std::sync::{Arc, Weak};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use mio::Token;
+
+use metrics::Metrics;
+
+/// What `Heartbeat::event` holds between events.
+const NO_EVENT: usize = usize::MAX;
+
+/// How many times per threshold the watchdog looks at the heartbeat.
+const CHECKS_PER_THRESHOLD: u32 = 4;
+
+/// What the poll loop shares with its watchdog.
+#[derive(Debug)]
+pub struct Heartbeat {
+    start: Instant,
+
+    // milliseconds from `start` to the last beat
+    last: AtomicU64,
+
+    // the token of the event being handled, or `NO_EVENT`
+    event: AtomicUsize,
+}
+
+impl Default for Heartbeat {
+    fn default() -> Heartbeat {
+        Heartbeat {
+            start: Instant::now(),
+            last: AtomicU64::new(0),
+            event: AtomicUsize::new(NO_EVENT),
+        }
+    }
+}
+
+impl Heartbeat {
+    pub fn new() -> Heartbeat {
+        Heartbeat::default()
+    }
+
+    /// Say the loop is still going.
+    pub fn beat(&self) {
+        self.beat_at(Instant::now());
+    }
+
+    fn beat_at(&self, now: Instant) {
+        let millis = now.saturating_duration_since(self.start).as_millis() as u64;
+        self.last.store(millis, Ordering::Relaxed);
+    }
+
+    /// Say which event the loop is handling, or `None` once it is done with them.
+    pub fn handling(&self, token: Option<Token>) {
+        self.event.store(token.map(|t| t.0).unwrap_or(NO_EVENT), Ordering::Relaxed);
+    }
+
+    /// The event being handled, if there is one.
+    pub fn event(&self) -> Option<Token> {
+        match self.event.load(Ordering::Relaxed) {
+            NO_EVENT => None,
+            token => Some(Token(token)),
+        }
+    }
+
+    /// How long it has been since the last beat.
+    fn since(&self, now: Instant) -> Duration {
+        let last = self.start + Duration::from_millis(self.last.load(Ordering::Relaxed));
+        now.saturating_duration_since(last)
+    }
+}
+
+/// Whether the loop is stalled, and what to say when that changes.
+#[derive(Debug, PartialEq, Eq)]
+enum Change {
+    Stalled(Duration),
+    Recovered(Duration),
+}
+
+/// A heartbeat's state as the watchdog last saw it.
+struct Watch {
+    threshold: Duration,
+
+    // how long the stall had gone on when last looked at, if the loop is stalled
+    stalled: Option<Duration>,
+}
+
+impl Watch {
+    fn new(threshold: Duration) -> Watch {
+        Watch { threshold, stalled: None }
+    }
+
+    /// Look at `heartbeat`, returning what changed since last time.
+    fn check(&mut self, heartbeat: &Heartbeat, now: Instant) -> Option<Change> {
+        let since = heartbeat.since(now);
+        match self.stalled {
+            None if since > self.threshold => {
+                self.stalled = Some(since);
+                Some(Change::Stalled(since))
+            }
+            Some(longest) if since < longest => {
+                self.stalled = None;
+                Some(Change::Recovered(longest))
+            }
+            Some(_) => {
+                self.stalled = Some(since);
+                None
+            }
+            None => None,
+        }
+    }
+}
+
+/// Watch `heartbeat` on a thread of its own, logging and counting in `metrics` every time the
+/// loop of server `server` goes more than `threshold` without a beat. The thread finishes once
+/// the heartbeat is dropped.
+pub fn spawn(heartbeat: &Arc<Heartbeat>, threshold: Duration, metrics: Arc<Metrics>, server: u64)
+    -> io::Result<thread::JoinHandle<()>>
+{
+    let heartbeat: Weak<Heartbeat> = Arc::downgrade(heartbeat);
+    let interval = cmp::max(threshold / CHECKS_PER_THRESHOLD, Duration::from_millis(10));
+
+    thread::Builder::new().name("mob-watchdog".to_string()).spawn(move || {
+        let mut watch = Watch::new(threshold);
+        loop {
+            thread::sleep(interval);
+            let heartbeat = match heartbeat.upgrade() {
+                Some(heartbeat) => heartbeat,
+                None => return,
+            };
+
+            match watch.check(&heartbeat, Instant::now()) {
+                Some(Change::Stalled(since)) => {
+                    error!("poll loop stalled; server={:016x} since={:?} threshold={:?} \
+                            event={:?}", server, since, threshold, heartbeat.event());
+                    metrics.loop_stalls.inc();
+                }
+                Some(Change::Recovered(longest)) => {
+                    warn!("poll loop running again; server={:016x} stalled={:?}",
+                          server, longest);
+                }
+                None => {}
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use mio::Token;
+
+    use super::{Change, Heartbeat, Watch};
+
+    #[test]
+    fn a_stall_is_reported_once_and_then_its_end() {
+        let heartbeat = Heartbeat::new();
+        let start = heartbeat.start;
+        heartbeat.beat_at(start);
+        heartbeat.handling(Some(Token(3)));
+
+        let mut watch = Watch::new(Duration::from_secs(5));
+        let at = |secs| start + Duration::from_secs(secs);
+        assert_eq!(watch.check(&heartbeat, at(4)), None);
+        assert_eq!(watch.check(&heartbeat, at(6)), Some(Change::Stalled(Duration::from_secs(6))));
+        assert_eq!(watch.check(&heartbeat, at(9)), None);
+        assert_eq!(heartbeat.event(), Some(Token(3)));
+
+        heartbeat.beat_at(at(10));
+        heartbeat.handling(None);
+        assert_eq!(watch.check(&heartbeat, at(11)),
+                   Some(Change::Recovered(Duration::from_secs(9))));
+        assert_eq!(watch.check(&heartbeat, at(12)), None);
+        assert_eq!(heartbeat.event(), None);
+    }
+}
@@ -0,0 +1,84 @@
+//! Talks to the admin socket over TCP. It changes the process wide logger, so these tests live in
+//! their own binary, away from the other end-to-end tests.
+
+extern crate mob;
+
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::time::Duration;
+
+use mob::admin::{self, Admin, Role};
+use mob::logging::{self, Format, Output};
+
+#[test]
+fn loglevel_changes_the_filter_of_the_running_logger() {
+    let log = logging::init("warn".parse().unwrap(), Output::Stderr, Format::Plain).unwrap();
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let mut admin = Admin::new();
+    admin.set_log(Some(log.clone()));
+    admin::spawn(listener, admin).unwrap();
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut lines = BufReader::new(stream.try_clone().unwrap()).lines();
+
+    writeln!(stream, "loglevel trace mob::connection").unwrap();
+    assert_eq!(lines.next().unwrap().unwrap(), "ok warn,mob::connection=trace");
+    assert_eq!(log.filter().to_string(), "warn,mob::connection=trace");
+
+    writeln!(stream, "loglevel loud").unwrap();
+    assert_eq!(lines.next().unwrap().unwrap(), "error unknown log level loud");
+
+    writeln!(stream, "loglevel").unwrap();
+    assert_eq!(lines.next().unwrap().unwrap(), "ok warn,mob::connection=trace");
+}
+
+#[test]
+fn only_commands_the_secret_allows_are_run() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let mut admin = Admin::new();
+    admin.set_secret(Role::ReadOnly, Some("look".to_string()));
+    admin::spawn(listener, admin).unwrap();
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut lines = BufReader::new(stream.try_clone().unwrap()).lines();
+
+    writeln!(stream, "stats").unwrap();
+    assert_eq!(lines.next().unwrap().unwrap(), "error authenticate first, with auth <secret>");
+
+    for _ in 0..2 {
+        writeln!(stream, "auth guess").unwrap();
+        assert_eq!(lines.next().unwrap().unwrap(), "error wrong secret");
+    }
+    writeln!(stream, "auth look").unwrap();
+    assert_eq!(lines.next().unwrap().unwrap(), "ok read only");
+    writeln!(stream, "drain").unwrap();
+    assert_eq!(lines.next().unwrap().unwrap(), "error drain needs the operator role");
+
+    // A third wrong secret closes the connection, whatever came before.
+    writeln!(stream, "auth guess").unwrap();
+    assert_eq!(lines.next().unwrap().unwrap(), "error wrong secret");
+    assert!(lines.next().is_none());
+}
+
+#[test]
+fn lines_over_the_limit_are_refused_before_they_are_read() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let mut admin = Admin::new();
+    admin.set_secret(Role::Operator, Some("secret".to_string()));
+    admin::spawn(listener, admin).unwrap();
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut lines = BufReader::new(stream.try_clone().unwrap()).lines();
+
+    // One byte over the 16 KiB limit, which is as much as is read of it.
+    writeln!(stream, "auth {}", "x".repeat(16 * 1024 - 4)).unwrap();
+    assert_eq!(lines.next().unwrap().unwrap(), "error line too long, the most is 16384 bytes");
+    assert!(lines.next().is_none());
+}
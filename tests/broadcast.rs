@@ -0,0 +1,831 @@
+//! End-to-end tests that run a real `Server` on a loopback port and talk to it over TCP.
+
+extern crate mio;
+extern crate mob;
+extern crate mob_client;
+
+use std::env;
+use std::fs;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::{mpsc, Arc};
+use std::process;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use mio::Poll;
+
+use mob::admin::{self, Admin};
+use mob::codec;
+use mob::dump;
+use mob::connection::{Coalesce, Framing, KeepAlive, PollStrategy};
+use mob::filter::{Action, Filters};
+use mob::format::{self, Envelope, PayloadFormat};
+use mob::limit::AcceptLimit;
+use mob::server::{Capacities, Mode, Relay, Server, Settings};
+use mob::shard;
+
+/// Start a server on an ephemeral port in a background thread and return its address.
+///
+/// The server is not `Send`, so it is created inside the thread that runs it.
+fn start_server() -> SocketAddr {
+    start_server_with(|_| {})
+}
+
+/// Start a server like `start_server`, letting `configure` change its settings first.
+fn start_server_with<F>(configure: F) -> SocketAddr
+    where F: FnOnce(&mut Server) + Send + 'static
+{
+    let (tx, rx) = mpsc::channel();
+
+    thread::spawn(move || {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        tx.send(listener.local_addr().unwrap()).unwrap();
+
+        let mut poll = Poll::new().unwrap();
+        let mut server = Server::from_listener(listener).unwrap();
+        configure(&mut server);
+        server.run(&mut poll).unwrap();
+    });
+
+    rx.recv().unwrap()
+}
+
+fn connect(addr: SocketAddr) -> TcpStream {
+    let stream = TcpStream::connect(addr).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    stream
+}
+
+/// Connect a client and wait until the server has accepted it.
+///
+/// The server does not acknowledge new connections, so we round trip a message through the new
+/// client to know it is part of the broadcast group.
+fn join(addr: SocketAddr) -> TcpStream {
+    let mut stream = connect(addr);
+    write_frame(&mut stream, b"join");
+    assert_eq!(read_frame(&mut stream), b"join");
+    stream
+}
+
+fn header(len: usize) -> [u8; codec::HEADER_LEN] {
+    codec::encode_header(len)
+}
+
+fn write_frame(stream: &mut TcpStream, payload: &[u8]) {
+    stream.write_all(&header(payload.len())).unwrap();
+    stream.write_all(payload).unwrap();
+}
+
+fn read_frame(stream: &mut TcpStream) -> Vec<u8> {
+    let mut buf = [0u8; codec::HEADER_LEN];
+    stream.read_exact(&mut buf).unwrap();
+
+    let mut payload = vec![0u8; codec::decode_header(&buf) as usize];
+    stream.read_exact(&mut payload).unwrap();
+    payload
+}
+
+/// Read an `ERROR`, `CLOSE` or `NOTICE` frame and return its text.
+fn read_reason(stream: &mut TcpStream, kind: u8) -> Vec<u8> {
+    let mut header = [0u8; codec::HEADER_LEN];
+    stream.read_exact(&mut header).unwrap();
+    assert_eq!(codec::decode_kind(&header), kind);
+
+    let mut reason = vec![0u8; codec::decode_len(&header) as usize];
+    stream.read_exact(&mut reason).unwrap();
+    reason
+}
+
+/// Assert that the server closed the connection.
+fn assert_closed(stream: &mut TcpStream) {
+    let mut buf = [0u8; 1];
+    match stream.read(&mut buf) {
+        Ok(0) => {},
+        Err(ref e) if e.kind() == ErrorKind::ConnectionReset => {},
+        other => panic!("expected the connection to be closed, got {:?}", other),
+    }
+}
+
+#[test]
+fn broadcast_reaches_every_client() {
+    let addr = start_server();
+    let mut clients: Vec<TcpStream> = (0..4).map(|_| join(addr)).collect();
+
+    // Drain the join messages of clients that connected later.
+    for (i, client) in clients.iter_mut().enumerate() {
+        for _ in i + 1..4 {
+            assert_eq!(read_frame(client), b"join");
+        }
+    }
+
+    write_frame(&mut clients[2], b"hello everyone");
+
+    for client in clients.iter_mut() {
+        assert_eq!(read_frame(client), b"hello everyone");
+    }
+}
+
+#[test]
+fn messages_arrive_in_order() {
+    let addr = start_server();
+    let mut a = join(addr);
+    let mut b = join(addr);
+    assert_eq!(read_frame(&mut a), b"join");
+
+    for i in 0..100 {
+        write_frame(&mut a, format!("message {}", i).as_bytes());
+    }
+
+    for i in 0..100 {
+        let expected = format!("message {}", i).into_bytes();
+        assert_eq!(read_frame(&mut a), expected);
+        assert_eq!(read_frame(&mut b), expected);
+    }
+}
+
+#[test]
+fn frames_split_across_writes_are_reassembled() {
+    let addr = start_server();
+    let mut a = join(addr);
+    let mut b = join(addr);
+    assert_eq!(read_frame(&mut a), b"join");
+
+    a.set_nodelay(true).unwrap();
+
+    let payload = b"split across several packets";
+    let header = header(payload.len());
+
+    a.write_all(&header[..3]).unwrap();
+    thread::sleep(Duration::from_millis(20));
+    a.write_all(&header[3..]).unwrap();
+    thread::sleep(Duration::from_millis(20));
+    a.write_all(&payload[..10]).unwrap();
+    thread::sleep(Duration::from_millis(20));
+    a.write_all(&payload[10..]).unwrap();
+
+    assert_eq!(read_frame(&mut b), &payload[..]);
+}
+
+#[test]
+fn coalesced_frames_are_split() {
+    let addr = start_server();
+    let mut a = join(addr);
+    let mut b = join(addr);
+    assert_eq!(read_frame(&mut a), b"join");
+
+    let mut buf = Vec::new();
+    for payload in &[&b"one"[..], &b"two"[..], &b"three"[..]] {
+        buf.extend_from_slice(&header(payload.len()));
+        buf.extend_from_slice(payload);
+    }
+    a.write_all(&buf).unwrap();
+
+    assert_eq!(read_frame(&mut b), b"one");
+    assert_eq!(read_frame(&mut b), b"two");
+    assert_eq!(read_frame(&mut b), b"three");
+}
+
+#[test]
+fn disconnect_mid_frame_is_not_broadcast() {
+    let addr = start_server();
+    let mut a = join(addr);
+    let mut b = join(addr);
+    assert_eq!(read_frame(&mut a), b"join");
+
+    a.write_all(&header(100)).unwrap();
+    a.write_all(b"only part of it").unwrap();
+    drop(a);
+
+    // The truncated message is dropped and the server keeps serving everyone else.
+    let mut c = join(addr);
+    assert_eq!(read_frame(&mut b), b"join");
+
+    write_frame(&mut c, b"after");
+    assert_eq!(read_frame(&mut b), b"after");
+}
+
+#[test]
+fn server_full_closes_new_connections() {
+    let addr = start_server();
+
+    // The server holds 128 connections.
+    let mut clients: Vec<TcpStream> = (0..128).map(|_| connect(addr)).collect();
+    write_frame(&mut clients[127], b"full");
+    assert_eq!(read_frame(&mut clients[0]), b"full");
+
+    let mut rejected = connect(addr);
+    assert_closed(&mut rejected);
+
+    // Freeing a slot lets a new client in.
+    drop(clients.pop());
+    thread::sleep(Duration::from_millis(50));
+
+    let mut late = connect(addr);
+    write_frame(&mut late, b"room again");
+    assert_eq!(read_frame(&mut late), b"room again");
+    assert_eq!(read_frame(&mut clients[0]), b"room again");
+}
+
+#[test]
+fn echo_mode_only_answers_the_sender() {
+    let addr = start_server_with(|server| server.set_mode(Mode::Echo));
+    let mut first = join(addr);
+    let mut second = join(addr);
+
+    write_frame(&mut second, b"just me");
+    write_frame(&mut first, b"me too");
+
+    assert_eq!(read_frame(&mut second), b"just me");
+    assert_eq!(read_frame(&mut first), b"me too");
+
+    // Nothing else is waiting for either of them.
+    for stream in [&mut first, &mut second].iter_mut() {
+        stream.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+        let mut buf = [0u8; 1];
+        let e = stream.read(&mut buf).unwrap_err();
+        assert!(e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut, "{:?}", e);
+    }
+}
+
+#[test]
+fn connect_flood_is_banned() {
+    let addr = start_server_with(|server| {
+        server.set_accept_limit(Some(AcceptLimit { per_minute: 2, ban: Duration::from_secs(60) }));
+    });
+
+    let mut first = join(addr);
+    let _second = join(addr);
+    assert_eq!(read_frame(&mut first), b"join");
+
+    let mut third = connect(addr);
+    assert_closed(&mut third);
+
+    // Connections accepted before the ban are left alone.
+    write_frame(&mut first, b"still here");
+    assert_eq!(read_frame(&mut first), b"still here");
+}
+
+#[test]
+fn text_mode_rejects_binary_payloads() {
+    let addr = start_server_with(|server| server.set_text_only(true));
+    let mut sender = join(addr);
+    let mut other = join(addr);
+    assert_eq!(read_frame(&mut sender), b"join");
+
+    write_frame(&mut sender, b"\xff\xfe binary");
+    write_frame(&mut sender, b"text");
+
+    // The sender is told why, and nobody sees the binary message.
+    assert_eq!(read_reason(&mut sender, codec::ERROR), b"Payload is not valid UTF-8");
+
+    assert_eq!(read_frame(&mut sender), b"text");
+    assert_eq!(read_frame(&mut other), b"text");
+}
+
+#[test]
+fn payload_format_rejects_malformed_envelopes() {
+    let addr = start_server_with(|server| server.set_payload_format(format::by_name("json")));
+    let mut sender = connect(addr);
+    write_frame(&mut sender, br#"{"body":"join"}"#);
+    assert_eq!(read_frame(&mut sender), br#"{"body":"join"}"#);
+    let mut other = connect(addr);
+    write_frame(&mut other, br#"{"body":"join"}"#);
+    assert_eq!(read_frame(&mut other), br#"{"body":"join"}"#);
+    assert_eq!(read_frame(&mut sender), br#"{"body":"join"}"#);
+
+    write_frame(&mut sender, b"not json");
+    write_frame(&mut sender, br#"{"topic":"chat","body":"hi"}"#);
+
+    assert_eq!(read_reason(&mut sender, codec::ERROR), b"Malformed JSON envelope");
+    assert_eq!(read_frame(&mut sender), br#"{"topic":"chat","body":"hi"}"#);
+    assert_eq!(read_frame(&mut other), br#"{"topic":"chat","body":"hi"}"#);
+}
+
+/// A format that panics on `boom`, standing in for a bug a connection can trip.
+struct Explosive;
+
+impl PayloadFormat for Explosive {
+    fn name(&self) -> &'static str {
+        "explosive"
+    }
+
+    fn encode(&self, envelope: &Envelope) -> Vec<u8> {
+        envelope.body.clone()
+    }
+
+    fn decode(&self, payload: &[u8]) -> io::Result<Envelope> {
+        assert!(payload != b"boom", "boom");
+        Ok(Envelope { topic: String::new(), body: payload.to_vec() })
+    }
+}
+
+#[test]
+fn a_panic_only_closes_the_connection_it_was_for() {
+    let addr = start_server_with(|server| server.set_payload_format(Some(Arc::new(Explosive))));
+    let mut sender = join(addr);
+    let mut other = join(addr);
+    assert_eq!(read_frame(&mut sender), b"join");
+
+    write_frame(&mut sender, b"boom");
+    assert_closed(&mut sender);
+
+    let mut late = join(addr);
+    assert_eq!(read_frame(&mut other), b"join");
+    write_frame(&mut late, b"still here");
+    assert_eq!(read_frame(&mut other), b"still here");
+}
+
+#[test]
+fn filtered_messages_are_not_broadcast() {
+    let addr = start_server_with(|server| {
+        let mut filters = Filters::new();
+        filters.deny_substrings.push(b"spam".to_vec());
+        filters.max_len = Some(16);
+        filters.action = Action::Reject;
+        server.set_filters(filters);
+    });
+    let mut sender = join(addr);
+    let mut other = join(addr);
+    assert_eq!(read_frame(&mut sender), b"join");
+
+    write_frame(&mut sender, b"buy spam now");
+    write_frame(&mut sender, b"this one is far too long");
+    write_frame(&mut sender, b"fine");
+
+    assert_eq!(read_reason(&mut sender, codec::ERROR), b"Payload denied");
+    assert_eq!(read_reason(&mut sender, codec::ERROR), b"Payload too large");
+
+    assert_eq!(read_frame(&mut sender), b"fine");
+    assert_eq!(read_frame(&mut other), b"fine");
+}
+
+#[test]
+fn coalesced_broadcasts_still_arrive() {
+    let addr = start_server_with(|server| {
+        server.set_coalesce(Some(Coalesce { delay: Duration::from_millis(5), max_bytes: 1400 }));
+    });
+    let mut sender = join(addr);
+    let mut other = join(addr);
+    assert_eq!(read_frame(&mut sender), b"join");
+
+    for i in 0..10 {
+        write_frame(&mut sender, format!("tiny {}", i).as_bytes());
+    }
+
+    for i in 0..10 {
+        let expected = format!("tiny {}", i);
+        assert_eq!(read_frame(&mut sender), expected.as_bytes());
+        assert_eq!(read_frame(&mut other), expected.as_bytes());
+    }
+}
+
+#[test]
+fn messages_over_the_read_budget_are_read_next_tick() {
+    let addr = start_server_with(|server| server.set_read_budget(Some(1)));
+    let mut sender = join(addr);
+    let mut other = join(addr);
+    assert_eq!(read_frame(&mut sender), b"join");
+
+    // Sent in one write, so there is only the one readable event for all of them.
+    let mut buf = Vec::new();
+    for payload in &[&b"one"[..], &b"two"[..], &b"three"[..]] {
+        buf.extend_from_slice(&header(payload.len()));
+        buf.extend_from_slice(payload);
+    }
+    sender.write_all(&buf).unwrap();
+
+    assert_eq!(read_frame(&mut other), b"one");
+    assert_eq!(read_frame(&mut other), b"two");
+    assert_eq!(read_frame(&mut other), b"three");
+}
+
+#[test]
+fn broadcasts_work_with_every_poll_strategy() {
+    for &edge in &[true, false] {
+        for &oneshot in &[true, false] {
+            let strategy = PollStrategy { edge, oneshot };
+            let addr = start_server_with(move |server| server.set_poll_strategy(strategy));
+            let mut a = join(addr);
+            let mut b = join(addr);
+            assert_eq!(read_frame(&mut a), b"join");
+
+            write_frame(&mut a, b"one");
+            write_frame(&mut b, b"two");
+            for client in &mut [&mut a, &mut b] {
+                let mut got = vec![read_frame(client), read_frame(client)];
+                got.sort();
+                assert_eq!(got, vec![b"one".to_vec(), b"two".to_vec()], "{:?}", strategy);
+            }
+        }
+    }
+}
+
+#[test]
+fn connection_capacity_can_be_changed() {
+    let addr = start_server_with(|server| {
+        let capacities = Capacities { connections: 2, write_batch: 16, ..Capacities::default() };
+        server.set_capacities(capacities).unwrap();
+    });
+
+    let mut first = join(addr);
+    let _second = join(addr);
+    assert_eq!(read_frame(&mut first), b"join");
+
+    let mut rejected = connect(addr);
+    assert_closed(&mut rejected);
+
+    // Messages bigger than a write batch go out a piece at a time.
+    let big = vec![b'x'; 100];
+    write_frame(&mut first, &big);
+    assert_eq!(read_frame(&mut first), big);
+}
+
+#[test]
+fn half_closed_peer_still_gets_its_broadcasts() {
+    let addr = start_server();
+    let mut client = join(addr);
+
+    for i in 0..100 {
+        write_frame(&mut client, format!("message {}", i).as_bytes());
+    }
+    client.shutdown(Shutdown::Write).unwrap();
+
+    // Everything sent before hanging up comes back, then the server closes the connection.
+    for i in 0..100 {
+        assert_eq!(read_frame(&mut client), format!("message {}", i).as_bytes());
+    }
+    assert_closed(&mut client);
+}
+
+#[test]
+fn protocol_errors_are_explained_before_closing() {
+    let addr = start_server();
+    let mut observer = join(addr);
+    let mut client = join(addr);
+    assert_eq!(read_frame(&mut observer), b"join");
+
+    write_frame(&mut client, b"before");
+    client.write_all(&codec::encode_frame_header(0x7f, 0)).unwrap();
+    write_frame(&mut client, b"after");
+
+    // What was sent before the bad frame still arrives, then the reason, then the end.
+    assert_eq!(read_frame(&mut client), b"before");
+    assert_eq!(read_reason(&mut client, codec::CLOSE), b"Unknown frame kind");
+    assert_closed(&mut client);
+
+    assert_eq!(read_frame(&mut observer), b"before");
+    write_frame(&mut observer, b"still here");
+    assert_eq!(read_frame(&mut observer), b"still here");
+}
+
+#[test]
+fn telnet_clients_chat_in_text_framing() {
+    let addr = start_server_with(|server| server.set_framing(Framing::Text));
+    let mut a = connect(addr);
+    let mut b = connect(addr);
+
+    // Telnet opens with option negotiation, which the server ignores.
+    a.write_all(&[255, 253, 1, 255, 251, 31]).unwrap();
+    b.write_all(b"\r\n").unwrap();
+    thread::sleep(Duration::from_millis(100));
+
+    a.write_all(b"hello\r\nsecond ").unwrap();
+    a.write_all(b"line\n").unwrap();
+
+    let expected = b"hello\r\nsecond line\r\n";
+    for client in &mut [&mut a, &mut b] {
+        let mut buf = vec![0u8; expected.len()];
+        client.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf[..], &expected[..]);
+    }
+}
+
+/// Send `sync` from `from` until `to` sees something, because messages sent before a federated
+/// link is up are not passed on. Fails if the link is not up within 5 seconds.
+fn wait_for_link(from: &mut TcpStream, to: &mut TcpStream) {
+    to.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+    let mut byte = [0u8; 1];
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        write_frame(from, b"sync");
+        if to.peek(&mut byte).is_ok() {
+            break;
+        }
+        assert!(Instant::now() < deadline, "the servers did not link up within 5 seconds");
+    }
+    to.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+}
+
+/// Read the next frame that is not left over from `wait_for_link`.
+fn read_unsynced(stream: &mut TcpStream) -> Vec<u8> {
+    loop {
+        let payload = read_frame(stream);
+        if payload != b"sync" {
+            return payload;
+        }
+    }
+}
+
+/// Start a server that federates with `peers`, and takes federated connections, all with the
+/// same secret.
+fn start_federated(peers: Vec<SocketAddr>, configure: fn(&mut Server)) -> SocketAddr {
+    start_server_with(move |server| {
+        server.set_peers(peers);
+        server.set_peer_secret(Some("s3cret".to_string()));
+        configure(server);
+    })
+}
+
+/// Send the `PEER` frame a server introduces itself with.
+fn write_peer(stream: &mut TcpStream, secret: &[u8]) {
+    let hello = codec::encode_peer(1, secret);
+    stream.write_all(&codec::encode_frame_header(codec::PEER, hello.len())).unwrap();
+    stream.write_all(&hello).unwrap();
+}
+
+#[test]
+fn federated_servers_pass_messages_on_once() {
+    let hub = start_federated(Vec::new(), |_| {});
+    let left = start_federated(vec![hub], |_| {});
+    let right = start_federated(vec![hub], |_| {});
+
+    let mut h = connect(hub);
+    let mut l = connect(left);
+    let mut r = connect(right);
+    wait_for_link(&mut l, &mut h);
+    wait_for_link(&mut r, &mut h);
+
+    // Each message reaches every client once, and the next one follows straight after, so
+    // nothing came back around in between.
+    write_frame(&mut l, b"from the left");
+    for client in &mut [&mut h, &mut l, &mut r] {
+        assert_eq!(read_unsynced(client), b"from the left");
+    }
+
+    write_frame(&mut h, b"from the hub");
+    for client in &mut [&mut h, &mut l, &mut r] {
+        assert_eq!(read_unsynced(client), b"from the hub");
+    }
+
+    write_frame(&mut r, b"from the right");
+    for client in &mut [&mut h, &mut l, &mut r] {
+        assert_eq!(read_unsynced(client), b"from the right");
+    }
+}
+
+#[test]
+fn clients_without_the_peer_secret_are_not_federated_with() {
+    let addr = start_federated(Vec::new(), |_| {});
+    let mut client = join(addr);
+
+    let mut impostor = connect(addr);
+    write_peer(&mut impostor, b"guess");
+    assert_eq!(read_reason(&mut impostor, codec::CLOSE), b"Wrong peer secret");
+    assert_closed(&mut impostor);
+
+    // A server without a secret takes no federated connections at all.
+    let mut impostor = connect(start_server());
+    write_peer(&mut impostor, b"");
+    assert_eq!(read_reason(&mut impostor, codec::CLOSE), b"Not taking federated servers");
+    assert_closed(&mut impostor);
+
+    write_frame(&mut client, b"still here");
+    assert_eq!(read_frame(&mut client), b"still here");
+}
+
+#[test]
+fn forwarded_messages_are_checked_against_our_rules() {
+    let strict = start_federated(Vec::new(), |server| server.set_text_only(true));
+    let loose = start_federated(vec![strict], |_| {});
+
+    let mut s = connect(strict);
+    let mut l = connect(loose);
+    wait_for_link(&mut l, &mut s);
+
+    // The loose server broadcasts the binary message itself, but the strict one drops it
+    // without telling anyone.
+    write_frame(&mut l, b"\xff\xfe binary");
+    write_frame(&mut l, b"text");
+    assert_eq!(read_unsynced(&mut l), b"\xff\xfe binary");
+    assert_eq!(read_unsynced(&mut l), b"text");
+    assert_eq!(read_unsynced(&mut s), b"text");
+}
+
+#[test]
+fn relay_sends_messages_downstream_in_order() {
+    let downstream = start_server();
+    let mut consumer = join(downstream);
+
+    let relay = start_server_with(move |server| {
+        server.set_relay(Some(Relay { addr: downstream, connections: 2 }))
+    });
+    let mut producers: Vec<TcpStream> = (0..3).map(|_| connect(relay)).collect();
+
+    for (i, producer) in producers.iter_mut().enumerate() {
+        for n in 0..3 {
+            write_frame(producer, format!("{} {}", i, n).as_bytes());
+        }
+    }
+
+    // Producers share connections downstream, but each one's messages stay in order.
+    let mut next = [0; 3];
+    for _ in 0..9 {
+        let message = String::from_utf8(read_frame(&mut consumer)).unwrap();
+        let i: usize = message[..1].parse().unwrap();
+        assert_eq!(message, format!("{} {}", i, next[i]));
+        next[i] += 1;
+    }
+}
+
+#[test]
+fn announcements_reach_every_client() {
+    let (tx, rx) = mpsc::channel();
+    let addr = start_server_with(move |server| tx.send(server.announcer()).unwrap());
+    let announcer = rx.recv().unwrap();
+
+    let mut a = join(addr);
+    let mut b = join(addr);
+    assert_eq!(read_frame(&mut a), b"join");
+
+    announcer.announce("restarting in 5 minutes").unwrap();
+    assert_eq!(read_reason(&mut a, codec::NOTICE), b"restarting in 5 minutes");
+    assert_eq!(read_reason(&mut b, codec::NOTICE), b"restarting in 5 minutes");
+
+    write_frame(&mut a, b"still here");
+    assert_eq!(read_frame(&mut b), b"still here");
+}
+
+#[test]
+fn reconfigured_settings_apply_to_clients_already_connected() {
+    let (tx, rx) = mpsc::channel();
+    let addr = start_server_with(move |server| tx.send(server.announcer()).unwrap());
+    let announcer = rx.recv().unwrap();
+
+    let mut sender = join(addr);
+    let mut other = join(addr);
+    assert_eq!(read_frame(&mut sender), b"join");
+
+    // Both are handled in order, so the notice arrives once the new settings are in place.
+    announcer.reconfigure(Settings { text_only: true, ..Settings::default() }).unwrap();
+    announcer.announce("text only from now on").unwrap();
+    assert_eq!(read_reason(&mut sender, codec::NOTICE), b"text only from now on");
+    assert_eq!(read_reason(&mut other, codec::NOTICE), b"text only from now on");
+
+    write_frame(&mut sender, b"\xff\xfe binary");
+    write_frame(&mut sender, b"text");
+    assert_eq!(read_reason(&mut sender, codec::ERROR), b"Payload is not valid UTF-8");
+    assert_eq!(read_frame(&mut sender), b"text");
+    assert_eq!(read_frame(&mut other), b"text");
+}
+
+#[test]
+fn the_admin_console_lists_kicks_and_drains_connections() {
+    let (tx, rx) = mpsc::channel();
+    let addr = start_server_with(move |server| tx.send(server.announcer()).unwrap());
+    let admin = Admin::new();
+    admin.add_announcer(rx.recv().unwrap());
+
+    let mut kicked = join(addr);
+    let mut drained = join(addr);
+    assert_eq!(read_frame(&mut kicked), b"join");
+
+    let list = admin.execute("list");
+    let mut lines = list.lines();
+    assert_eq!(lines.next(), Some("ok 2 connections"));
+    let kicked_addr = kicked.local_addr().unwrap().to_string();
+    let line = lines.find(|line| line.contains(&kicked_addr)).expect(&list);
+    assert!(line.ends_with(" open read=1 queued=0/0"), "{}", line);
+
+    let id = line.split_whitespace().next().unwrap();
+    assert_eq!(admin.execute(&format!("kick {}", id)), format!("ok kicked {}", id));
+    assert_eq!(read_reason(&mut kicked, codec::CLOSE), b"Kicked");
+    assert_closed(&mut kicked);
+    assert_eq!(admin.execute("kick 0/99"), "error no connection 99");
+
+    assert_eq!(admin.execute("drain"), "ok draining 1 servers");
+    assert_eq!(read_reason(&mut drained, codec::CLOSE), b"Server draining");
+    assert_closed(&mut drained);
+    assert_closed(&mut connect(addr));
+    assert!(admin.execute("stats").ends_with(" draining"));
+}
+
+#[test]
+fn the_admin_console_exports_figures_for_each_connection() {
+    let (tx, rx) = mpsc::channel();
+    let addr = start_server_with(move |server| tx.send(server.announcer()).unwrap());
+    let admin = Admin::new();
+    admin.add_announcer(rx.recv().unwrap());
+
+    // The client's own message comes back to it, so one of each went each way.
+    let client = join(addr);
+    let client_addr = client.local_addr().unwrap().to_string();
+    let frame_len = codec::HEADER_LEN + 4;
+
+    let csv = admin.execute("export csv");
+    let mut lines = csv.lines();
+    assert_eq!(lines.next(), Some("ok 1 connections"));
+    assert_eq!(lines.next().map(str::trim), Some(admin::CSV_HEADER));
+    let row: Vec<&str> = lines.next().expect(&csv).trim().split(',').collect();
+    assert_eq!((row[0], row[2], row[3]), ("0", &*client_addr, "false"));
+    assert_eq!(row[5..].join(","), format!("{0},{0},1,1,0,0,0", frame_len));
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let http_addr = listener.local_addr().unwrap();
+    admin::spawn_http(listener, admin).unwrap();
+    let mut http = connect(http_addr);
+    write!(http, "GET /connections.json HTTP/1.1\r\nHost: mob\r\n\r\n").unwrap();
+    let mut response = String::new();
+    http.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n"),
+            "{}", response);
+    let expected = format!(r#""addr":"{}","peer":false,"#, client_addr);
+    assert!(response.contains(&expected), "{}", response);
+    assert!(response.contains(r#""messages_read":1,"frames_written":1,"#), "{}", response);
+}
+
+#[test]
+fn peers_that_miss_heartbeats_are_reset() {
+    let addr = start_server_with(|server| {
+        server.set_keep_alive(Some(KeepAlive { interval: Duration::from_secs(1), misses: 1 }));
+    });
+    let mut silent = join(addr);
+
+    // The client library answers PINGs while it waits in `recv`, so it outlives the silent one.
+    let mut client = mob_client::Client::connect(addr).unwrap();
+    client.send(b"join").unwrap();
+    assert_eq!(client.recv().unwrap(), Some(b"join".to_vec()));
+    assert_eq!(read_frame(&mut silent), b"join");
+    client.set_read_timeout(Some(Duration::from_secs(4))).unwrap();
+    assert_eq!(client.recv().unwrap_err().kind(), ErrorKind::TimedOut);
+
+    assert_eq!(read_reason(&mut silent, codec::PING), b"");
+    assert_closed(&mut silent);
+    client.send(b"still here").unwrap();
+    assert_eq!(client.recv().unwrap(), Some(b"still here".to_vec()));
+}
+
+#[test]
+fn clients_that_say_nothing_are_closed_after_the_handshake_timeout() {
+    let addr = start_server_with(|server| {
+        server.set_handshake_timeout(Some(Duration::from_secs(1)));
+    });
+    let mut talker = join(addr);
+    let mut silent = connect(addr);
+
+    assert_eq!(read_reason(&mut silent, codec::CLOSE), b"Read timed out");
+    assert_closed(&mut silent);
+
+    // Only the first message has a deadline.
+    thread::sleep(Duration::from_secs(2));
+    write_frame(&mut talker, b"still here");
+    assert_eq!(read_frame(&mut talker), b"still here");
+}
+
+#[test]
+fn a_requested_dump_describes_every_connection() {
+    let dir = env::temp_dir().join(format!("mob-dump-test-{}", process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let server_dir = dir.clone();
+    let addr = start_server_with(move |server| server.set_dump_dir(Some(server_dir.clone())));
+    let _a = join(addr);
+    let _b = join(addr);
+
+    dump::request();
+    let mut files = Vec::new();
+    for _ in 0..50 {
+        files = fs::read_dir(&dir).unwrap().map(|e| e.unwrap().path())
+            .filter(|p| p.extension().is_some_and(|ext| ext == "json"))
+            .collect::<Vec<_>>();
+        if !files.is_empty() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    assert_eq!(files.len(), 1, "{:?}", files);
+
+    let state = fs::read_to_string(&files[0]).unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+    assert!(state.starts_with("{\"server\":"), "{}", state);
+    assert!(state.contains("\"connections\":2,"), "{}", state);
+    assert_eq!(state.matches("\"interest\":").count(), 2, "{}", state);
+}
+
+#[test]
+fn broadcasts_reach_clients_of_every_shard() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || shard::run(listener, 3, |_| {}).unwrap());
+
+    let mut clients: Vec<TcpStream> = (0..4).map(|_| join(addr)).collect();
+    for (i, client) in clients.iter_mut().enumerate() {
+        for _ in i + 1..4 {
+            assert_eq!(read_frame(client), b"join");
+        }
+    }
+
+    write_frame(&mut clients[2], b"hello every shard");
+    for client in clients.iter_mut() {
+        assert_eq!(read_frame(client), b"hello every shard");
+    }
+}
@@ -0,0 +1,384 @@
+//! Tests for the `mob-client` library against a real `Server` on a loopback port.
+
+extern crate mio;
+extern crate mob;
+extern crate mob_client;
+
+use std::io::{ErrorKind, Write};
+use std::net::{SocketAddr, TcpListener};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use mio::Poll;
+
+use mob::server::Server;
+use mob_client::codec::{Endian, Frame, Route};
+use mob_client::{codec, Client, FailoverClient, Handler, Heartbeat, Timeouts};
+
+fn start_server() -> SocketAddr {
+    let (tx, rx) = mpsc::channel();
+
+    thread::spawn(move || {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        tx.send(listener.local_addr().unwrap()).unwrap();
+
+        let mut poll = Poll::new().unwrap();
+        let mut server = Server::from_listener(listener).unwrap();
+        server.run(&mut poll).unwrap();
+    });
+
+    rx.recv().unwrap()
+}
+
+/// Connect a client and round trip a message, so we know the server has accepted it.
+fn join(addr: SocketAddr) -> Client {
+    let mut client = Client::connect(addr).unwrap();
+    client.get_ref().set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    client.send(b"join").unwrap();
+    assert_eq!(client.recv().unwrap(), Some(b"join".to_vec()));
+    client
+}
+
+/// Call `try_recv` until it returns a message, or give up after a few seconds.
+fn poll_recv(client: &mut Client) -> Vec<u8> {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while Instant::now() < deadline {
+        if let Some(msg) = client.try_recv().unwrap() {
+            return msg;
+        }
+        thread::sleep(Duration::from_millis(5));
+    }
+    panic!("no message arrived");
+}
+
+#[test]
+fn send_is_received_by_every_client() {
+    let addr = start_server();
+    let mut a = join(addr);
+    let mut b = join(addr);
+    assert_eq!(a.recv().unwrap(), Some(b"join".to_vec()));
+
+    b.send(b"hello").unwrap();
+    assert_eq!(a.recv().unwrap(), Some(b"hello".to_vec()));
+    assert_eq!(b.recv().unwrap(), Some(b"hello".to_vec()));
+}
+
+#[test]
+fn try_recv_does_not_wait() {
+    let addr = start_server();
+    let mut a = join(addr);
+    let mut b = join(addr);
+    assert_eq!(a.recv().unwrap(), Some(b"join".to_vec()));
+
+    assert_eq!(a.try_recv().unwrap(), None);
+
+    b.send(b"later").unwrap();
+    assert_eq!(poll_recv(&mut a), b"later");
+    assert_eq!(a.try_recv().unwrap(), None);
+
+    // try_recv leaves the stream blocking for recv
+    b.send(b"blocking").unwrap();
+    assert_eq!(a.recv().unwrap(), Some(b"blocking".to_vec()));
+}
+
+#[test]
+fn recv_ends_after_shutdown() {
+    let addr = start_server();
+    let mut client = join(addr);
+
+    client.shutdown().unwrap();
+    assert_eq!(client.recv().unwrap(), None);
+}
+
+#[test]
+fn oversized_message_is_sent_in_fragments() {
+    let addr = start_server();
+    let mut a = join(addr);
+    let mut b = join(addr);
+    assert_eq!(a.recv().unwrap(), Some(b"join".to_vec()));
+
+    let big: Vec<u8> = (0..codec::MAX_PAYLOAD_LEN + 100).map(|i| i as u8).collect();
+    b.send(&big).unwrap();
+    b.send(b"after").unwrap();
+
+    assert_eq!(a.recv().unwrap(), Some(big));
+    assert_eq!(a.recv().unwrap(), Some(b"after".to_vec()));
+}
+
+#[derive(Debug, PartialEq)]
+enum Event {
+    Connect,
+    Message(Vec<u8>),
+    Disconnect,
+}
+
+/// Forwards every callback to a channel so the test can check what happened, and in what order.
+struct Recorder(mpsc::Sender<Event>);
+
+impl Handler for Recorder {
+    fn on_connect(&mut self, _addr: SocketAddr) {
+        self.0.send(Event::Connect).unwrap();
+    }
+
+    fn on_message(&mut self, msg: Vec<u8>) {
+        self.0.send(Event::Message(msg)).unwrap();
+    }
+
+    fn on_disconnect(&mut self) {
+        self.0.send(Event::Disconnect).unwrap();
+    }
+}
+
+#[test]
+fn handler_receives_every_event() {
+    let addr = start_server();
+    let (tx, rx) = mpsc::channel();
+    let mut dispatcher = join(addr).spawn(Recorder(tx)).unwrap();
+    let timeout = Duration::from_secs(5);
+
+    assert_eq!(rx.recv_timeout(timeout).unwrap(), Event::Connect);
+
+    dispatcher.send(b"pushed").unwrap();
+    assert_eq!(rx.recv_timeout(timeout).unwrap(), Event::Message(b"pushed".to_vec()));
+
+    dispatcher.shutdown().unwrap();
+    assert_eq!(rx.recv_timeout(timeout).unwrap(), Event::Disconnect);
+    dispatcher.join();
+}
+
+#[test]
+fn split_halves_work_from_different_threads() {
+    let addr = start_server();
+    let (outbox, inbox) = join(addr).split().unwrap();
+    let timeout = Duration::from_secs(5);
+
+    let sender = thread::spawn(move || {
+        for i in 0..100 {
+            outbox.send(format!("msg {}", i).as_bytes()).unwrap();
+        }
+        outbox
+    });
+
+    for i in 0..100 {
+        assert_eq!(inbox.recv_timeout(timeout).unwrap(), Some(format!("msg {}", i).into_bytes()));
+    }
+
+    // Hang up once everything has been echoed.
+    drop(sender.join().unwrap());
+
+    // Every outbox is gone, so the client hung up and the server closed the connection.
+    assert_eq!(inbox.recv().unwrap(), None);
+}
+
+#[test]
+fn split_inbox_does_not_hold_up_outbox() {
+    let addr = start_server();
+    let (outbox, inbox) = join(addr).split().unwrap();
+    let mut other = join(addr);
+
+    // Nothing is reading the inbox, but sending still goes through.
+    outbox.send(b"not blocked").unwrap();
+    assert_eq!(other.recv().unwrap(), Some(b"not blocked".to_vec()));
+    assert_eq!(inbox.try_recv().unwrap(), Some(b"join".to_vec()));
+}
+
+#[test]
+fn failover_client_moves_on_when_the_primary_hangs_up() {
+    // A primary that accepts and immediately hangs up on everyone.
+    let primary = TcpListener::bind("127.0.0.1:0").unwrap();
+    let primary_addr = primary.local_addr().unwrap();
+    thread::spawn(move || {
+        for stream in primary.incoming() {
+            drop(stream);
+        }
+    });
+    let secondary = start_server();
+
+    let mut client = FailoverClient::connect(vec![primary_addr, secondary]).unwrap();
+    assert_eq!(client.current(), Some(primary_addr));
+
+    // Keep sending until we land on the secondary and our message comes back.
+    let msg = loop {
+        client.send(b"anyone there").unwrap();
+        if client.current() == Some(secondary) {
+            break client.recv().unwrap();
+        }
+        thread::sleep(Duration::from_millis(10));
+    };
+    assert_eq!(msg, b"anyone there");
+}
+
+#[test]
+fn connect_races_past_an_address_that_refuses() {
+    let addr = start_server();
+    let refused = TcpListener::bind("[::1]:0").or_else(|_| TcpListener::bind("127.0.0.1:0"))
+        .unwrap().local_addr().unwrap();
+
+    let mut client = Client::connect(&[refused, addr][..]).unwrap();
+    assert_eq!(client.peer_addr().unwrap(), addr);
+    client.send(b"made it").unwrap();
+    assert_eq!(client.recv().unwrap(), Some(b"made it".to_vec()));
+}
+
+#[test]
+fn recv_times_out_and_keeps_partial_messages() {
+    // A server that sends half a frame, then the rest once told to.
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (tx, rx) = mpsc::channel::<()>();
+    thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let frame = codec::encode(b"eventually");
+        stream.write_all(&frame[..5]).unwrap();
+        rx.recv().unwrap();
+        stream.write_all(&frame[5..]).unwrap();
+        rx.recv().ok();
+    });
+
+    let timeouts = Timeouts { read: Some(Duration::from_millis(50)), ..Timeouts::default() };
+    let mut client = Client::connect_with(addr, timeouts).unwrap();
+
+    let e = client.recv().unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::TimedOut);
+
+    tx.send(()).unwrap();
+    assert_eq!(client.recv().unwrap(), Some(b"eventually".to_vec()));
+}
+
+#[test]
+fn heartbeat_keeps_a_quiet_connection_alive() {
+    let addr = start_server();
+    let mut client = join(addr);
+    client.set_read_timeout(Some(Duration::from_millis(300))).unwrap();
+    client.set_heartbeat(Some(Heartbeat {
+        interval: Duration::from_millis(20),
+        timeout: Duration::from_millis(200),
+    })).unwrap();
+
+    // The server answers every PING, so the only thing that ends the wait is the read timeout.
+    let e = client.recv().unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::TimedOut);
+
+    client.send(b"still alive").unwrap();
+    assert_eq!(client.recv().unwrap(), Some(b"still alive".to_vec()));
+}
+
+#[test]
+fn missed_heartbeat_aborts_the_connection() {
+    // A server that accepts and then never says anything.
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        let (_stream, _) = listener.accept().unwrap();
+        thread::sleep(Duration::from_secs(5));
+    });
+
+    let mut client = Client::connect(addr).unwrap();
+    client.set_heartbeat(Some(Heartbeat {
+        interval: Duration::from_millis(20),
+        timeout: Duration::from_millis(50),
+    })).unwrap();
+
+    let e = client.recv().unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::ConnectionAborted);
+}
+
+#[test]
+fn request_waits_for_its_reply() {
+    let addr = start_server();
+    let mut responder = join(addr);
+    let mut requester = join(addr);
+
+    let answering = thread::spawn(move || {
+        loop {
+            match responder.recv_frame().unwrap() {
+                Some(Frame::Request { id, payload }) => {
+                    let mut answer = b"re: ".to_vec();
+                    answer.extend_from_slice(&payload);
+                    responder.reply(id, &answer).unwrap();
+                    return responder;
+                }
+                Some(_) => {},
+                None => panic!("server closed the connection"),
+            }
+        }
+    });
+
+    assert_eq!(requester.request(b"ping?").unwrap(), b"re: ping?".to_vec());
+
+    // A broadcast sent after the request is still delivered, but the tagged frames are not.
+    let mut responder = answering.join().unwrap();
+    responder.send(b"plain").unwrap();
+    assert_eq!(requester.recv().unwrap(), Some(b"plain".to_vec()));
+}
+
+#[test]
+fn who_lists_connected_clients() {
+    let addr = start_server();
+    let mut a = join(addr);
+    let mut b = join(addr);
+
+    let seen_by_a = a.who().unwrap();
+    let seen_by_b = b.who().unwrap();
+    assert_eq!(seen_by_a.len(), 2);
+    assert_eq!(seen_by_b, vec![seen_by_a[1], seen_by_a[0]]);
+
+    // The broadcast that arrived while waiting for the answer is still delivered.
+    assert_eq!(a.recv().unwrap(), Some(b"join".to_vec()));
+}
+
+#[test]
+fn switching_byte_order_only_affects_that_connection() {
+    let addr = start_server();
+    let mut little = join(addr);
+    let mut big = join(addr);
+
+    little.switch_endian(Endian::Little).unwrap();
+    little.send(b"reversed").unwrap();
+
+    assert_eq!(big.recv().unwrap(), Some(b"reversed".to_vec()));
+    assert_eq!(little.recv().unwrap(), Some(b"join".to_vec()));
+    assert_eq!(little.recv().unwrap(), Some(b"reversed".to_vec()));
+}
+
+#[test]
+fn messages_come_back_on_the_stream_they_were_sent_on() {
+    let addr = start_server();
+    let mut a = join(addr);
+    let mut b = join(addr);
+    assert_eq!(a.recv().unwrap(), Some(b"join".to_vec()));
+
+    b.send_routed(b"chat", Route { priority: 0, stream: 1 }).unwrap();
+    b.send_routed(b"telemetry", Route { priority: 0, stream: 2 }).unwrap();
+
+    assert_eq!(a.recv().unwrap(), Some(b"chat".to_vec()));
+    assert_eq!(a.route().stream, 1);
+    assert_eq!(a.recv().unwrap(), Some(b"telemetry".to_vec()));
+    assert_eq!(a.route().stream, 2);
+}
+
+#[test]
+fn server_only_sends_what_it_has_credit_for() {
+    let addr = start_server();
+    let mut a = join(addr);
+    let mut b = join(addr);
+
+    // Once the server has answered, it has had the grant too.
+    a.grant_credit(1).unwrap();
+    a.who().unwrap();
+
+    for msg in &[&b"one"[..], b"two", b"three"] {
+        b.send(msg).unwrap();
+    }
+    for msg in &[&b"one"[..], b"two", b"three"] {
+        assert_eq!(b.recv().unwrap(), Some(msg.to_vec()));
+    }
+
+    // What was queued before the grant still arrives.
+    assert_eq!(a.recv().unwrap(), Some(b"join".to_vec()));
+    assert_eq!(a.recv().unwrap(), Some(b"one".to_vec()));
+
+    a.grant_credit(8).unwrap();
+    assert_eq!(a.recv_frame().unwrap(), Some(Frame::Missed(2)));
+}
@@ -0,0 +1,91 @@
+//! Runs a real `Server` with the file descriptor limit lowered. The limit belongs to the whole
+//! process, so these tests live in their own binary, away from the other end-to-end tests.
+
+extern crate libc;
+extern crate mio;
+extern crate mob;
+
+use std::fs::{self, File};
+use std::io::{ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use mio::Poll;
+
+use mob::codec;
+use mob::server::Server;
+
+fn start_server() -> SocketAddr {
+    let (tx, rx) = mpsc::channel();
+
+    thread::spawn(move || {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        tx.send(listener.local_addr().unwrap()).unwrap();
+
+        let mut poll = Poll::new().unwrap();
+        let mut server = Server::from_listener(listener).unwrap();
+        server.run(&mut poll).unwrap();
+    });
+
+    rx.recv().unwrap()
+}
+
+fn connect(addr: SocketAddr) -> TcpStream {
+    let stream = TcpStream::connect(addr).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    stream
+}
+
+fn write_frame(stream: &mut TcpStream, payload: &[u8]) {
+    stream.write_all(&codec::encode(payload)).unwrap();
+}
+
+fn read_frame(stream: &mut TcpStream) -> Vec<u8> {
+    let mut buf = [0u8; codec::HEADER_LEN];
+    stream.read_exact(&mut buf).unwrap();
+
+    let mut payload = vec![0u8; codec::decode_header(&buf) as usize];
+    stream.read_exact(&mut payload).unwrap();
+    payload
+}
+
+/// Lower the soft limit on open file descriptors for the whole process.
+fn set_fd_limit(limit: u64) {
+    let mut rlim = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+    assert_eq!(unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut rlim) }, 0);
+    rlim.rlim_cur = limit as libc::rlim_t;
+    assert_eq!(unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &rlim) }, 0);
+}
+
+#[test]
+fn connections_over_the_fd_limit_are_closed() {
+    let addr = start_server();
+    let mut fits = connect(addr);
+    write_frame(&mut fits, b"join");
+    assert_eq!(read_frame(&mut fits), b"join");
+
+    // Use up every file descriptor under a lower limit, then give one back for our end of the
+    // next connection.
+    let open = fs::read_dir("/proc/self/fd").unwrap().count();
+    set_fd_limit(open as u64 + 16);
+    let mut filler = Vec::new();
+    while let Ok(file) = File::open("/dev/null") {
+        filler.push(file);
+    }
+    filler.pop();
+
+    // The server has no descriptor left for this one, so it closes it instead of leaving it in
+    // the backlog.
+    let mut over = connect(addr);
+    let mut buf = [0u8; 1];
+    match over.read(&mut buf) {
+        Ok(0) => {},
+        Err(ref e) if e.kind() == ErrorKind::ConnectionReset => {},
+        other => panic!("expected the connection to be closed, got {:?}", other),
+    }
+
+    write_frame(&mut fits, b"still here");
+    assert_eq!(read_frame(&mut fits), b"still here");
+}